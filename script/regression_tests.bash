@@ -146,15 +146,15 @@ function with_http_proxy_inject {
 export RUST_BACKTRACE=1
 
 cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1500000-1500001
-cargo run --features regression_force_update_derived -- --main-schema custom_Main_Schema --index-all-contracts -l 1500002-1500005
+cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1500002-1500005
 # running one time with 'new' fake response fields injected, to verify que pasa doesn't break on unexpected new json fields
-with_http_proxy_inject cargo run --features regression_force_update_derived -- --main-schema custom_Main_Schema --index-all-contracts -l 1700002-1700005
+with_http_proxy_inject cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1700002-1700005
 
 # the latter has a delete bigmap, the first 3 have rows indexed of the deleted bigmap
-cargo run --features regression_force_update_derived -- --main-schema custom_Main_Schema --index-all-contracts -l 1768431
-cargo run --features regression_force_update_derived --  --main-schema custom_Main_Schema --index-all-contracts -l 1768503
-cargo run --features regression_force_update_derived -- --main-schema custom_Main_Schema --index-all-contracts -l 1768506
-cargo run --features regression_force_update_derived -- --main-schema custom_Main_Schema --index-all-contracts -l 1768606
+cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1768431
+cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1768503
+cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1768506
+cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1768606
 
 if [[ "$MODE" == "inspect" ]]; then
     psql
@@ -170,7 +170,8 @@ if [[ "$MODE" == "generate" ]]; then
     exit
 fi
 
-# verifying here that the repopulate also works with deleted bigmap rows
+# verifying here that re-processing a level (with deleted bigmap rows) leaves
+# the derived tables the same
 cargo run -- --main-schema custom_Main_Schema --index-all-contracts -l 1768606
 
 assert
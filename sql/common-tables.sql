@@ -31,7 +31,8 @@ CREATE TYPE indexer_mode AS ENUM (
 CREATE TABLE indexer_state (
     quepasa_version TEXT NOT NULL,
     max_id BIGINT NOT NULL,
-    mode indexer_mode NOT NULL
+    mode indexer_mode NOT NULL,
+    derived_tables_stale BOOLEAN NOT NULL DEFAULT false
 );
 INSERT INTO indexer_state (
     quepasa_version, max_id, mode
@@ -135,6 +136,47 @@ CREATE TABLE bigmap_keys(
     FOREIGN KEY (tx_context_id) REFERENCES tx_contexts(id) ON DELETE CASCADE
 );
 
+CREATE TABLE contract_requests (
+    name TEXT PRIMARY KEY,
+    address VARCHAR(100) NOT NULL,
+    status TEXT NOT NULL DEFAULT 'requested',
+    error TEXT,
+    requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE TABLE catalog_tables (
+    contract TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    parent_table TEXT,
+    parent_fk_column TEXT,
+    PRIMARY KEY (contract, table_name)
+);
+
+CREATE TABLE catalog_columns (
+    contract TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    column_name TEXT NOT NULL,
+    original_name TEXT NOT NULL,
+    michelson_type TEXT,
+    annotation TEXT,
+    is_index BOOLEAN NOT NULL,
+    PRIMARY KEY (contract, table_name, column_name)
+);
+
+-- the hash of each storage snapshot stored while skipping unchanged
+-- snapshots, and for the skipped ones the tx context of the snapshot they
+-- are the same as
+CREATE TABLE storage_snapshots (
+    tx_context_id BIGINT PRIMARY KEY REFERENCES tx_contexts(id) ON DELETE CASCADE,
+    hash TEXT NOT NULL,
+    source_tx_context_id BIGINT REFERENCES tx_contexts(id) ON DELETE RESTRICT
+);
+
+CREATE INDEX storage_snapshots_source ON storage_snapshots(source_tx_context_id);
+
 
 CREATE OR REPLACE FUNCTION "{main_schema}".last_context_at(lvl INT) RETURNS TABLE (tx_context_id BIGINT, level INT, operation_group_number INT, operation_number INT, content_number INT, internal_number INT)
 AS $$
@@ -0,0 +1,161 @@
+CREATE TABLE levels (
+    level INTEGER PRIMARY KEY,
+    hash VARCHAR(60),
+    prev_hash VARCHAR(60),
+    baked_at TIMESTAMP WITH TIME ZONE);
+
+CREATE UNIQUE INDEX levels_hash ON levels(hash);
+
+CREATE TABLE contracts (
+    name TEXT PRIMARY KEY,
+    address VARCHAR(100) NOT NULL,
+
+    UNIQUE(address)
+);
+
+CREATE TABLE contract_levels (
+    contract TEXT NOT NULL REFERENCES contracts(name) ON DELETE CASCADE,
+    level INTEGER NOT NULL,
+    is_origination BOOLEAN NOT NULL DEFAULT false,
+    PRIMARY KEY(contract, level)
+);
+
+CREATE INDEX contract_levels_level ON contract_levels(level);
+CREATE INDEX contract_levels_origination ON contract_levels(contract, is_origination);
+
+CREATE TABLE indexer_state (
+    quepasa_version TEXT NOT NULL,
+    max_id BIGINT NOT NULL,
+    mode TEXT NOT NULL CHECK (mode IN ('Bootstrap', 'Head')),
+    derived_tables_stale BOOLEAN NOT NULL DEFAULT false
+);
+INSERT INTO indexer_state (
+    quepasa_version, max_id, mode
+) VALUES (
+    '{quepasa_version}', 1, 'Bootstrap'
+);
+
+create table tx_contexts (
+    id bigint not null primary key,
+    level integer not null references levels(level) on delete cascade,
+    contract text not null,
+    operation_group_number integer not null,
+    operation_number integer not null,
+    content_number integer not null,
+    internal_number integer
+);
+
+CREATE UNIQUE INDEX tx_contexts_order ON tx_contexts(
+    level,
+    contract,
+    operation_group_number,
+    operation_number,
+    content_number,
+    coalesce(internal_number, -1)
+);
+
+CREATE TABLE txs (
+    id INTEGER PRIMARY KEY,
+    tx_context_id BIGINT NOT NULL REFERENCES tx_contexts(id) ON DELETE CASCADE,
+
+    operation_hash varchar(100) not null,
+    source VARCHAR(100) NOT NULL,
+    destination VARCHAR(100),
+    entrypoint VARCHAR(100),
+
+    amount TEXT,
+    fee BIGINT,
+    gas_limit BIGINT,
+    storage_limit BIGINT,
+
+    consumed_milligas BIGINT,
+    storage_size BIGINT,
+    paid_storage_size_diff BIGINT
+);
+
+CREATE UNIQUE INDEX txs_tx_context_id ON txs(tx_context_id);
+
+CREATE VIEW txs_ordered AS
+    SELECT
+        DENSE_RANK() OVER (
+            ORDER BY
+                ctx.level,
+                ctx.operation_group_number,
+                ctx.operation_number,
+                ctx.content_number,
+                coalesce(ctx.internal_number, -1)
+        ) ordering,
+        ctx.level,
+        meta.baked_at as level_timestamp,
+        tx.*
+    FROM txs tx
+    JOIN tx_contexts ctx
+      ON ctx.id = tx.tx_context_id
+    JOIN levels meta
+      ON meta.level = ctx.level
+    ORDER BY ordering;
+
+CREATE TABLE bigmap_meta_actions (
+    id INTEGER PRIMARY KEY,
+
+    tx_context_id BIGINT NOT NULL REFERENCES tx_contexts(id) ON DELETE CASCADE,
+    bigmap_id INT NOT NULL,
+
+    action TEXT NOT NULL,
+    value TEXT
+);
+
+CREATE INDEX bigmap_meta_actions_bigmap ON bigmap_meta_actions(bigmap_id, action, tx_context_id);
+CREATE INDEX bigmap_meta_actions_tx_context_id ON bigmap_meta_actions(tx_context_id);
+
+CREATE TABLE contract_deps (
+    level INT NOT NULL,
+
+    src_contract TEXT NOT NULL,
+    dest_schema TEXT NOT NULL,
+    is_deep_copy BOOLEAN NOT NULL DEFAULT true,
+
+    PRIMARY KEY (level, src_contract, dest_schema, is_deep_copy)
+);
+
+CREATE TABLE bigmap_keys(
+    id INTEGER PRIMARY KEY,
+    bigmap_id INTEGER NOT NULL,
+    tx_context_id BIGINT NOT NULL,
+    keyhash TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT,
+
+    UNIQUE(tx_context_id, bigmap_id, keyhash),
+    FOREIGN KEY (tx_context_id) REFERENCES tx_contexts(id) ON DELETE CASCADE
+);
+
+CREATE TABLE catalog_tables (
+    contract TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    parent_table TEXT,
+    parent_fk_column TEXT,
+    PRIMARY KEY (contract, table_name)
+);
+
+CREATE TABLE catalog_columns (
+    contract TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    column_name TEXT NOT NULL,
+    original_name TEXT NOT NULL,
+    michelson_type TEXT,
+    annotation TEXT,
+    is_index BOOLEAN NOT NULL,
+    PRIMARY KEY (contract, table_name, column_name)
+);
+
+CREATE TABLE contract_requests (
+    name TEXT PRIMARY KEY,
+    address VARCHAR(100) NOT NULL,
+    status TEXT NOT NULL DEFAULT 'requested',
+    error TEXT,
+    requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -11,7 +11,19 @@ AS $$
   WITH latest_context_id AS (
     SELECT
       ctx.id AS tx_context_id
-    FROM "{{ contract_schema }}"."{{ table }}" AS t
+    FROM (
+        SELECT tx_context_id
+        FROM "{{ contract_schema }}"."{{ table }}"
+      UNION ALL
+        -- unchanged snapshots that were stored as a reference (see
+        -- storage_snapshots)
+        SELECT snapshot.tx_context_id
+        FROM "{{ main_schema }}".storage_snapshots snapshot
+        WHERE snapshot.source_tx_context_id IN (
+          SELECT tx_context_id
+          FROM "{{ contract_schema }}"."{{ table }}"
+        )
+    ) AS t
     JOIN "{{ main_schema }}".tx_contexts ctx
       ON ctx.id = t.tx_context_id
     WHERE ARRAY[
@@ -33,5 +45,10 @@ AS $$
   SELECT
     {% call unfold(columns, "t", false) %}
   FROM "{{ contract_schema }}"."{{ table }}" AS t
-  WHERE t.tx_context_id = (SELECT tx_context_id FROM latest_context_id)
+  WHERE t.tx_context_id = (
+    SELECT COALESCE(snapshot.source_tx_context_id, latest.tx_context_id)
+    FROM latest_context_id latest
+    LEFT JOIN "{{ main_schema }}".storage_snapshots snapshot
+      ON snapshot.tx_context_id = latest.tx_context_id
+  )
 $$ LANGUAGE SQL;
@@ -7,6 +7,36 @@
     {%- endfor -%}
 {% endmacro %}
 
+{% macro snapshot_rows(from_table) %}(
+    SELECT
+        t.id,
+        t.tx_context_id
+        {% call unfold(columns, "t", true) %}
+    FROM "{{ contract_schema }}"."{{ from_table }}" t
+  UNION ALL
+    -- unchanged snapshots that were stored as a reference (see
+    -- storage_snapshots)
+    SELECT
+        t.id,
+        snapshot.tx_context_id
+        {% call unfold(columns, "t", true) %}
+    FROM "{{ contract_schema }}"."{{ from_table }}" t
+    JOIN "{{ main_schema }}".storage_snapshots snapshot
+      ON snapshot.source_tx_context_id = t.tx_context_id
+){% endmacro %}
+
+{% macro snapshot_contexts(from_table) %}(
+    SELECT tx_context_id
+    FROM "{{ contract_schema }}"."{{ from_table }}"
+  UNION ALL
+    SELECT snapshot.tx_context_id
+    FROM "{{ main_schema }}".storage_snapshots snapshot
+    WHERE snapshot.source_tx_context_id IN (
+      SELECT tx_context_id
+      FROM "{{ contract_schema }}"."{{ from_table }}"
+    )
+){% endmacro %}
+
 
 DELETE FROM "{{ contract_schema }}"."{{ table }}_live";
 INSERT INTO "{{ contract_schema }}"."{{ table }}_live" (
@@ -21,11 +51,11 @@ FROM (
         t.id,
         t.tx_context_id
         {% call unfold(columns, "t", true) %}
-    FROM "{{ contract_schema }}"."{{ table }}" t, (
+    FROM {% call snapshot_rows(table) %} t, (
       SELECT
         ctx.id,
         ctx.level
-      FROM "{{ contract_schema }}"."{{ parent_table }}" t
+      FROM {% call snapshot_contexts(parent_table) %} t
       JOIN "{{ main_schema }}".tx_contexts ctx
         ON ctx.id = t.tx_context_id
       ORDER BY
@@ -63,7 +93,7 @@ FROM (
         t.id,
         t.tx_context_id
         {% call unfold(columns, "t", true) %}
-    FROM "{{ contract_schema }}"."{{ table }}" t
+    FROM {% call snapshot_rows(table) %} t
     JOIN "{{ main_schema }}".tx_contexts ctx
       ON ctx.id = t.tx_context_id
     JOIN "{{ main_schema }}".levels level_meta
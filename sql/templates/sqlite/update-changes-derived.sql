@@ -0,0 +1,293 @@
+-- update based on newly processed tx contexts. they may be older than the
+-- ones processed before (eg when bootstrapping, levels are processed from
+-- new to old)
+
+{% macro unfold(column_names, from_table, sep_first) %}
+    {%- for col in column_names -%}
+        {%- if sep_first.clone() || !loop.first %}, {% endif -%}
+        {% if !from_table.is_empty() %}{{ from_table }}.{% endif %}{{ col }}
+    {%- endfor -%}
+{% endmacro %}
+
+
+CREATE INDEX IF NOT EXISTS "{{ contract_schema }}.{{ table }}_bykey"
+    ON "{{ contract_schema }}.{{ table }}"({% call unfold(indices, "", false) %});
+CREATE INDEX IF NOT EXISTS "{{ contract_schema }}.{{ table }}_live_bykey"
+    ON "{{ contract_schema }}.{{ table }}_live"({% call unfold(indices, "", false) %});
+CREATE INDEX IF NOT EXISTS "{{ contract_schema }}.{{ table }}_ordering"
+    ON "{{ contract_schema }}.{{ table }}_ordered"(level, ordering);
+
+
+DELETE FROM "{{ contract_schema }}.{{ table }}_live"
+WHERE bigmap_id IN (
+    SELECT
+        bigmap_id
+    FROM bigmap_meta_actions
+    WHERE tx_context_id in ({% call unfold(tx_context_ids, "", false) %})
+      AND action = 'clear'
+);
+
+-- The live rows of the keys changed in the batch are derived again from all
+-- of their changes, those of the batch may be older than what is live
+DROP TABLE IF EXISTS temp.changed_keys;
+CREATE TEMPORARY TABLE changed_keys AS
+SELECT DISTINCT
+    {% call unfold(indices, "t", false) %}
+FROM "{{ contract_schema }}.{{ table }}" t
+WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %});
+
+DELETE FROM "{{ contract_schema }}.{{ table }}_live" AS live
+WHERE EXISTS (
+    SELECT 1
+    FROM changed_keys
+    WHERE
+      {%- for idx in indices %}
+        {% if !loop.first %}AND {% endif -%}
+        changed_keys.{{ idx }} = live.{{ idx }}
+      {%- endfor %}
+);
+
+INSERT INTO "{{ contract_schema }}.{{ table }}_live" (
+    level, level_timestamp, id, tx_context_id, bigmap_id {% call unfold(columns, "", true) %}
+)
+SELECT
+    level,
+    level_timestamp,
+    id,
+    tx_context_id,
+    bigmap_id
+    {% call unfold(columns, "t", true) %}
+FROM (
+    SELECT
+        ROW_NUMBER() OVER (
+            PARTITION BY {% call unfold(indices, "t", false) %}
+            ORDER BY
+                ctx.level DESC,
+                ctx.operation_group_number DESC,
+                ctx.operation_number DESC,
+                ctx.content_number DESC,
+                COALESCE(ctx.internal_number, -1) DESC
+        ) AS key_version,
+        ctx.level AS level,
+        level_meta.baked_at AS level_timestamp,
+        t.*
+    FROM "{{ contract_schema }}.{{ table }}" t
+    JOIN changed_keys
+      ON
+        {%- for idx in indices %}
+          {% if !loop.first %}AND {% endif -%}
+          changed_keys.{{ idx }} = t.{{ idx }}
+        {%- endfor %}
+    JOIN tx_contexts ctx
+      ON ctx.id = t.tx_context_id
+    JOIN levels level_meta
+      ON level_meta.level = ctx.level
+    WHERE t.bigmap_id NOT IN (
+        SELECT bigmap_id FROM bigmap_meta_actions WHERE action = 'clear'
+    )
+) t
+WHERE t.key_version = 1
+  AND NOT t.deleted;
+
+
+-- The _ordered rows of all levels from the batch's first level with changes
+-- (or with a clear of a big map changed in the batch) up to its last are
+-- (re)derived. Because the ordering has no gaps, the rows on one side of
+-- these levels are shifted to make room, whichever side has the fewest rows.
+DROP TABLE IF EXISTS temp.ordered_window;
+CREATE TEMPORARY TABLE ordered_window AS
+SELECT
+    min(ctx.level) AS first_level,
+    max(ctx.level) AS last_level
+FROM tx_contexts ctx
+WHERE ctx.id IN (
+    SELECT
+        t.tx_context_id
+    FROM "{{ contract_schema }}.{{ table }}" t
+    WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
+  UNION ALL
+    SELECT
+        bigmap_meta.tx_context_id
+    FROM bigmap_meta_actions bigmap_meta
+    WHERE bigmap_meta.action = 'clear'
+      AND (
+        bigmap_meta.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
+        OR bigmap_meta.bigmap_id IN (
+            SELECT
+                t.bigmap_id
+            FROM "{{ contract_schema }}.{{ table }}" t
+            WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
+        )
+      )
+);
+
+DROP TABLE IF EXISTS temp.window_contexts;
+CREATE TEMPORARY TABLE window_contexts AS
+SELECT
+    ctx.id
+FROM tx_contexts ctx, ordered_window w
+WHERE ctx.level BETWEEN w.first_level AND w.last_level;
+
+DROP TABLE IF EXISTS temp.ordered_rows;
+CREATE TEMPORARY TABLE ordered_rows AS
+SELECT
+    DENSE_RANK() OVER (
+        ORDER BY
+            ctx.level,
+            ctx.operation_group_number,
+            ctx.operation_number,
+            ctx.content_number,
+            COALESCE(ctx.internal_number, -1)
+    ) AS ordering,
+    ctx.level AS level,
+    level_meta.baked_at AS level_timestamp,
+    t.id,
+    t.tx_context_id,
+    t.deleted
+    {% call unfold(columns, "t", true) %}
+FROM (
+    SELECT
+        t.tx_context_id,
+        t.id,
+        t.deleted
+        {% call unfold(columns, "t", true) %}
+    FROM "{{ contract_schema }}.{{ table }}" t
+    WHERE t.tx_context_id IN (SELECT id FROM window_contexts)
+
+    UNION ALL
+
+    SELECT
+        t.tx_context_id,
+        t.id,
+        TRUE AS deleted
+        {% call unfold(columns, "t", true) %}
+    FROM (
+        SELECT DISTINCT
+            bigmap_meta.tx_context_id,
+            LAST_VALUE(t.id) OVER w AS id,
+            LAST_VALUE(t.deleted) OVER w AS latest_deleted
+          {%- for col in columns %}
+            , LAST_VALUE(t.{{ col }}) OVER w AS {{ col }}
+          {%- endfor %}
+        FROM bigmap_meta_actions AS bigmap_meta
+        JOIN "{{ contract_schema }}.{{ table }}" t
+          ON t.bigmap_id = bigmap_meta.bigmap_id
+        JOIN tx_contexts ctx
+          ON ctx.id = t.tx_context_id
+        WHERE bigmap_meta.tx_context_id IN (SELECT id FROM window_contexts)
+          AND bigmap_meta.action = 'clear'
+        WINDOW w AS (
+            PARTITION BY {% call unfold(indices, "t", false) %}
+            ORDER BY
+                ctx.level,
+                ctx.operation_group_number,
+                ctx.operation_number,
+                ctx.content_number,
+                COALESCE(ctx.internal_number, -1)
+            ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING
+        )
+    ) t
+    LEFT JOIN "{{ contract_schema }}.{{ table }}" t2
+      ON  t2.tx_context_id = t.tx_context_id
+    {% for idx in indices %}
+      AND t.{{ idx }} = t2.{{ idx }}
+    {%- endfor %}
+    WHERE NOT t.latest_deleted
+      AND t2.id IS NULL
+) t  -- t with bigmap clears unfolded
+JOIN tx_contexts ctx
+  ON ctx.id = t.tx_context_id
+JOIN levels level_meta
+  ON level_meta.level = ctx.level;
+
+DROP TABLE IF EXISTS temp.ordered_shift;
+CREATE TEMPORARY TABLE ordered_shift AS
+SELECT
+    n_new - n_old AS delta,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window <= before_window - first_ordering
+        AS shift_after,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window > before_window - first_ordering
+        AS shift_before,
+    CASE
+        WHEN after_window IS NULL THEN COALESCE(before_window, 0)
+        WHEN before_window IS NULL THEN after_window - n_new - 1
+        WHEN last_ordering - after_window <= before_window - first_ordering
+            THEN before_window
+        ELSE after_window - n_new - 1
+    END AS ordering_offset
+FROM (
+    SELECT
+        (SELECT COALESCE(max(r.ordering), 0) FROM ordered_rows r) AS n_new,
+        (
+            SELECT count(DISTINCT o.ordering)
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o, ordered_window w
+            WHERE o.level BETWEEN w.first_level AND w.last_level
+        ) AS n_old,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o, ordered_window w
+            WHERE o.level < w.first_level
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS before_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o, ordered_window w
+            WHERE o.level > w.last_level
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS after_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS first_ordering,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS last_ordering
+) counts;
+
+UPDATE "{{ contract_schema }}.{{ table }}_ordered" AS o
+SET ordering = o.ordering + s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_after
+  AND s.delta <> 0
+  AND o.level > w.last_level;
+
+UPDATE "{{ contract_schema }}.{{ table }}_ordered" AS o
+SET ordering = o.ordering - s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_before
+  AND s.delta <> 0
+  AND o.level < w.first_level;
+
+DELETE FROM "{{ contract_schema }}.{{ table }}_ordered" AS o
+WHERE o.level BETWEEN (SELECT first_level FROM ordered_window)
+                  AND (SELECT last_level FROM ordered_window);
+
+INSERT INTO "{{ contract_schema }}.{{ table }}_ordered" (
+    ordering, level, level_timestamp, id, tx_context_id, deleted {% call unfold(columns, "", true) %}
+)
+SELECT
+    r.ordering + s.ordering_offset,
+    r.level,
+    r.level_timestamp,
+    r.id,
+    r.tx_context_id,
+    r.deleted
+    {% call unfold(columns, "r", true) %}
+FROM ordered_rows r, ordered_shift s;
+
+
+-- (temporary tables outlive the transaction in SQLite)
+DROP TABLE temp.changed_keys;
+DROP TABLE temp.ordered_window;
+DROP TABLE temp.window_contexts;
+DROP TABLE temp.ordered_rows;
+DROP TABLE temp.ordered_shift;
@@ -0,0 +1,185 @@
+-- update based on newly processed tx contexts. they may be older than the
+-- ones processed before (eg when bootstrapping, levels are processed from
+-- new to old)
+
+{% macro unfold(column_names, from_table, sep_first) %}
+    {%- for col in column_names -%}
+        {%- if sep_first.clone() || !loop.first %}, {% endif -%}
+        {% if !from_table.is_empty() %}{{ from_table }}.{% endif %}{{ col }}
+    {%- endfor -%}
+{% endmacro %}
+
+
+CREATE INDEX IF NOT EXISTS "{{ contract_schema }}.{{ table }}_ordering"
+    ON "{{ contract_schema }}.{{ table }}_ordered"(level, ordering);
+
+DROP TABLE IF EXISTS temp.batch_contexts;
+CREATE TEMPORARY TABLE batch_contexts AS
+SELECT
+    ctx.id
+FROM tx_contexts ctx
+WHERE ctx.id IN ({% call unfold(tx_context_ids, "", false) %});
+
+
+-- The _ordered rows of all levels from the batch's first level with rows in
+-- the table up to its last are (re)derived. Because the ordering has no
+-- gaps, the rows on one side of these levels are shifted to make room,
+-- whichever side has the fewest rows.
+DROP TABLE IF EXISTS temp.ordered_window;
+CREATE TEMPORARY TABLE ordered_window AS
+SELECT
+    min(ctx.level) AS first_level,
+    max(ctx.level) AS last_level
+FROM "{{ contract_schema }}.{{ table }}" t
+JOIN tx_contexts ctx
+  ON ctx.id = t.tx_context_id
+WHERE t.tx_context_id IN (SELECT id FROM batch_contexts);
+
+DROP TABLE IF EXISTS temp.ordered_rows;
+CREATE TEMPORARY TABLE ordered_rows AS
+SELECT
+    DENSE_RANK() OVER (
+        ORDER BY
+            ctx.level,
+            ctx.operation_group_number,
+            ctx.operation_number,
+            ctx.content_number,
+            COALESCE(ctx.internal_number, -1)
+    ) AS ordering,
+    ctx.level AS level,
+    level_meta.baked_at AS level_timestamp,
+    t.id,
+    t.tx_context_id
+    {% call unfold(columns, "t", true) %}
+FROM "{{ contract_schema }}.{{ table }}" t
+JOIN tx_contexts ctx
+  ON ctx.id = t.tx_context_id
+JOIN levels level_meta
+  ON level_meta.level = ctx.level
+JOIN ordered_window w
+  ON ctx.level BETWEEN w.first_level AND w.last_level;
+
+DROP TABLE IF EXISTS temp.ordered_shift;
+CREATE TEMPORARY TABLE ordered_shift AS
+SELECT
+    n_new - n_old AS delta,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window <= before_window - first_ordering
+        AS shift_after,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window > before_window - first_ordering
+        AS shift_before,
+    CASE
+        WHEN after_window IS NULL THEN COALESCE(before_window, 0)
+        WHEN before_window IS NULL THEN after_window - n_new - 1
+        WHEN last_ordering - after_window <= before_window - first_ordering
+            THEN before_window
+        ELSE after_window - n_new - 1
+    END AS ordering_offset
+FROM (
+    SELECT
+        (SELECT COALESCE(max(r.ordering), 0) FROM ordered_rows r) AS n_new,
+        (
+            SELECT count(DISTINCT o.ordering)
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o, ordered_window w
+            WHERE o.level BETWEEN w.first_level AND w.last_level
+        ) AS n_old,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o, ordered_window w
+            WHERE o.level < w.first_level
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS before_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o, ordered_window w
+            WHERE o.level > w.last_level
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS after_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS first_ordering,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}.{{ table }}_ordered" o
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS last_ordering
+) counts;
+
+UPDATE "{{ contract_schema }}.{{ table }}_ordered" AS o
+SET ordering = o.ordering + s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_after
+  AND s.delta <> 0
+  AND o.level > w.last_level;
+
+UPDATE "{{ contract_schema }}.{{ table }}_ordered" AS o
+SET ordering = o.ordering - s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_before
+  AND s.delta <> 0
+  AND o.level < w.first_level;
+
+DELETE FROM "{{ contract_schema }}.{{ table }}_ordered" AS o
+WHERE o.level BETWEEN (SELECT first_level FROM ordered_window)
+                  AND (SELECT last_level FROM ordered_window);
+
+INSERT INTO "{{ contract_schema }}.{{ table }}_ordered" (
+    ordering, level, level_timestamp, id, tx_context_id {% call unfold(columns, "", true) %}
+)
+SELECT
+    r.ordering + s.ordering_offset,
+    r.level,
+    r.level_timestamp,
+    r.id,
+    r.tx_context_id
+    {% call unfold(columns, "r", true) %}
+FROM ordered_rows r, ordered_shift s;
+
+
+-- The parent table's _ordered table is up to date at this point (see
+-- SqliteClient::update_derived_tables). Only if its last tx context is one
+-- of the batch, the _live table changes.
+DROP TABLE IF EXISTS temp.live_context;
+CREATE TEMPORARY TABLE live_context AS
+SELECT
+    o.tx_context_id AS id,
+    o.level
+FROM "{{ contract_schema }}.{{ parent_table }}_ordered" o
+ORDER BY o.level DESC, o.ordering DESC
+LIMIT 1;
+
+DELETE FROM live_context
+WHERE id NOT IN (SELECT id FROM batch_contexts);
+
+DELETE FROM "{{ contract_schema }}.{{ table }}_live"
+WHERE EXISTS (SELECT 1 FROM live_context);
+
+INSERT INTO "{{ contract_schema }}.{{ table }}_live" (
+    level, level_timestamp, id, tx_context_id {% call unfold(columns, "", true) %}
+)
+SELECT
+    last_ctx.level AS level,
+    level_meta.baked_at AS level_timestamp,
+    t.id,
+    t.tx_context_id
+    {% call unfold(columns, "t", true) %}
+FROM "{{ contract_schema }}.{{ table }}" t
+JOIN live_context last_ctx
+  ON last_ctx.id = t.tx_context_id
+JOIN levels level_meta
+  ON level_meta.level = last_ctx.level;
+
+
+-- (temporary tables outlive the transaction in SQLite)
+DROP TABLE temp.batch_contexts;
+DROP TABLE temp.ordered_window;
+DROP TABLE temp.ordered_rows;
+DROP TABLE temp.ordered_shift;
+DROP TABLE temp.live_context;
@@ -1,4 +1,6 @@
--- update based on newly processed block (note: _must_ be a *newer* block)
+-- update based on newly processed tx contexts. they may be older than the
+-- ones processed before (eg when bootstrapping, levels are processed from
+-- new to old)
 
 {% macro unfold(column_names, from_table, sep_first) %}
     {%- for col in column_names -%}
@@ -8,6 +10,14 @@
 {% endmacro %}
 
 
+CREATE INDEX IF NOT EXISTS "{{ table }}_keys"
+    ON "{{ contract_schema }}"."{{ table }}"({% call unfold(indices, "", false) %});
+CREATE INDEX IF NOT EXISTS "{{ table }}_livekeys"
+    ON "{{ contract_schema }}"."{{ table }}_live"({% call unfold(indices, "", false) %});
+CREATE INDEX IF NOT EXISTS "{{ table }}_ordering"
+    ON "{{ contract_schema }}"."{{ table }}_ordered"(level, ordering);
+
+
 DELETE FROM "{{ contract_schema }}"."{{ table }}_live"
 WHERE bigmap_id IN (
     SELECT
@@ -17,27 +27,25 @@ WHERE bigmap_id IN (
       AND action = 'clear'
 );
 
-DELETE FROM "{{ contract_schema }}"."{{ table }}_live"
-WHERE id IN (
-    SELECT
-        live.id
-    FROM (
-        SELECT DISTINCT
-            {% call unfold(indices, "t", false) %}
-        FROM "{{ contract_schema }}"."{{ table }}" t
-        JOIN "{{ main_schema }}".tx_contexts ctx
-          ON ctx.id = t.tx_context_id
-        JOIN "{{ main_schema }}".levels level_meta
-          ON level_meta.level = ctx.level
-        WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
-    ) as overwritten_indices
-    JOIN "{{ contract_schema }}"."{{ table }}_live" live
-      ON
-        {% for idx in indices %}
-            {% if !loop.first %} AND {% endif %}
-            overwritten_indices.{{ idx }} = live.{{ idx }}
-        {%- endfor %}
-);
+-- The live rows of the keys changed in the batch are derived again from all
+-- of their changes, those of the batch may be older than what is live
+DROP TABLE IF EXISTS changed_keys;
+CREATE TEMPORARY TABLE changed_keys ON COMMIT DROP AS
+SELECT DISTINCT
+    {% call unfold(indices, "t", false) %}
+FROM "{{ contract_schema }}"."{{ table }}" t
+WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %});
+-- (temporary tables are not analyzed automatically, without statistics
+-- the planner's estimates are far off)
+ANALYZE changed_keys;
+
+DELETE FROM "{{ contract_schema }}"."{{ table }}_live" live
+USING changed_keys
+WHERE
+  {%- for idx in indices %}
+    {% if !loop.first %}AND {% endif -%}
+    changed_keys.{{ idx }} = live.{{ idx }}
+  {%- endfor %};
 
 INSERT INTO "{{ contract_schema }}"."{{ table }}_live" (
     level, level_timestamp, id, tx_context_id, bigmap_id {% call unfold(columns, "", true) %}
@@ -55,11 +63,19 @@ FROM (
         level_meta.baked_at AS level_timestamp,
         t.*
     FROM "{{ contract_schema }}"."{{ table }}" t
+    JOIN changed_keys
+      ON
+        {%- for idx in indices %}
+          {% if !loop.first %}AND {% endif -%}
+          changed_keys.{{ idx }} = t.{{ idx }}
+        {%- endfor %}
     JOIN "{{ main_schema }}".tx_contexts ctx
       ON ctx.id = t.tx_context_id
     JOIN "{{ main_schema }}".levels level_meta
       ON level_meta.level = ctx.level
-    WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
+    WHERE t.bigmap_id NOT IN (
+        SELECT bigmap_id FROM "{{ main_schema }}".bigmap_meta_actions WHERE action = 'clear'
+    )
     ORDER BY
         {% call unfold(indices, "t", false) %},
         ctx.level DESC,
@@ -71,85 +87,200 @@ FROM (
 WHERE NOT t.deleted;
 
 
-INSERT INTO "{{ contract_schema }}"."{{ table }}_ordered" (
-    ordering, level, level_timestamp, id, tx_context_id, deleted {% call unfold(columns, "", true) %}
-)
+-- The _ordered rows of all levels from the batch's first level with changes
+-- (or with a clear of a big map changed in the batch) up to its last are
+-- (re)derived. Because the ordering has no gaps, the rows on one side of
+-- these levels are shifted to make room, whichever side has the fewest rows.
+DROP TABLE IF EXISTS ordered_window;
+CREATE TEMPORARY TABLE ordered_window ON COMMIT DROP AS
 SELECT
-    ordering + COALESCE((SELECT max(ordering) FROM "{{ contract_schema }}"."{{ table }}_ordered"), 0) as ordering,
-    level,
-    level_timestamp,
-    id,
-    tx_context_id,
-    deleted
-    {% call unfold(columns, "", true) %}
+    min(ctx.level) AS first_level,
+    max(ctx.level) AS last_level
+FROM "{{ main_schema }}".tx_contexts ctx
+WHERE ctx.id IN (
+    SELECT
+        t.tx_context_id
+    FROM "{{ contract_schema }}"."{{ table }}" t
+    WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
+  UNION ALL
+    SELECT
+        bigmap_meta.tx_context_id
+    FROM "{{ main_schema }}".bigmap_meta_actions bigmap_meta
+    WHERE bigmap_meta.action = 'clear'
+      AND (
+        bigmap_meta.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
+        OR bigmap_meta.bigmap_id IN (
+            SELECT
+                t.bigmap_id
+            FROM "{{ contract_schema }}"."{{ table }}" t
+            WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
+        )
+      )
+);
+ANALYZE ordered_window;
+
+DROP TABLE IF EXISTS window_contexts;
+CREATE TEMPORARY TABLE window_contexts ON COMMIT DROP AS
+SELECT
+    ctx.id
+FROM "{{ main_schema }}".tx_contexts ctx, ordered_window w
+WHERE ctx.level BETWEEN w.first_level AND w.last_level;
+ANALYZE window_contexts;
+
+DROP TABLE IF EXISTS ordered_rows;
+CREATE TEMPORARY TABLE ordered_rows ON COMMIT DROP AS
+SELECT
+    DENSE_RANK() OVER (
+        ORDER BY
+            ctx.level,
+            ctx.operation_group_number,
+            ctx.operation_number,
+            ctx.content_number,
+            COALESCE(ctx.internal_number, -1)
+    ) AS ordering,
+    ctx.level AS level,
+    level_meta.baked_at AS level_timestamp,
+    t.id,
+    t.tx_context_id,
+    t.deleted
+    {% call unfold(columns, "t", true) %}
 FROM (
     SELECT
-        DENSE_RANK() OVER (
+        t.tx_context_id,
+        t.id,
+        t.deleted
+        {% call unfold(columns, "t", true) %}
+    FROM "{{ contract_schema }}"."{{ table }}" t
+    WHERE t.tx_context_id IN (SELECT id FROM window_contexts)
+
+    UNION ALL
+
+    SELECT
+        t.tx_context_id,
+        t.id,
+        'true' AS deleted
+        {% call unfold(columns, "t", true) %}
+    FROM (
+        SELECT DISTINCT
+            bigmap_meta.tx_context_id,
+            LAST_VALUE(t.id) OVER w AS id,
+            LAST_VALUE(t.deleted) OVER w AS latest_deleted
+          {%- for col in columns %}
+            , LAST_VALUE(t.{{ col }}) OVER w AS {{ col }}
+          {%- endfor %}
+        FROM "{{ main_schema }}".bigmap_meta_actions bigmap_meta
+        JOIN "{{ contract_schema }}"."{{ table }}" t
+          ON t.bigmap_id = bigmap_meta.bigmap_id
+        JOIN "{{ main_schema }}".tx_contexts ctx
+          ON ctx.id = t.tx_context_id
+        WHERE bigmap_meta.tx_context_id IN (SELECT id FROM window_contexts)
+          AND bigmap_meta.action = 'clear'
+        WINDOW w AS (
+            PARTITION BY ({% call unfold(indices, "t", false) %})
             ORDER BY
                 ctx.level,
                 ctx.operation_group_number,
                 ctx.operation_number,
                 ctx.content_number,
                 COALESCE(ctx.internal_number, -1)
-        ) AS ordering,
-        ctx.level AS level,
-        level_meta.baked_at AS level_timestamp,
-        t.id,
-        t.tx_context_id,
-        t.deleted
-        {% call unfold(columns, "t", true) %}
-    FROM (
-        SELECT
-            t.tx_context_id,
-            t.id,
-            t.deleted
-            {% call unfold(columns, "t", true) %}
-        FROM "{{ contract_schema }}"."{{ table }}" t
-        WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
-
-        UNION ALL
-
-        SELECT
-            t.tx_context_id,
-            t.id,
-            'true' AS deleted
-            {% call unfold(columns, "t", true) %}
-        FROM (
-            SELECT DISTINCT ON({% call unfold(indices, "t", false) %})
-                bigmap_meta.tx_context_id,
-                LAST_VALUE(t.id) OVER w as id,
-                LAST_VALUE(t.deleted) OVER w as latest_deleted
-              {%- for col in columns %}
-                , LAST_VALUE(t.{{ col }}) OVER w as {{ col }}
-              {%- endfor %}
-            FROM "{{ main_schema }}".bigmap_meta_actions bigmap_meta
-            JOIN "{{ contract_schema }}"."{{ table }}" t
-              ON t.bigmap_id = bigmap_meta.bigmap_id
-            JOIN "{{ main_schema }}".tx_contexts ctx
-              ON ctx.id = t.tx_context_id
-            WHERE bigmap_meta.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
-              AND bigmap_meta.action = 'clear'
-            WINDOW w AS (
-                PARTITION BY ({% call unfold(indices, "t", false) %})
-                ORDER BY
-                    ctx.level,
-                    ctx.operation_group_number,
-                    ctx.operation_number,
-                    ctx.content_number,
-                    COALESCE(ctx.internal_number, -1)
-                ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING
-            )
-        ) t
-        LEFT JOIN "{{ contract_schema }}"."{{ table }}" t2
-          ON  t2.tx_context_id = t.tx_context_id
-        {%- for idx in indices %}
-          AND t.{{ idx }} = t2.{{ idx }}
-        {% endfor %}
-        WHERE NOT t.latest_deleted
-          AND t2 IS NULL
-    ) t  -- t with bigmap clears unfolded
-    JOIN "{{ main_schema }}".tx_contexts ctx
-      ON ctx.id = t.tx_context_id
-    JOIN "{{ main_schema }}".levels level_meta
-      ON level_meta.level = ctx.level
-) t;
+            ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING
+        )
+    ) t
+    LEFT JOIN "{{ contract_schema }}"."{{ table }}" t2
+      ON  t2.tx_context_id = t.tx_context_id
+    {%- for idx in indices %}
+      AND t.{{ idx }} = t2.{{ idx }}
+    {% endfor %}
+    WHERE NOT t.latest_deleted
+      AND t2 IS NULL
+) t  -- t with bigmap clears unfolded
+JOIN "{{ main_schema }}".tx_contexts ctx
+  ON ctx.id = t.tx_context_id
+JOIN "{{ main_schema }}".levels level_meta
+  ON level_meta.level = ctx.level;
+ANALYZE ordered_rows;
+
+DROP TABLE IF EXISTS ordered_shift;
+CREATE TEMPORARY TABLE ordered_shift ON COMMIT DROP AS
+SELECT
+    n_new - n_old AS delta,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window <= before_window - first_ordering
+        AS shift_after,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window > before_window - first_ordering
+        AS shift_before,
+    CASE
+        WHEN after_window IS NULL THEN COALESCE(before_window, 0)
+        WHEN before_window IS NULL THEN after_window - n_new - 1
+        WHEN last_ordering - after_window <= before_window - first_ordering
+            THEN before_window
+        ELSE after_window - n_new - 1
+    END AS ordering_offset
+FROM (
+    SELECT
+        (SELECT COALESCE(max(r.ordering), 0) FROM ordered_rows r) AS n_new,
+        (
+            SELECT count(DISTINCT o.ordering)
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o, ordered_window w
+            WHERE o.level BETWEEN w.first_level AND w.last_level
+        ) AS n_old,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o, ordered_window w
+            WHERE o.level < w.first_level
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS before_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o, ordered_window w
+            WHERE o.level > w.last_level
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS after_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS first_ordering,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS last_ordering
+) counts;
+ANALYZE ordered_shift;
+
+UPDATE "{{ contract_schema }}"."{{ table }}_ordered" o
+SET ordering = o.ordering + s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_after
+  AND s.delta <> 0
+  AND o.level > w.last_level;
+
+UPDATE "{{ contract_schema }}"."{{ table }}_ordered" o
+SET ordering = o.ordering - s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_before
+  AND s.delta <> 0
+  AND o.level < w.first_level;
+
+DELETE FROM "{{ contract_schema }}"."{{ table }}_ordered" o
+USING ordered_window w
+WHERE o.level BETWEEN w.first_level AND w.last_level;
+
+INSERT INTO "{{ contract_schema }}"."{{ table }}_ordered" (
+    ordering, level, level_timestamp, id, tx_context_id, deleted {% call unfold(columns, "", true) %}
+)
+SELECT
+    r.ordering + s.ordering_offset,
+    r.level,
+    r.level_timestamp,
+    r.id,
+    r.tx_context_id,
+    r.deleted
+    {% call unfold(columns, "r", true) %}
+FROM ordered_rows r, ordered_shift s;
@@ -1,4 +1,6 @@
--- update based on newly processed block (note: _must_ be a *newer* block)
+-- update based on newly processed tx contexts. they may be older than the
+-- ones processed before (eg when bootstrapping, levels are processed from
+-- new to old)
 
 {% macro unfold(column_names, from_table, sep_first) %}
     {%- for col in column_names -%}
@@ -7,70 +9,201 @@
     {%- endfor -%}
 {% endmacro %}
 
-
-DELETE FROM "{{ contract_schema }}"."{{ table }}_live";
-INSERT INTO "{{ contract_schema }}"."{{ table }}_live" (
-    level, level_timestamp, id, tx_context_id {% call unfold(columns, "", true) %}
-)
-SELECT
-    *
-FROM (
+{% macro snapshot_rows(from_table, contexts) %}(
     SELECT
-        last_ctx.level AS level,
-        level_meta.baked_at AS level_timestamp,
         t.id,
         t.tx_context_id
         {% call unfold(columns, "t", true) %}
-    FROM "{{ contract_schema }}"."{{ table }}" t, (
-      SELECT
-        ctx.id,
-        ctx.level
-      FROM "{{ contract_schema }}"."{{ parent_table }}" t
-      JOIN "{{ main_schema }}".tx_contexts ctx
-        ON ctx.id = t.tx_context_id
-      ORDER BY
-          ctx.level DESC,
-          ctx.operation_group_number DESC,
-          ctx.operation_number DESC,
-          ctx.content_number DESC,
-          COALESCE(ctx.internal_number, -1) DESC
-      LIMIT 1
-    ) last_ctx
-    JOIN "{{ main_schema }}".levels level_meta
-      ON level_meta.level = last_ctx.level
-    WHERE t.tx_context_id = last_ctx.id
-) t;
+    FROM "{{ contract_schema }}"."{{ from_table }}" t
+    WHERE t.tx_context_id IN (SELECT id FROM {{ contexts }})
+  UNION ALL
+    -- unchanged snapshots that were stored as a reference (see
+    -- storage_snapshots)
+    SELECT
+        t.id,
+        snapshot.tx_context_id
+        {% call unfold(columns, "t", true) %}
+    FROM "{{ contract_schema }}"."{{ from_table }}" t
+    JOIN "{{ main_schema }}".storage_snapshots snapshot
+      ON snapshot.source_tx_context_id = t.tx_context_id
+    WHERE snapshot.tx_context_id IN (SELECT id FROM {{ contexts }})
+){% endmacro %}
 
 
+CREATE INDEX IF NOT EXISTS "{{ table }}_ordering"
+    ON "{{ contract_schema }}"."{{ table }}_ordered"(level, ordering);
+
+DROP TABLE IF EXISTS batch_contexts;
+CREATE TEMPORARY TABLE batch_contexts ON COMMIT DROP AS
+SELECT
+    ctx.id
+FROM "{{ main_schema }}".tx_contexts ctx
+WHERE ctx.id IN ({% call unfold(tx_context_ids, "", false) %});
+-- (temporary tables are not analyzed automatically, without statistics
+-- the planner's estimates are far off)
+ANALYZE batch_contexts;
+
+
+-- The _ordered rows of all levels from the batch's first level with rows in
+-- the table up to its last are (re)derived. Because the ordering has no
+-- gaps, the rows on one side of these levels are shifted to make room,
+-- whichever side has the fewest rows.
+DROP TABLE IF EXISTS ordered_window;
+CREATE TEMPORARY TABLE ordered_window ON COMMIT DROP AS
+SELECT
+    min(ctx.level) AS first_level,
+    max(ctx.level) AS last_level
+FROM {% call snapshot_rows(table, "batch_contexts") %} t
+JOIN "{{ main_schema }}".tx_contexts ctx
+  ON ctx.id = t.tx_context_id;
+ANALYZE ordered_window;
+
+DROP TABLE IF EXISTS window_contexts;
+CREATE TEMPORARY TABLE window_contexts ON COMMIT DROP AS
+SELECT
+    ctx.id
+FROM "{{ main_schema }}".tx_contexts ctx, ordered_window w
+WHERE ctx.level BETWEEN w.first_level AND w.last_level;
+ANALYZE window_contexts;
+
+DROP TABLE IF EXISTS ordered_rows;
+CREATE TEMPORARY TABLE ordered_rows ON COMMIT DROP AS
+SELECT
+    DENSE_RANK() OVER (
+        ORDER BY
+            ctx.level,
+            ctx.operation_group_number,
+            ctx.operation_number,
+            ctx.content_number,
+            COALESCE(ctx.internal_number, -1)
+    ) AS ordering,
+    ctx.level AS level,
+    level_meta.baked_at AS level_timestamp,
+    t.id,
+    t.tx_context_id
+    {% call unfold(columns, "t", true) %}
+FROM {% call snapshot_rows(table, "window_contexts") %} t
+JOIN "{{ main_schema }}".tx_contexts ctx
+  ON ctx.id = t.tx_context_id
+JOIN "{{ main_schema }}".levels level_meta
+  ON level_meta.level = ctx.level;
+ANALYZE ordered_rows;
+
+DROP TABLE IF EXISTS ordered_shift;
+CREATE TEMPORARY TABLE ordered_shift ON COMMIT DROP AS
+SELECT
+    n_new - n_old AS delta,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window <= before_window - first_ordering
+        AS shift_after,
+    before_window IS NOT NULL AND after_window IS NOT NULL
+        AND last_ordering - after_window > before_window - first_ordering
+        AS shift_before,
+    CASE
+        WHEN after_window IS NULL THEN COALESCE(before_window, 0)
+        WHEN before_window IS NULL THEN after_window - n_new - 1
+        WHEN last_ordering - after_window <= before_window - first_ordering
+            THEN before_window
+        ELSE after_window - n_new - 1
+    END AS ordering_offset
+FROM (
+    SELECT
+        (SELECT COALESCE(max(r.ordering), 0) FROM ordered_rows r) AS n_new,
+        (
+            SELECT count(DISTINCT o.ordering)
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o, ordered_window w
+            WHERE o.level BETWEEN w.first_level AND w.last_level
+        ) AS n_old,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o, ordered_window w
+            WHERE o.level < w.first_level
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS before_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o, ordered_window w
+            WHERE o.level > w.last_level
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS after_window,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o
+            ORDER BY o.level, o.ordering
+            LIMIT 1
+        ) AS first_ordering,
+        (
+            SELECT o.ordering
+            FROM "{{ contract_schema }}"."{{ table }}_ordered" o
+            ORDER BY o.level DESC, o.ordering DESC
+            LIMIT 1
+        ) AS last_ordering
+) counts;
+ANALYZE ordered_shift;
+
+UPDATE "{{ contract_schema }}"."{{ table }}_ordered" o
+SET ordering = o.ordering + s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_after
+  AND s.delta <> 0
+  AND o.level > w.last_level;
+
+UPDATE "{{ contract_schema }}"."{{ table }}_ordered" o
+SET ordering = o.ordering - s.delta
+FROM ordered_shift s, ordered_window w
+WHERE s.shift_before
+  AND s.delta <> 0
+  AND o.level < w.first_level;
+
+DELETE FROM "{{ contract_schema }}"."{{ table }}_ordered" o
+USING ordered_window w
+WHERE o.level BETWEEN w.first_level AND w.last_level;
+
 INSERT INTO "{{ contract_schema }}"."{{ table }}_ordered" (
     ordering, level, level_timestamp, id, tx_context_id {% call unfold(columns, "", true) %}
 )
 SELECT
-    ordering + (SELECT max(ordering) FROM "{{ contract_schema }}"."{{ table }}_ordered") as ordering,
-    level,
-    level_timestamp,
-    id,
-    tx_context_id
-    {% call unfold(columns, "", true) %}
-FROM (
-    SELECT
-        DENSE_RANK() OVER (
-            ORDER BY
-                ctx.level,
-                ctx.operation_group_number,
-                ctx.operation_number,
-                ctx.content_number,
-                COALESCE(ctx.internal_number, -1)
-        ) AS ordering,
-        ctx.level AS level,
-        level_meta.baked_at AS level_timestamp,
-        t.id,
-        t.tx_context_id
-        {% call unfold(columns, "t", true) %}
-    FROM "{{ contract_schema }}"."{{ table }}" t
-    JOIN "{{ main_schema }}".tx_contexts ctx
-      ON ctx.id = t.tx_context_id
-    JOIN "{{ main_schema }}".levels level_meta
-      ON level_meta.level = ctx.level
-    WHERE t.tx_context_id IN ({% call unfold(tx_context_ids, "", false) %})
-) t;
+    r.ordering + s.ordering_offset,
+    r.level,
+    r.level_timestamp,
+    r.id,
+    r.tx_context_id
+    {% call unfold(columns, "r", true) %}
+FROM ordered_rows r, ordered_shift s;
+
+
+-- The parent table's _ordered table is up to date at this point (see
+-- DBClient::update_derived_tables). Only if its last tx context is one of
+-- the batch, the _live table changes.
+DROP TABLE IF EXISTS live_context;
+CREATE TEMPORARY TABLE live_context ON COMMIT DROP AS
+SELECT
+    o.tx_context_id AS id,
+    o.level
+FROM "{{ contract_schema }}"."{{ parent_table }}_ordered" o
+ORDER BY o.level DESC, o.ordering DESC
+LIMIT 1;
+ANALYZE live_context;
+
+DELETE FROM live_context
+WHERE id NOT IN (SELECT id FROM batch_contexts);
+
+DELETE FROM "{{ contract_schema }}"."{{ table }}_live"
+WHERE EXISTS (SELECT 1 FROM live_context);
+
+INSERT INTO "{{ contract_schema }}"."{{ table }}_live" (
+    level, level_timestamp, id, tx_context_id {% call unfold(columns, "", true) %}
+)
+SELECT
+    last_ctx.level AS level,
+    level_meta.baked_at AS level_timestamp,
+    t.id,
+    t.tx_context_id
+    {% call unfold(columns, "t", true) %}
+FROM {% call snapshot_rows(table, "live_context") %} t
+JOIN live_context last_ctx
+  ON last_ctx.id = t.tx_context_id
+JOIN "{{ main_schema }}".levels level_meta
+  ON level_meta.level = last_ctx.level;
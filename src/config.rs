@@ -1,7 +1,10 @@
+use crate::octez::replay::ReplayDir;
+use crate::storage_structure::selection::Selection;
 use anyhow::Result;
 use clap::{App, Arg};
 use serde_yaml;
 use smart_default::SmartDefault;
+use std::collections::HashMap;
 use std::fs;
 
 #[derive(Clone, SmartDefault, Debug)]
@@ -9,28 +12,61 @@ pub struct Config {
     pub main_schema: String,
 
     pub contracts: Vec<ContractID>,
+    /// Per contract name, the tables to rename (path -> name)
+    pub contract_renames: HashMap<String, HashMap<String, String>>,
+    /// Per contract name, which parts of the contract to index
+    pub(crate) contract_selections: HashMap<String, Selection>,
     pub all_contracts: bool,
     pub database_url: String,
 
     pub reinit: bool,
     pub only_migrate: bool,
+    pub dry_run: bool,
+    pub rebuild_derived: bool,
 
     pub levels: Vec<u32>,
     pub node_urls: Vec<String>,
     pub node_comm_retries: i32,
 
-    pub bcd_url: Option<String>,
-    pub bcd_network: String,
+    pub replay_dir: Option<String>,
+    pub metrics_addr: Option<String>,
+    pub event_sink: Option<String>,
+    pub notify_channel: Option<String>,
+    pub ready_max_lag: u32,
+    pub ready_max_seconds_since_last_processed_block: i64,
+
+    pub block_cache_dir: Option<String>,
+    pub block_cache_max_size: u64,
+    pub block_cache_compress: bool,
+
+    pub level_source: Option<LevelSourceConfig>,
+    pub export_levels_file: Option<String>,
+    pub add_contract: Option<ContractID>,
+    pub remove_contract: Option<String>,
 
     pub getters_cap: usize,
     pub workers_cap: usize,
+    pub inserter_cap: usize,
     pub always_yes: bool,
     pub reports_interval: usize,
 
+    pub index_lambdas: bool,
+    pub light_scan: bool,
+    pub skip_unchanged_snapshots: bool,
+
     #[default(_code = "chrono::Duration::hours(1)")]
     pub allowed_unbootstrapped_offset: chrono::Duration,
 }
 
+/// External api to ask in which levels the contracts have been active (for
+/// fast sync).
+#[derive(Clone, Debug, PartialEq)]
+pub enum LevelSourceConfig {
+    BCD { api_url: String, network: String },
+    TzKT { api_url: String },
+    File { path: String },
+}
+
 #[derive(
     Hash, Eq, PartialEq, Clone, Default, Debug, Serialize, Deserialize,
 )]
@@ -79,6 +115,20 @@ pub fn init_config() -> Result<Config> {
                 .multiple(true)
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("add_contract")
+                .long("add-contract")
+                .value_name("ADD_CONTRACT")
+                .help("request the running indexer to start indexing an additional contract (in syntax: <name>=<address>), then exit. The running indexer bootstraps the contract in the background and then adds it to the indexing of the chain's head")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("remove_contract")
+                .long("remove-contract")
+                .value_name("REMOVE_CONTRACT")
+                .help("remove a contract (by name) from the database: drop its schema and delete its rows from the common tables, then exit. All other contracts are left as is")
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("index_all_contracts")
                 .long("index-all-contracts")
@@ -112,11 +162,84 @@ pub fn init_config() -> Result<Config> {
                 .value_name("NODE_COMM_RETRIES")
                 .help("The number of times to retry a node RPC call on any error, set to smaller than 0 for infinite")
                 .takes_value(true))
+        .arg(
+            Arg::with_name("metrics_addr")
+                .long("metrics-addr")
+                .env("METRICS_ADDR")
+                .value_name("METRICS_ADDR")
+                .help("If set, serve Prometheus metrics on http://<METRICS_ADDR>/metrics (eg: 0.0.0.0:9100), and health checks on /health and /ready")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("event_sink")
+                .long("event-sink")
+                .env("EVENT_SINK")
+                .value_name("EVENT_SINK")
+                .help("If set, emit the processed changes as JSON events to: stdout, file:<path>, nats://<host:port>/<subject>, or a Kafka REST proxy's topic url (http(s)://..)")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("notify_channel")
+                .long("notify-channel")
+                .env("NOTIFY_CHANNEL")
+                .value_name("NOTIFY_CHANNEL")
+                .help("If set, NOTIFY this PostgreSQL channel of every level committed in head mode, and of levels deleted because of a fork")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("ready_max_lag")
+                .long("ready-max-lag")
+                .env("READY_MAX_LAG")
+                .default_value("5")
+                .value_name("READY_MAX_LAG")
+                .help("The number of levels the database may be behind the node's head for /ready to report the indexer as ready")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("ready_max_seconds_since_last_processed_block")
+                .long("ready-max-seconds-since-last-processed-block")
+                .env("READY_MAX_SECONDS_SINCE_LAST_PROCESSED_BLOCK")
+                .default_value("300")
+                .value_name("READY_MAX_SECONDS_SINCE_LAST_PROCESSED_BLOCK")
+                .help("The number of seconds since the last block was processed after which /ready reports the indexer as not ready")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("replay_dir")
+                .long("replay-dir")
+                .env("REPLAY_DIR")
+                .value_name("REPLAY_DIR")
+                .help("Replay mode: instead of from a node, get the blocks and contract scripts from a directory of captured files (named like the files in test/: <contract>.level-<level>.json and <contract>.script). Unless --levels is set, all levels in the directory are processed")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("block_cache_dir")
+                .long("block-cache-dir")
+                .env("BLOCK_CACHE_DIR")
+                .value_name("BLOCK_CACHE_DIR")
+                .help("If set, cache the blocks fetched from the node in this directory, and load them from there when they are needed again (eg on re-init or when processing --levels)")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("block_cache_max_size")
+                .long("block-cache-max-size")
+                .env("BLOCK_CACHE_MAX_SIZE")
+                .value_name("BLOCK_CACHE_MAX_SIZE")
+                .default_value("10000")
+                .help("Max size (in MB) of the block cache, once exceeded the least recently used blocks are evicted")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("block_cache_compress")
+                .long("block-cache-compress")
+                .value_name("BLOCK_CACHE_COMPRESS")
+                .help("If set, gzip the blocks stored in the block cache")
+                .takes_value(false))
+        .arg(
+            Arg::with_name("level_source")
+                .long("level-source")
+                .value_name("LEVEL_SOURCE")
+                .env("LEVEL_SOURCE")
+                .possible_values(&["bcd", "tzkt"])
+                .help("enable fast sync, using this external api to find the levels in which the contracts have been active (helps increase bootstrapping low-activity contracts)")
+                .takes_value(true))
         .arg(
             Arg::with_name("bcd_enable")
                 .long("bcd-enable")
                 .value_name("BCD_ENABLE")
-                .help("enable usage of better-call.dev api (helps increase bootstrapping low-activity contracts)")
+                .help("enable usage of better-call.dev api, same as --level-source=bcd")
                 .takes_value(false))
         .arg(
             Arg::with_name("bcd_url")
@@ -129,10 +252,30 @@ pub fn init_config() -> Result<Config> {
                 .long("bcd-network")
                 .value_name("BCD_NETWORK")
                 .env("BCD_NETWORK")
-                .possible_values(&["mainnet", "ghostnet", "jakartanet", "ithacanet", "hangzhou2net", "hangzhounet", "granadanet", "florencenet"])
                 .default_value("mainnet")
                 .help("For better-call.dev: name of the Tezos network to target")
                 .takes_value(true))
+        .arg(
+            Arg::with_name("tzkt_url")
+                .long("tzkt-url")
+                .value_name("TZKT_URL")
+                .env("TZKT_URL")
+                .default_value("https://api.tzkt.io/v1")
+                .help("For tzkt: the api url (including the version path), eg https://api.ghostnet.tzkt.io/v1 for ghostnet")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("levels_file")
+                .long("levels-file")
+                .value_name("LEVELS_FILE")
+                .conflicts_with_all(&["level_source", "bcd_enable"])
+                .help("enable fast sync offline, using a CSV (with a contract,level header) or JSON (.json extension) file of the levels in which the contracts have been active (see --export-levels-file)")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("export_levels_file")
+                .long("export-levels-file")
+                .value_name("EXPORT_LEVELS_FILE")
+                .help("export the levels in which the contracts indexed in the database have been active to a file (CSV, or JSON with the .json extension) usable with --levels-file, then exit")
+                .takes_value(true))
         .arg(
             Arg::with_name("getters_cap")
                 .long("getters-cap")
@@ -151,6 +294,15 @@ pub fn init_config() -> Result<Config> {
                 .help("max number of processes used to concurrently process block data (for faster bootstrap)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("inserter_cap")
+                .long("inserter-cap")
+                .value_name("INSERTER_CAP")
+                .env("INSERTER_CAP")
+                .default_value("4")
+                .help("max number of threads used to concurrently build the rows of different contracts' data (for faster inserts when indexing many contracts)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("levels")
                 .short("l")
@@ -181,7 +333,41 @@ pub fn init_config() -> Result<Config> {
             Arg::with_name("only_migrate")
                 .long("only-migrate")
                 .value_name("ONLY_MIGRATE")
-                .help("If set, apply migrations (if any applicable) and then quit without processing levels.")
+                .help("If set, apply migrations (if any applicable) and then quit without processing levels. This includes migrating a database initialized by an older Que Pasa version")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .requires("only_migrate")
+                .help("With --only-migrate: only print the migrations that would be applied, without applying them")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("rebuild_derived")
+                .long("rebuild-derived")
+                .help("If set, derive the _live and _ordered tables of all contracts again from scratch before processing levels (this is done regardless if a migration marked them stale)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("index_lambdas")
+                .long("index-lambdas")
+                .value_name("INDEX_LAMBDAS")
+                .help("If set, store lambda (and never/constant) values as their raw Micheline in JSONB columns, instead of dropping them")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("light_scan")
+                .long("light-scan")
+                .value_name("LIGHT_SCAN")
+                .help("enable fast sync without an external level source: first scan the missing levels' headers and manager operations only, then fully process only the levels in which the contracts have been active")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("skip_unchanged_snapshots")
+                .long("skip-unchanged-snapshots")
+                .value_name("SKIP_UNCHANGED_SNAPSHOTS")
+                .help("If set, a storage snapshot that is the same as the contract's previous snapshot is stored as a reference to it, instead of inserting all its rows again")
                 .takes_value(false),
         )
         .arg(
@@ -197,9 +383,7 @@ pub fn init_config() -> Result<Config> {
                 .value_name("ALLOWED_UNBOOTSTRAPPED_OFFSET")
                 .env("ALLOWED_UNBOOTSTRAPPED_OFFSET")
                 .help("Ensure we bootstrap until at least <now - allowed_unbootstrapped_offset>,
-from there it's acceptable if continuous mode is running (setting an acceptable duration may
-be necessary depending on how long it takes to derive the _ordered and _live tables,
-unfortunately.")
+from there it's acceptable if continuous mode is running.")
                 .default_value("1h")
                 .takes_value(true));
     let matches = matches.get_matches();
@@ -211,21 +395,26 @@ unfortunately.")
 
     if let Some(fpath) = matches.value_of("contract_settings") {
         info!("loading contract settings from {}", fpath);
-        config.contracts = parse_contract_settings_file(fpath).unwrap();
+        (
+            config.contracts,
+            config.contract_renames,
+            config.contract_selections,
+        ) = parse_contract_settings_file(fpath).unwrap();
     }
     if let Some(contracts) = matches.values_of("contracts") {
         config.contracts.extend(
-            contracts.flat_map(|c| c.split_whitespace()).map(|c| {
-                match c.split_once('=') {
-                    Some((name, address)) => ContractID {
-                        name: name.to_string(),
-                        address: address.to_string(),
-                    },
-                    None => panic!("bad contract arg format (expected: <name>=<address>, got {}", c),
-                }
-            }).collect::<Vec<ContractID>>(),
+            contracts
+                .flat_map(|c| c.split_whitespace())
+                .map(parse_contract_arg)
+                .collect::<Vec<ContractID>>(),
         );
     }
+    config.add_contract = matches
+        .value_of("add_contract")
+        .map(parse_contract_arg);
+    config.remove_contract = matches
+        .value_of("remove_contract")
+        .map(String::from);
 
     config.database_url = matches
         .value_of("database_url")
@@ -240,8 +429,14 @@ unfortunately.")
 
     config.reinit = matches.is_present("reinit");
     config.only_migrate = matches.is_present("only_migrate");
+    config.dry_run = matches.is_present("dry_run");
+    config.rebuild_derived = matches.is_present("rebuild_derived");
     config.all_contracts = matches.is_present("index_all_contracts");
     config.always_yes = matches.is_present("always_yes");
+    config.index_lambdas = matches.is_present("index_lambdas");
+    config.light_scan = matches.is_present("light_scan");
+    config.skip_unchanged_snapshots =
+        matches.is_present("skip_unchanged_snapshots");
 
     config.levels = matches
         .value_of("levels")
@@ -259,19 +454,80 @@ unfortunately.")
         .unwrap()
         .parse::<i32>()?;
 
-    if matches.is_present("bcd_enable") {
-        config.bcd_url = matches
-            .value_of("bcd_url")
-            .map(String::from);
-        config.bcd_network = matches
-            .value_of("bcd_network")
-            .unwrap()
-            .to_string();
-        if config.bcd_network == "ithacanet" {
-            config.bcd_network = "ghostnet".to_string();
+    config.metrics_addr = matches
+        .value_of("metrics_addr")
+        .map(String::from);
+    config.event_sink = matches
+        .value_of("event_sink")
+        .map(String::from);
+    config.notify_channel = matches
+        .value_of("notify_channel")
+        .map(String::from);
+    config.ready_max_lag = matches
+        .value_of("ready_max_lag")
+        .unwrap()
+        .parse::<u32>()?;
+    config.ready_max_seconds_since_last_processed_block = matches
+        .value_of("ready_max_seconds_since_last_processed_block")
+        .unwrap()
+        .parse::<i64>()?;
+    config.replay_dir = matches
+        .value_of("replay_dir")
+        .map(String::from);
+    if let Some(dir) = &config.replay_dir {
+        if config.levels.is_empty() {
+            config.levels = ReplayDir::new(dir)?.levels();
         }
     }
 
+    config.block_cache_dir = matches
+        .value_of("block_cache_dir")
+        .map(String::from);
+    config.block_cache_max_size = matches
+        .value_of("block_cache_max_size")
+        .unwrap()
+        .parse::<u64>()?
+        * 1_000_000;
+    config.block_cache_compress = matches.is_present("block_cache_compress");
+
+    let level_source = if matches.is_present("bcd_enable") {
+        Some("bcd")
+    } else {
+        matches.value_of("level_source")
+    };
+    config.level_source = match level_source {
+        Some("bcd") => {
+            let mut network = matches
+                .value_of("bcd_network")
+                .unwrap()
+                .to_string();
+            if network == "ithacanet" {
+                network = "ghostnet".to_string();
+            }
+            Some(LevelSourceConfig::BCD {
+                api_url: matches
+                    .value_of("bcd_url")
+                    .unwrap()
+                    .to_string(),
+                network,
+            })
+        }
+        Some("tzkt") => Some(LevelSourceConfig::TzKT {
+            api_url: matches
+                .value_of("tzkt_url")
+                .unwrap()
+                .to_string(),
+        }),
+        _ => matches
+            .value_of("levels_file")
+            .map(|path| LevelSourceConfig::File {
+                path: path.to_string(),
+            }),
+    };
+    config.export_levels_file = matches
+        .value_of("export_levels_file")
+        .map(String::from);
+
     config.reports_interval = matches
         .value_of("reports_interval")
         .unwrap()
@@ -300,6 +556,17 @@ unfortunately.")
         );
         config.workers_cap = 1;
     }
+    config.inserter_cap = matches
+        .value_of("inserter_cap")
+        .unwrap()
+        .parse::<usize>()?;
+    if config.inserter_cap == 0 {
+        warn!(
+            "set inserter_cap ({}) is invalid. defaulting to 1",
+            config.inserter_cap
+        );
+        config.inserter_cap = 1;
+    }
 
     debug!("Config={:#?}", config);
     Ok(config)
@@ -329,12 +596,60 @@ fn range(arg: &str) -> Vec<u32> {
     result
 }
 
-fn parse_contract_settings_file(fpath: &str) -> Result<Vec<ContractID>> {
+fn parse_contract_arg(c: &str) -> ContractID {
+    match c.split_once('=') {
+        Some((name, address)) => ContractID {
+            name: name.to_string(),
+            address: address.to_string(),
+        },
+        None => panic!(
+            "bad contract arg format (expected: <name>=<address>, got {}",
+            c
+        ),
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn parse_contract_settings_file(
+    fpath: &str,
+) -> Result<(
+    Vec<ContractID>,
+    HashMap<String, HashMap<String, String>>,
+    HashMap<String, Selection>,
+)> {
     let content = fs::read_to_string(fpath)?;
-    #[derive(Serialize, Deserialize)]
+    #[derive(Deserialize)]
+    struct ContractSettings {
+        name: String,
+        address: String,
+        #[serde(default)]
+        rename: HashMap<String, String>,
+        #[serde(flatten)]
+        selection: Selection,
+    }
+    #[derive(Deserialize)]
     struct ParseType {
-        contracts: Vec<ContractID>,
+        contracts: Vec<ContractSettings>,
     }
     let res: ParseType = serde_yaml::from_str(&content)?;
-    Ok(res.contracts)
+
+    let mut renames: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut selections: HashMap<String, Selection> = HashMap::new();
+    let contracts = res
+        .contracts
+        .into_iter()
+        .map(|c| {
+            if !c.rename.is_empty() {
+                renames.insert(c.name.clone(), c.rename);
+            }
+            if c.selection != Selection::default() {
+                selections.insert(c.name.clone(), c.selection);
+            }
+            ContractID {
+                name: c.name,
+                address: c.address,
+            }
+        })
+        .collect();
+    Ok((contracts, renames, selections))
 }
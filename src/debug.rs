@@ -17,3 +17,95 @@ pub(crate) fn load_test(name: &str) -> String {
     println!("loading: {}", name);
     std::fs::read_to_string(std::path::Path::new(name)).unwrap()
 }
+
+/// Serves recorded api responses over http, each response is served for the
+/// exact (url decoded) request path, including query string, it was recorded
+/// for. The requests served are kept (their method and path, and body).
+#[cfg(test)]
+pub(crate) struct MockHttpServer {
+    pub url: String,
+    pub requests: std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>,
+}
+
+#[cfg(test)]
+impl MockHttpServer {
+    pub fn start(
+        pages: std::collections::HashMap<String, serde_json::Value>,
+    ) -> Self {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let requests_cl = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader
+                    .read_line(&mut request_line)
+                    .unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim_end().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let mut request = request_line.split_whitespace();
+                let method = request.next().unwrap_or("");
+                let path = url_decode(request.next().unwrap_or(""));
+                requests_cl.lock().unwrap().push((
+                    format!("{} {}", method, path),
+                    String::from_utf8(body).unwrap(),
+                ));
+                let (status, body) = match pages.get(&path) {
+                    Some(page) => ("200 OK", page.to_string()),
+                    None => ("404 Not Found", format!("no page for {}", path)),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        Self { url, requests }
+    }
+}
+
+#[cfg(test)]
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut res: Vec<u8> = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(b) = u8::from_str_radix(&s[i + 1..i + 3], 16) {
+                res.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        res.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(res).unwrap()
+}
@@ -0,0 +1,833 @@
+use anyhow::{anyhow, Context, Result};
+use backoff::{retry, Error, ExponentialBackoff};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::octez::block::{Tx, TxContext};
+use crate::sql::insert::{Insert, Value};
+use crate::sql::inserter::{ClearedLevel, ProcessedBlock};
+use crate::sql::postgresql_generator::PostgresqlGenerator;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// Where in the level a change happened, the same as the tx_contexts
+/// table's ordering columns
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct EventContext {
+    pub contract: String,
+    pub level: u32,
+    pub operation_group_number: usize,
+    pub operation_number: usize,
+    pub content_number: usize,
+    pub internal_number: Option<i32>,
+}
+
+impl EventContext {
+    fn new(contract: &str, tx_context: &TxContext) -> Self {
+        Self {
+            contract: contract.to_string(),
+            level: tx_context.level,
+            operation_group_number: tx_context.operation_group_number,
+            operation_number: tx_context.operation_number,
+            content_number: tx_context.content_number,
+            internal_number: tx_context.internal_number,
+        }
+    }
+}
+
+/// A processed change, as emitted by the EventSink (one JSON object per
+/// event, the kind of event is under "kind")
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// A row inserted into one of the contract's tables (other than a big
+    /// map's changes)
+    Insert {
+        #[serde(flatten)]
+        context: EventContext,
+        table: String,
+        values: serde_json::Map<String, serde_json::Value>,
+    },
+    BigmapUpdate {
+        #[serde(flatten)]
+        context: EventContext,
+        table: String,
+        bigmap_id: i32,
+        values: serde_json::Map<String, serde_json::Value>,
+    },
+    BigmapDelete {
+        #[serde(flatten)]
+        context: EventContext,
+        table: String,
+        bigmap_id: i32,
+        values: serde_json::Map<String, serde_json::Value>,
+    },
+    Tx {
+        #[serde(flatten)]
+        context: EventContext,
+        operation_hash: String,
+        source: Option<String>,
+        destination: Option<String>,
+        entrypoint: Option<String>,
+        amount: Option<String>,
+        fee: Option<i64>,
+    },
+    /// Everything emitted before for the level (only for the contract, if
+    /// set) is no longer valid: the level was forked away from, or is being
+    /// processed again. Its events, if any, are emitted again after.
+    Retract {
+        level: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        contract: Option<String>,
+    },
+}
+
+impl Event {
+    /// The events of the processed blocks, per block and contract its txs
+    /// followed by the rows inserted for it
+    pub(crate) fn from_processed_blocks(
+        processed_blocks: &[ProcessedBlock],
+    ) -> Result<Vec<Self>> {
+        let mut res: Vec<Self> = vec![];
+        for cres in processed_blocks.iter().flatten() {
+            let contract = &cres.contract.cid.name;
+            let tx_contexts: HashMap<i64, &TxContext> = cres
+                .tx_contexts
+                .iter()
+                .filter_map(|ctx| ctx.id.map(|id| (id, ctx)))
+                .collect();
+            let context_of = |tx_context_id: i64| -> Result<EventContext> {
+                let tx_context = tx_contexts
+                    .get(&tx_context_id)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "no tx context with id={} for contract {}",
+                            tx_context_id,
+                            contract
+                        )
+                    })?;
+                Ok(EventContext::new(contract, tx_context))
+            };
+
+            for tx in &cres.txs {
+                res.push(Self::from_tx(context_of(tx.tx_context_id)?, tx));
+            }
+            for insert in &cres.inserts {
+                // (the same parent table as the row references in the
+                // database, see DBClient::apply_inserts_for_table)
+                let parent_name = match cres
+                    .contract
+                    .tables
+                    .get(&insert.table_name)
+                {
+                    Some(t) => t.parent.clone(),
+                    None => {
+                        PostgresqlGenerator::parent_name(&insert.table_name)
+                    }
+                };
+                res.push(Self::from_insert(
+                    context_of(insert.get_tx_context_id()?)?,
+                    insert,
+                    parent_name.as_deref(),
+                )?);
+            }
+        }
+        Ok(res)
+    }
+
+    fn from_tx(context: EventContext, tx: &Tx) -> Self {
+        Self::Tx {
+            context,
+            operation_hash: tx.operation_hash.clone(),
+            source: tx.source.clone(),
+            destination: tx.destination.clone(),
+            entrypoint: tx.entrypoint.clone(),
+            amount: tx
+                .amount
+                .as_ref()
+                .and_then(|amount| amount.n.as_ref())
+                .map(|amount| amount.to_string()),
+            fee: tx.fee,
+        }
+    }
+
+    /// The retractions of what was emitted for the cleared levels
+    pub(crate) fn retractions(cleared: &[ClearedLevel]) -> Vec<Self> {
+        cleared
+            .iter()
+            .map(|cleared| Self::Retract {
+                level: cleared.level,
+                contract: cleared.contract.clone(),
+            })
+            .collect()
+    }
+
+    /// The values are those of the row's columns, including its id and
+    /// (if it is a child row of a snapshot) the id of its parent row
+    fn from_insert(
+        context: EventContext,
+        insert: &Insert,
+        parent_name: Option<&str>,
+    ) -> Result<Self> {
+        let mut values = serde_json::Map::new();
+        let mut bigmap_id: Option<i32> = None;
+        let mut deleted = false;
+        for column in insert.get_columns_with_parent(parent_name)? {
+            match (column.name.as_str(), &column.value) {
+                // (the context is emitted instead)
+                ("tx_context_id", _) => {}
+                ("bigmap_id", Value::Int(id)) => bigmap_id = Some(*id),
+                ("deleted", Value::Bool(b)) => deleted = *b,
+                (name, value) => {
+                    values.insert(name.to_string(), json_value(value)?);
+                }
+            }
+        }
+        let table = insert.table_name.clone();
+        Ok(match bigmap_id {
+            None => Self::Insert {
+                context,
+                table,
+                values,
+            },
+            Some(bigmap_id) if deleted => Self::BigmapDelete {
+                context,
+                table,
+                bigmap_id,
+                values,
+            },
+            Some(bigmap_id) => Self::BigmapUpdate {
+                context,
+                table,
+                bigmap_id,
+                values,
+            },
+        })
+    }
+}
+
+/// Numeric values are passed as strings, so that none of their digits get
+/// lost in consumers that parse JSON numbers as floats
+fn json_value(value: &Value) -> Result<serde_json::Value> {
+    Ok(match value {
+        Value::String(s) => serde_json::Value::String(s.clone()),
+        Value::Bool(b) => serde_json::Value::Bool(*b),
+        Value::Numeric(n) => match &n.n {
+            Some(n) => serde_json::Value::String(n.to_string()),
+            None => serde_json::Value::Null,
+        },
+        Value::Int(i) => serde_json::Value::from(*i),
+        Value::BigInt(i) => serde_json::Value::from(*i),
+        Value::Timestamp(Some(t)) => serde_json::Value::String(t.to_rfc3339()),
+        Value::Timestamp(None) => {
+            serde_json::Value::String("infinity".to_string())
+        }
+        Value::Micheline(j) => serde_json::from_str(&j.0)?,
+        Value::Null => serde_json::Value::Null,
+    })
+}
+
+enum Target {
+    Stdout,
+    File(File),
+    Nats(NatsConn),
+    KafkaRest {
+        cli: reqwest::blocking::Client,
+        url: String,
+    },
+}
+
+/// Emits processed changes once they are committed to the database (see
+/// Event), one JSON object per event. A level that is processed again (eg
+/// after a fork) has its events emitted again. Should the indexer stop in
+/// between committing a batch and emitting its events, they are not
+/// emitted.
+#[derive(Clone)]
+pub struct EventSink {
+    target: Arc<Mutex<Target>>,
+}
+
+impl EventSink {
+    /// Opens the sink of the target:
+    /// - "stdout"
+    /// - file:<path>, appends to the file
+    /// - nats://[user:pass@]host:port/<subject>, publishes to the subject
+    /// - http(s)://.., posts to a Kafka REST proxy's topic url (eg
+    ///   http://localhost:8082/topics/quepasa)
+    pub(crate) fn connect(target: &str) -> Result<Self> {
+        let target = if target == "stdout" {
+            Target::Stdout
+        } else if let Some(path) = target.strip_prefix("file:") {
+            Target::File(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| {
+                        format!("failed to open event sink file {}", path)
+                    })?,
+            )
+        } else if target.starts_with("nats://") {
+            Target::Nats(NatsConn::connect(target)?)
+        } else if target.starts_with("http://")
+            || target.starts_with("https://")
+        {
+            Target::KafkaRest {
+                cli: reqwest::blocking::Client::new(),
+                url: target.to_string(),
+            }
+        } else {
+            return Err(anyhow!("unsupported event sink: {}", target));
+        };
+        Ok(Self {
+            target: Arc::new(Mutex::new(target)),
+        })
+    }
+
+    pub(crate) fn writes_to_stdout(&self) -> bool {
+        matches!(self.target.lock().as_deref(), Ok(Target::Stdout))
+    }
+
+    pub(crate) fn emit_retractions(&self, levels: &[u32]) -> Result<()> {
+        self.emit(
+            &levels
+                .iter()
+                .map(|level| Event::Retract {
+                    level: *level,
+                    contract: None,
+                })
+                .collect::<Vec<Event>>(),
+        )
+    }
+
+    pub(crate) fn emit(&self, events: &[Event]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let mut target = self
+            .target
+            .lock()
+            .map_err(|err| anyhow!("err: {}", err))?;
+        match &mut *target {
+            Target::Stdout => {
+                let stdout = std::io::stdout();
+                let mut out = stdout.lock();
+                for event in events {
+                    serde_json::to_writer(&mut out, event)?;
+                    out.write_all(b"\n")?;
+                }
+                out.flush()?;
+            }
+            Target::File(f) => {
+                let mut buf: Vec<u8> = vec![];
+                for event in events {
+                    serde_json::to_writer(&mut buf, event)?;
+                    buf.push(b'\n');
+                }
+                f.write_all(&buf)?;
+                f.flush()?;
+            }
+            Target::Nats(conn) => {
+                let payloads = events
+                    .iter()
+                    .map(serde_json::to_vec)
+                    .collect::<serde_json::Result<Vec<Vec<u8>>>>()?;
+                retry(ExponentialBackoff::default(), || {
+                    conn.publish(&payloads)
+                        .map_err(transient_err)
+                })
+                .map_err(|e| anyhow!(e))?;
+            }
+            Target::KafkaRest { cli, url } => {
+                let body = serde_json::json!({
+                    "records": events
+                        .iter()
+                        .map(|event| serde_json::json!({ "value": event }))
+                        .collect::<Vec<serde_json::Value>>(),
+                })
+                .to_string();
+                retry(ExponentialBackoff::default(), || {
+                    let op = || -> Result<()> {
+                        cli.post(url.as_str())
+                            .header(
+                                "Content-Type",
+                                "application/vnd.kafka.json.v2+json",
+                            )
+                            .body(body.clone())
+                            .send()?
+                            .error_for_status()?;
+                        Ok(())
+                    };
+                    op().map_err(transient_err)
+                })
+                .map_err(|e| anyhow!(e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn transient_err(e: anyhow::Error) -> Error<anyhow::Error> {
+    warn!("transient event sink error, retrying.. err={}", e);
+    Error::Transient(e)
+}
+
+/// A connection to a NATS server, speaking its (plain text) client
+/// protocol. Reconnects when the connection was lost.
+struct NatsConn {
+    addr: String,
+    subject: String,
+    connect_msg: String,
+    conn: Option<(TcpStream, BufReader<TcpStream>)>,
+}
+
+impl NatsConn {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn connect(url: &str) -> Result<Self> {
+        let url = reqwest::Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("nats url {} has no host", url))?;
+        let subject = url.path().trim_start_matches('/');
+        if subject.is_empty() {
+            return Err(anyhow!("nats url {} has no subject", url));
+        }
+        let mut connect_opts = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "que-pasa",
+        });
+        if !url.username().is_empty() {
+            connect_opts["user"] = url.username().into();
+            connect_opts["pass"] = url.password().unwrap_or("").into();
+        }
+        let mut res = Self {
+            addr: format!("{}:{}", host, url.port().unwrap_or(4222)),
+            subject: subject.replace('/', "."),
+            connect_msg: format!("CONNECT {}\r\n", connect_opts),
+            conn: None,
+        };
+        res.reconnect()?;
+        Ok(res)
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.conn = None;
+        let stream = TcpStream::connect(&self.addr).with_context(|| {
+            format!("failed to connect to nats server {}", self.addr)
+        })?;
+        stream.set_read_timeout(Some(Self::TIMEOUT))?;
+        stream.set_write_timeout(Some(Self::TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let mut info = String::new();
+        reader.read_line(&mut info)?;
+        if !info.starts_with("INFO") {
+            return Err(anyhow!("unexpected nats server greeting: {}", info));
+        }
+        (&stream).write_all(self.connect_msg.as_bytes())?;
+        self.conn = Some((stream, reader));
+        Ok(())
+    }
+
+    /// Publishes the payloads, and waits for the server to have processed
+    /// them (the server answers a PING once it processed everything sent
+    /// before it)
+    fn publish(&mut self, payloads: &[Vec<u8>]) -> Result<()> {
+        if self.conn.is_none() {
+            self.reconnect()?;
+        }
+        let res = self.publish_internal(payloads);
+        if res.is_err() {
+            self.conn = None;
+        }
+        res
+    }
+
+    fn publish_internal(&mut self, payloads: &[Vec<u8>]) -> Result<()> {
+        let (stream, reader) = self.conn.as_mut().unwrap();
+
+        let mut buf: Vec<u8> = vec![];
+        for payload in payloads {
+            buf.extend(
+                format!("PUB {} {}\r\n", self.subject, payload.len())
+                    .as_bytes(),
+            );
+            buf.extend(payload);
+            buf.extend(b"\r\n");
+        }
+        buf.extend(b"PING\r\n");
+        stream.write_all(&buf)?;
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(anyhow!("nats server closed the connection"));
+            }
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                "PING" => stream.write_all(b"PONG\r\n")?,
+                "+OK" => {}
+                l if l.starts_with("INFO") => {}
+                l => return Err(anyhow!("nats server error: {}", l)),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_event_json() {
+    use crate::sql::insert::{Column, RawJson};
+    use pg_bigdecimal::{BigDecimal, PgNumeric};
+    use std::str::FromStr;
+
+    struct TestCase {
+        name: String,
+        insert: Insert,
+        exp: serde_json::Value,
+    }
+    let tx_context = TxContext {
+        id: Some(3),
+        contract: "KT1".to_string(),
+        level: 10,
+        operation_group_number: 1,
+        operation_number: 0,
+        content_number: 2,
+        internal_number: None,
+    };
+    let column = |name: &str, value: Value| Column {
+        name: name.to_string(),
+        value,
+    };
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "snapshot".to_string(),
+            insert: Insert {
+                table_name: "storage".to_string(),
+                id: 5,
+                fk_id: None,
+                columns: vec![
+                    column("tx_context_id", Value::BigInt(3)),
+                    column(
+                        "supply",
+                        Value::Numeric(PgNumeric::new(Some(
+                            BigDecimal::from_str("123456789012345678901")
+                                .unwrap(),
+                        ))),
+                    ),
+                    column("paused", Value::Bool(false)),
+                    column(
+                        "lambda",
+                        Value::Micheline(RawJson(
+                            r#"{"prim":"Unit"}"#.to_string(),
+                        )),
+                    ),
+                ],
+            },
+            exp: serde_json::json!({
+                "kind": "insert",
+                "contract": "nft",
+                "level": 10,
+                "operation_group_number": 1,
+                "operation_number": 0,
+                "content_number": 2,
+                "internal_number": null,
+                "table": "storage",
+                "values": {
+                    "supply": "123456789012345678901",
+                    "paused": false,
+                    "lambda": {"prim": "Unit"},
+                    "id": 5,
+                },
+            }),
+        },
+        TestCase {
+            name: "snapshot child row".to_string(),
+            insert: Insert {
+                table_name: "storage.tokens".to_string(),
+                id: 8,
+                fk_id: Some(5),
+                columns: vec![
+                    column("tx_context_id", Value::BigInt(3)),
+                    column("idx_nat", Value::String("1".to_string())),
+                ],
+            },
+            exp: serde_json::json!({
+                "kind": "insert",
+                "contract": "nft",
+                "level": 10,
+                "operation_group_number": 1,
+                "operation_number": 0,
+                "content_number": 2,
+                "internal_number": null,
+                "table": "storage.tokens",
+                "values": {
+                    "idx_nat": "1",
+                    "id": 8,
+                    "storage_id": 5,
+                },
+            }),
+        },
+        TestCase {
+            name: "bigmap update".to_string(),
+            insert: Insert {
+                table_name: "storage.ledger".to_string(),
+                id: 6,
+                fk_id: None,
+                columns: vec![
+                    column("tx_context_id", Value::BigInt(3)),
+                    column("idx_address", Value::String("tz1".to_string())),
+                    column("bigmap_id", Value::Int(42)),
+                ],
+            },
+            exp: serde_json::json!({
+                "kind": "bigmap_update",
+                "contract": "nft",
+                "level": 10,
+                "operation_group_number": 1,
+                "operation_number": 0,
+                "content_number": 2,
+                "internal_number": null,
+                "table": "storage.ledger",
+                "bigmap_id": 42,
+                "values": {"idx_address": "tz1", "id": 6},
+            }),
+        },
+        TestCase {
+            name: "bigmap delete".to_string(),
+            insert: Insert {
+                table_name: "storage.ledger".to_string(),
+                id: 7,
+                fk_id: None,
+                columns: vec![
+                    column("tx_context_id", Value::BigInt(3)),
+                    column("idx_address", Value::String("tz1".to_string())),
+                    column("deleted", Value::Bool(true)),
+                    column("bigmap_id", Value::Int(42)),
+                ],
+            },
+            exp: serde_json::json!({
+                "kind": "bigmap_delete",
+                "contract": "nft",
+                "level": 10,
+                "operation_group_number": 1,
+                "operation_number": 0,
+                "content_number": 2,
+                "internal_number": null,
+                "table": "storage.ledger",
+                "bigmap_id": 42,
+                "values": {"idx_address": "tz1", "id": 7},
+            }),
+        },
+    ];
+
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let event = Event::from_insert(
+            EventContext::new("nft", &tx_context),
+            &tc.insert,
+            PostgresqlGenerator::parent_name(&tc.insert.table_name).as_deref(),
+        )
+        .unwrap();
+        assert_eq!(tc.exp, serde_json::to_value(&event).unwrap());
+    }
+
+    assert_eq!(
+        vec![
+            serde_json::json!({"kind": "retract", "level": 10}),
+            serde_json::json!({"kind": "retract", "level": 11, "contract": "nft"}),
+        ],
+        Event::retractions(&[
+            ClearedLevel {
+                level: 10,
+                contract: None,
+            },
+            ClearedLevel {
+                level: 11,
+                contract: Some("nft".to_string()),
+            },
+        ])
+        .iter()
+        .map(|event| serde_json::to_value(event).unwrap())
+        .collect::<Vec<serde_json::Value>>()
+    );
+}
+
+/// A fake NATS server: answers the PINGs of its n-th connection with the
+/// n-th replies (eg "PONG"), and closes the connection once these are
+/// exhausted. Keeps the lines received per connection.
+#[cfg(test)]
+fn fake_nats_server(
+    conn_replies: Vec<Vec<&'static str>>,
+) -> (String, Arc<Mutex<Vec<Vec<String>>>>) {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let received: Arc<Mutex<Vec<Vec<String>>>> = Arc::new(Mutex::new(vec![]));
+    let received_cl = received.clone();
+    std::thread::spawn(move || {
+        for replies in conn_replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream
+                .write_all(b"INFO {\"server_id\":\"fake\"}\r\n")
+                .unwrap();
+
+            let conn_num = {
+                let mut received = received_cl.lock().unwrap();
+                received.push(vec![]);
+                received.len() - 1
+            };
+            let mut replies = replies.into_iter();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                received_cl.lock().unwrap()[conn_num].push(line.clone());
+                if line != "PING" {
+                    continue;
+                }
+                match replies.next() {
+                    Some(reply) => stream
+                        .write_all(format!("{}\r\n", reply).as_bytes())
+                        .unwrap(),
+                    None => break,
+                }
+                if replies.len() == 0 {
+                    break;
+                }
+            }
+        }
+    });
+    (addr, received)
+}
+
+#[test]
+fn test_nats_sink() {
+    struct TestCase {
+        name: String,
+        conn_replies: Vec<Vec<&'static str>>,
+        exp_conns: Vec<Vec<usize>>,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "publishes the events".to_string(),
+            conn_replies: vec![vec!["PONG", "PONG"]],
+            exp_conns: vec![vec![0, 1]],
+        },
+        TestCase {
+            name: "reconnects once the server closed the connection"
+                .to_string(),
+            conn_replies: vec![vec!["PONG"], vec!["PONG"]],
+            exp_conns: vec![vec![0], vec![1]],
+        },
+        TestCase {
+            name: "retries on server errors".to_string(),
+            conn_replies: vec![
+                vec!["-ERR 'Authorization Violation'"],
+                vec!["PONG", "PONG"],
+            ],
+            exp_conns: vec![vec![0], vec![0, 1]],
+        },
+    ];
+    let events = [
+        Event::Retract {
+            level: 10,
+            contract: None,
+        },
+        Event::Retract {
+            level: 11,
+            contract: None,
+        },
+    ];
+
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let (addr, received) = fake_nats_server(tc.conn_replies);
+        let sink = EventSink::connect(&format!(
+            "nats://quepasa:secret@{}/quepasa/events",
+            addr
+        ))
+        .unwrap();
+        for event in &events {
+            sink.emit(std::slice::from_ref(event))
+                .unwrap();
+        }
+
+        // (each connection's events, each emitted followed by a PING)
+        let exp: Vec<Vec<String>> = tc
+            .exp_conns
+            .iter()
+            .map(|conn_events| {
+                let mut lines = vec![r#"CONNECT {"name":"que-pasa","pass":"secret","pedantic":false,"user":"quepasa","verbose":false}"#.to_string()];
+                for i in conn_events {
+                    let payload = serde_json::to_string(&events[*i]).unwrap();
+                    lines.push(format!(
+                        "PUB quepasa.events {}",
+                        payload.len()
+                    ));
+                    lines.push(payload);
+                    lines.push("PING".to_string());
+                }
+                lines
+            })
+            .collect();
+        assert_eq!(exp, *received.lock().unwrap());
+    }
+}
+
+#[test]
+fn test_kafka_rest_sink() {
+    use crate::debug::MockHttpServer;
+
+    let server = MockHttpServer::start(
+        vec![(
+            "/topics/quepasa".to_string(),
+            serde_json::json!({"offsets": [{"partition": 0, "offset": 0}]}),
+        )]
+        .into_iter()
+        .collect(),
+    );
+    let sink =
+        EventSink::connect(&format!("{}/topics/quepasa", server.url)).unwrap();
+    sink.emit(&[
+        Event::Retract {
+            level: 10,
+            contract: None,
+        },
+        Event::Retract {
+            level: 11,
+            contract: None,
+        },
+    ])
+    .unwrap();
+    // (nothing is posted without events)
+    sink.emit(&[]).unwrap();
+
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(1, requests.len());
+    assert_eq!("POST /topics/quepasa", requests[0].0);
+    assert_eq!(
+        serde_json::json!({
+            "records": [
+                {"value": {"kind": "retract", "level": 10}},
+                {"value": {"kind": "retract", "level": 11}},
+            ],
+        }),
+        serde_json::from_str::<serde_json::Value>(&requests[0].1).unwrap()
+    );
+}
@@ -1,7 +1,7 @@
 use anyhow::{anyhow, ensure, Context, Result};
 use chrono::Duration;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
@@ -10,23 +10,41 @@ use std::thread;
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 
-use crate::config::ContractID;
+use crate::config::{ContractID, LevelSourceConfig};
+use crate::contract_denylist::is_contract_denylisted;
 use crate::debug;
-use crate::octez::bcd;
+use crate::events::EventSink;
+use crate::metrics;
 use crate::octez::block::{get_implicit_origination_level, Block, LevelMeta};
 use crate::octez::block_getter::ConcurrentBlockGetter;
-use crate::octez::node::NodeClient;
+use crate::octez::level_source::new_level_source;
+use crate::octez::node::{self, NodeClient};
 use crate::relational::RelationalAST;
-use crate::sql::db::{DBClient, IndexerMode};
+use crate::sql::backend::Backend;
+use crate::sql::db::{ContractRequestStatus, IndexerMode};
 use crate::sql::inserter::{
     insert_processed, DBInserter, ProcessedBlock, ProcessedContractBlock,
 };
+use crate::sql::notify;
 use crate::stats::StatsLogger;
+use crate::storage_structure::naming::NamingPolicy;
 use crate::storage_structure::relational;
+use crate::storage_structure::selection::Selection;
 use crate::storage_structure::typing;
 use crate::storage_update::bigmap::IntraBlockBigmapDiffsProcessor;
 use crate::storage_update::processor::StorageProcessor;
 
+/// The protocols before Babylon, whose nodes did not serve the script of
+/// originated accounts without code (Babylon gave them one)
+const PRE_BABYLON_PROTOCOLS: [&str; 6] = [
+    "PrihK96nBAFSxVL1GLJTVhu9YnzkMFiBeuJRPA8NwuZVZCE1L6i",
+    "Ps9mPmXaRzmzk35gbAYNCAw6UXdE2qoABTHbN2oEEc1qM7CwT9P",
+    "PtCJ7pwoxe8JasnHY8YonnLYjcVHmhiARPJvqcC6VfHT5s8k8sY",
+    "PsYLVpVvgbLhAhoqAkMFUo6gudkJ9weNXhUYCiLDzcUpFpkk8Wt",
+    "PsddFKi32cMJ2qPjf43Qv5GDWLDPZb3T3bF6fLKiF5HtvHNU7aP",
+    "Pt24m4xiPbLDhVgVfABUjirbmda3yohdN82Sp1FeuAXvQjv3sDg",
+];
+
 pub struct SaveLevelResult {
     pub level: u32,
     pub hash: String,
@@ -53,12 +71,48 @@ impl SaveLevelResult {
     }
 }
 
+/// How contracts requested while running in head mode are bootstrapped
+/// (see Executor::accept_contract_requests).
+#[derive(Clone)]
+struct ContractRequests {
+    level_source: Option<LevelSourceConfig>,
+    num_getters: usize,
+    num_processors: usize,
+    acceptable_head_offset: Duration,
+
+    // names of the contracts that are being bootstrapped
+    bootstrapping: HashSet<String>,
+    done_send: flume::Sender<(ContractID, Result<Executor>)>,
+    done_recv: flume::Receiver<(ContractID, Result<Executor>)>,
+}
+
+/// Set on executors that bootstrap requested contracts next to the head
+/// loop. These leave the levels above the ceiling to the head loop, only
+/// replace their own contracts' data in the levels they process, and never
+/// touch the indexer's mode.
+#[derive(Clone)]
+struct BackgroundBootstrap {
+    level_ceiling: u32,
+    // the contracts indexed by the head loop
+    head_contracts: Vec<ContractID>,
+}
+
 #[derive(Clone)]
 pub struct Executor {
     node_cli: NodeClient,
-    dbcli: DBClient,
+    dbcli: Box<dyn Backend>,
 
     all_contracts: bool,
+    index_lambdas: bool,
+    light_scan: bool,
+
+    // per contract name, how to name its tables and columns
+    naming: HashMap<String, NamingPolicy>,
+    selections: HashMap<String, Selection>,
+
+    contract_requests: Option<ContractRequests>,
+    background: Option<BackgroundBootstrap>,
+    event_sink: Option<EventSink>,
 
     // Everything below this level has nothing to do with what we are indexing
     mutexed_state: MutexedState,
@@ -67,15 +121,22 @@ pub struct Executor {
 }
 
 impl Executor {
-    pub fn new(
+    pub(crate) fn new(
         node_cli: NodeClient,
-        dbcli: DBClient,
+        dbcli: Box<dyn Backend>,
         reports_interval: usize,
     ) -> Self {
         Self {
             node_cli,
             dbcli,
             all_contracts: false,
+            index_lambdas: false,
+            light_scan: false,
+            naming: HashMap::new(),
+            selections: HashMap::new(),
+            contract_requests: None,
+            background: None,
+            event_sink: None,
             mutexed_state: MutexedState::new(),
             stats: StatsLogger::new(std::time::Duration::new(
                 reports_interval as u64,
@@ -88,12 +149,92 @@ impl Executor {
         self.all_contracts = true
     }
 
+    pub fn index_lambdas(&mut self) {
+        self.index_lambdas = true
+    }
+
+    pub fn light_scan(&mut self) {
+        self.light_scan = true
+    }
+
+    pub(crate) fn set_naming(&mut self, naming: HashMap<String, NamingPolicy>) {
+        self.naming = naming
+    }
+
+    pub(crate) fn set_selections(
+        &mut self,
+        selections: HashMap<String, Selection>,
+    ) {
+        self.selections = selections
+    }
+
+    /// Emits the processed changes (and the retraction of forked levels) to
+    /// the sink once they are committed
+    pub(crate) fn set_event_sink(&mut self, event_sink: EventSink) {
+        self.event_sink = Some(event_sink);
+    }
+
+    fn selection(&self, contract_id: &ContractID) -> Selection {
+        self.selections
+            .get(&contract_id.name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn naming(&self, contract_id: &ContractID) -> NamingPolicy {
+        self.naming
+            .get(&contract_id.name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Makes exec_continuous pick up the contracts requested with
+    /// --add-contract: each is bootstrapped historically in a background
+    /// thread, and then added to the head loop.
+    pub fn accept_contract_requests(
+        &mut self,
+        level_source: &Option<LevelSourceConfig>,
+        num_getters: usize,
+        num_processors: usize,
+        acceptable_head_offset: Duration,
+    ) -> Result<()> {
+        let (done_send, done_recv) = flume::unbounded();
+        self.contract_requests = Some(ContractRequests {
+            level_source: level_source.clone(),
+            num_getters,
+            num_processors,
+            acceptable_head_offset,
+            bootstrapping: HashSet::new(),
+            done_send,
+            done_recv,
+        });
+        Ok(())
+    }
+
+    /// Adds the contracts that were added earlier through a request
+    /// (--add-contract).
+    pub fn add_requested_contracts(&mut self) -> Result<()> {
+        for contract_id in self
+            .dbcli
+            .get_contract_requests(&[ContractRequestStatus::Added])?
+        {
+            self.add_contract(&contract_id)?;
+        }
+        Ok(())
+    }
+
     pub fn add_contract(&mut self, contract_id: &ContractID) -> Result<bool> {
         debug!(
             "getting the storage definition for contract={}..",
             contract_id.name
         );
-        let mut contract = get_contract_rel(&self.node_cli, contract_id)?;
+        let mut contract = get_contract_rel(
+            &self.node_cli,
+            contract_id,
+            self.index_lambdas,
+            &self.naming(contract_id),
+            &self.selection(contract_id),
+        )?;
 
         contract.level_floor = self
             .dbcli
@@ -112,7 +253,13 @@ impl Executor {
         let mut l: Vec<relational::Contract> = vec![];
 
         for contract_id in contracts {
-            l.push(get_contract_rel(&self.node_cli, contract_id)?);
+            l.push(get_contract_rel(
+                &self.node_cli,
+                contract_id,
+                self.index_lambdas,
+                &self.naming(contract_id),
+                &self.selection(contract_id),
+            )?);
         }
 
         self.dbcli
@@ -166,10 +313,14 @@ impl Executor {
     pub fn add_dependency_contracts(&mut self) -> Result<()> {
         let deps = self
             .dbcli
-            .get_config_deps(&self.get_config()?)
-            .unwrap();
+            .get_config_deps(&self.get_config()?)?;
 
         for dep in &deps {
+            if let Some(bg) = &self.background {
+                if bg.head_contracts.contains(dep) {
+                    continue;
+                }
+            }
             self.add_contract(dep)?;
         }
 
@@ -181,25 +332,35 @@ impl Executor {
         // in the db
         let mode = self.dbcli.get_indexer_mode()?;
         if mode == IndexerMode::Bootstrap {
-            self.repopulate_derived_tables(true)?;
+            self.end_bootstrap()?;
         }
 
-        fn wait(first_wait: &mut bool) {
-            if *first_wait {
-                print!("waiting for the next block");
-            } else {
-                print!(".");
+        // (when events are emitted to stdout, the wait is not shown there)
+        fn wait(first_wait: &mut bool, show: bool) {
+            if show {
+                if *first_wait {
+                    print!("waiting for the next block");
+                } else {
+                    print!(".");
+                }
+                io::stdout().flush().unwrap();
             }
             *first_wait = false;
-            io::stdout().flush().unwrap();
             std::thread::sleep(std::time::Duration::from_millis(1000));
         }
-        fn wait_done(first_wait: &mut bool) {
+        fn wait_done(first_wait: &mut bool, show: bool) {
             if !*first_wait {
-                println!();
+                if show {
+                    println!();
+                }
                 *first_wait = false;
             }
         }
+        let show_wait = !self
+            .event_sink
+            .as_ref()
+            .map(|sink| sink.writes_to_stdout())
+            .unwrap_or(false);
         let mut first_wait = true;
         loop {
             let chain_head = self.node_cli.head()?;
@@ -219,9 +380,13 @@ impl Executor {
                 }
             }?;
             debug!("db: {} chain: {}", db_head.level, chain_head.level);
+            metrics::set_heads(chain_head.level, db_head.level);
+            if self.contract_requests.is_some() {
+                self.exec_contract_requests(db_head.level)?;
+            }
             match chain_head.level.cmp(&db_head.level) {
                 Ordering::Greater => {
-                    wait_done(&mut first_wait);
+                    wait_done(&mut first_wait, show_wait);
                     for level in (db_head.level + 1)..=chain_head.level {
                         Self::print_status(level, &self.exec_level(level)?);
                     }
@@ -229,13 +394,13 @@ impl Executor {
                     continue;
                 }
                 Ordering::Less => {
-                    wait(&mut first_wait);
+                    wait(&mut first_wait, show_wait);
                     continue;
                 }
                 Ordering::Equal => {
                     // they are equal, so we will just check that the hashes match.
                     if db_head.hash != chain_head.hash {
-                        wait_done(&mut first_wait);
+                        wait_done(&mut first_wait, show_wait);
                         warn!(
                             "Hashes don't match at level={:?}: {:?} (db) <> {:?} (chain)",
                             db_head.level, db_head.hash, chain_head.hash
@@ -244,16 +409,11 @@ impl Executor {
                             "reprocessing following forked levels: {:?}",
                             vec![db_head.level],
                         );
+                        metrics::FORKED_LEVELS.inc();
 
-                        let mut conn = self.dbcli.dbconn()?;
-                        let mut tx = conn.transaction()?;
-                        DBClient::delete_levels(
-                            &mut tx,
-                            &[db_head.level as i32],
-                        )?;
-                        tx.commit()?;
+                        self.delete_levels(&[db_head.level])?;
                     }
-                    wait(&mut first_wait);
+                    wait(&mut first_wait, show_wait);
                 }
             }
         }
@@ -324,16 +484,7 @@ impl Executor {
                     forked_levels
                 );
 
-                let mut conn = self.dbcli.dbconn()?;
-                let mut tx = conn.transaction()?;
-                DBClient::delete_levels(
-                    &mut tx,
-                    &forked_levels
-                        .iter()
-                        .map(|lvl| *lvl as i32)
-                        .collect::<Vec<i32>>(),
-                )?;
-                tx.commit()?;
+                self.delete_levels(&forked_levels)?;
 
                 self.exec_levels(num_getters, num_processors, forked_levels)
             }
@@ -347,15 +498,15 @@ impl Executor {
 
     pub fn exec_new_contracts_historically(
         &mut self,
-        bcd_settings: &Option<(String, String)>,
+        level_source: &Option<LevelSourceConfig>,
         num_getters: usize,
         num_processors: usize,
         acceptable_head_offset: Duration,
     ) -> Result<Vec<ContractID>> {
         let mut res: Vec<ContractID> = vec![];
         loop {
-            self.add_dependency_contracts().unwrap();
-            let new_contracts = self.create_contract_schemas().unwrap();
+            self.add_dependency_contracts()?;
+            let new_contracts = self.create_contract_schemas()?;
 
             if new_contracts.is_empty() {
                 break;
@@ -368,20 +519,338 @@ impl Executor {
             );
 
             self.exec_missing_levels(
-                bcd_settings,
+                level_source,
                 num_getters,
                 num_processors,
                 acceptable_head_offset,
                 false,
-            )
-            .unwrap();
+            )?;
         }
         if !res.is_empty() {
-            self.exec_dependents().unwrap();
+            self.exec_dependents()?;
+        }
+        Ok(res)
+    }
+
+    fn contract_requests(&mut self) -> Result<&mut ContractRequests> {
+        self.contract_requests
+            .as_mut()
+            .ok_or_else(|| anyhow!("contract requests are not accepted"))
+    }
+
+    fn exec_contract_requests(&mut self, db_head: u32) -> Result<()> {
+        let done: Vec<(ContractID, Result<Executor>)> = self
+            .contract_requests()?
+            .done_recv
+            .try_iter()
+            .collect();
+        for (contract_id, res) in done {
+            self.contract_requests()?
+                .bootstrapping
+                .remove(&contract_id.name);
+            match res.and_then(|bootstrapped| {
+                self.merge_bootstrapped_contracts(bootstrapped)
+            }) {
+                Ok(added) => {
+                    for cid in &added {
+                        info!("contract {} added to the head loop", cid.name);
+                    }
+                    self.dbcli.set_contract_request_status(
+                        &contract_id,
+                        ContractRequestStatus::Added,
+                        None,
+                    )?;
+                }
+                Err(e) => {
+                    error!(
+                        "failed to add requested contract {}, err: {:?}",
+                        contract_id.name, e
+                    );
+                    self.dbcli.set_contract_request_status(
+                        &contract_id,
+                        ContractRequestStatus::Failed,
+                        Some(format!("{:#}", e)),
+                    )?;
+                }
+            }
+        }
+
+        // Requests still marked as bootstrapping were interrupted by a
+        // restart, these are started over
+        for contract_id in self.dbcli.get_contract_requests(&[
+            ContractRequestStatus::Requested,
+            ContractRequestStatus::Bootstrapping,
+        ])? {
+            if self
+                .contract_requests()?
+                .bootstrapping
+                .contains(&contract_id.name)
+            {
+                continue;
+            }
+            if let Err(e) = self.check_contract_request(&contract_id) {
+                warn!(
+                    "rejected request to add contract {}, err: {}",
+                    contract_id.name, e
+                );
+                self.dbcli.set_contract_request_status(
+                    &contract_id,
+                    ContractRequestStatus::Failed,
+                    Some(e.to_string()),
+                )?;
+                continue;
+            }
+            if self
+                .get_config()?
+                .contains(&contract_id)
+            {
+                self.dbcli.set_contract_request_status(
+                    &contract_id,
+                    ContractRequestStatus::Added,
+                    None,
+                )?;
+                continue;
+            }
+
+            info!(
+                "bootstrapping requested contract {} ({}) in the background",
+                contract_id.name, contract_id.address
+            );
+            self.dbcli.set_contract_request_status(
+                &contract_id,
+                ContractRequestStatus::Bootstrapping,
+                None,
+            )?;
+            let mut bootstrapper = self.new_background_bootstrapper(db_head)?;
+            let requests = self.contract_requests()?;
+            requests
+                .bootstrapping
+                .insert(contract_id.name.clone());
+            let settings = requests.clone();
+            thread::spawn(move || {
+                let res = bootstrapper
+                    .add_contract(&contract_id)
+                    .and_then(|_| {
+                        bootstrapper.exec_new_contracts_historically(
+                            &settings.level_source,
+                            settings.num_getters,
+                            settings.num_processors,
+                            settings.acceptable_head_offset,
+                        )
+                    })
+                    .and_then(|_| {
+                        bootstrapper.catch_up_with_head_loop(
+                            settings.num_getters,
+                            settings.num_processors,
+                        )
+                    })
+                    .map(|_| bootstrapper);
+                // the receiver only disconnects when the head loop stopped
+                let _ = settings
+                    .done_send
+                    .send((contract_id, res));
+            });
+        }
+        Ok(())
+    }
+
+    fn check_contract_request(&self, contract_id: &ContractID) -> Result<()> {
+        ensure!(
+            !is_contract_denylisted(&contract_id.address),
+            anyhow!("contract {} is denylisted", contract_id.address)
+        );
+        for indexed in self.get_config()? {
+            ensure!(
+                indexed.name != contract_id.name
+                    || indexed.address == contract_id.address,
+                anyhow!(
+                    "name {} is already assigned to contract {}",
+                    indexed.name,
+                    indexed.address
+                )
+            );
+            ensure!(
+                indexed.address != contract_id.address
+                    || indexed.name == contract_id.name,
+                anyhow!(
+                    "contract {} is already indexed as {}",
+                    indexed.address,
+                    indexed.name
+                )
+            );
         }
+        Ok(())
+    }
+
+    /// Returns an executor without any contracts, for bootstrapping
+    /// contracts next to the head loop. It only processes levels up to
+    /// level_ceiling, the levels above are left to the head loop.
+    fn new_background_bootstrapper(&self, level_ceiling: u32) -> Result<Self> {
+        let mut res = self.clone();
+        res.mutexed_state = MutexedState::new();
+        res.stats = StatsLogger::new(self.stats.interval());
+        res.contract_requests = None;
+        res.background = Some(BackgroundBootstrap {
+            level_ceiling,
+            head_contracts: self.get_config()?,
+        });
         Ok(res)
     }
 
+    /// Processes, for the contracts of a background bootstrapper, the levels
+    /// the head loop committed above its ceiling in the meantime. Passes are
+    /// repeated until the head loop committed nothing new during one.
+    fn catch_up_with_head_loop(
+        &mut self,
+        num_getters: usize,
+        num_processors: usize,
+    ) -> Result<()> {
+        let head_contracts = match &self.background {
+            Some(bg) => bg.head_contracts.clone(),
+            None => return Err(anyhow!("not a background bootstrapper")),
+        };
+        let contracts: Vec<ContractID> = self
+            .get_config()?
+            .into_iter()
+            .filter(|contract_id| !head_contracts.contains(contract_id))
+            .collect();
+        while let Some(db_head) = self.dbcli.get_head()? {
+            let bg = self
+                .background
+                .as_mut()
+                .ok_or_else(|| anyhow!("not a background bootstrapper"))?;
+            if db_head.level <= bg.level_ceiling {
+                break;
+            }
+            bg.level_ceiling = db_head.level;
+            let levels = self
+                .dbcli
+                .get_missing_levels(&contracts, db_head.level)?;
+            self.exec_levels(num_getters, num_processors, levels)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the contracts of a background bootstrapper to the head loop. The
+    /// bootstrapper caught up with the head loop before it was done (see
+    /// catch_up_with_head_loop), what is left are the levels the head loop
+    /// committed since, if any.
+    fn merge_bootstrapped_contracts(
+        &mut self,
+        mut bootstrapped: Executor,
+    ) -> Result<Vec<ContractID>> {
+        let requests = self.contract_requests()?;
+        let (num_getters, num_processors) =
+            (requests.num_getters, requests.num_processors);
+        let new_contracts = self
+            .mutexed_state
+            .get_missing_contracts(&bootstrapped.get_config()?)?;
+        bootstrapped.catch_up_with_head_loop(num_getters, num_processors)?;
+        for contract_id in &new_contracts {
+            let contract = bootstrapped
+                .mutexed_state
+                .get_contract(contract_id)?
+                .ok_or_else(|| {
+                    anyhow!(
+                        "bootstrapped contract {} is missing from its bootstrapper",
+                        contract_id.name
+                    )
+                })?;
+            self.mutexed_state
+                .add_contract(contract)?;
+        }
+        self.update_level_floor()?;
+        Ok(new_contracts)
+    }
+
+    fn exec_light_scanned(
+        &mut self,
+        num_getters: usize,
+        num_processors: usize,
+        missing_levels: Vec<u32>,
+    ) -> Result<()> {
+        let config = &self.get_config_sorted()?;
+        let latest_level = *missing_levels.iter().max().unwrap();
+
+        info!("light scanning {} missing levels..", missing_levels.len());
+        let mut levels =
+            self.scan_active_levels(num_getters, missing_levels, config)?;
+        info!(
+            "light scan done, processing {} levels with activity",
+            levels.len()
+        );
+
+        // Like with the level sources, always process the last level, so
+        // that all levels up to it are marked as processed below
+        levels.push(latest_level);
+        levels.sort_unstable();
+        levels.dedup();
+        levels.reverse();
+        self.exec_levels(num_getters, num_processors, levels)?;
+
+        for contract_id in config {
+            self.mark_missing_levels_empty(contract_id)?;
+        }
+        self.exec_partially_processed(num_getters, num_processors)?;
+        Ok(())
+    }
+
+    /// Fetches light blocks (see NodeClient::level_json_light) of given
+    /// levels, and returns those levels in which any of the contracts is
+    /// active.
+    fn scan_active_levels(
+        &mut self,
+        num_getters: usize,
+        levels: Vec<u32>,
+        contracts: &[ContractID],
+    ) -> Result<Vec<u32>> {
+        let (height_send, height_recv) = flume::bounded::<u32>(num_getters);
+        let (block_send, block_recv) =
+            flume::bounded::<Box<(LevelMeta, Block)>>(num_getters * 5);
+
+        let mut block_getter =
+            ConcurrentBlockGetter::new(self.node_cli.clone(), num_getters);
+        block_getter.light();
+        let mut threads = vec![thread::spawn(move || {
+            send_levels(&height_send, levels);
+            Ok(())
+        })];
+        threads.extend(block_getter.run(height_recv, block_send));
+
+        self.stats.reset()?;
+        let stats_thread = self.stats.run();
+
+        let mut active_levels: Vec<u32> = vec![];
+        for b in block_recv {
+            let (meta, block) = *b;
+            if contracts.iter().any(|c| {
+                block.has_contract_origination(&c.address)
+                    || block.is_contract_active(&c.address)
+            }) {
+                active_levels.push(meta.level);
+                self.stats
+                    .add("light scan", "active levels", 1)?;
+            }
+            self.stats
+                .add("light scan", "levels", 1)?;
+            self.stats.set(
+                "light scan",
+                "last scanned level",
+                format!("{} ({:?})", meta.level, meta.baked_at.unwrap()),
+            )?;
+        }
+
+        let res = join_pipeline(threads);
+        self.stats.stop();
+        stats_thread.thread().unpark();
+        stats_thread.join().map_err(|e| {
+            anyhow!("failed to stop light scan statistics logger, err: {:?}", e)
+        })?;
+        res?;
+
+        Ok(active_levels)
+    }
+
     fn exec_partially_processed(
         &mut self,
         num_getters: usize,
@@ -401,14 +870,25 @@ impl Executor {
 
     pub fn exec_missing_levels(
         &mut self,
-        bcd_settings: &Option<(String, String)>,
+        level_source: &Option<LevelSourceConfig>,
         num_getters: usize,
         num_processors: usize,
         acceptable_head_offset: Duration,
         exec_dependent_levels: bool,
     ) -> Result<()> {
         loop {
-            let latest_level: LevelMeta = self.node_cli.head()?;
+            let latest_level: LevelMeta = match &self.background {
+                Some(bg) => {
+                    self.node_cli
+                        .level_json(bg.level_ceiling)?
+                        .0
+                }
+                None => {
+                    let head = self.node_cli.head()?;
+                    metrics::CHAIN_HEAD.set(head.level as i64);
+                    head
+                }
+            };
 
             let mut missing_levels: Vec<u32> = self
                 .dbcli
@@ -433,8 +913,7 @@ impl Executor {
                 break;
             }
 
-            if missing_levels.len() > 1000 && bcd_settings.is_some() {
-                let (bcd_url, network) = bcd_settings.as_ref().unwrap();
+            if missing_levels.len() > 1000 && level_source.is_some() {
                 let config = &self.get_config_sorted()?;
 
                 let mut exclude_levels: Vec<u32> = self
@@ -442,45 +921,54 @@ impl Executor {
                     .get_fully_processed_levels(config)?;
                 for contract_id in config {
                     info!("Indexing missing levels for {}..", contract_id.name);
-                    let bcd_cli = bcd::BCDClient::new(
-                        bcd_url.clone(),
-                        network.clone(),
-                        contract_id.clone(),
-                    );
+                    let source =
+                        new_level_source(level_source.as_ref().unwrap())?;
+                    let cid = contract_id.clone();
 
                     let excl = exclude_levels.clone();
                     let stats = self.stats.clone();
                     let node_cli = self.node_cli.clone();
-                    let processed_levels = self
-                        .exec_parallel(
-                            num_getters,
-                            num_processors,
-                            move |height_chan| {
-                                bcd_cli
-                                    .populate_levels_chan(
-                                        || Ok(node_cli.head()?.level),
-                                        &stats,
-                                        &height_chan,
-                                        &excl,
-                                    )
-                                    .unwrap()
-                            },
-                        )
-                        .unwrap();
+                    let processed_levels = self.exec_parallel(
+                        num_getters,
+                        num_processors,
+                        move |height_chan| {
+                            let res = source.populate_levels_chan(
+                                &cid,
+                                &|| Ok(node_cli.head()?.level),
+                                &stats,
+                                &height_chan,
+                                &excl,
+                            );
+                            if height_chan.is_disconnected() {
+                                // the block getters stopped, if they
+                                // failed they report their error
+                                return Ok(());
+                            }
+                            res
+                        },
+                    )?;
                     exclude_levels.extend(processed_levels);
 
                     if let Some(l) =
                         get_implicit_origination_level(&contract_id.address)
                     {
-                        self.exec_level(l).unwrap();
+                        self.exec_level(l)?;
                     }
 
-                    self.mark_missing_levels_empty(contract_id)
-                        .unwrap();
+                    self.mark_missing_levels_empty(contract_id)?;
 
                     info!("contract {} initialized.", contract_id.name)
                 }
                 self.exec_partially_processed(num_getters, num_processors)?;
+            } else if missing_levels.len() > 1000
+                && self.light_scan
+                && !self.all_contracts
+            {
+                self.exec_light_scanned(
+                    num_getters,
+                    num_processors,
+                    missing_levels,
+                )?;
             } else {
                 missing_levels.reverse();
                 info!("processing {} missing levels", missing_levels.len());
@@ -493,45 +981,35 @@ impl Executor {
         Ok(())
     }
 
-    pub(crate) fn repopulate_derived_tables(
-        &mut self,
-        ensure_sane_input_state: bool,
-    ) -> Result<()> {
+    /// Marks the end of bootstrapping. The derived tables (_live, _ordered)
+    /// are kept up to date while bootstrapping, so they are ready as is.
+    pub(crate) fn end_bootstrap(&mut self) -> Result<()> {
+        info!("bootstrap done, switching to head mode");
+        self.dbcli
+            .set_indexer_mode(IndexerMode::Head)
+    }
+
+    /// Derives the _live and _ordered tables of all contracts in the
+    /// database again from scratch. Only needed when they went stale (see
+    /// --rebuild-derived), they are otherwise kept up to date with every
+    /// inserted batch.
+    pub(crate) fn rebuild_derived_tables(&mut self) -> Result<()> {
         info!(
-            "re-populating derived tables (_live, _ordered). may take a while (expect minutes-hours, not seconds-minutes)."
+            "rebuilding the derived tables (_live, _ordered). may take a while (expect minutes-hours, not seconds-minutes)."
         );
-
-        if ensure_sane_input_state {
-            let latest_level: LevelMeta = self.node_cli.head()?;
-            let missing_levels: Vec<u32> = self
-                .dbcli
-                .get_missing_levels(&self.get_config()?, latest_level.level)?;
-            let has_gaps = missing_levels
-                .windows(2)
-                .any(|w| w[0] != w[1] - 1);
-            ensure!(
-                !has_gaps,
-                anyhow!("cannot re-populate derived tables, there are gaps in the processed levels")
-            );
-            ensure!(
-                self.dbcli
-                    .get_partial_processed_levels(&self.get_config()?)?
-                    .is_empty(),
-                anyhow!("cannot re-populate derived tables, some levels are only partially processed (not processed for some contracts)")
-            );
-        }
-
-        for contract in self
-            .mutexed_state
-            .get_contracts()?
-            .values()
-        {
+        for contract_id in self.dbcli.get_contracts()? {
+            let contract = get_contract_rel(
+                &self.node_cli,
+                &contract_id,
+                self.index_lambdas,
+                &self.naming(&contract_id),
+                &self.selection(&contract_id),
+            )?;
             self.dbcli
-                .repopulate_derived_tables(contract)?;
+                .rebuild_derived_tables(&contract)?;
         }
         self.dbcli
-            .set_indexer_mode(IndexerMode::Head)?;
-        Ok(())
+            .set_derived_tables_stale(false)
     }
 
     pub fn mark_missing_levels_empty(
@@ -564,12 +1042,17 @@ impl Executor {
             num_getters,
             num_processors,
             move |height_chan| {
-                for l in levels {
-                    if have_floor && l < st.get_level_floor().unwrap() {
-                        continue;
-                    }
-                    height_chan.send(l).unwrap();
-                }
+                let level_floor = match have_floor {
+                    true => st.get_level_floor()?,
+                    false => 0,
+                };
+                send_levels(
+                    &height_chan,
+                    levels
+                        .into_iter()
+                        .filter(|l| *l >= level_floor),
+                );
+                Ok(())
             },
         )?;
         Ok(processed_levels)
@@ -582,13 +1065,15 @@ impl Executor {
         levels_selector: F,
     ) -> Result<Vec<u32>>
     where
-        F: FnOnce(flume::Sender<u32>) + Send + 'static,
+        F: FnOnce(flume::Sender<u32>) -> Result<()> + Send + 'static,
     {
-        // a parallel exec has the consequence that we need to re-derive the
-        // _live and _ordered tables when done. therefore we change the mode to
-        // "bootstrap" here
-        self.dbcli
-            .set_indexer_mode(IndexerMode::Bootstrap)?;
+        // a parallel exec processes levels out of order, until it's done the
+        // indexer is "bootstrapping" (not ready, see health). background
+        // bootstraps don't affect the head loop's mode
+        if self.background.is_none() {
+            self.dbcli
+                .set_indexer_mode(IndexerMode::Bootstrap)?;
+        }
 
         let (height_send, height_recv) = flume::bounded::<u32>(num_getters);
         let (block_send, block_recv) =
@@ -596,15 +1081,18 @@ impl Executor {
 
         let block_getter =
             ConcurrentBlockGetter::new(self.node_cli.clone(), num_getters);
-        let mut threads = block_getter.run(height_recv, block_send);
-
-        threads.push(thread::spawn(|| levels_selector(height_send)));
+        let mut threads = vec![thread::spawn(|| levels_selector(height_send))];
+        threads.extend(block_getter.run(height_recv, block_send));
 
         self.stats.reset()?;
         let stats_thread = self.stats.run();
 
         let batch_size = 10;
-        let inserter = DBInserter::new(self.dbcli.clone(), batch_size);
+        let mut inserter = DBInserter::new(self.dbcli.clone(), batch_size);
+        if self.background.is_some() {
+            inserter.contracts_scoped();
+        }
+        inserter.set_event_sink(self.event_sink.clone());
         let (processed_send, processed_recv) =
             flume::bounded::<Box<ProcessedBlock>>(batch_size * 10);
 
@@ -613,12 +1101,18 @@ impl Executor {
         let processed_results: Arc<Mutex<(Vec<u32>, Vec<u32>)>> =
             Arc::new(Mutex::new((vec![], vec![])));
 
+        let mut processor_res: Result<()> = Ok(());
         if num_processors <= 1 {
-            let (processed, reprocess) =
-                self.read_block_chan(block_recv, processed_send)?;
-            let mut res = processed_results.lock().unwrap();
-            res.0.extend(processed);
-            res.1.extend(reprocess);
+            // on failure, the other threads are still joined below (they stop
+            // as their channels to this processor are disconnected)
+            match self.read_block_chan(block_recv, processed_send) {
+                Ok((processed, reprocess)) => {
+                    let mut res = processed_results.lock().unwrap();
+                    res.0.extend(processed);
+                    res.1.extend(reprocess);
+                }
+                Err(e) => processor_res = Err(e),
+            }
         } else {
             info!("starting {} concurrent processors", num_processors);
             for _ in 0..num_processors {
@@ -627,28 +1121,25 @@ impl Executor {
                 let w_send_ch = processed_send.clone();
                 let res_arc = processed_results.clone();
                 threads.push(thread::spawn(move || {
-                    let (processed, reprocess) = exec
-                        .read_block_chan(w_recv_ch, w_send_ch)
-                        .unwrap();
+                    let (processed, reprocess) =
+                        exec.read_block_chan(w_recv_ch, w_send_ch)?;
 
                     let mut res = res_arc.lock().unwrap();
                     res.0.extend(processed);
                     res.1.extend(reprocess);
+                    Ok(())
                 }));
             }
             drop(processed_send);
         }
 
-        for t in threads {
-            t.join().map_err(|e| {
-                anyhow!("parallel execution thread failed with err: {:?}", e)
-            })?;
-        }
+        let res = processor_res.and(join_pipeline(threads));
         self.stats.stop();
         stats_thread.thread().unpark();
         stats_thread.join().map_err(|e| {
             anyhow!("failed to stop processor statistics logger, err: {:?}", e)
         })?;
+        res?;
 
         let (mut processed_levels, reprocess_levels) =
             Arc::try_unwrap(processed_results)
@@ -682,6 +1173,11 @@ impl Executor {
         let mut reprocess_levels: Vec<u32> = vec![];
         for b in block_ch {
             let (meta, block) = *b;
+            if let Some(bg) = &self.background {
+                if meta.level > bg.level_ceiling {
+                    continue;
+                }
+            }
 
             let (processed_block, forked_lvls) = self
                 .exec_for_block(&meta, &block)
@@ -719,7 +1215,14 @@ impl Executor {
                     processed_ch.capacity().unwrap()
                 ),
             )?;
-            processed_ch.send(Box::new(processed_block))?;
+            if processed_ch
+                .send(Box::new(processed_block))
+                .is_err()
+            {
+                // the inserter stopped, if it failed it reports its error
+                // itself
+                break;
+            }
             self.stats
                 .add("processor", "levels", 1)?;
             self.stats.set(
@@ -760,16 +1263,7 @@ impl Executor {
         if !forked_lvls.is_empty() {
             warn!("reprocessing following forked levels: {:?}", forked_lvls);
 
-            let mut conn = self.dbcli.dbconn()?;
-            let mut tx = conn.transaction()?;
-            DBClient::delete_levels(
-                &mut tx,
-                &forked_lvls
-                    .iter()
-                    .map(|lvl| *lvl as i32)
-                    .collect::<Vec<i32>>(),
-            )?;
-            tx.commit()?;
+            self.delete_levels(&forked_lvls)?;
 
             for lvl in forked_lvls {
                 Self::print_status(lvl, &self.exec_level(lvl)?);
@@ -780,15 +1274,13 @@ impl Executor {
             res.push(SaveLevelResult::from_processed_block(cres));
         }
 
-        let update_derived_tables =
-            self.dbcli.get_indexer_mode()? == IndexerMode::Head;
-        #[cfg(feature = "regression_force_update_derived")]
-        let update_derived_tables = true | update_derived_tables;
-
+        let notification = notify::level_payload(&meta, &processed_block)?;
         insert_processed(
-            &mut self.dbcli.clone(),
-            update_derived_tables,
+            self.dbcli.as_mut(),
+            self.background.is_some(),
+            self.event_sink.as_ref(),
             processed_block,
+            notification,
         )?;
 
         Ok(res)
@@ -852,7 +1344,11 @@ impl Executor {
                     })?,
             );
         }
+        metrics::level_processed();
         for cres in &contract_results {
+            metrics::CONTRACT_CALLS
+                .with_label_values(&[&cres.contract.cid.name])
+                .inc_by(cres.tx_contexts.len() as u64);
             if cres.is_origination {
                 self.update_contract_floor(
                     &cres.contract.cid,
@@ -888,6 +1384,13 @@ impl Executor {
             });
         }
 
+        let contract = &self.get_contract_version(
+            contract,
+            meta.level,
+            &block.protocol,
+            is_origination,
+        )?;
+
         let mut storage_processor = self.get_storage_processor()?;
         storage_processor.set_stats_logger(self.stats.clone());
         storage_processor
@@ -919,6 +1422,84 @@ impl Executor {
         })
     }
 
+    /// Returns the version of the contract's storage type that the block was
+    /// applied with. Scripts only change on protocol migrations, so there's
+    /// one version per protocol. Versions that differ from the contract's
+    /// current (head) type get their tables and columns added to the
+    /// contract's schema.
+    ///
+    /// Failing to get the script fails the level (so that it's retried),
+    /// except before Babylon where the node may not serve it at all: then
+    /// the head type is used for this level, without remembering it for the
+    /// protocol.
+    fn get_contract_version(
+        &self,
+        contract: &relational::Contract,
+        level: u32,
+        protocol: &str,
+        is_origination: bool,
+    ) -> Result<relational::Contract> {
+        if protocol.is_empty() {
+            return Ok(contract.clone());
+        }
+        // The block is applied with the script as it was at the end of the
+        // previous level (a migration rewrites scripts at the end of the
+        // last level of the old protocol), except for the block that
+        // originates the contract
+        let script_level = match is_origination {
+            true => level,
+            false => level.saturating_sub(1),
+        };
+        let res = self
+            .mutexed_state
+            .get_or_add_contract_version(&contract.cid, protocol, || {
+                let version = get_contract_rel_at(
+                    &self.node_cli,
+                    &contract.cid,
+                    Some(script_level),
+                    self.index_lambdas,
+                    &self.naming(&contract.cid),
+                    &self.selection(&contract.cid),
+                )
+                .with_context(|| {
+                    anyhow!(
+                        "contract {}: failed to get its storage type under protocol {} (level {})",
+                        contract.cid.name, protocol, script_level
+                    )
+                })?;
+                if version.storage_ast == contract.storage_ast
+                    && version.entrypoint_asts == contract.entrypoint_asts
+                {
+                    return Ok(contract.clone());
+                }
+
+                info!(
+                    "contract {}: storage type under protocol {} differs from the one at head",
+                    contract.cid.name, protocol
+                );
+                self.dbcli
+                    .clone()
+                    .add_contract_version_tables(&version)?;
+                Ok(relational::Contract {
+                    level_floor: contract.level_floor,
+                    ..version
+                })
+            });
+        match res {
+            Err(e)
+                if PRE_BABYLON_PROTOCOLS.contains(&protocol)
+                    && node::is_not_found(&e) =>
+            {
+                warn!(
+                    "{:#}, the node does not serve it before Babylon, assuming it's the same as at head",
+                    e
+                );
+                Ok(contract.clone())
+            }
+            res => res,
+        }
+    }
+
     fn update_contract_floor(
         &mut self,
         contract_id: &ContractID,
@@ -937,6 +1518,15 @@ impl Executor {
         self.mutexed_state.set_level_floor()
     }
 
+    /// Deletes forked levels, and retracts what was emitted for them
+    fn delete_levels(&mut self, levels: &[u32]) -> Result<()> {
+        self.dbcli.delete_levels(levels)?;
+        if let Some(event_sink) = &self.event_sink {
+            event_sink.emit_retractions(levels)?;
+        }
+        Ok(())
+    }
+
     fn ensure_level_hash(
         &mut self,
         level: u32,
@@ -975,12 +1565,13 @@ impl Executor {
             }
         }
 
+        metrics::FORKED_LEVELS.inc_by(forked_lvls.len() as u64);
         Ok(forked_lvls)
     }
 
     fn get_storage_processor(
         &self,
-    ) -> Result<StorageProcessor<NodeClient, DBClient>> {
+    ) -> Result<StorageProcessor<NodeClient, Box<dyn Backend>>> {
         Ok(StorageProcessor::new(
             1,
             self.node_cli.clone(),
@@ -1022,6 +1613,10 @@ struct MutexedState {
     #[allow(clippy::type_complexity)]
     contracts: Arc<Mutex<HashMap<ContractID, relational::Contract>>>,
     level_floor: Arc<Mutex<u32>>,
+    /// The contracts' storage type versions, per protocol
+    #[allow(clippy::type_complexity)]
+    contract_versions:
+        Arc<Mutex<HashMap<(ContractID, String), relational::Contract>>>,
 }
 
 impl MutexedState {
@@ -1029,6 +1624,7 @@ impl MutexedState {
         Self {
             contracts: Arc::new(Mutex::new(HashMap::new())),
             level_floor: Arc::new(Mutex::new(0)),
+            contract_versions: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -1108,6 +1704,32 @@ impl MutexedState {
         Ok(contracts.clone())
     }
 
+    /// Returns the contract's version for the protocol, derives it with
+    /// new_version if it is not known yet. The lock is held while deriving,
+    /// so that each version is only derived (and its tables added) once.
+    pub fn get_or_add_contract_version<F>(
+        &self,
+        contract_id: &ContractID,
+        protocol: &str,
+        new_version: F,
+    ) -> Result<relational::Contract>
+    where
+        F: FnOnce() -> Result<relational::Contract>,
+    {
+        let mut versions = self
+            .contract_versions
+            .lock()
+            .map_err(|_| anyhow!("failed to lock contract_versions mutex"))?;
+
+        let key = (contract_id.clone(), protocol.to_string());
+        if let Some(version) = versions.get(&key) {
+            return Ok(version.clone());
+        }
+        let version = new_version()?;
+        versions.insert(key, version.clone());
+        Ok(version)
+    }
+
     pub fn get_missing_contracts(
         &self,
         l: &[ContractID],
@@ -1124,12 +1746,56 @@ impl MutexedState {
     }
 }
 
+/// Sends the levels to the block getters, stops early if they stopped (if
+/// they failed they report their error themselves)
+fn send_levels(
+    height_send: &flume::Sender<u32>,
+    levels: impl IntoIterator<Item = u32>,
+) {
+    for l in levels {
+        if height_send.send(l).is_err() {
+            return;
+        }
+    }
+}
+
+/// Joins the threads of a parallel execution, and returns the first error
+/// of any of them. A thread that can no longer pass on its results (because
+/// the next thread stopped) stops without error, so this is the error that
+/// made the execution stop.
+fn join_pipeline(threads: Vec<thread::JoinHandle<Result<()>>>) -> Result<()> {
+    let mut res = Ok(());
+    for t in threads {
+        let thread_res = t.join().map_err(|e| {
+            anyhow!("parallel execution thread failed with err: {:?}", e)
+        });
+        res = res.and(thread_res.and_then(|r| r));
+    }
+    res
+}
+
 pub(crate) fn get_contract_rel(
     node_cli: &NodeClient,
     cid: &ContractID,
+    index_lambdas: bool,
+    naming: &NamingPolicy,
+    selection: &Selection,
+) -> Result<relational::Contract> {
+    get_contract_rel_at(node_cli, cid, None, index_lambdas, naming, selection)
+}
+
+/// Builds the contract's relational representation from its script at the
+/// given level (or at head if level is None)
+pub(crate) fn get_contract_rel_at(
+    node_cli: &NodeClient,
+    cid: &ContractID,
+    level: Option<u32>,
+    index_lambdas: bool,
+    naming: &NamingPolicy,
+    selection: &Selection,
 ) -> Result<relational::Contract> {
     let (storage_def, _) =
-        &node_cli.get_contract_storage_definition(&cid.address, None)?;
+        &node_cli.get_contract_storage_definition(&cid.address, level)?;
     let type_ast = typing::type_ast_from_json(storage_def)
         .with_context(|| {
             "failed to derive a storage type from the storage definition"
@@ -1145,7 +1811,14 @@ pub(crate) fn get_contract_rel(
     );
 
     // Build the internal representation from the storage defition
-    let storage_ast = relational::ASTBuilder::new("storage")
+    let mut storage_builder = relational::ASTBuilder::new("storage");
+    storage_builder
+        .naming(naming.clone())
+        .selection(selection.clone());
+    if index_lambdas {
+        storage_builder.micheline_lambdas();
+    }
+    let storage_ast = storage_builder
         .build_relational_ast(&type_ast)
         .with_context(|| {
             "failed to build a relational AST from the storage type"
@@ -1153,11 +1826,16 @@ pub(crate) fn get_contract_rel(
         .with_context(|| anyhow!("contract address={}", cid.address))?;
     debug!("rel_ast: {:#?}", storage_ast);
 
+    let mut tables = storage_builder.tables().clone();
+
     let entrypoint_defs =
-        &node_cli.get_contract_entrypoint_definitions(&cid.address, None)?;
+        &node_cli.get_contract_entrypoint_definitions(&cid.address, level)?;
 
     let mut entrypoint_asts: HashMap<String, RelationalAST> = HashMap::new();
     for (entrypoint, entrypoint_def) in entrypoint_defs {
+        if !selection.selects_entrypoint(entrypoint) {
+            continue;
+        }
         let type_ast = typing::type_ast_from_json(entrypoint_def)
             .with_context(|| "failed to derive an entrypoint type ast")
             .with_context(|| {
@@ -1169,23 +1847,30 @@ pub(crate) fn get_contract_rel(
             })?;
 
         // Build the internal representation from the storage defition
-        let rel_ast = relational::ASTBuilder::new(
+        let mut entrypoint_builder = relational::ASTBuilder::new(
             format!("entry.{}", entrypoint).as_str(),
-        )
-        .memoryless_bigmaps()
-        .build_relational_ast(&type_ast)
-        .with_context(|| {
-            "failed to build a relational AST from the entrypoint type"
-        })
-        .with_context(|| {
-            anyhow!(
-                "contract address={}, entrypoint={}",
-                cid.address,
-                entrypoint
-            )
-        })?;
+        );
+        entrypoint_builder
+            .memoryless_bigmaps()
+            .naming(naming.clone());
+        if index_lambdas {
+            entrypoint_builder.micheline_lambdas();
+        }
+        let rel_ast = entrypoint_builder
+            .build_relational_ast(&type_ast)
+            .with_context(|| {
+                "failed to build a relational AST from the entrypoint type"
+            })
+            .with_context(|| {
+                anyhow!(
+                    "contract address={}, entrypoint={}",
+                    cid.address,
+                    entrypoint
+                )
+            })?;
 
         entrypoint_asts.insert(entrypoint.clone(), rel_ast);
+        tables.extend(entrypoint_builder.tables().clone());
     }
 
     Ok(relational::Contract {
@@ -1194,6 +1879,8 @@ pub(crate) fn get_contract_rel(
 
         storage_ast,
         entrypoint_asts,
+        tables,
+        selection: selection.clone(),
     })
 }
 
@@ -1298,3 +1985,74 @@ fn test_get_origination_operations_from_block() {
 
 #[test]
 fn test_storage() {}
+
+#[test]
+fn test_contract_versions() {
+    let mut node_cli = NodeClient::new(
+        vec!["http://localhost:0".to_string()],
+        "main".to_string(),
+        0,
+    );
+    node_cli
+        .use_replay_dir(crate::octez::replay::ReplayDir::new("test/").unwrap());
+    let cid = ContractID {
+        name: "test".to_string(),
+        address: "KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k".to_string(),
+    };
+    let naming = NamingPolicy::default();
+    let selection = Selection::default();
+    let head =
+        get_contract_rel(&node_cli, &cid, false, &naming, &selection).unwrap();
+    let historical = get_contract_rel_at(
+        &node_cli,
+        &cid,
+        Some(50503),
+        false,
+        &naming,
+        &selection,
+    )
+    .unwrap();
+    assert_eq!(head.storage_ast, historical.storage_ast);
+    assert_eq!(head.entrypoint_asts, historical.entrypoint_asts);
+
+    // Each version is derived once per protocol
+    let st = MutexedState::new();
+    let mut derived = 0;
+    for protocol in ["PtA", "PtA", "PtB"] {
+        let got = st
+            .get_or_add_contract_version(&cid, protocol, || {
+                derived += 1;
+                Ok(historical.clone())
+            })
+            .unwrap();
+        assert_eq!(historical.storage_ast, got.storage_ast);
+    }
+    assert_eq!(2, derived);
+}
+
+#[test]
+fn test_join_pipeline() {
+    // the levels selector stops as the processor fails, the processor's
+    // error is returned
+    let (height_send, height_recv) = flume::bounded::<u32>(1);
+    let threads: Vec<thread::JoinHandle<Result<()>>> = vec![
+        thread::spawn(move || {
+            send_levels(&height_send, 0..1000);
+            Ok(())
+        }),
+        thread::spawn(move || {
+            for l in height_recv {
+                if l == 5 {
+                    return Err(anyhow!("failed to process level {}", l));
+                }
+            }
+            Ok(())
+        }),
+    ];
+    assert_eq!(
+        "failed to process level 5",
+        join_pipeline(threads)
+            .unwrap_err()
+            .to_string()
+    );
+}
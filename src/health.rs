@@ -0,0 +1,265 @@
+use crate::metrics;
+use crate::octez::node::NodeClient;
+use crate::sql::backend::Backend;
+use crate::sql::db::IndexerMode;
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// How often the indexer's state is refreshed in the background.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+/// After how long without a completed refresh (eg because the node or the
+/// database does not respond) the cached state is considered unknown.
+const MAX_REFRESH_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct HealthReport {
+    pub mode: String,
+    pub db_head: Option<u32>,
+    pub node_head: u32,
+    pub lag: u32,
+    pub seconds_since_last_processed_block: Option<i64>,
+    pub ready: bool,
+}
+
+struct Heads {
+    mode: IndexerMode,
+    db_head: Option<u32>,
+    node_head: u32,
+}
+
+struct Refresh {
+    at: Instant,
+    heads: std::result::Result<Heads, String>,
+}
+
+/// Checks the indexer's state for the health and readiness endpoints. The
+/// indexer is ready once it is in head mode, at most max_lag levels behind
+/// the node's head, and has processed a block in the last
+/// max_seconds_since_last_processed_block seconds.
+///
+/// The database and the node are queried on a background thread, so that
+/// a slow or unreachable node does not block the http server; the
+/// endpoints serve the result of the last refresh.
+pub struct HealthChecker {
+    last: Arc<Mutex<Option<Refresh>>>,
+    max_lag: u32,
+    max_seconds_since_last_processed_block: i64,
+}
+
+impl HealthChecker {
+    pub(crate) fn start(
+        mut dbcli: Box<dyn Backend>,
+        node_cli: NodeClient,
+        max_lag: u32,
+        max_seconds_since_last_processed_block: i64,
+    ) -> Self {
+        let last: Arc<Mutex<Option<Refresh>>> = Arc::new(Mutex::new(None));
+        let refreshed = last.clone();
+        thread::spawn(move || loop {
+            let heads = get_heads(dbcli.as_mut(), &node_cli).map_err(|e| {
+                warn!("health check failed, err: {:?}", e);
+                format!("{:#}", e)
+            });
+            *refreshed.lock().unwrap() = Some(Refresh {
+                at: Instant::now(),
+                heads,
+            });
+            thread::sleep(REFRESH_INTERVAL);
+        });
+        Self {
+            last,
+            max_lag,
+            max_seconds_since_last_processed_block,
+        }
+    }
+
+    pub(crate) fn report(&self) -> Result<HealthReport> {
+        let last = self.last.lock().unwrap();
+        let refresh = last
+            .as_ref()
+            .ok_or_else(|| anyhow!("the indexer's state is not known yet"))?;
+        let refresh_age = refresh.at.elapsed();
+        if refresh_age > MAX_REFRESH_AGE {
+            return Err(anyhow!(
+                "the indexer's state has not been refreshed for {} seconds",
+                refresh_age.as_secs()
+            ));
+        }
+        let heads = refresh
+            .heads
+            .as_ref()
+            .map_err(|e| anyhow!("{}", e))?;
+
+        let last_processed = metrics::LAST_PROCESSED_TIMESTAMP.get();
+        let seconds_since_last_processed_block = if last_processed > 0 {
+            Some(chrono::Utc::now().timestamp() - last_processed)
+        } else {
+            None
+        };
+
+        Ok(new_report(
+            &heads.mode,
+            heads.db_head,
+            heads.node_head,
+            seconds_since_last_processed_block,
+            self.max_lag,
+            self.max_seconds_since_last_processed_block,
+        ))
+    }
+}
+
+fn get_heads(dbcli: &mut dyn Backend, node_cli: &NodeClient) -> Result<Heads> {
+    let mode = dbcli.get_indexer_mode()?;
+    let db_head = dbcli.get_head()?.map(|head| head.level);
+    let node_head = node_cli.head_level()?;
+    Ok(Heads {
+        mode,
+        db_head,
+        node_head,
+    })
+}
+
+/// The indexer is not ready either if it has not processed any block since
+/// it started.
+fn new_report(
+    mode: &IndexerMode,
+    db_head: Option<u32>,
+    node_head: u32,
+    seconds_since_last_processed_block: Option<i64>,
+    max_lag: u32,
+    max_seconds_since_last_processed_block: i64,
+) -> HealthReport {
+    let lag = node_head.saturating_sub(db_head.unwrap_or(0));
+    HealthReport {
+        ready: *mode == IndexerMode::Head
+            && lag <= max_lag
+            && seconds_since_last_processed_block.is_some_and(|seconds| {
+                seconds <= max_seconds_since_last_processed_block
+            }),
+        mode: format!("{:?}", mode),
+        db_head,
+        node_head,
+        lag,
+        seconds_since_last_processed_block,
+    }
+}
+
+#[test]
+fn test_new_report() {
+    struct TestCase {
+        name: String,
+        mode: IndexerMode,
+        db_head: Option<u32>,
+        node_head: u32,
+        seconds_since_last_processed_block: Option<i64>,
+        exp_lag: u32,
+        exp_ready: bool,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "head mode, caught up".to_string(),
+            mode: IndexerMode::Head,
+            db_head: Some(100),
+            node_head: 100,
+            seconds_since_last_processed_block: Some(3),
+            exp_lag: 0,
+            exp_ready: true,
+        },
+        TestCase {
+            name: "head mode, lag at the threshold".to_string(),
+            mode: IndexerMode::Head,
+            db_head: Some(95),
+            node_head: 100,
+            seconds_since_last_processed_block: Some(3),
+            exp_lag: 5,
+            exp_ready: true,
+        },
+        TestCase {
+            name: "head mode, lag beyond the threshold".to_string(),
+            mode: IndexerMode::Head,
+            db_head: Some(94),
+            node_head: 100,
+            seconds_since_last_processed_block: Some(3),
+            exp_lag: 6,
+            exp_ready: false,
+        },
+        TestCase {
+            name: "node behind the db".to_string(),
+            mode: IndexerMode::Head,
+            db_head: Some(101),
+            node_head: 100,
+            seconds_since_last_processed_block: Some(3),
+            exp_lag: 0,
+            exp_ready: true,
+        },
+        TestCase {
+            name: "bootstrapping".to_string(),
+            mode: IndexerMode::Bootstrap,
+            db_head: Some(100),
+            node_head: 100,
+            seconds_since_last_processed_block: Some(3),
+            exp_lag: 0,
+            exp_ready: false,
+        },
+        TestCase {
+            name: "empty db".to_string(),
+            mode: IndexerMode::Head,
+            db_head: None,
+            node_head: 100,
+            seconds_since_last_processed_block: Some(3),
+            exp_lag: 100,
+            exp_ready: false,
+        },
+        TestCase {
+            name: "last block processed at the threshold".to_string(),
+            mode: IndexerMode::Head,
+            db_head: Some(100),
+            node_head: 100,
+            seconds_since_last_processed_block: Some(120),
+            exp_lag: 0,
+            exp_ready: true,
+        },
+        TestCase {
+            name: "last block processed too long ago".to_string(),
+            mode: IndexerMode::Head,
+            db_head: Some(100),
+            node_head: 100,
+            seconds_since_last_processed_block: Some(121),
+            exp_lag: 0,
+            exp_ready: false,
+        },
+        TestCase {
+            name: "no block processed yet".to_string(),
+            mode: IndexerMode::Head,
+            db_head: Some(100),
+            node_head: 100,
+            seconds_since_last_processed_block: None,
+            exp_lag: 0,
+            exp_ready: false,
+        },
+    ];
+
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let got = new_report(
+            &tc.mode,
+            tc.db_head,
+            tc.node_head,
+            tc.seconds_since_last_processed_block,
+            5,
+            120,
+        );
+        assert_eq!(tc.exp_lag, got.lag);
+        assert_eq!(tc.exp_ready, got.ready);
+        assert_eq!(
+            tc.seconds_since_last_processed_block,
+            got.seconds_since_last_processed_block
+        );
+    }
+}
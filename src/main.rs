@@ -9,8 +9,12 @@ extern crate serde;
 pub mod config;
 pub mod contract_denylist;
 pub mod debug;
+pub mod events;
 pub mod executor;
+pub mod health;
+pub mod metrics;
 pub mod octez;
+pub mod server;
 pub mod sql;
 pub mod stats;
 pub mod storage_structure;
@@ -20,8 +24,12 @@ pub mod storage_value;
 use anyhow::Context;
 use config::CONFIG;
 use env_logger::Env;
+use events::EventSink;
+use octez::block_cache::BlockCache;
 use octez::node;
-use sql::db::DBClient;
+use octez::replay::ReplayDir;
+use sql::backend::{self, Backend};
+use sql::migrations;
 use std::collections::HashMap;
 use std::panic;
 use std::process;
@@ -29,6 +37,7 @@ use std::thread;
 
 use config::ContractID;
 use contract_denylist::is_contract_denylisted;
+use storage_structure::naming::NamingPolicy;
 use storage_structure::relational;
 
 fn main() {
@@ -47,30 +56,145 @@ fn main() {
 
     let config = CONFIG.as_ref().unwrap();
 
-    let node_cli = &node::NodeClient::new(
+    let mut node_cli = node::NodeClient::new(
         config.node_urls.clone(),
         "main".to_string(),
         config.node_comm_retries,
     );
+    if let Some(dir) = &config.block_cache_dir {
+        node_cli.use_block_cache(
+            BlockCache::new(
+                dir,
+                config.block_cache_max_size,
+                config.block_cache_compress,
+            )
+            .with_context(|| "failed to open the block cache")
+            .unwrap(),
+        );
+    }
+    if let Some(dir) = &config.replay_dir {
+        node_cli.use_replay_dir(ReplayDir::new(dir).unwrap());
+    }
+    let node_cli = &node_cli;
+
+    let naming: HashMap<String, NamingPolicy> = config
+        .contract_renames
+        .iter()
+        .map(|(name, renames)| {
+            (name.clone(), NamingPolicy::new(renames.clone()))
+        })
+        .collect();
+    let naming_of = |contract_id: &ContractID| {
+        naming
+            .get(&contract_id.name)
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let selection_of = |contract_id: &ContractID| {
+        config
+            .contract_selections
+            .get(&contract_id.name)
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let mut dbcli = backend::connect(config)
+        .with_context(|| "failed to connect to the db")
+        .unwrap();
+
+    if let Some(addr) = &config.metrics_addr {
+        server::serve(
+            addr,
+            Some(health::HealthChecker::start(
+                dbcli.clone(),
+                node_cli.clone(),
+                config.ready_max_lag,
+                config.ready_max_seconds_since_last_processed_block,
+            )),
+        )
+        .unwrap();
+    }
+
+    if let Some(path) = &config.export_levels_file {
+        assert_sane_db(dbcli.as_mut());
+        let levels = dbcli
+            .get_active_contract_levels()
+            .unwrap();
+        octez::levels_file::write_levels_file(path, &levels).unwrap();
+        info!("exported {} contract levels to {}", levels.len(), path);
+        return;
+    }
+
+    if let Some(contract_id) = &config.add_contract {
+        assert_sane_db(dbcli.as_mut());
+        if is_contract_denylisted(&contract_id.address) {
+            exit_with_err(format!("bad contract settings provided: denylisted contract cannot be indexed ({})", contract_id.name).as_str());
+        }
+        dbcli
+            .request_contract(contract_id)
+            .with_context(|| "failed to request the contract")
+            .unwrap();
+        info!("requested the running indexer to add contract {} ({}), follow its progress in the contract_requests table", contract_id.name, contract_id.address);
+        return;
+    }
+
+    if let Some(name) = &config.remove_contract {
+        assert_sane_db(dbcli.as_mut());
+        if !confirm_request(&format!("
+Removing contract {} -- all of its indexed data will be destroyed (other contracts are left as is). Continue?", name)) {
+            process::exit(1);
+        }
+        dbcli
+            .delete_contract(node_cli, name, &|node_cli, contract_id| {
+                executor::get_contract_rel(
+                    node_cli,
+                    contract_id,
+                    config.index_lambdas,
+                    &naming_of(contract_id),
+                    &selection_of(contract_id),
+                )
+            })
+            .with_context(|| format!("failed to remove contract {}", name))
+            .unwrap();
+        info!("removed contract {}, make sure to also remove it from the contract settings", name);
+        return;
+    }
 
-    let mut dbcli = DBClient::connect(
-        &config.database_url,
-        &config.main_schema,
-        std::time::Duration::from_millis(5 * 60 * 1000),
-        10,
-    )
-    .with_context(|| "failed to connect to the db")
-    .unwrap();
+    if config.only_migrate && dbcli.common_tables_exist().unwrap() {
+        let plan = migrations::plan_migrations(dbcli.as_mut())
+            .with_context(|| "failed to plan the db migrations")
+            .unwrap();
+        migrations::print_plan(&plan);
+        if config.dry_run {
+            return;
+        }
+        if !plan.is_empty() {
+            dbcli
+                .apply_migrations(&plan)
+                .with_context(|| "failed to migrate the db")
+                .unwrap();
+            info!("db migrated to Que Pasa {}", config::QUEPASA_VERSION);
+        }
+    }
 
     let setup_db = config.reinit || !dbcli.common_tables_exist().unwrap();
     if config.reinit {
-        assert_sane_db(&mut dbcli);
+        assert_sane_db(dbcli.as_mut());
         if !confirm_request("
 Re-initializing -- all data in DB related to ever set-up contracts, including those set-up in prior runs (!), will be destroyed. Continue?") {
             process::exit(1);
         }
         dbcli
-            .delete_everything(node_cli, executor::get_contract_rel)
+            .delete_everything(node_cli, &|node_cli, contract_id| {
+                executor::get_contract_rel(
+                    node_cli,
+                    contract_id,
+                    config.index_lambdas,
+                    &naming_of(contract_id),
+                    &selection_of(contract_id),
+                )
+            })
             .with_context(|| "failed to delete the db's content")
             .unwrap();
     }
@@ -78,21 +202,42 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
         dbcli.create_common_tables().unwrap();
         info!("Common tables set up in db");
     } else {
-        assert_sane_db(&mut dbcli);
+        assert_sane_db(dbcli.as_mut());
     }
-
-    let bcd_settings = config
-        .bcd_url
-        .as_ref()
-        .map(|url| (url.clone(), config.bcd_network.clone()));
+    let rebuild_derived = !config.only_migrate
+        && (config.rebuild_derived
+            || dbcli
+                .get_derived_tables_stale()
+                .unwrap());
 
     let mut executor = executor::Executor::new(
         node_cli.clone(),
         dbcli,
         config.reports_interval,
     );
+    if config.index_lambdas {
+        executor.index_lambdas();
+    }
+    if config.light_scan {
+        executor.light_scan();
+    }
+    executor.set_naming(naming.clone());
+    executor.set_selections(config.contract_selections.clone());
+    if let Some(target) = &config.event_sink {
+        executor.set_event_sink(
+            EventSink::connect(target)
+                .with_context(|| "failed to open the event sink")
+                .unwrap(),
+        );
+    }
+    if rebuild_derived {
+        executor
+            .rebuild_derived_tables()
+            .with_context(|| "failed to rebuild the derived tables")
+            .unwrap();
+    }
     if config.all_contracts {
-        index_all_contracts(config, &bcd_settings, executor);
+        index_all_contracts(config, executor);
         return;
     }
 
@@ -101,6 +246,9 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
             .add_contract(contract_id)
             .unwrap();
     }
+    executor
+        .add_requested_contracts()
+        .unwrap();
 
     if config.only_migrate {
         executor
@@ -132,7 +280,7 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
 
     let new_initialized = executor
         .exec_new_contracts_historically(
-            &bcd_settings,
+            &config.level_source,
             num_getters,
             num_processors,
             config.allowed_unbootstrapped_offset,
@@ -154,7 +302,7 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
     // We will first load missing levels (if any)
     executor
         .exec_missing_levels(
-            &bcd_settings,
+            &config.level_source,
             num_getters,
             num_processors,
             config.allowed_unbootstrapped_offset,
@@ -167,13 +315,20 @@ Re-initializing -- all data in DB related to ever set-up contracts, including th
         .unwrap();
 
     // At last, normal operation.
+    executor
+        .accept_contract_requests(
+            &config.level_source,
+            num_getters,
+            num_processors,
+            config.allowed_unbootstrapped_offset,
+        )
+        .unwrap();
     info!("processing blocks at the chain head");
     executor.exec_continuous().unwrap();
 }
 
 fn index_all_contracts(
     config: &config::Config,
-    bcd_settings: &Option<(String, String)>,
     mut executor: executor::Executor,
 ) {
     executor.index_all_contracts();
@@ -185,19 +340,12 @@ fn index_all_contracts(
                 config.levels.clone(),
             )
             .unwrap();
-        #[cfg(feature = "regression_force_update_derived")]
-        if true {
-            info!("skipping re-populating of derived tables, always_update_derived enabled");
-            return;
-        }
-        executor
-            .repopulate_derived_tables(false)
-            .unwrap();
+        executor.end_bootstrap().unwrap();
     } else {
         info!("processing missing levels");
         executor
             .exec_missing_levels(
-                bcd_settings,
+                &config.level_source,
                 config.getters_cap,
                 config.workers_cap,
                 config.allowed_unbootstrapped_offset,
@@ -227,36 +375,24 @@ fn assert_contracts_ok(contracts: &[ContractID]) {
     }
 }
 
-fn schema_version(v: &str) -> String {
-    match v {
-        // The first versions of Que Pasa didn't follow the semantics of using
-        // minor versioning for non-db schema related changes only
-        "1.0.0" | "1.0.1" | "1.0.2" | "1.0.3" | "1.0.4" | "1.0.5" => {
-            v.to_string()
-        }
-        _ => {
-            // Minor version bumps (_._.x) have same db schemas
-            v.to_string()
-                .rsplit_once('.')
-                .map(|(db_ver, _)| db_ver.to_string())
-                .unwrap_or_else(|| "".to_string())
-        }
-    }
-}
-
-fn assert_sane_db(dbcli: &mut DBClient) {
+fn assert_sane_db(dbcli: &mut dyn Backend) {
     let db_version = dbcli.get_quepasa_version().unwrap();
-    if schema_version(&db_version)
-        != schema_version(crate::config::QUEPASA_VERSION)
+    if migrations::schema_version(&db_version)
+        != migrations::schema_version(crate::config::QUEPASA_VERSION)
     {
+        let hint = match migrations::plan_migrations(dbcli) {
+            Ok(_) => "Run with --only-migrate to migrate the database (add --dry-run to only see the migrations that would be applied).",
+            Err(_) => "There is no migration path between these versions. Either drop the old database namespace or keep it and target a different one.",
+        };
         exit_with_err(
             format!(
                 "
 Cannot target a database that was initialized with an incompatible quepasa version.
 This database was initialized with Que Pasa {}, currently running Que Pasa {}.
-Either drop the old database namespace or keep it and target a different one.",
+{}",
                 db_version,
                 crate::config::QUEPASA_VERSION,
+                hint,
             )
             .as_str(),
         );
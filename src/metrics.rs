@@ -0,0 +1,149 @@
+use anyhow::Result;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+
+lazy_static! {
+    pub(crate) static ref REGISTRY: Registry = Registry::new();
+    pub(crate) static ref CHAIN_HEAD: IntGauge = register(IntGauge::new(
+        "quepasa_chain_head_level",
+        "Level of the chain's head, as last seen on the node",
+    ));
+    pub(crate) static ref DB_HEAD: IntGauge = register(IntGauge::new(
+        "quepasa_db_head_level",
+        "Highest level processed into the database",
+    ));
+    pub(crate) static ref HEAD_LAG: IntGauge = register(IntGauge::new(
+        "quepasa_head_lag_levels",
+        "Number of levels the database is behind the chain's head",
+    ));
+    pub(crate) static ref LAST_PROCESSED_TIMESTAMP: IntGauge =
+        register(IntGauge::new(
+            "quepasa_last_processed_timestamp_seconds",
+            "Unix time at which the last level was processed",
+        ));
+    pub(crate) static ref LEVELS_PROCESSED: IntCounter =
+        register(IntCounter::new(
+            "quepasa_levels_processed_total",
+            "Number of levels processed",
+        ));
+    pub(crate) static ref CONTRACT_CALLS: IntCounterVec =
+        register(IntCounterVec::new(
+            Opts::new(
+                "quepasa_contract_calls_total",
+                "Number of processed calls (tx contexts), per contract",
+            ),
+            &["contract"],
+        ));
+    pub(crate) static ref INSERTS: IntCounterVec =
+        register(IntCounterVec::new(
+            Opts::new(
+                "quepasa_inserts_total",
+                "Number of rows inserted into the contract tables, per table",
+            ),
+            &["contract", "table"],
+        ));
+    pub(crate) static ref FORKED_LEVELS: IntCounter =
+        register(IntCounter::new(
+            "quepasa_forked_levels_total",
+            "Number of levels that were reprocessed because of a fork",
+        ));
+    pub(crate) static ref NODE_RPC_DURATION: Histogram =
+        register(Histogram::with_opts(HistogramOpts::new(
+            "quepasa_node_rpc_duration_seconds",
+            "Duration of successful node RPC calls",
+        )));
+    pub(crate) static ref NODE_RPC_RETRIES: IntCounter =
+        register(IntCounter::new(
+            "quepasa_node_rpc_retries_total",
+            "Number of failed node RPC calls that were retried",
+        ));
+    pub(crate) static ref INSERTER_BATCH_DURATION: Histogram =
+        register(Histogram::with_opts(
+            HistogramOpts::new(
+                "quepasa_inserter_batch_duration_seconds",
+                "Duration of inserting a batch of processed levels",
+            )
+            .buckets(prometheus::exponential_buckets(0.01, 2.0, 14).unwrap()),
+        ));
+    pub(crate) static ref STATS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "quepasa_stats_total",
+            "The counters of the periodic statistics reports",
+        ),
+        &["report", "field"],
+    ));
+}
+
+fn register<M>(metric: prometheus::Result<M>) -> M
+where
+    M: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.unwrap();
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .unwrap();
+    metric
+}
+
+pub(crate) fn set_heads(chain_head: u32, db_head: u32) {
+    CHAIN_HEAD.set(chain_head as i64);
+    DB_HEAD.set(db_head as i64);
+    HEAD_LAG.set(chain_head as i64 - db_head as i64);
+}
+
+/// Counts rows inserted into a contract table, to be called once they are
+/// committed.
+pub(crate) fn rows_inserted(contract: &str, table: &str, num_rows: usize) {
+    INSERTS
+        .with_label_values(&[contract, table])
+        .inc_by(num_rows as u64);
+}
+
+pub(crate) fn level_processed() {
+    LEVELS_PROCESSED.inc();
+    LAST_PROCESSED_TIMESTAMP.set(chrono::Utc::now().timestamp());
+}
+
+pub(crate) fn encode() -> Result<String> {
+    let mut buf: Vec<u8> = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Registers all metrics upfront, so they are exposed even before they're
+/// first touched
+pub(crate) fn init() {
+    lazy_static::initialize(&CHAIN_HEAD);
+    lazy_static::initialize(&DB_HEAD);
+    lazy_static::initialize(&HEAD_LAG);
+    lazy_static::initialize(&LAST_PROCESSED_TIMESTAMP);
+    lazy_static::initialize(&LEVELS_PROCESSED);
+    lazy_static::initialize(&CONTRACT_CALLS);
+    lazy_static::initialize(&INSERTS);
+    lazy_static::initialize(&FORKED_LEVELS);
+    lazy_static::initialize(&NODE_RPC_DURATION);
+    lazy_static::initialize(&NODE_RPC_RETRIES);
+    lazy_static::initialize(&INSERTER_BATCH_DURATION);
+    lazy_static::initialize(&STATS);
+}
+
+#[test]
+fn test_encode() {
+    set_heads(120, 100);
+    LEVELS_PROCESSED.inc();
+    INSERTS
+        .with_label_values(&["mycontract", "storage"])
+        .inc_by(3);
+
+    let body = encode().unwrap();
+    for exp in &[
+        "quepasa_chain_head_level 120",
+        "quepasa_db_head_level 100",
+        "quepasa_head_lag_levels 20",
+        "quepasa_inserts_total{contract=\"mycontract\",table=\"storage\"} 3",
+    ] {
+        assert!(body.lines().any(|l| l == *exp), "missing {}", exp);
+    }
+}
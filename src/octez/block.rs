@@ -40,8 +40,8 @@ pub struct Block {
     pub header: Header,
     pub operations: Vec<Vec<Operation>>,
 
-    #[serde(skip)]
-    protocol: String,
+    #[serde(default)]
+    pub protocol: String,
     #[serde(skip)]
     chain_id: String,
     #[serde(skip)]
@@ -143,7 +143,29 @@ impl Ord for TxContext {
     }
 }
 
+/// Index of the validation pass that holds the manager operations (the
+/// transactions, originations, etc.) in a block's operations.
+pub(crate) const MANAGER_OPERATIONS_PASS: usize = 3;
+
 impl Block {
+    /// A block with only its manager operations (all other validation passes
+    /// are left empty). Enough to find out which contracts are active in it.
+    pub(crate) fn from_manager_operations(
+        hash: String,
+        header: Header,
+        manager_operations: Vec<Operation>,
+    ) -> Self {
+        let mut operations: Vec<Vec<Operation>> =
+            vec![vec![]; MANAGER_OPERATIONS_PASS];
+        operations.push(manager_operations);
+        Self {
+            hash,
+            header,
+            operations,
+            ..Default::default()
+        }
+    }
+
     pub(crate) fn operations(&self) -> Vec<Vec<Operation>> {
         self.operations.clone()
     }
@@ -983,16 +1005,32 @@ pub struct Diff {
     pub action: String,
     pub updates: Option<Updates>,
     pub source: Option<String>,
+    #[serde(default)]
+    pub memo_size: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum Updates {
     Updates(Vec<Update>),
+    SaplingUpdates(SaplingUpdates),
     Update(Update),
     Unknown(serde_json::Value),
 }
 
+#[derive(
+    Default,
+    Debug,
+    Clone,
+    PartialEq,
+    serde_derive::Serialize,
+    serde_derive::Deserialize,
+)]
+pub struct SaplingUpdates {
+    pub commitments_and_ciphertexts: Vec<serde_json::Value>,
+    pub nullifiers: Vec<String>,
+}
+
 #[derive(
     Default,
     Debug,
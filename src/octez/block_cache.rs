@@ -0,0 +1,311 @@
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+struct Entry {
+    hash: String,
+    compressed: bool,
+    size: u64,
+    last_used: SystemTime,
+}
+
+struct State {
+    entries: HashMap<u32, Entry>,
+    total_size: u64,
+}
+
+/// On-disk cache of block jsons. Each block is stored in its own file, named
+/// after its level and hash (<level>-<hash>.json, or .json.gz when
+/// compressed). Blocks are looked up by level and hash, so that a block
+/// replaced by a fork, or of another network, is never served. Once the
+/// cache grows beyond its max size the least recently used blocks are
+/// evicted (when they were last used survives restarts as the files'
+/// modification time).
+#[derive(Clone)]
+pub struct BlockCache {
+    dir: PathBuf,
+    max_size: u64,
+    compress: bool,
+
+    state: Arc<Mutex<State>>,
+}
+
+impl BlockCache {
+    pub fn new(dir: &str, max_size: u64, compress: bool) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| {
+            format!("failed to create block cache directory {}", dir)
+        })?;
+
+        let mut entries: HashMap<u32, Entry> = HashMap::new();
+        let mut total_size: u64 = 0;
+        for dir_entry in fs::read_dir(dir)? {
+            let dir_entry = dir_entry?;
+            let filename = dir_entry.file_name();
+            let (level, hash, compressed) =
+                match Self::parse_filename(&filename.to_string_lossy()) {
+                    Some(x) => x,
+                    None => continue,
+                };
+            let metadata = dir_entry.metadata()?;
+            if let Some(prev) = entries.get(&level) {
+                // Multiple blocks for the same level (due to a fork), only
+                // keep the most recent one
+                if prev.last_used > metadata.modified()? {
+                    fs::remove_file(dir_entry.path())?;
+                    continue;
+                }
+                let prev = entries.remove(&level).unwrap();
+                total_size -= prev.size;
+                fs::remove_file(Self::path(
+                    Path::new(dir),
+                    level,
+                    &prev.hash,
+                    prev.compressed,
+                ))?;
+            }
+            total_size += metadata.len();
+            entries.insert(
+                level,
+                Entry {
+                    hash,
+                    compressed,
+                    size: metadata.len(),
+                    last_used: metadata.modified()?,
+                },
+            );
+        }
+        info!(
+            "block cache at {} has {} blocks ({} MB)",
+            dir,
+            entries.len(),
+            total_size / 1_000_000
+        );
+
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            max_size,
+            compress,
+            state: Arc::new(Mutex::new(State {
+                entries,
+                total_size,
+            })),
+        })
+    }
+
+    fn parse_filename(filename: &str) -> Option<(u32, String, bool)> {
+        let (name, compressed) = match filename.strip_suffix(".json.gz") {
+            Some(name) => (name, true),
+            None => (filename.strip_suffix(".json")?, false),
+        };
+        let (level, hash) = name.split_once('-')?;
+        Some((level.parse::<u32>().ok()?, hash.to_string(), compressed))
+    }
+
+    fn path(dir: &Path, level: u32, hash: &str, compressed: bool) -> PathBuf {
+        let ext = if compressed { "json.gz" } else { "json" };
+        dir.join(format!("{}-{}.{}", level, hash, ext))
+    }
+
+    pub(crate) fn get(&self, level: u32, hash: &str) -> Result<Option<String>> {
+        let now = SystemTime::now();
+        let path = {
+            let mut state = self
+                .state
+                .lock()
+                .map_err(|_| anyhow!("failed to lock block cache mutex"))?;
+            match state.entries.get_mut(&level) {
+                Some(entry) if entry.hash == hash => {
+                    entry.last_used = now;
+                    Self::path(&self.dir, level, &entry.hash, entry.compressed)
+                }
+                _ => return Ok(None),
+            }
+        };
+
+        let mut body = String::new();
+        let file = match fs::File::open(&path) {
+            Ok(f) => f,
+            // evicted in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to open {:?}", path))
+            }
+        };
+        if let Err(e) = file.set_modified(now) {
+            debug!("failed to touch {:?}, err: {:?}", path, e);
+        }
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "gz")
+        {
+            GzDecoder::new(file).read_to_string(&mut body)?;
+        } else {
+            std::io::BufReader::new(file).read_to_string(&mut body)?;
+        }
+        debug!("loaded level {} from block cache", level);
+        Ok(Some(body))
+    }
+
+    pub(crate) fn put(&self, level: u32, hash: &str, body: &str) -> Result<()> {
+        let path = Self::path(&self.dir, level, hash, self.compress);
+        let content = if self.compress {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(body.as_bytes())?;
+            encoder.finish()?
+        } else {
+            body.as_bytes().to_vec()
+        };
+        // Write to a temporary file first, so that a concurrent reader never
+        // sees a partially written block
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &content)?;
+        fs::rename(&tmp_path, &path)?;
+
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("failed to lock block cache mutex"))?;
+        if let Some(prev) = state.entries.insert(
+            level,
+            Entry {
+                hash: hash.to_string(),
+                compressed: self.compress,
+                size: content.len() as u64,
+                last_used: SystemTime::now(),
+            },
+        ) {
+            state.total_size -= prev.size;
+            if prev.hash != hash || prev.compressed != self.compress {
+                fs::remove_file(Self::path(
+                    &self.dir,
+                    level,
+                    &prev.hash,
+                    prev.compressed,
+                ))?;
+            }
+        }
+        state.total_size += content.len() as u64;
+
+        self.evict(&mut state)
+    }
+
+    fn evict(&self, state: &mut State) -> Result<()> {
+        if state.total_size <= self.max_size {
+            return Ok(());
+        }
+        let mut lru: Vec<(SystemTime, u32)> = state
+            .entries
+            .iter()
+            .map(|(level, entry)| (entry.last_used, *level))
+            .collect();
+        lru.sort_unstable();
+
+        let mut evicted = 0;
+        for (_, level) in lru {
+            if state.total_size <= self.max_size {
+                break;
+            }
+            let entry = state.entries.remove(&level).unwrap();
+            fs::remove_file(Self::path(
+                &self.dir,
+                level,
+                &entry.hash,
+                entry.compressed,
+            ))?;
+            state.total_size -= entry.size;
+            evicted += 1;
+        }
+        debug!("evicted {} blocks from the block cache", evicted);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_block_cache() {
+    let dir = std::env::temp_dir().join("quepasa-test-block-cache");
+    let _ = fs::remove_dir_all(&dir);
+    let dir = dir.to_str().unwrap();
+
+    for compress in [false, true] {
+        println!("test case: compress={}", compress);
+        let cache = BlockCache::new(dir, 1_000_000, compress).unwrap();
+
+        assert_eq!(None, cache.get(1, "BLhash1").unwrap());
+        cache
+            .put(1, "BLhash1", "{\"level\": 1}")
+            .unwrap();
+        assert_eq!(
+            Some("{\"level\": 1}".to_string()),
+            cache.get(1, "BLhash1").unwrap()
+        );
+
+        // Overwriting a level (eg after a fork) replaces its block
+        cache
+            .put(1, "BLhash1b", "{\"level\": 1, \"b\": 1}")
+            .unwrap();
+        assert_eq!(
+            Some("{\"level\": 1, \"b\": 1}".to_string()),
+            cache.get(1, "BLhash1b").unwrap()
+        );
+        assert_eq!(None, cache.get(1, "BLhash1").unwrap());
+
+        // The cache is restored from disk
+        let cache = BlockCache::new(dir, 1_000_000, compress).unwrap();
+        assert_eq!(
+            Some("{\"level\": 1, \"b\": 1}".to_string()),
+            cache.get(1, "BLhash1b").unwrap()
+        );
+        assert_eq!(1, fs::read_dir(dir).unwrap().count());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn test_block_cache_eviction() {
+    let dir = std::env::temp_dir().join("quepasa-test-block-cache-eviction");
+    let _ = fs::remove_dir_all(&dir);
+    let dir = dir.to_str().unwrap();
+
+    // Room for 2 blocks of 12 bytes
+    let cache = BlockCache::new(dir, 30, false).unwrap();
+    cache
+        .put(1, "BLhash1", "{\"level\": 1}")
+        .unwrap();
+    cache
+        .put(2, "BLhash2", "{\"level\": 2}")
+        .unwrap();
+    cache.get(1, "BLhash1").unwrap();
+
+    // Level 2 is the least recently used, also once the cache is restored
+    // from disk
+    let cache = BlockCache::new(dir, 30, false).unwrap();
+    cache
+        .put(3, "BLhash3", "{\"level\": 3}")
+        .unwrap();
+    assert_eq!(None, cache.get(2, "BLhash2").unwrap());
+    assert!(cache
+        .get(1, "BLhash1")
+        .unwrap()
+        .is_some());
+    assert!(cache
+        .get(3, "BLhash3")
+        .unwrap()
+        .is_some());
+    assert_eq!(2, fs::read_dir(dir).unwrap().count());
+
+    fs::remove_dir_all(dir).unwrap();
+}
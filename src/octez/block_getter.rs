@@ -7,26 +7,38 @@ use std::thread;
 pub struct ConcurrentBlockGetter {
     node_cli: node::NodeClient,
     workers: usize,
+    light: bool,
 }
 
 impl ConcurrentBlockGetter {
     pub fn new(node_cli: node::NodeClient, workers: usize) -> Self {
-        Self { node_cli, workers }
+        Self {
+            node_cli,
+            workers,
+            light: false,
+        }
+    }
+
+    /// Only get the block headers and manager operations (see
+    /// NodeClient::level_json_light)
+    pub fn light(&mut self) {
+        self.light = true;
     }
 
     pub fn run(
         &self,
         recv_ch: flume::Receiver<u32>,
         send_ch: flume::Sender<Box<(LevelMeta, Block)>>,
-    ) -> Vec<thread::JoinHandle<()>> {
+    ) -> Vec<thread::JoinHandle<Result<()>>> {
         let mut threads = vec![];
 
         for _ in 0..self.workers {
             let w_node_cli = self.node_cli.clone();
             let w_recv_ch = recv_ch.clone();
             let w_send_ch = send_ch.clone();
+            let light = self.light;
             threads.push(thread::spawn(move || {
-                Self::worker_fn(w_node_cli, w_recv_ch, w_send_ch).unwrap();
+                Self::worker_fn(w_node_cli, light, w_recv_ch, w_send_ch)
             }));
         }
 
@@ -35,16 +47,27 @@ impl ConcurrentBlockGetter {
 
     fn worker_fn(
         node_cli: node::NodeClient,
+        light: bool,
         recv_ch: flume::Receiver<u32>,
         send_ch: flume::Sender<Box<(LevelMeta, Block)>>,
     ) -> Result<()> {
         for level_height in recv_ch {
-            let (level, block) = node_cli
-                .level_json(level_height)
-                .with_context(|| {
-                    anyhow!("failed to get json for block {}", level_height)
-                })?;
-            send_ch.send(Box::new((level, block)))?;
+            let res = if light {
+                node_cli.level_json_light(level_height)
+            } else {
+                node_cli.level_json(level_height)
+            };
+            let (level, block) = res.with_context(|| {
+                anyhow!("failed to get json for block {}", level_height)
+            })?;
+            if send_ch
+                .send(Box::new((level, block)))
+                .is_err()
+            {
+                // the receiving end stopped, if it failed it reports its
+                // error itself
+                break;
+            }
         }
         Ok(())
     }
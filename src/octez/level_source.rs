@@ -0,0 +1,229 @@
+use crate::config::{ContractID, LevelSourceConfig};
+use crate::octez::bcd::BCDClient;
+use crate::octez::levels_file::LevelsFile;
+use crate::octez::tzkt::TzKTClient;
+use crate::stats::StatsLogger;
+use anyhow::{anyhow, Result};
+use backoff::{retry, Error, ExponentialBackoff};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[cfg(test)]
+use crate::debug::{load_test, MockHttpServer};
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// An external source (some indexer's api) that knows in which levels a
+/// contract has been active. Used to only process those levels when
+/// bootstrapping a contract.
+pub(crate) trait LevelSource {
+    /// Name used in logs and progress reports.
+    fn name(&self) -> &str;
+
+    /// The latest level the source has indexed.
+    fn get_latest_level(&self) -> Result<u32>;
+
+    /// The latest level up to which the source knows in which levels the
+    /// contract has been active.
+    fn get_contract_latest_level(&self, _contract_addr: &str) -> Result<u32> {
+        self.get_latest_level()
+    }
+
+    /// Returns a page of levels in which the contract was active, and the
+    /// cursor to get the next page with. An empty page means there are no
+    /// more levels.
+    fn get_levels_page(
+        &self,
+        contract_address: &str,
+        cursor: Option<String>,
+    ) -> Result<(Vec<u32>, String)>;
+
+    fn populate_levels_chan(
+        &self,
+        contract_id: &ContractID,
+        node_at_height: &dyn Fn() -> Result<u32>,
+        stats: &StatsLogger,
+        height_send: &flume::Sender<u32>,
+        exclude_levels: &[u32],
+    ) -> Result<()> {
+        let mut exclude: HashMap<u32, ()> = HashMap::new();
+        for l in exclude_levels {
+            exclude.insert(*l, ());
+        }
+
+        let mut send_level = |l: u32| -> Result<()> {
+            if exclude.contains_key(&l) {
+                return Ok(());
+            }
+            height_send.send(l)?;
+            exclude.insert(l, ());
+            Ok(())
+        };
+
+        // (all levels up to it that aren't sent are marked as processed,
+        // see Executor::mark_missing_levels_empty)
+        let latest_level =
+            self.get_contract_latest_level(&contract_id.address)?;
+        loop {
+            let node_height = node_at_height()?;
+            if node_height >= latest_level {
+                break;
+            }
+            warn!("waiting for the node to reach the same height ({}) as {} (currently the node is at height {}). The node is {} levels behind the first block to be indexed. trying again in 1 second..", latest_level, self.name(), node_height, latest_level - node_height);
+            std::thread::sleep(std::time::Duration::from_millis(1000));
+        }
+        send_level(latest_level)?;
+
+        let report_name = &format!("{} '{}'", self.name(), &contract_id.name);
+
+        let mut cursor = None;
+        loop {
+            let (levels, new_cursor) =
+                self.get_levels_page(&contract_id.address, cursor)?;
+            if levels.is_empty() {
+                break;
+            }
+
+            for level in levels {
+                send_level(level)?;
+            }
+
+            stats.add(report_name, "pages", 1)?;
+            stats.set(report_name, "cursor", new_cursor.clone())?;
+
+            cursor = Some(new_cursor);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn new_level_source(
+    config: &LevelSourceConfig,
+) -> Result<Box<dyn LevelSource + Send>> {
+    Ok(match config {
+        LevelSourceConfig::BCD { api_url, network } => {
+            Box::new(BCDClient::new(api_url.clone(), network.clone()))
+        }
+        LevelSourceConfig::TzKT { api_url } => {
+            Box::new(TzKTClient::new(api_url.clone()))
+        }
+        LevelSourceConfig::File { path } => Box::new(LevelsFile::load(path)?),
+    })
+}
+
+/// Requests the endpoint of a source's api and parses the response,
+/// retrying on failure (source_name is used in the logs)
+pub(crate) fn load<F, O>(
+    source_name: &str,
+    uri: String,
+    timeout: Duration,
+    query_params: &[(String, String)],
+    parse_func: F,
+) -> Result<O>
+where
+    F: Fn(&str) -> Result<O>,
+{
+    let transient_err = |e: anyhow::Error| -> Error<anyhow::Error> {
+        warn!(
+            "transient {} communication error, retrying.. err={}",
+            source_name, e
+        );
+        Error::Transient(e)
+    };
+    let op = || -> Result<O> {
+        debug!("GET {}..", uri);
+
+        let cli = reqwest::blocking::Client::new();
+        let body = cli
+            .get(&uri)
+            .query(query_params)
+            .timeout(timeout)
+            .send()?
+            .error_for_status()?
+            .text()?;
+        let parsed: O = parse_func(&body)?;
+        Ok(parsed)
+    };
+    retry(ExponentialBackoff::default(), || {
+        op().map_err(transient_err)
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+#[test]
+fn test_populate_levels_chan() {
+    struct TestCase {
+        name: String,
+        pages_file: String,
+        config: fn(String) -> LevelSourceConfig,
+        exclude_levels: Vec<u32>,
+        exp: Vec<u32>,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "bcd".to_string(),
+            pages_file: "test/level_source/bcd-pages.json".to_string(),
+            config: |api_url| LevelSourceConfig::BCD {
+                api_url,
+                network: "ghostnet".to_string(),
+            },
+            exclude_levels: vec![],
+            exp: vec![1300, 1205, 1200, 1103, 1100],
+        },
+        TestCase {
+            name: "bcd (with excluded levels)".to_string(),
+            pages_file: "test/level_source/bcd-pages.json".to_string(),
+            config: |api_url| LevelSourceConfig::BCD {
+                api_url,
+                network: "ghostnet".to_string(),
+            },
+            exclude_levels: vec![1300, 1200],
+            exp: vec![1205, 1103, 1100],
+        },
+        TestCase {
+            name: "tzkt".to_string(),
+            pages_file: "test/level_source/tzkt-pages.json".to_string(),
+            config: |api_url| LevelSourceConfig::TzKT { api_url },
+            exclude_levels: vec![],
+            exp: vec![2000, 1000, 1010, 1500, 1800],
+        },
+        TestCase {
+            // (the file's highest level is that of another contract)
+            name: "levels file".to_string(),
+            pages_file: "test/level_source/tzkt-pages.json".to_string(),
+            config: |_| LevelSourceConfig::File {
+                path: "test/levels_file/levels.csv".to_string(),
+            },
+            exclude_levels: vec![],
+            exp: vec![1205, 1100],
+        },
+    ];
+
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let server = MockHttpServer::start(
+            serde_json::from_str(&load_test(&tc.pages_file)).unwrap(),
+        );
+        let source =
+            new_level_source(&(tc.config)(server.url.clone())).unwrap();
+
+        let (height_send, height_recv) = flume::unbounded::<u32>();
+        source
+            .populate_levels_chan(
+                &ContractID {
+                    name: "testcontract".to_string(),
+                    address: "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+                },
+                &|| Ok(u32::MAX),
+                &StatsLogger::new(std::time::Duration::new(0, 0)),
+                &height_send,
+                &tc.exclude_levels,
+            )
+            .unwrap();
+        drop(height_send);
+
+        let got: Vec<u32> = height_recv.iter().collect();
+        assert_eq!(tc.exp, got);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::octez::level_source::LevelSource;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// A (contract address, level) pair, as stored in a levels file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContractLevel {
+    pub contract: String,
+    pub level: u32,
+}
+
+/// Levels files are either JSON (a list of {"contract", "level"} objects,
+/// selected by the .json extension) or CSV (with a "contract,level" header).
+fn is_json(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+pub(crate) fn read_levels_file(path: &str) -> Result<Vec<ContractLevel>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read levels file {}", path))?;
+    if is_json(path) {
+        return serde_json::from_str(&content).with_context(|| {
+            format!("failed to parse levels file {} as json", path)
+        });
+    }
+
+    let mut res: Vec<ContractLevel> = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line == "contract,level") {
+            continue;
+        }
+        let (contract, level) = line.split_once(',').ok_or_else(|| {
+            anyhow!(
+                "levels file {}, line {}: expected contract,level",
+                path,
+                i + 1
+            )
+        })?;
+        res.push(ContractLevel {
+            contract: contract.trim().to_string(),
+            level: level
+                .trim()
+                .parse::<u32>()
+                .with_context(|| {
+                    format!("levels file {}, line {}: bad level", path, i + 1)
+                })?,
+        });
+    }
+    Ok(res)
+}
+
+pub(crate) fn write_levels_file(
+    path: &str,
+    levels: &[ContractLevel],
+) -> Result<()> {
+    let content = if is_json(path) {
+        serde_json::to_string_pretty(levels)?
+    } else {
+        let mut content = "contract,level\n".to_string();
+        for l in levels {
+            content += &format!("{},{}\n", l.contract, l.level);
+        }
+        content
+    };
+    fs::write(path, content)
+        .with_context(|| format!("failed to write levels file {}", path))
+}
+
+/// Offline level source, serving the levels from a levels file (eg one
+/// exported from another Que Pasa database). The file is expected to be
+/// complete up to the highest level of each contract (contracts exported
+/// from a database may have been processed up to different levels).
+pub struct LevelsFile {
+    // per contract its levels, sorted
+    levels: HashMap<String, Vec<u32>>,
+}
+
+impl LevelsFile {
+    pub(crate) fn load(path: &str) -> Result<Self> {
+        let mut levels: HashMap<String, Vec<u32>> = HashMap::new();
+        for l in read_levels_file(path)? {
+            levels
+                .entry(l.contract)
+                .or_default()
+                .push(l.level);
+        }
+        if levels.is_empty() {
+            return Err(anyhow!("levels file {} has no levels", path));
+        }
+        for contract_levels in levels.values_mut() {
+            contract_levels.sort_unstable();
+            contract_levels.dedup();
+        }
+        Ok(Self { levels })
+    }
+}
+
+impl LevelSource for LevelsFile {
+    fn name(&self) -> &str {
+        "levels file"
+    }
+
+    fn get_latest_level(&self) -> Result<u32> {
+        Ok(self
+            .levels
+            .values()
+            .filter_map(|levels| levels.last())
+            .max()
+            .cloned()
+            .unwrap_or(0))
+    }
+
+    fn get_contract_latest_level(&self, contract_addr: &str) -> Result<u32> {
+        self.levels
+            .get(contract_addr)
+            .and_then(|levels| levels.last())
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!("levels file has no levels for {}", contract_addr)
+            })
+    }
+
+    fn get_levels_page(
+        &self,
+        contract_addr: &str,
+        cursor: Option<String>,
+    ) -> Result<(Vec<u32>, String)> {
+        // All levels of a contract are served in a single page
+        if cursor.is_some() {
+            return Ok((vec![], "eof".to_string()));
+        }
+        match self.levels.get(contract_addr) {
+            Some(levels) => Ok((levels.clone(), "eof".to_string())),
+            None => {
+                warn!("levels file has no levels for {}", contract_addr);
+                Ok((vec![], "eof".to_string()))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_read_levels_file() {
+    let exp = vec![
+        ContractLevel {
+            contract: "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+            level: 1100,
+        },
+        ContractLevel {
+            contract: "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+            level: 1205,
+        },
+        ContractLevel {
+            contract: "KT1HbQepzV1nVGg8QVznG7z4RcHseD5kwqBn".to_string(),
+            level: 1300,
+        },
+    ];
+    for path in &[
+        "test/levels_file/levels.csv",
+        "test/levels_file/levels.json",
+    ] {
+        println!("test case: {}", path);
+        assert_eq!(exp, read_levels_file(path).unwrap());
+    }
+
+    let path = std::env::temp_dir().join("quepasa-test-levels.csv");
+    let path = path.to_str().unwrap();
+    write_levels_file(path, &exp).unwrap();
+    assert_eq!(exp, read_levels_file(path).unwrap());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_levels_file_source() {
+    let source = LevelsFile::load("test/levels_file/levels.csv").unwrap();
+
+    assert_eq!(1300, source.get_latest_level().unwrap());
+    assert_eq!(
+        1205,
+        source
+            .get_contract_latest_level("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq")
+            .unwrap()
+    );
+    assert!(source
+        .get_contract_latest_level("KT1unknown")
+        .is_err());
+    assert_eq!(
+        (vec![1100, 1205], "eof".to_string()),
+        source
+            .get_levels_page("KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq", None)
+            .unwrap()
+    );
+    assert_eq!(
+        (vec![], "eof".to_string()),
+        source
+            .get_levels_page(
+                "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq",
+                Some("eof".to_string())
+            )
+            .unwrap()
+    );
+    assert_eq!(
+        (vec![], "eof".to_string()),
+        source
+            .get_levels_page("KT1unknown", None)
+            .unwrap()
+    );
+}
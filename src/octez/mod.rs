@@ -1,4 +1,9 @@
 pub mod bcd;
 pub mod block;
+pub mod block_cache;
 pub mod block_getter;
+pub mod level_source;
+pub mod levels_file;
 pub mod node;
+pub mod replay;
+pub mod tzkt;
@@ -1,4 +1,9 @@
-use crate::octez::block::{Block, LevelMeta};
+use crate::metrics;
+use crate::octez::block::{
+    Block, Header, LevelMeta, Operation, MANAGER_OPERATIONS_PASS,
+};
+use crate::octez::block_cache::BlockCache;
+use crate::octez::replay::{entrypoints_from_parameter, ReplayDir};
 use anyhow::{anyhow, Context, Result};
 use backoff::{retry, Error, ExponentialBackoff};
 use chrono::{DateTime, Utc};
@@ -15,6 +20,16 @@ pub struct NodeClient {
     chain: String,
     timeout: Duration,
     comm_retries: i32,
+
+    block_cache: Option<BlockCache>,
+    replay: Option<ReplayDir>,
+}
+
+lazy_static! {
+    // Blocks younger than this may still be replaced by a fork, so they are
+    // not cached
+    static ref BLOCK_CACHE_MIN_AGE: chrono::Duration =
+        chrono::Duration::minutes(30);
 }
 
 #[derive(Error, Debug)]
@@ -31,6 +46,15 @@ struct HttpError {
     status_code: u32,
 }
 
+/// Whether the node answered the request with a 404 (eg for an RPC the
+/// protocol of the requested block does not serve).
+pub(crate) fn is_not_found(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.downcast_ref::<HttpError>()
+            .is_some_and(|e| e.status_code == 404)
+    })
+}
+
 impl NodeClient {
     pub fn new(
         node_urls: Vec<String>,
@@ -42,36 +66,180 @@ impl NodeClient {
             chain,
             timeout: Duration::from_secs(20),
             comm_retries,
+            block_cache: None,
+            replay: None,
         }
     }
 
+    pub fn use_block_cache(&mut self, block_cache: BlockCache) {
+        self.block_cache = Some(block_cache);
+    }
+
+    /// Serve everything from the replay dir, instead of from the node
+    pub fn use_replay_dir(&mut self, replay: ReplayDir) {
+        self.replay = Some(replay);
+    }
+
     /// Return the highest level on the chain
     pub(crate) fn head(&self) -> Result<LevelMeta> {
+        if let Some(replay) = &self.replay {
+            return Ok(self.level_json(replay.head_level())?.0);
+        }
         let (meta, _) = self.level_json_internal("head")?;
         Ok(meta)
     }
 
+    /// Return the level of the chain's head, only fetching its header
+    pub(crate) fn head_level(&self) -> Result<u32> {
+        if let Some(replay) = &self.replay {
+            return Ok(replay.head_level());
+        }
+        let header: Header = self
+            .load("blocks/head/header", Self::load_from_node)
+            .and_then(|body| Ok(serde_json::from_str(&body)?))
+            .with_context(|| "failed to get the head's header")?;
+        Ok(header.level)
+    }
+
+    fn level_hash(&self, level: u32) -> Result<String> {
+        let body = self
+            .load(&format!("blocks/{}/hash", level), Self::load_from_node)
+            .with_context(|| {
+                format!("failed to get the hash of level {}", level)
+            })?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
     pub(crate) fn level_json(&self, level: u32) -> Result<(LevelMeta, Block)> {
-        self.level_json_internal(&format!("{}", level))
+        if let Some(replay) = &self.replay {
+            return Self::parse_block(&replay.block_body(level)?);
+        }
+        let cache = match &self.block_cache {
+            Some(cache) => cache,
+            None => return self.level_json_internal(&format!("{}", level)),
+        };
+        // Only the hash of the node's block at the level is fetched, the
+        // cached block is served if it's that same block
+        let hash = self.level_hash(level)?;
+        match cache.get(level, &hash) {
+            Ok(Some(body)) => return Self::parse_block(&body),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "failed to load level {} from the block cache, err: {:?}",
+                level, e
+            ),
+        }
+
+        let body = self.load_block_body(&format!("{}", level))?;
+        let (meta, block) = Self::parse_block(&body)?;
+        if Utc::now() - meta.baked_at.unwrap() > *BLOCK_CACHE_MIN_AGE {
+            if let Err(e) = cache.put(level, &block.hash, &body) {
+                warn!(
+                    "failed to store level {} in the block cache, err: {:?}",
+                    level, e
+                );
+            }
+        }
+        Ok((meta, block))
     }
 
     fn level_json_internal(&self, level: &str) -> Result<(LevelMeta, Block)> {
-        let body = self
+        Self::parse_block(&self.load_block_body(level)?)
+    }
+
+    fn load_block_body(&self, level: &str) -> Result<String> {
+        self.load(
+            &format!("blocks/{}", level),
+            Self::load_from_node_retry_on_transient_err,
+        )
+        .with_context(|| {
+            format!("failed to get level_json for level={}", level)
+        })
+    }
+
+    fn parse_block(body: &str) -> Result<(LevelMeta, Block)> {
+        let mut deserializer = serde_json::Deserializer::from_str(body);
+        deserializer.disable_recursion_limit();
+        let block: Block = Block::deserialize(&mut deserializer)
+            .with_context(|| anyhow!("failed to deserialize block json"))?;
+
+        let meta = LevelMeta {
+            level: block.header.level,
+            hash: Some(block.hash.clone()),
+            prev_hash: Some(block.header.predecessor.clone()),
+            baked_at: Some(Self::timestamp_from_block(&block)?),
+        };
+        Ok((meta, block))
+    }
+
+    /// Like level_json, but only loads the block's header and its manager
+    /// operations (skipping the manager operations entirely if there are
+    /// none). Much lighter than the full block, as that is mostly made up of
+    /// consensus operations.
+    pub(crate) fn level_json_light(
+        &self,
+        level: u32,
+    ) -> Result<(LevelMeta, Block)> {
+        if self.replay.is_some() {
+            return self.level_json(level);
+        }
+
+        #[derive(Deserialize)]
+        struct BlockHeader {
+            hash: String,
+            #[serde(flatten)]
+            header: Header,
+        }
+        let header: BlockHeader = self
             .load(
-                &format!("blocks/{}", level),
+                &format!("blocks/{}/header", level),
                 Self::load_from_node_retry_on_transient_err,
             )
+            .and_then(|body| Ok(serde_json::from_str(&body)?))
             .with_context(|| {
-                format!("failed to get level_json for level={}", level)
+                format!("failed to get header for level={}", level)
             })?;
 
-        let mut deserializer = serde_json::Deserializer::from_str(&body);
-        deserializer.disable_recursion_limit();
-        let block: Block = Block::deserialize(&mut deserializer)
-            .with_context(|| anyhow!("failed to deserialize block json"))?;
+        let op_hashes: Vec<String> = self
+            .load(
+                &format!(
+                    "blocks/{}/operation_hashes/{}",
+                    level, MANAGER_OPERATIONS_PASS
+                ),
+                Self::load_from_node_retry_on_transient_err,
+            )
+            .and_then(|body| Ok(serde_json::from_str(&body)?))
+            .with_context(|| {
+                format!("failed to get operation hashes for level={}", level)
+            })?;
+        let operations: Vec<Operation> = if op_hashes.is_empty() {
+            vec![]
+        } else {
+            let body = self
+                .load(
+                    &format!(
+                        "blocks/{}/operations/{}",
+                        level, MANAGER_OPERATIONS_PASS
+                    ),
+                    Self::load_from_node_retry_on_transient_err,
+                )
+                .with_context(|| {
+                    format!("failed to get operations for level={}", level)
+                })?;
+            let mut deserializer = serde_json::Deserializer::from_str(&body);
+            deserializer.disable_recursion_limit();
+            Vec::<Operation>::deserialize(&mut deserializer).with_context(
+                || anyhow!("failed to deserialize operations json"),
+            )?
+        };
 
+        let block = Block::from_manager_operations(
+            header.hash,
+            header.header,
+            operations,
+        );
         let meta = LevelMeta {
-            level: block.header.level as u32,
+            level: block.header.level,
             hash: Some(block.hash.clone()),
             prev_hash: Some(block.header.predecessor.clone()),
             baked_at: Some(Self::timestamp_from_block(&block)?),
@@ -106,10 +274,19 @@ impl NodeClient {
         };
 
         let cache_dir = ".quepasa-cache";
-        let cache_filename =
-            format!("{}/contract-script-{}.json", cache_dir, contract_id);
+        let cache_filename = match level.as_str() {
+            "head" => {
+                format!("{}/contract-script-{}.json", cache_dir, contract_id)
+            }
+            _ => format!(
+                "{}/contract-script-{}-{}.json",
+                cache_dir, contract_id, level
+            ),
+        };
         let body;
-        if Self::file_exists(&cache_filename)? {
+        if let Some(replay) = &self.replay {
+            body = replay.script_body(contract_id)?;
+        } else if Self::file_exists(&cache_filename)? {
             info!(
                 "loading {} storage definition from {}",
                 contract_id, cache_filename
@@ -170,25 +347,39 @@ impl NodeClient {
         contract_id: &str,
         level: Option<u32>,
     ) -> Result<serde_json::map::Map<String, serde_json::Value>> {
+        if self.replay.is_some() {
+            return self.derive_entrypoint_definitions(contract_id, level);
+        }
+
         let lvl_ref = match level {
             Some(x) => format!("{}", x),
             None => "head".to_string(),
         };
 
-        let body = self
-            .load(
-                &format!(
-                    "blocks/{}/context/contracts/{}/entrypoints",
-                    lvl_ref, contract_id
-                ),
-                Self::load_from_node_retry_on_transient_err,
-            )
-            .with_context(|| {
-                format!(
+        let body = match self.load(
+            &format!(
+                "blocks/{}/context/contracts/{}/entrypoints",
+                lvl_ref, contract_id
+            ),
+            Self::load_from_node_retry_on_transient_err,
+        ) {
+            Ok(body) => body,
+            // Protocols before Babylon don't serve the entrypoints RPC, for
+            // historical levels derive them from the parameter type instead
+            Err(e) if level.is_some() => {
+                debug!(
+                    "failed to get entrypoints for contract='{}', level={}, deriving them from the parameter type, err: {:?}",
+                    contract_id, lvl_ref, e
+                );
+                return self.derive_entrypoint_definitions(contract_id, level);
+            }
+            Err(e) => {
+                return Err(e.context(format!(
                     "failed to get entrypoints for contract='{}', level={}",
                     contract_id, lvl_ref
-                )
-            })?;
+                )))
+            }
+        };
         let json = Self::deserialize(&body)?;
 
         let mut res = json["entrypoints"]
@@ -204,6 +395,18 @@ impl NodeClient {
         Ok(res)
     }
 
+    fn derive_entrypoint_definitions(
+        &self,
+        contract_id: &str,
+        level: Option<u32>,
+    ) -> Result<serde_json::map::Map<String, serde_json::Value>> {
+        let (_, param_def) =
+            self.get_contract_storage_definition(contract_id, level)?;
+        let mut res = entrypoints_from_parameter(&param_def);
+        res.insert("default".to_string(), param_def);
+        Ok(res)
+    }
+
     fn parse_rfc3339(rfc3339: &str) -> Result<DateTime<Utc>> {
         let fixedoffset = chrono::DateTime::parse_from_rfc3339(rfc3339)?;
         Ok(fixedoffset.with_timezone(&Utc))
@@ -225,21 +428,28 @@ impl NodeClient {
         };
 
         let mut i = 0;
+        let mut last_err = None;
         loop {
             if self.comm_retries >= 0 && i > self.comm_retries {
                 break;
             }
             for node_url in &self.node_urls {
-                let res = from_node_func(self, endpoint, node_url);
-                if res.is_ok() {
-                    return res;
-                }
-                warn!("failed to call tezos node RPC endpoint on node_url {} (attempt {}/{}) (endpoint={}), err: {:?}", node_url, i+1, max_retries, endpoint, res.unwrap_err());
+                let err = match from_node_func(self, endpoint, node_url) {
+                    Ok(res) => return Ok(res),
+                    Err(err) => err,
+                };
+                warn!("failed to call tezos node RPC endpoint on node_url {} (attempt {}/{}) (endpoint={}), err: {:?}", node_url, i+1, max_retries, endpoint, err);
+                metrics::NODE_RPC_RETRIES.inc();
+                last_err = Some(err);
                 std::thread::sleep(std::time::Duration::from_millis(1000));
             }
             i += 1;
         }
-        Err(anyhow!("failed to call tezos node RPC endpoint on all node_urls (endpoint={}", endpoint))
+        let msg = format!("failed to call tezos node RPC endpoint on all node_urls (endpoint={})", endpoint);
+        Err(match last_err {
+            Some(err) => err.context(msg),
+            None => anyhow!(msg),
+        })
     }
 
     fn load_from_node_retry_on_transient_err(
@@ -262,6 +472,7 @@ impl NodeClient {
                     // 56: RECEIVE ERROR
                     7 | 28 | 56 => {
                         warn!("transient node communication error, retrying.. err={:?}", curl_err);
+                        metrics::NODE_RPC_RETRIES.inc();
                         return Error::Transient(anyhow!("{:?}", curl_err));
                     }
                     _ => {}
@@ -289,12 +500,12 @@ impl NodeClient {
                 let err = http_err.unwrap();
                 if err.status_code == 429 {
                     warn!("transient node communication error, retrying.. err={:?}", err);
+                    metrics::NODE_RPC_RETRIES.inc();
                     return Error::Transient(anyhow!("{:?}", err));
                 }
-                return Error::Permanent(anyhow!(
-                    "bad http status code {}, not retrying..",
-                    err.status_code
-                ));
+                return Error::Permanent(
+                    anyhow::Error::new(err).context("not retrying.."),
+                );
             }
             warn!(
                 "permanent node communication error, not retrying.. err={:?}",
@@ -306,12 +517,15 @@ impl NodeClient {
             self.load_from_node(endpoint, node_url)
                 .map_err(transient_err)
         })
-        .map_err(|e| anyhow!(e))
+        .map_err(|e| match e {
+            Error::Permanent(e) | Error::Transient(e) => e,
+        })
     }
 
     fn load_from_node(&self, endpoint: &str, node_url: &str) -> Result<String> {
         let uri = format!("{}/chains/{}/{}", node_url, self.chain, endpoint);
         debug!("loading: {}", uri);
+        let start = std::time::Instant::now();
 
         let mut resp_data = Vec::new();
         let mut handle = Easy::new();
@@ -343,6 +557,8 @@ impl NodeClient {
             return Err(HttpError { status_code }.into());
         }
 
+        metrics::NODE_RPC_DURATION.observe(start.elapsed().as_secs_f64());
+
         let body = std::str::from_utf8(&resp_data).with_context(|| {
             format!("failed to parse response as utf8 for uri='{}'", uri)
         })?;
@@ -357,6 +573,44 @@ impl NodeClient {
         let json = serde_json::Value::deserialize(deserializer)?;
         Ok(json)
     }
+
+    /// One of the sizes of a sapling state (commitments_size or
+    /// nullifiers_size), read from the raw context
+    fn get_sapling_size(
+        &self,
+        level: u32,
+        sapling_state: i32,
+        size: &str,
+    ) -> Result<i64> {
+        let body = self
+            .load(
+                &format!(
+                    "blocks/{}/context/raw/json/sapling/index/{}/{}",
+                    level, sapling_state, size
+                ),
+                Self::load_from_node_retry_on_transient_err,
+            )
+            .with_context(|| {
+                format!(
+                    "failed to get {} of sapling state (level={}, sapling_state={})",
+                    size, level, sapling_state
+                )
+            })?;
+        // (int64 values are encoded as strings)
+        let value = Self::deserialize(&body)?;
+        value
+            .as_i64()
+            .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "unexpected {} of sapling state (level={}, sapling_state={}): {}",
+                    size,
+                    level,
+                    sapling_state,
+                    value
+                )
+            })
+    }
 }
 
 pub(crate) trait StorageGetter {
@@ -372,6 +626,12 @@ pub(crate) trait StorageGetter {
         bigmap_id: i32,
         keyhash: &str,
     ) -> Result<Option<serde_json::Value>>;
+
+    fn get_sapling_root(
+        &self,
+        level: u32,
+        sapling_state: i32,
+    ) -> Result<String>;
 }
 
 impl StorageGetter for NodeClient {
@@ -380,6 +640,9 @@ impl StorageGetter for NodeClient {
         contract_id: &str,
         level: u32,
     ) -> Result<serde_json::Value> {
+        if let Some(replay) = &self.replay {
+            return replay.get_contract_storage(contract_id, level);
+        }
         let body = self
             .load(
                 &format!(
@@ -408,6 +671,9 @@ impl StorageGetter for NodeClient {
         bigmap_id: i32,
         keyhash: &str,
     ) -> Result<Option<serde_json::Value>> {
+        if let Some(replay) = &self.replay {
+            return replay.get_bigmap_value(level, bigmap_id, keyhash);
+        }
         let body = self.load(&format!(
             "blocks/{}/context/big_maps/{}/{}",
             level, bigmap_id, keyhash,
@@ -421,4 +687,195 @@ impl StorageGetter for NodeClient {
 
         Ok(serde_json::Value::from_str(&body).ok())
     }
+
+    fn get_sapling_root(
+        &self,
+        level: u32,
+        sapling_state: i32,
+    ) -> Result<String> {
+        if let Some(replay) = &self.replay {
+            return replay.get_sapling_root(level, sapling_state);
+        }
+        // Without offsets the diff holds all of the state's commitments and
+        // nullifiers since its creation. With the offsets at the state's
+        // current sizes, it holds none of them, only the root.
+        let offset_commitment =
+            self.get_sapling_size(level, sapling_state, "commitments_size")?;
+        let offset_nullifier =
+            self.get_sapling_size(level, sapling_state, "nullifiers_size")?;
+        let body = self
+            .load(
+                &format!(
+                    "blocks/{}/context/sapling/{}/get_diff?offset_commitment={}&offset_nullifier={}",
+                    level, sapling_state, offset_commitment, offset_nullifier,
+                ),
+                Self::load_from_node_retry_on_transient_err,
+            )
+            .with_context(|| {
+                format!(
+                    "failed to get diff of sapling state (level={}, sapling_state={})",
+                    level, sapling_state
+                )
+            })?;
+        sapling_root(&Self::deserialize(&body)?).with_context(|| {
+            format!(
+                "failed to parse diff of sapling state (level={}, sapling_state={})",
+                level, sapling_state
+            )
+        })
+    }
+}
+
+/// The root from the response of the sapling state's get_diff RPC
+pub(crate) fn sapling_root(diff: &serde_json::Value) -> Result<String> {
+    diff["root"]
+        .as_str()
+        .map(|root| root.to_string())
+        .ok_or_else(|| anyhow!("no root in sapling diff"))
+}
+
+#[test]
+fn test_level_json_light() {
+    use crate::debug::{load_test, MockHttpServer};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    // The light blocks are served from full recorded blocks, split up into
+    // the responses of the endpoints used
+    let tests: Vec<(&str, u32)> = vec![
+        ("KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k", 50503),
+        ("KT1McJxUCT8qAybMfS6n5kjaESsi7cFbfck8", 228459),
+        ("KT1McJxUCT8qAybMfS6n5kjaESsi7cFbfck8", 228460),
+    ];
+    for (contract, level) in tests {
+        println!("test case: {} at level {}", contract, level);
+
+        let full: serde_json::Value = serde_json::from_str(&load_test(
+            &format!("test/{}.level-{}.json", contract, level),
+        ))
+        .unwrap();
+        let mut header = full["header"].clone();
+        header["hash"] = full["hash"].clone();
+        let operations = full["operations"][MANAGER_OPERATIONS_PASS].clone();
+        let op_hashes: Vec<serde_json::Value> = operations
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|op| op["hash"].clone())
+            .collect();
+
+        let mut pages: HashMap<String, serde_json::Value> = HashMap::new();
+        let prefix = format!("/chains/main/blocks/{}", level);
+        pages.insert(format!("{}/header", prefix), header);
+        pages.insert(
+            format!("{}/operation_hashes/{}", prefix, MANAGER_OPERATIONS_PASS),
+            serde_json::Value::Array(op_hashes),
+        );
+        pages.insert(
+            format!("{}/operations/{}", prefix, MANAGER_OPERATIONS_PASS),
+            operations,
+        );
+        let server = MockHttpServer::start(pages);
+        let node_cli =
+            NodeClient::new(vec![server.url.clone()], "main".to_string(), 0);
+
+        let (meta, block) = node_cli
+            .level_json_light(level)
+            .unwrap();
+
+        let full_block: Block = serde_json::from_value(full).unwrap();
+        assert_eq!(level, meta.level);
+        assert_eq!(Some(full_block.hash.clone()), meta.hash);
+        assert_eq!(
+            full_block.operations[MANAGER_OPERATIONS_PASS],
+            block.operations[MANAGER_OPERATIONS_PASS]
+        );
+        assert_eq!(
+            full_block.is_contract_active(contract),
+            block.is_contract_active(contract)
+        );
+        assert_eq!(
+            full_block.has_contract_origination(contract),
+            block.has_contract_origination(contract)
+        );
+        assert_eq!(full_block.active_contracts(), block.active_contracts());
+    }
+}
+
+#[test]
+fn test_get_sapling_root() {
+    use crate::debug::MockHttpServer;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    let mut pages: HashMap<String, serde_json::Value> = HashMap::new();
+    for &(state, commitments_size, nullifiers_size) in
+        [(14, "12", "3"), (15, "0", "0")].iter()
+    {
+        pages.insert(
+            format!(
+                "/chains/main/blocks/100/context/raw/json/sapling/index/{}/commitments_size",
+                state
+            ),
+            serde_json::json!(commitments_size),
+        );
+        pages.insert(
+            format!(
+                "/chains/main/blocks/100/context/raw/json/sapling/index/{}/nullifiers_size",
+                state
+            ),
+            serde_json::json!(nullifiers_size),
+        );
+    }
+    pages.insert(
+        "/chains/main/blocks/100/context/sapling/14/get_diff?offset_commitment=12&offset_nullifier=3".to_string(),
+        serde_json::json!({
+            "root": "fbc2f4300c01f0b7820d00e3347c8da4ee614674376cbc45359daa54f9b5493e",
+            "commitments_and_ciphertexts": [],
+            "nullifiers": [],
+        }),
+    );
+    pages.insert(
+        "/chains/main/blocks/100/context/sapling/15/get_diff?offset_commitment=0&offset_nullifier=0".to_string(),
+        serde_json::json!({"nullifiers": []}),
+    );
+    let server = MockHttpServer::start(pages);
+    let node_cli =
+        NodeClient::new(vec![server.url.clone()], "main".to_string(), 0);
+
+    assert_eq!(
+        "fbc2f4300c01f0b7820d00e3347c8da4ee614674376cbc45359daa54f9b5493e",
+        node_cli
+            .get_sapling_root(100, 14)
+            .unwrap()
+    );
+    assert!(node_cli
+        .get_sapling_root(100, 15)
+        .is_err());
+}
+
+#[test]
+fn test_is_not_found() {
+    use crate::debug::MockHttpServer;
+    use std::collections::HashMap;
+
+    let mut pages: HashMap<String, serde_json::Value> = HashMap::new();
+    pages.insert(
+        "/chains/main/blocks/100/context/contracts/KT1Malformed/script"
+            .to_string(),
+        serde_json::json!({}),
+    );
+    let server = MockHttpServer::start(pages);
+    let node_cli =
+        NodeClient::new(vec![server.url.clone()], "main".to_string(), 0);
+
+    let err = node_cli
+        .get_contract_storage_definition("KT1Missing", Some(100))
+        .unwrap_err();
+    assert!(is_not_found(&err));
+
+    let err = node_cli
+        .get_contract_storage_definition("KT1Malformed", Some(100))
+        .unwrap_err();
+    assert!(!is_not_found(&err));
 }
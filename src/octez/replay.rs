@@ -0,0 +1,234 @@
+use crate::octez::node::StorageGetter;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// Serves blocks and contract scripts from a directory of captured files,
+/// instead of from a node. The files are named like the ones in test/:
+///  - <contract>.level-<level>.json: the block at <level>
+///  - <contract>.script: the contract's script
+///  - <contract>.storage-<level>.json: the contract's storage at <level>
+///    (optional, only needed for contracts whose storage is requested)
+///  - sapling-<id>.diff-<level>.json: the diff of sapling state <id> at
+///    <level> (optional, only needed for contracts with a sapling state)
+#[derive(Clone, Debug)]
+pub struct ReplayDir {
+    dir: PathBuf,
+    blocks: HashMap<u32, PathBuf>,
+}
+
+impl ReplayDir {
+    pub fn new(dir: &str) -> Result<Self> {
+        let mut blocks: HashMap<u32, PathBuf> = HashMap::new();
+        for dir_entry in fs::read_dir(dir)
+            .with_context(|| format!("failed to read replay dir {}", dir))?
+        {
+            let path = dir_entry?.path();
+            let filename = match path.file_name() {
+                Some(f) => f.to_string_lossy().to_string(),
+                None => continue,
+            };
+            if let Some(level) = filename
+                .split_once(".level-")
+                .and_then(|(_, level)| level.strip_suffix(".json"))
+                .and_then(|level| level.parse::<u32>().ok())
+            {
+                blocks.insert(level, path);
+            }
+        }
+        if blocks.is_empty() {
+            return Err(anyhow!("replay dir {} has no blocks", dir));
+        }
+        info!("replaying {} blocks from {}", blocks.len(), dir);
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            blocks,
+        })
+    }
+
+    /// All levels that have a block in the replay dir, in ascending order
+    pub(crate) fn levels(&self) -> Vec<u32> {
+        let mut levels: Vec<u32> = self.blocks.keys().copied().collect();
+        levels.sort_unstable();
+        levels
+    }
+
+    /// The highest level that has a block in the replay dir, serves as the
+    /// chain's head
+    pub(crate) fn head_level(&self) -> u32 {
+        *self.blocks.keys().max().unwrap()
+    }
+
+    pub(crate) fn block_body(&self, level: u32) -> Result<String> {
+        let path = self.blocks.get(&level).ok_or_else(|| {
+            anyhow!(
+                "replay dir {:?} has no block for level {}",
+                self.dir,
+                level
+            )
+        })?;
+        Self::read(path)
+    }
+
+    pub(crate) fn script_body(&self, contract_id: &str) -> Result<String> {
+        Self::read(
+            &self
+                .dir
+                .join(format!("{}.script", contract_id)),
+        )
+    }
+
+    fn read(path: &Path) -> Result<String> {
+        fs::read_to_string(path)
+            .with_context(|| format!("failed to read replay file {:?}", path))
+    }
+}
+
+/// Derives the entrypoints from a contract's parameter type, as the node's
+/// entrypoints RPC would: each annotated branch of the parameter's (nested)
+/// or types is an entrypoint.
+pub(crate) fn entrypoints_from_parameter(
+    param_def: &serde_json::Value,
+) -> serde_json::map::Map<String, serde_json::Value> {
+    let mut res = serde_json::map::Map::new();
+    fn walk(
+        node: &serde_json::Value,
+        res: &mut serde_json::map::Map<String, serde_json::Value>,
+    ) {
+        if let Some(annots) = node["annots"].as_array() {
+            for annot in annots {
+                if let Some(name) = annot
+                    .as_str()
+                    .and_then(|a| a.strip_prefix('%'))
+                {
+                    res.insert(name.to_string(), node.clone());
+                }
+            }
+        }
+        if node["prim"] == "or" {
+            if let Some(args) = node["args"].as_array() {
+                for arg in args {
+                    walk(arg, res);
+                }
+            }
+        }
+    }
+    walk(param_def, &mut res);
+    res
+}
+
+impl StorageGetter for ReplayDir {
+    fn get_contract_storage(
+        &self,
+        contract_id: &str,
+        level: u32,
+    ) -> Result<serde_json::Value> {
+        let body = Self::read(
+            &self
+                .dir
+                .join(format!("{}.storage-{}.json", contract_id, level)),
+        )?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    fn get_bigmap_value(
+        &self,
+        level: u32,
+        bigmap_id: i32,
+        keyhash: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        Err(anyhow!(
+            "bigmap values are not available in replay mode (level={}, bigmap_id={}, keyhash={})",
+            level,
+            bigmap_id,
+            keyhash
+        ))
+    }
+
+    fn get_sapling_root(
+        &self,
+        level: u32,
+        sapling_state: i32,
+    ) -> Result<String> {
+        let body = Self::read(
+            &self
+                .dir
+                .join(format!("sapling-{}.diff-{}.json", sapling_state, level)),
+        )?;
+        crate::octez::node::sapling_root(&serde_json::from_str(&body)?)
+    }
+}
+
+#[test]
+fn test_replay_dir() {
+    let replay = ReplayDir::new("test/").unwrap();
+    assert_eq!(1678750, replay.head_level());
+    assert!(replay.levels().contains(&50503));
+    assert!(replay.block_body(50503).is_ok());
+    assert!(replay.block_body(50504).is_err());
+    assert!(replay
+        .script_body("KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k")
+        .is_ok());
+
+    // A node client in replay mode never contacts the node
+    let mut node_cli = crate::octez::node::NodeClient::new(
+        vec!["http://localhost:0".to_string()],
+        "main".to_string(),
+        0,
+    );
+    node_cli.use_replay_dir(replay);
+    assert_eq!(1678750, node_cli.head().unwrap().level);
+    assert_eq!(
+        50503,
+        node_cli
+            .level_json(50503)
+            .unwrap()
+            .0
+            .level
+    );
+
+    let contract = crate::executor::get_contract_rel(
+        &node_cli,
+        &crate::config::ContractID {
+            name: "test".to_string(),
+            address: "KT1GT5sQWfK4f8x1DqqEfKvKoZg4sZciio7k".to_string(),
+        },
+        false,
+        &crate::storage_structure::naming::NamingPolicy::default(),
+        &crate::storage_structure::selection::Selection::default(),
+    )
+    .unwrap();
+    assert!(!contract.entrypoint_asts.is_empty());
+}
+
+#[test]
+fn test_entrypoints_from_parameter() {
+    let param_def: serde_json::Value = serde_json::from_str(
+        r#"{
+  "prim": "or",
+  "args": [
+    {"prim": "nat", "annots": ["%mint"]},
+    {
+      "prim": "or",
+      "annots": ["%admin"],
+      "args": [
+        {"prim": "address", "annots": ["%set_admin"]},
+        {"prim": "unit", "annots": ["%pause"]}
+      ]
+    }
+  ]
+}"#,
+    )
+    .unwrap();
+
+    let mut got: Vec<String> = entrypoints_from_parameter(&param_def)
+        .keys()
+        .cloned()
+        .collect();
+    got.sort();
+    assert_eq!(vec!["admin", "mint", "pause", "set_admin"], got);
+}
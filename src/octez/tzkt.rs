@@ -0,0 +1,113 @@
+// tzkt => tzkt.io
+use crate::octez::level_source::{self, LevelSource};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::time::Duration;
+
+const PAGE_SIZE: usize = 1000;
+
+pub struct TzKTClient {
+    api_url: String,
+    timeout: Duration,
+}
+
+impl TzKTClient {
+    pub(crate) fn new(api_url: String) -> Self {
+        Self {
+            api_url,
+            timeout: Duration::from_secs(20),
+        }
+    }
+
+    fn get_origination_level(&self, contract_addr: &str) -> Result<u32> {
+        let levels: Vec<u32> = level_source::load(
+            self.name(),
+            format!("{}/operations/originations", self.api_url),
+            self.timeout,
+            &[
+                ("originatedContract".to_string(), contract_addr.to_string()),
+                ("select".to_string(), "level".to_string()),
+            ],
+            |resp| {
+                let parsed: Vec<u32> = serde_json::from_str(resp)?;
+                Ok(parsed)
+            },
+        )?;
+        levels.first().copied().ok_or_else(|| {
+            anyhow!("tzkt has no origination for contract={}", contract_addr)
+        })
+    }
+}
+
+impl LevelSource for TzKTClient {
+    fn name(&self) -> &str {
+        "tzkt"
+    }
+
+    fn get_levels_page(
+        &self,
+        contract_addr: &str,
+        last_id: Option<String>,
+    ) -> Result<(Vec<u32>, String)> {
+        let mut params = vec![
+            ("target".to_string(), contract_addr.to_string()),
+            ("status".to_string(), "applied".to_string()),
+            ("select".to_string(), "id,level".to_string()),
+            ("sort.asc".to_string(), "id".to_string()),
+            ("limit".to_string(), PAGE_SIZE.to_string()),
+        ];
+        if let Some(last_id) = &last_id {
+            params.push(("offset.cr".to_string(), last_id.clone()))
+        }
+
+        #[derive(Deserialize)]
+        struct Operation {
+            id: u64,
+            level: u32,
+        }
+        let operations: Vec<Operation> = level_source::load(
+            self.name(),
+            format!("{}/operations/transactions", self.api_url),
+            self.timeout,
+            &params,
+            |resp| {
+                let parsed: Vec<Operation> = serde_json::from_str(resp)?;
+                Ok(parsed)
+            },
+        )?;
+
+        let mut levels: Vec<u32> = vec![];
+        // Unlike the transactions, the origination is not an operation
+        // that targets the contract, so it's included separately in the
+        // first page.
+        if last_id.is_none() {
+            levels.push(self.get_origination_level(contract_addr)?);
+        }
+        levels.extend(operations.iter().map(|op| op.level));
+        levels.dedup();
+
+        let new_last_id = match operations.last() {
+            Some(op) => op.id.to_string(),
+            None => last_id.unwrap_or_else(|| "0".to_string()),
+        };
+        Ok((levels, new_last_id))
+    }
+
+    fn get_latest_level(&self) -> Result<u32> {
+        #[derive(Deserialize)]
+        struct Parsed {
+            level: u32,
+        }
+        let parsed: Parsed = level_source::load(
+            self.name(),
+            format!("{}/head", self.api_url),
+            self.timeout,
+            &[],
+            |resp| {
+                let parsed: Parsed = serde_json::from_str(resp)?;
+                Ok(parsed)
+            },
+        )?;
+        Ok(parsed.level)
+    }
+}
@@ -0,0 +1,84 @@
+use crate::health::HealthChecker;
+use crate::metrics;
+use anyhow::{anyhow, Result};
+use std::thread;
+
+fn respond_json<T: serde::Serialize>(
+    body: &T,
+    status_code: u16,
+) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    tiny_http::Response::from_string(
+        serde_json::to_string(body).unwrap_or_default(),
+    )
+    .with_status_code(status_code)
+    .with_header(
+        "Content-Type: application/json"
+            .parse::<tiny_http::Header>()
+            .unwrap(),
+    )
+}
+
+/// Serves on http://<addr>:
+///  - /metrics: the Prometheus metrics
+///  - /health: the indexer's state, fails (503) if it cannot be determined
+///  - /ready: same as /health, but also fails if the indexer is not ready
+///    (not in head mode, lagging behind the node's head, or no block
+///    processed recently)
+///
+/// /health and /ready are only served if a health checker is given. They
+/// serve the state last refreshed by the health checker's thread, so that
+/// they (and /metrics) never wait on the database or the node.
+pub fn serve(
+    addr: &str,
+    health: Option<HealthChecker>,
+) -> Result<thread::JoinHandle<()>> {
+    metrics::init();
+
+    let server = tiny_http::Server::http(addr).map_err(|e| {
+        anyhow!("failed to start http server on {}, err: {}", addr, e)
+    })?;
+    info!("serving metrics on http://{}/metrics", addr);
+    if health.is_some() {
+        info!("serving health checks on http://{}/health and /ready", addr);
+    }
+
+    Ok(thread::spawn(move || {
+        for req in server.incoming_requests() {
+            let resp = match (req.url(), &health) {
+                ("/metrics", _) => match metrics::encode() {
+                    Ok(body) => tiny_http::Response::from_string(body),
+                    Err(e) => {
+                        error!("failed to encode metrics, err: {:?}", e);
+                        tiny_http::Response::from_string(e.to_string())
+                            .with_status_code(500)
+                    }
+                },
+                (url @ ("/health" | "/ready"), Some(health)) => {
+                    match health.report() {
+                        Ok(report) => {
+                            let status_code =
+                                if url == "/ready" && !report.ready {
+                                    503
+                                } else {
+                                    200
+                                };
+                            respond_json(&report, status_code)
+                        }
+                        Err(e) => respond_json(
+                            &serde_json::json!({
+                                "error": format!("{:#}", e),
+                                "ready": false,
+                            }),
+                            503,
+                        ),
+                    }
+                }
+                _ => tiny_http::Response::from_string("not found")
+                    .with_status_code(404),
+            };
+            if let Err(e) = req.respond(resp) {
+                warn!("failed to respond to http request, err: {:?}", e);
+            }
+        }
+    }))
+}
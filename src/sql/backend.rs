@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+use crate::config::{Config, ContractID};
+use crate::octez::block::LevelMeta;
+use crate::octez::levels_file::ContractLevel;
+use crate::octez::node::NodeClient;
+use crate::sql::db::{
+    BigmapEntry, BigmapKeysGetter, ContractRequestStatus, DBClient, IndexerMode,
+};
+use crate::sql::inserter::{ClearedLevel, ProcessedBlock};
+use crate::sql::migrations::Migration;
+use crate::sql::sqlite::SqliteClient;
+use crate::stats::StatsLogger;
+use crate::storage_structure::relational;
+
+pub(crate) type ContractRelGetter<'a> =
+    &'a dyn Fn(&NodeClient, &ContractID) -> Result<relational::Contract>;
+
+/// The database the indexer writes to. Everything the indexer needs from
+/// the database goes through here: setting up its tables (the common tables
+/// and the contracts' tables), inserting processed blocks while keeping the
+/// derived tables up to date, and keeping track of which levels have been
+/// processed. Implemented for PostgreSQL (DBClient) and SQLite
+/// (SqliteClient).
+pub(crate) trait Backend: BigmapKeysGetter + Send {
+    fn clone_backend(&self) -> Box<dyn Backend>;
+
+    fn get_quepasa_version(&mut self) -> Result<String>;
+    fn common_tables_exist(&mut self) -> Result<bool>;
+    fn create_common_tables(&mut self) -> Result<()>;
+    fn delete_everything(
+        &mut self,
+        node_cli: &NodeClient,
+        get_contract_rel: ContractRelGetter,
+    ) -> Result<()>;
+    /// Deletes everything that was indexed for one contract: its tables, and
+    /// its rows in the common tables. Other contracts are left as is.
+    fn delete_contract(
+        &mut self,
+        node_cli: &NodeClient,
+        name: &str,
+        get_contract_rel: ContractRelGetter,
+    ) -> Result<()>;
+    /// Applies the migrations in a single transaction (see
+    /// migrations::apply_migrations).
+    fn apply_migrations(&mut self, migrations: &[Migration]) -> Result<()>;
+
+    /// Creates the tables of the contracts that are not in the database
+    /// yet. Returns whether there were any.
+    fn create_contract_schemas(
+        &mut self,
+        contracts: &mut Vec<relational::Contract>,
+    ) -> Result<bool>;
+    /// Evolves the tables of an already created contract, so that they can
+    /// hold the data of another version of the contract's storage type.
+    fn add_contract_version_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<()>;
+    /// Derives the contract's _live and _ordered tables again from scratch
+    /// (see Executor::rebuild_derived_tables).
+    fn rebuild_derived_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<()>;
+    /// The contracts whose tables are in the database
+    fn get_contracts(&mut self) -> Result<Vec<ContractID>>;
+
+    /// Saves the processed blocks, and updates the derived tables of their
+    /// contracts accordingly. With contracts_scoped, only the processed
+    /// contracts' data in the levels is replaced, instead of the levels
+    /// entirely. The notifications (see notify::level_payload) are issued
+    /// in the same transaction, if a notify channel is set. Returns the
+    /// levels whose previously saved data was replaced.
+    fn insert_batch(
+        &mut self,
+        stats: Option<&StatsLogger>,
+        contracts_scoped: bool,
+        processed_blocks: Vec<ProcessedBlock>,
+        notifications: &[String],
+    ) -> Result<Vec<ClearedLevel>>;
+    /// Deletes the forked levels, along with everything that was indexed in
+    /// them, and notifies it in the same transaction if a notify channel is
+    /// set (see notify::forked_levels_payloads).
+    fn delete_levels(&mut self, levels: &[u32]) -> Result<()>;
+
+    fn get_head(&mut self) -> Result<Option<LevelMeta>>;
+    fn get_level(&mut self, level: u32) -> Result<Option<LevelMeta>>;
+    fn get_missing_levels(
+        &mut self,
+        contracts: &[ContractID],
+        end: u32,
+    ) -> Result<Vec<u32>>;
+    fn get_forked_levels(&mut self) -> Result<Vec<u32>>;
+    fn get_fully_processed_levels(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<Vec<u32>>;
+    fn get_partial_processed_levels(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<Vec<u32>>;
+    fn mark_missing_levels_empty(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<u64>;
+    fn get_origination(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<Option<u32>>;
+    /// Returns for each contract the levels it has been active in (including
+    /// its origination), plus the highest level processed for it so that the
+    /// range processed for the contract is preserved.
+    fn get_active_contract_levels(&mut self) -> Result<Vec<ContractLevel>>;
+
+    fn get_indexer_mode(&mut self) -> Result<IndexerMode>;
+    fn set_indexer_mode(&mut self, mode: IndexerMode) -> Result<()>;
+    /// Whether the derived tables have to be rebuilt, because they were
+    /// left stale by an older version (see migrations::migrations)
+    fn get_derived_tables_stale(&mut self) -> Result<bool>;
+    fn set_derived_tables_stale(&mut self, stale: bool) -> Result<()>;
+
+    fn get_config_deps(
+        &mut self,
+        config: &[ContractID],
+    ) -> Result<Vec<ContractID>>;
+    fn get_dependent_levels(
+        &mut self,
+        config: &[ContractID],
+    ) -> Result<Vec<u32>>;
+
+    /// Requests the running indexer to start indexing the contract (see
+    /// Executor::accept_contract_requests). Re-requesting a contract
+    /// resets its request.
+    fn request_contract(&mut self, contract_id: &ContractID) -> Result<()>;
+    fn get_contract_requests(
+        &mut self,
+        statuses: &[ContractRequestStatus],
+    ) -> Result<Vec<ContractID>>;
+    fn set_contract_request_status(
+        &mut self,
+        contract_id: &ContractID,
+        status: ContractRequestStatus,
+        error: Option<String>,
+    ) -> Result<()>;
+}
+
+impl Clone for Box<dyn Backend> {
+    fn clone(&self) -> Self {
+        self.clone_backend()
+    }
+}
+
+impl BigmapKeysGetter for Box<dyn Backend> {
+    fn get(&mut self, level: u32, bigmap_id: i32) -> Result<Vec<BigmapEntry>> {
+        (**self).get(level, bigmap_id)
+    }
+}
+
+/// Connects to the database of the database url: a SQLite database for
+/// urls of the form sqlite:<path> (sqlite::memory: for an in-memory one),
+/// a PostgreSQL database otherwise.
+pub(crate) fn connect(config: &Config) -> Result<Box<dyn Backend>> {
+    if let Some(path) = config
+        .database_url
+        .strip_prefix("sqlite:")
+    {
+        if config.skip_unchanged_snapshots {
+            return Err(anyhow!(
+                "--skip-unchanged-snapshots is not supported with SQLite"
+            ));
+        }
+        if config.notify_channel.is_some() {
+            return Err(anyhow!(
+                "--notify-channel is not supported with SQLite"
+            ));
+        }
+        return Ok(Box::new(SqliteClient::connect(path)?));
+    }
+
+    let mut dbcli = DBClient::connect(
+        &config.database_url,
+        &config.main_schema,
+        Duration::from_millis(5 * 60 * 1000),
+        10,
+    )?;
+    if config.skip_unchanged_snapshots {
+        dbcli.skip_unchanged_snapshots()?;
+    }
+    dbcli.set_inserter_cap(config.inserter_cap);
+    if let Some(channel) = &config.notify_channel {
+        dbcli.set_notify_channel(channel);
+    }
+    Ok(Box::new(dbcli))
+}
@@ -0,0 +1,247 @@
+use crate::sql::postgresql_generator::PostgresqlGenerator;
+use crate::sql::table::Table;
+use crate::sql::table_builder::TableBuilder;
+use crate::storage_structure::relational;
+use crate::storage_update::sapling;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// A table of a contract's schema, as described in the catalog_tables table
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct CatalogTable {
+    pub table_name: String,
+    /// The table's path in the contract's storage or parameter type
+    pub path: String,
+    pub kind: &'static str,
+    /// The table this table's rows belong to in the contract's type
+    pub parent_table: Option<String>,
+    /// The column referencing the parent table's id, only snapshot and
+    /// entrypoint tables have one
+    pub parent_fk_column: Option<String>,
+}
+
+/// A column of a contract's schema, as described in the catalog_columns
+/// table
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct CatalogColumn {
+    pub table_name: String,
+    pub column_name: String,
+    /// The column's name before it was shortened
+    pub original_name: String,
+    pub michelson_type: Option<String>,
+    pub annotation: Option<String>,
+    pub is_index: bool,
+}
+
+fn table_kind(table: &Table) -> &'static str {
+    if table.name == sapling::UPDATES_TABLE {
+        "sapling_state_updates"
+    } else if table.path().starts_with("entry.") {
+        "entrypoint"
+    } else if !table.contains_snapshots() {
+        "bigmap_changes"
+    } else {
+        "snapshot"
+    }
+}
+
+/// Describes the tables of the contract's schema and their columns (leaving
+/// out the columns every table of its kind has, eg id and tx_context_id).
+pub(crate) fn contract_catalog(
+    contract: &relational::Contract,
+) -> (Vec<CatalogTable>, Vec<CatalogColumn>) {
+    let (mut tables, _, _) = TableBuilder::tables_from_contract(contract);
+    tables.sort_by_key(|t| t.name.clone());
+
+    let mut catalog_tables: Vec<CatalogTable> = vec![];
+    let mut catalog_columns: Vec<CatalogColumn> = vec![];
+    for table in &tables {
+        let table_path = contract.tables.get(&table.name);
+        let parent_fk_table = PostgresqlGenerator::table_parent_name(table);
+        catalog_tables.push(CatalogTable {
+            table_name: table.name.clone(),
+            path: table.path().to_string(),
+            kind: table_kind(table),
+            parent_table: match table_path {
+                Some(table_path) => table_path.parent.clone(),
+                None => PostgresqlGenerator::parent_name(&table.name),
+            },
+            parent_fk_column: parent_fk_table
+                .map(|parent| PostgresqlGenerator::parent_ref(&parent)),
+        });
+
+        let keywords = table.keywords();
+        for column in table.get_columns() {
+            if keywords.contains(&column.name)
+                || PostgresqlGenerator::create_sql(column).is_none()
+            {
+                continue;
+            }
+            let column_path =
+                table_path.and_then(|t| t.columns.get(&column.name));
+            catalog_columns.push(CatalogColumn {
+                table_name: table.name.clone(),
+                column_name: column.name.clone(),
+                original_name: column_path
+                    .map(|c| c.name.clone())
+                    .unwrap_or_else(|| column.name.clone()),
+                michelson_type: column_path
+                    .and_then(|c| c.michelson_type.clone()),
+                annotation: column_path.and_then(|c| c.annotation.clone()),
+                is_index: table.indices.contains(&column.name),
+            });
+        }
+    }
+    (catalog_tables, catalog_columns)
+}
+
+#[test]
+fn test_contract_catalog() {
+    use crate::config::ContractID;
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing::type_ast_from_json;
+    use std::collections::HashMap;
+
+    let storage_def: serde_json::Value = serde_json::from_str(
+        r#"{
+  "prim": "pair",
+  "args": [
+    {
+      "prim": "big_map",
+      "annots": ["%ledger"],
+      "args": [{"prim": "address"}, {"prim": "nat"}]
+    },
+    {
+      "prim": "map",
+      "annots": ["%operators"],
+      "args": [{"prim": "nat"}, {"prim": "set", "args": [{"prim": "address"}]}]
+    }
+  ]
+}"#,
+    )
+    .unwrap();
+    let entrypoint_def: serde_json::Value = serde_json::from_str(
+        r#"{
+  "prim": "pair",
+  "args": [
+    {"prim": "nat", "annots": ["%amount"]},
+    {"prim": "key", "annots": ["%signer"]},
+    {"prim": "chain_id", "annots": ["%chain"]},
+    {"prim": "chest"}
+  ]
+}"#,
+    )
+    .unwrap();
+
+    let mut builder = ASTBuilder::new("storage");
+    let storage_ast = builder
+        .build_relational_ast(&type_ast_from_json(&storage_def).unwrap())
+        .unwrap();
+    let mut tables = builder.tables().clone();
+
+    let mut builder = ASTBuilder::new("entry.mint");
+    let entrypoint_ast = builder
+        .build_relational_ast(&type_ast_from_json(&entrypoint_def).unwrap())
+        .unwrap();
+    tables.extend(builder.tables().clone());
+
+    let contract = relational::Contract {
+        cid: ContractID {
+            name: "test".to_string(),
+            address: "KT1".to_string(),
+        },
+        level_floor: None,
+        storage_ast,
+        entrypoint_asts: HashMap::from([("mint".to_string(), entrypoint_ast)]),
+        tables,
+        selection: Default::default(),
+    };
+    let (got_tables, got_columns) = contract_catalog(&contract);
+
+    struct TestCase {
+        table_name: &'static str,
+        kind: &'static str,
+        parent_table: Option<&'static str>,
+        parent_fk_column: Option<&'static str>,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            table_name: "entry.mint",
+            kind: "entrypoint",
+            parent_table: None,
+            parent_fk_column: None,
+        },
+        TestCase {
+            table_name: "storage",
+            kind: "snapshot",
+            parent_table: None,
+            parent_fk_column: None,
+        },
+        TestCase {
+            table_name: "storage.ledger",
+            kind: "bigmap_changes",
+            parent_table: Some("storage"),
+            parent_fk_column: None,
+        },
+        TestCase {
+            table_name: "storage.operators",
+            kind: "snapshot",
+            parent_table: Some("storage"),
+            parent_fk_column: Some("storage_id"),
+        },
+        TestCase {
+            table_name: "storage.operators.noname",
+            kind: "snapshot",
+            parent_table: Some("storage.operators"),
+            parent_fk_column: Some("operators_id"),
+        },
+    ];
+    assert_eq!(tests.len(), got_tables.len());
+    for (tc, got) in tests.iter().zip(got_tables.iter()) {
+        println!("test case: {}", tc.table_name);
+        assert_eq!(
+            CatalogTable {
+                table_name: tc.table_name.to_string(),
+                path: tc.table_name.to_string(),
+                kind: tc.kind,
+                parent_table: tc.parent_table.map(|s| s.to_string()),
+                parent_fk_column: tc
+                    .parent_fk_column
+                    .map(|s| s.to_string()),
+            },
+            *got
+        );
+    }
+
+    let ledger_key = got_columns
+        .iter()
+        .find(|c| c.table_name == "storage.ledger" && c.is_index)
+        .unwrap();
+    assert_eq!(Some("address".to_string()), ledger_key.michelson_type);
+    // The columns have the type they were derived from, also when they are
+    // stored as another type
+    for (column_name, exp_type) in [
+        ("amount", "nat"),
+        ("signer", "key"),
+        ("chain", "chain_id"),
+        ("chest", "chest"),
+    ] {
+        println!("test case: {}", column_name);
+        let got = got_columns
+            .iter()
+            .find(|c| {
+                c.table_name == "entry.mint" && c.column_name == column_name
+            })
+            .unwrap();
+        assert_eq!(Some(exp_type.to_string()), got.michelson_type);
+    }
+    let amount = got_columns
+        .iter()
+        .find(|c| c.column_name == "amount")
+        .unwrap();
+    assert_eq!(Some("amount".to_string()), amount.annotation);
+    assert!(!got_columns
+        .iter()
+        .any(|c| c.column_name == "id" || c.column_name == "tx_context_id"));
+}
@@ -1,23 +1,32 @@
 use anyhow::{anyhow, Result};
 use askama::Template;
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 
+use postgres::binary_copy::BinaryCopyInWriter;
 use postgres::fallible_iterator::FallibleIterator;
-use postgres::types::{BorrowToSql, FromSql, ToSql};
+use postgres::types::{BorrowToSql, FromSql, ToSql, Type};
 use postgres::Transaction;
 
 use chrono::{DateTime, Utc};
 
 use crate::config::ContractID;
 use crate::octez::block::{LevelMeta, Tx, TxContext};
+use crate::octez::levels_file::ContractLevel;
 use crate::octez::node::NodeClient;
+use crate::sql::backend::{Backend, ContractRelGetter};
+use crate::sql::catalog;
 use crate::sql::insert::{Column, Insert, Value};
+use crate::sql::inserter::{self, ClearedLevel, ProcessedBlock};
+use crate::sql::migrations::{self, Migration};
+use crate::sql::notify;
 use crate::sql::postgresql_generator::PostgresqlGenerator;
 use crate::sql::table::Table;
 use crate::sql::table_builder::TableBuilder;
 use crate::sql::types::BigmapMetaAction;
+use crate::stats::StatsLogger;
 use crate::storage_structure::relational;
 
 use r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
@@ -29,6 +38,27 @@ pub(crate) enum IndexerMode {
     Head,
 }
 
+/// Status of a request to start indexing a contract while the indexer is
+/// running (see --add-contract).
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum ContractRequestStatus {
+    Requested,
+    Bootstrapping,
+    Added,
+    Failed,
+}
+
+impl ContractRequestStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Requested => "requested",
+            Self::Bootstrapping => "bootstrapping",
+            Self::Added => "added",
+            Self::Failed => "failed",
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "repopulate-snapshot-derived.sql", escape = "none")]
 struct RepopulateSnapshotDerivedTmpl<'a> {
@@ -75,10 +105,27 @@ type DBPooledConn = r2d2::PooledConnection<PostgresConnectionManager<NoTls>>;
 pub struct DBClient {
     dbpool: DBPool,
     main_schema: String,
+
+    // store storage snapshots that are the same as the contract's previous
+    // one as a reference to it (see snapshots::skip_unchanged_snapshots),
+    // except for the contracts whose schemas have snapshot functions that
+    // predate this (they would not resolve the references)
+    skip_unchanged_snapshots: bool,
+    legacy_snapshot_schemas: HashSet<String>,
+
+    // max number of threads used to build the rows of a batch's contracts
+    // concurrently (see inserter::insert_batch)
+    inserter_cap: usize,
+
+    // the channel notified of committed levels and forks (see notify)
+    notify_channel: Option<String>,
 }
 
 impl DBClient {
     const INSERT_BATCH_SIZE: usize = 100;
+    // from this many rows on, rows are bulk loaded into a table with a COPY
+    // instead of being inserted in chunks of INSERT_BATCH_SIZE
+    const COPY_MIN_ROWS: usize = 1000;
 
     pub(crate) fn connect(
         url: &str,
@@ -95,98 +142,95 @@ impl DBClient {
         Ok(DBClient {
             dbpool,
             main_schema: main_schema.to_string(),
+            skip_unchanged_snapshots: false,
+            legacy_snapshot_schemas: HashSet::new(),
+            inserter_cap: 1,
+            notify_channel: None,
         })
     }
 
-    pub(crate) fn dbconn(&self) -> Result<DBPooledConn> {
-        let mut conn = self
-            .dbpool
-            .get()
-            .map_err(|err| anyhow!("err: {}", err))?;
-        conn.simple_query(
-            format!(r#"SET SCHEMA '{}'"#, self.main_schema).as_str(),
-        )?;
-        Ok(conn)
-    }
+    pub(crate) fn skip_unchanged_snapshots(&mut self) -> Result<()> {
+        self.skip_unchanged_snapshots = true;
 
-    pub(crate) fn get_quepasa_version(&mut self) -> Result<String> {
+        // The snapshot functions (<table>_at) created before Que Pasa 1.3
+        // select the snapshot rows of the last tx context with rows, they
+        // don't look up storage_snapshots
         let mut conn = self.dbconn()?;
-
-        let version: String = conn
-            .query_one(
-                "
-SELECT
-    quepasa_version
-FROM indexer_state
-            ",
+        self.legacy_snapshot_schemas = conn
+            .query(
+                r#"
+SELECT DISTINCT n.nspname
+FROM pg_proc p
+JOIN pg_namespace n
+  ON n.oid = p.pronamespace
+WHERE p.proname LIKE '%\_at'
+  AND p.prosrc LIKE '%latest_context_id%'
+  AND p.prosrc NOT LIKE '%storage_snapshots%'"#,
                 &[],
             )?
-            .get(0);
-        Ok(version)
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for schema in &self.legacy_snapshot_schemas {
+            warn!(
+                "contract {}: its snapshot functions predate --skip-unchanged-snapshots, all of its snapshots are stored",
+                schema
+            );
+        }
+        Ok(())
     }
 
-    pub(crate) fn create_common_tables(&mut self) -> Result<()> {
-        let mut conn = self.dbconn()?;
+    pub(crate) fn skips_unchanged_snapshots(
+        &self,
+        contract_schema: &str,
+    ) -> bool {
+        self.skip_unchanged_snapshots
+            && !self
+                .legacy_snapshot_schemas
+                .contains(contract_schema)
+    }
 
-        conn.simple_query(
-            format!(r#"CREATE SCHEMA IF NOT EXISTS "{}""#, self.main_schema)
-                .as_str(),
-        )?;
-        conn.simple_query(
-            PostgresqlGenerator::create_common_tables(&self.main_schema)
-                .as_str(),
-        )?;
-        Ok(())
+    pub(crate) fn set_inserter_cap(&mut self, inserter_cap: usize) {
+        self.inserter_cap = inserter_cap
     }
 
-    pub(crate) fn common_tables_exist(&mut self) -> Result<bool> {
-        let mut conn = self.dbconn()?;
+    pub(crate) fn get_inserter_cap(&self) -> usize {
+        self.inserter_cap
+    }
 
-        let res = conn.query_opt(
-            "
-SELECT 1
-FROM information_schema.tables
-WHERE table_schema = $1
-  AND table_name = 'levels'
-",
-            &[&self.main_schema],
-        )?;
-        Ok(res.is_some())
+    pub(crate) fn set_notify_channel(&mut self, channel: &str) {
+        self.notify_channel = Some(channel.to_string())
     }
 
-    pub(crate) fn repopulate_derived_tables(
-        &mut self,
-        contract: &relational::Contract,
+    /// Notifies the listeners of the notify channel (if one is set) with the
+    /// payloads. They are delivered once the transaction commits, and not at
+    /// all if it doesn't.
+    pub(crate) fn notify(
+        &self,
+        tx: &mut Transaction,
+        payloads: &[String],
     ) -> Result<()> {
-        let (mut tables, noview_prefixes, _): (
-            Vec<Table>,
-            Vec<String>,
-            Vec<String>,
-        ) = TableBuilder::tables_from_contract(contract);
-
-        tables.sort_by_key(|t| t.name.clone());
-
-        let mut conn = self.dbconn()?;
-        let mut tx = conn.transaction()?;
-        for (i, table) in tables.iter().enumerate() {
-            if !noview_prefixes
-                .iter()
-                .any(|prefix| table.name.starts_with(prefix))
-            {
-                info!(
-                    "repopulating {table} _live and _ordered ({contract} table {table_i}/~{table_total})",
-                    contract = contract.cid.name,
-                    table = table.name,
-                    table_i = i,
-                    table_total = tables.len(),
-                );
-                self.repopulate_derived_table(&mut tx, &contract.cid, table)?;
-            }
+        let channel = match &self.notify_channel {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        for payload in payloads {
+            tx.execute("SELECT pg_notify($1, $2)", &[channel, payload])?;
         }
-        tx.commit()?;
         Ok(())
     }
 
+    pub(crate) fn dbconn(&self) -> Result<DBPooledConn> {
+        let mut conn = self
+            .dbpool
+            .get()
+            .map_err(|err| anyhow!("err: {}", err))?;
+        conn.simple_query(
+            format!(r#"SET SCHEMA '{}'"#, self.main_schema).as_str(),
+        )?;
+        Ok(conn)
+    }
+
     fn repopulate_derived_table(
         &self,
         tx: &mut Transaction,
@@ -237,12 +281,14 @@ WHERE table_schema = $1
             Vec<String>,
         ) = TableBuilder::tables_from_contract(contract);
 
-        tables.sort_by_key(|t| t.name.clone());
+        // A snapshot table's _live table is derived from its parent table's
+        // _ordered table, so parent tables go first
+        tables.sort_by_key(|t| t.path().to_string());
 
         for table in &tables {
             if !noview_prefixes
                 .iter()
-                .any(|prefix| table.name.starts_with(prefix))
+                .any(|prefix| table.path().starts_with(prefix))
             {
                 self.update_derived_table(
                     tx,
@@ -297,104 +343,255 @@ WHERE table_schema = $1
         Ok(())
     }
 
-    pub(crate) fn create_contract_schemas(
-        &mut self,
-        contracts: &mut Vec<relational::Contract>,
-    ) -> Result<bool> {
-        let mut conn = self.dbconn()?;
-        let mut tx = conn.transaction()?;
-
-        contracts.sort_by_key(|c| c.cid.name.clone());
-
-        let num_columns = 2;
-        let v_refs = (1..(num_columns * contracts.len()) + 1)
-            .map(|i| format!("${}", i))
-            .collect::<Vec<String>>()
-            .chunks(num_columns)
-            .map(|x| x.join(", "))
-            .join("), (");
-        let stmt = tx.prepare(&format!(
-            "
-INSERT INTO contracts (name, address)
-VALUES ({})
-ON CONFLICT DO NOTHING
-RETURNING name",
-            v_refs
-        ))?;
+    fn create_table_stmnts(
+        generator: &PostgresqlGenerator,
+        contract_name: &str,
+        table: &Table,
+        noview_prefixes: &[String],
+        nofunctions_prefixes: &[String],
+    ) -> Result<Vec<String>> {
+        let mut stmnts = vec![generator.create_table_definition(table)?];
 
-        let values: Vec<&dyn postgres::types::ToSql> = contracts
+        if !noview_prefixes
             .iter()
-            .flat_map(|c| {
-                [c.cid.name.borrow_to_sql(), c.cid.address.borrow_to_sql()]
-            })
-            .collect();
-
-        let new_contracts = tx
-            .query_raw(&stmt, values)?
-            .map(|x| x.try_get(0))
-            .collect::<Vec<String>>()?;
-        if new_contracts.is_empty() {
-            tx.rollback()?;
-            return Ok(false);
+            .any(|prefix| table.path().starts_with(prefix))
+        {
+            stmnts.extend(generator.create_derived_table_definitions(table)?);
         }
-        let mut stmnts: Vec<String> = vec![];
-        for name in &new_contracts {
-            let contract = contracts
-                .iter()
-                .find(|c| &c.cid.name == name)
-                .unwrap();
-
-            let (mut tables, noview_prefixes, nofunctions_prefixes): (
-                Vec<Table>,
-                Vec<String>,
-                Vec<String>,
-            ) = TableBuilder::tables_from_contract(contract);
 
-            tables.sort_by_key(|t| t.name.clone());
+        if !nofunctions_prefixes
+            .iter()
+            .any(|prefix| table.path().starts_with(prefix))
+        {
+            stmnts.extend(
+                generator.create_table_functions(contract_name, table)?,
+            );
+        }
+        Ok(stmnts)
+    }
 
-            stmnts.push(format!(
-                r#"
-CREATE SCHEMA IF NOT EXISTS "{contract_schema}";
-"#,
-                contract_schema = contract.cid.name
-            ));
+    /// The type of the column as defined by column_sql (see
+    /// PostgresqlGenerator::create_sql), spelled the way
+    /// information_schema.columns does (eg "character varying(127)" for
+    /// VARCHAR(127)).
+    fn information_schema_type(column_name: &str, column_sql: &str) -> String {
+        column_sql
+            .trim_start_matches(&PostgresqlGenerator::quote_id(column_name))
+            .trim()
+            .to_lowercase()
+            .replace("varchar", "character varying")
+    }
 
-            let generator = PostgresqlGenerator::new(
-                self.main_schema.clone(),
-                &contract.cid,
-            );
+    /// Returns the hash of the contract's last snapshot before the given
+    /// tx context, along with the tx context its rows are stored under.
+    pub(crate) fn get_last_storage_snapshot(
+        tx: &mut Transaction,
+        before: &TxContext,
+    ) -> Result<Option<(String, i64)>> {
+        let res = tx.query_opt(
+            "
+SELECT
+    snapshot.hash,
+    COALESCE(snapshot.source_tx_context_id, snapshot.tx_context_id)
+FROM storage_snapshots snapshot
+JOIN tx_contexts ctx
+  ON ctx.id = snapshot.tx_context_id
+WHERE ctx.contract = $1
+  AND ctx.level <= $2
+  AND ARRAY[
+        ctx.level,
+        ctx.operation_group_number,
+        ctx.operation_number,
+        ctx.content_number,
+        COALESCE(ctx.internal_number, -1)]
+      <
+      ARRAY[$2, $3, $4, $5, COALESCE($6, -1)]
+ORDER BY
+    ctx.level DESC,
+    ctx.operation_group_number DESC,
+    ctx.operation_number DESC,
+    ctx.content_number DESC,
+    COALESCE(ctx.internal_number, -1) DESC
+LIMIT 1",
+            &[
+                &before.contract,
+                &(before.level as i32),
+                &(before.operation_group_number as i32),
+                &(before.operation_number as i32),
+                &(before.content_number as i32),
+                &before.internal_number,
+            ],
+        )?;
+        Ok(res.map(|row| (row.get(0), row.get(1))))
+    }
 
-            for table in &tables {
-                let table_def = generator.create_table_definition(table)?;
-                stmnts.push(table_def);
+    /// Saves the hashes of the snapshots, and for each skipped snapshot the
+    /// tx context of the snapshot it is the same as.
+    pub(crate) fn save_storage_snapshots(
+        tx: &mut Transaction,
+        snapshots: &[(i64, String, Option<i64>)],
+    ) -> Result<()> {
+        let stmt = tx.prepare(
+            "
+INSERT INTO storage_snapshots (tx_context_id, hash, source_tx_context_id)
+VALUES ($1, $2, $3)",
+        )?;
+        for (tx_context_id, hash, source) in snapshots {
+            tx.execute(&stmt, &[tx_context_id, hash, source])?;
+        }
+        Ok(())
+    }
 
-                if !noview_prefixes
-                    .iter()
-                    .any(|prefix| table.name.starts_with(prefix))
-                {
-                    for derived_table_def in
-                        generator.create_derived_table_definitions(table)?
-                    {
-                        stmnts.push(derived_table_def);
-                    }
-                }
+    /// Prepares the tx contexts for their deletion, so that the snapshots
+    /// stored as a reference to one of them keep their rows: the rows are
+    /// moved to the first of the snapshots referencing it (they are the same
+    /// snapshot), and the other references are pointed to that one instead.
+    fn detach_storage_snapshots(
+        tx: &mut Transaction,
+        tx_context_ids: &[i64],
+    ) -> Result<()> {
+        tx.execute(
+            "DELETE FROM storage_snapshots WHERE tx_context_id = ANY($1)",
+            &[&tx_context_ids],
+        )?;
+        let moves: Vec<(i64, i64, String)> = tx
+            .query(
+                "
+SELECT DISTINCT ON (snapshot.source_tx_context_id)
+    snapshot.source_tx_context_id,
+    snapshot.tx_context_id,
+    contract.name
+FROM storage_snapshots snapshot
+JOIN tx_contexts ctx
+  ON ctx.id = snapshot.tx_context_id
+JOIN contracts contract
+  ON contract.address = ctx.contract
+WHERE snapshot.source_tx_context_id = ANY($1)
+ORDER BY
+    snapshot.source_tx_context_id,
+    ctx.level,
+    ctx.operation_group_number,
+    ctx.operation_number,
+    ctx.content_number,
+    COALESCE(ctx.internal_number, -1)",
+                &[&tx_context_ids],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
 
-                if !nofunctions_prefixes
+        // The snapshot tables whose rows are skipped are those with derived
+        // tables, other than the big map changes tables (see
+        // snapshots::skip_unchanged_snapshots)
+        let mut snapshot_tables: HashMap<String, Vec<String>> = HashMap::new();
+        for (source, first_ref, contract) in &moves {
+            let tables = match snapshot_tables.entry(contract.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    tx.query(
+                        "
+SELECT t.table_name
+FROM information_schema.tables t
+JOIN information_schema.tables live
+  ON live.table_schema = t.table_schema
+ AND live.table_name = t.table_name || '_live'
+WHERE t.table_schema = $1
+  AND NOT EXISTS (
+    SELECT 1
+    FROM information_schema.columns col
+    WHERE col.table_schema = t.table_schema
+      AND col.table_name = t.table_name
+      AND col.column_name = 'bigmap_id'
+  )",
+                        &[contract],
+                    )?
                     .iter()
-                    .any(|prefix| table.name.starts_with(prefix))
-                {
-                    let function_def = generator
-                        .create_table_functions(&contract.cid.name, table)?;
-                    stmnts.extend(function_def);
-                }
+                    .map(|row| row.get(0))
+                    .collect(),
+                ),
+            };
+            for table in tables.iter() {
+                tx.execute(
+                    format!(
+                        r#"UPDATE "{}"."{}" SET tx_context_id = $1 WHERE tx_context_id = $2"#,
+                        contract, table
+                    )
+                    .as_str(),
+                    &[first_ref, source],
+                )?;
             }
+            tx.execute(
+                "
+UPDATE storage_snapshots
+SET source_tx_context_id = CASE
+        WHEN tx_context_id = $1 THEN NULL
+        ELSE $1
+    END
+WHERE source_tx_context_id = $2",
+                &[first_ref, source],
+            )?;
         }
-        for stmnt in stmnts {
-            tx.simple_query(stmnt.as_str())?;
+        Ok(())
+    }
+
+    /// Describes the contract's schema in the catalog tables. Rows are
+    /// upserted, so that tables and columns added for other versions of the
+    /// contract's storage type are described as well.
+    fn save_catalog(
+        tx: &mut Transaction,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let (tables, columns) = catalog::contract_catalog(contract);
+
+        let stmt = tx.prepare(
+            "
+INSERT INTO catalog_tables (contract, table_name, path, kind, parent_table, parent_fk_column)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (contract, table_name) DO UPDATE
+SET path = EXCLUDED.path,
+    kind = EXCLUDED.kind,
+    parent_table = EXCLUDED.parent_table,
+    parent_fk_column = EXCLUDED.parent_fk_column",
+        )?;
+        for table in &tables {
+            tx.execute(
+                &stmt,
+                &[
+                    &contract.cid.name,
+                    &table.table_name,
+                    &table.path,
+                    &table.kind,
+                    &table.parent_table,
+                    &table.parent_fk_column,
+                ],
+            )?;
         }
-        tx.commit()?;
 
-        Ok(true)
+        let stmt = tx.prepare(
+            "
+INSERT INTO catalog_columns (contract, table_name, column_name, original_name, michelson_type, annotation, is_index)
+VALUES ($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT (contract, table_name, column_name) DO UPDATE
+SET original_name = EXCLUDED.original_name,
+    michelson_type = EXCLUDED.michelson_type,
+    annotation = EXCLUDED.annotation,
+    is_index = EXCLUDED.is_index",
+        )?;
+        for column in &columns {
+            tx.execute(
+                &stmt,
+                &[
+                    &contract.cid.name,
+                    &column.table_name,
+                    &column.column_name,
+                    &column.original_name,
+                    &column.michelson_type,
+                    &column.annotation,
+                    &column.is_index,
+                ],
+            )?;
+        }
+        Ok(())
     }
 
     pub(crate) fn delete_contract_schema(
@@ -413,7 +610,7 @@ CREATE SCHEMA IF NOT EXISTS "{contract_schema}";
         for table in &tables {
             if !nofunctions_prefixes
                 .iter()
-                .any(|prefix| table.name.starts_with(prefix))
+                .any(|prefix| table.path().starts_with(prefix))
             {
                 tx.simple_query(
                     format!(
@@ -439,7 +636,7 @@ DROP FUNCTION IF EXISTS "{contract_schema}"."{table}_at(INT)";
 
             if !noview_prefixes
                 .iter()
-                .any(|prefix| table.name.starts_with(prefix))
+                .any(|prefix| table.path().starts_with(prefix))
             {
                 tx.simple_query(
                     format!(
@@ -510,14 +707,34 @@ DROP TABLE "{contract_schema}"."{table}";
         tx: &mut Transaction,
         bigmap_keyhashes: BigmapEntries,
     ) -> Result<()> {
-        for chunk in bigmap_keyhashes
+        let bigmap_keyhashes = bigmap_keyhashes
             .into_iter()
             .collect::<Vec<(
                 (i32, TxContext, String),
                 (serde_json::Value, Option<serde_json::Value>),
-            )>>()
-            .chunks(Self::INSERT_BATCH_SIZE)
+            )>>();
+        if bigmap_keyhashes.len() >= Self::COPY_MIN_ROWS
+            && Self::copy_rows(
+                tx,
+                "bigmap_keys",
+                &["tx_context_id", "bigmap_id", "keyhash", "key", "value"],
+                bigmap_keyhashes
+                    .iter()
+                    .map(|((bigmap_id, tx_context, keyhash), (key, value))| {
+                        vec![
+                            tx_context.id.borrow_to_sql(),
+                            bigmap_id.borrow_to_sql(),
+                            keyhash.borrow_to_sql(),
+                            key.borrow_to_sql(),
+                            value.borrow_to_sql(),
+                        ]
+                    })
+                    .collect(),
+            )?
         {
+            return Ok(());
+        }
+        for chunk in bigmap_keyhashes.chunks(Self::INSERT_BATCH_SIZE) {
             let num_columns = 5;
             let v_refs = (1..(num_columns * chunk.len()) + 1)
                 .map(|i| format!("${}", i))
@@ -565,7 +782,64 @@ Values ({})",
             pub content_number: i32,
             pub internal_number: Option<i32>,
         }
-        for chunk in tx_contexts.chunks(Self::INSERT_BATCH_SIZE) {
+        let tx_contexts_pg: Vec<TxContextPG> = tx_contexts
+            .iter()
+            .map(|tx_context| TxContextPG {
+                id: tx_context
+                    .id
+                    .ok_or_else(|| anyhow!("Missing ID on TxContext"))
+                    .unwrap(),
+                level: tx_context.level as i32,
+                contract: tx_context.contract.clone(),
+                operation_group_number: tx_context.operation_group_number
+                    as i32,
+                operation_number: tx_context.operation_number as i32,
+                content_number: tx_context.content_number as i32,
+                internal_number: tx_context.internal_number,
+            })
+            .collect();
+        fn row(tx_context: &TxContextPG) -> [&dyn ToSql; 7] {
+            [
+                tx_context.id.borrow_to_sql(),
+                tx_context.level.borrow_to_sql(),
+                tx_context.contract.borrow_to_sql(),
+                tx_context
+                    .operation_group_number
+                    .borrow_to_sql(),
+                tx_context
+                    .operation_number
+                    .borrow_to_sql(),
+                tx_context
+                    .content_number
+                    .borrow_to_sql(),
+                tx_context
+                    .internal_number
+                    .borrow_to_sql(),
+            ]
+        }
+
+        if tx_contexts_pg.len() >= Self::COPY_MIN_ROWS
+            && Self::copy_rows(
+                tx,
+                "tx_contexts",
+                &[
+                    "id",
+                    "level",
+                    "contract",
+                    "operation_group_number",
+                    "operation_number",
+                    "content_number",
+                    "internal_number",
+                ],
+                tx_contexts_pg
+                    .iter()
+                    .map(|tx_context| row(tx_context).to_vec())
+                    .collect(),
+            )?
+        {
+            return Ok(());
+        }
+        for chunk in tx_contexts_pg.chunks(Self::INSERT_BATCH_SIZE) {
             let num_columns = 7;
             let v_refs = (1..(num_columns * chunk.len()) + 1)
                 .map(|i| format!("${}", i))
@@ -588,46 +862,8 @@ VALUES ( {} )",
                 v_refs
             ))?;
 
-            let tx_contexts_pg: Vec<TxContextPG> = chunk
-                .iter()
-                .map(|tx_context| TxContextPG {
-                    id: tx_context
-                        .id
-                        .ok_or_else(|| anyhow!("Missing ID on TxContext"))
-                        .unwrap(),
-                    level: tx_context.level as i32,
-                    contract: tx_context.contract.clone(),
-                    operation_group_number: tx_context.operation_group_number
-                        as i32,
-                    operation_number: tx_context.operation_number as i32,
-                    content_number: tx_context.content_number as i32,
-                    internal_number: tx_context
-                        .internal_number
-                        .map(|n| n as i32),
-                })
-                .collect();
-            let values: Vec<&dyn postgres::types::ToSql> = tx_contexts_pg
-                .iter()
-                .flat_map(|tx_context| {
-                    [
-                        tx_context.id.borrow_to_sql(),
-                        tx_context.level.borrow_to_sql(),
-                        tx_context.contract.borrow_to_sql(),
-                        tx_context
-                            .operation_group_number
-                            .borrow_to_sql(),
-                        tx_context
-                            .operation_number
-                            .borrow_to_sql(),
-                        tx_context
-                            .content_number
-                            .borrow_to_sql(),
-                        tx_context
-                            .internal_number
-                            .borrow_to_sql(),
-                    ]
-                })
-                .collect();
+            let values: Vec<&dyn postgres::types::ToSql> =
+                chunk.iter().flat_map(row).collect();
 
             tx.query_raw(&stmt, values)?;
         }
@@ -636,6 +872,49 @@ VALUES ( {} )",
     }
 
     pub(crate) fn save_txs(tx: &mut Transaction, txs: &[Tx]) -> Result<()> {
+        fn row(tx: &Tx) -> [&dyn ToSql; 12] {
+            [
+                tx.tx_context_id.borrow_to_sql(),
+                tx.operation_hash.borrow_to_sql(),
+                tx.source.borrow_to_sql(),
+                tx.destination.borrow_to_sql(),
+                tx.entrypoint.borrow_to_sql(),
+                tx.amount.borrow_to_sql(),
+                tx.fee.borrow_to_sql(),
+                tx.gas_limit.borrow_to_sql(),
+                tx.storage_limit.borrow_to_sql(),
+                tx.consumed_milligas.borrow_to_sql(),
+                tx.storage_size.borrow_to_sql(),
+                tx.paid_storage_size_diff
+                    .borrow_to_sql(),
+            ]
+        }
+
+        if txs.len() >= Self::COPY_MIN_ROWS
+            && Self::copy_rows(
+                tx,
+                "txs",
+                &[
+                    "tx_context_id",
+                    "operation_hash",
+                    "source",
+                    "destination",
+                    "entrypoint",
+                    "amount",
+                    "fee",
+                    "gas_limit",
+                    "storage_limit",
+                    "consumed_milligas",
+                    "storage_size",
+                    "paid_storage_size_diff",
+                ],
+                txs.iter()
+                    .map(|tx| row(tx).to_vec())
+                    .collect(),
+            )?
+        {
+            return Ok(());
+        }
         for txs_chunk in txs.chunks(Self::INSERT_BATCH_SIZE) {
             let num_columns = 12;
             let v_refs = (1..(num_columns * txs_chunk.len()) + 1)
@@ -667,26 +946,8 @@ VALUES ( {} )",
                 v_refs
             ))?;
 
-            let values: Vec<&dyn postgres::types::ToSql> = txs_chunk
-                .iter()
-                .flat_map(|tx| {
-                    [
-                        tx.tx_context_id.borrow_to_sql(),
-                        tx.operation_hash.borrow_to_sql(),
-                        tx.source.borrow_to_sql(),
-                        tx.destination.borrow_to_sql(),
-                        tx.entrypoint.borrow_to_sql(),
-                        tx.amount.borrow_to_sql(),
-                        tx.fee.borrow_to_sql(),
-                        tx.gas_limit.borrow_to_sql(),
-                        tx.storage_limit.borrow_to_sql(),
-                        tx.consumed_milligas.borrow_to_sql(),
-                        tx.storage_size.borrow_to_sql(),
-                        tx.paid_storage_size_diff
-                            .borrow_to_sql(),
-                    ]
-                })
-                .collect();
+            let values: Vec<&dyn postgres::types::ToSql> =
+                txs_chunk.iter().flat_map(row).collect();
 
             tx.query_raw(&stmt, values)?;
         }
@@ -694,11 +955,13 @@ VALUES ( {} )",
         Ok(())
     }
 
-    pub(crate) fn apply_inserts(
-        tx: &mut postgres::Transaction,
-        contract_id: &ContractID,
+    /// Groups the contract's inserts into the rows to write per table (see
+    /// write_rows). Doesn't touch the database, so that the rows of
+    /// different contracts can be built concurrently.
+    pub(crate) fn build_rows(
+        contract: &relational::Contract,
         inserts: &[Insert],
-    ) -> Result<()> {
+    ) -> Result<Vec<TableRows>> {
         let mut table_grouped: HashMap<(String, Vec<String>), Vec<&Insert>> =
             HashMap::new();
         for insert in inserts {
@@ -721,120 +984,68 @@ VALUES ( {} )",
         let mut keys: Vec<&(String, Vec<String>)> =
             table_grouped.keys().collect();
         keys.sort();
+
+        let mut res: Vec<TableRows> = vec![];
         for k in keys {
             let table_inserts = table_grouped.get(k).unwrap();
-            for chunk in table_inserts.chunks(Self::INSERT_BATCH_SIZE) {
-                Self::apply_inserts_for_table(tx, contract_id, chunk)?;
-            }
-        }
-        Ok(())
-    }
-
-    pub(crate) fn get_config_deps(
-        &mut self,
-        config: &[ContractID],
-    ) -> Result<Vec<ContractID>> {
-        if config.is_empty() {
-            return Ok(vec![]);
-        }
-        let v_refs = (0..config.len())
-            .map(|i| format!("${}", (i + 1)))
-            .collect::<Vec<String>>()
-            .join(", ");
-
-        let mut conn = self.dbconn()?;
-
-        let mut it = conn.query_raw(
-            format!(
-                "
-SELECT DISTINCT
-    src_contract
-FROM contract_deps
-WHERE dest_schema IN ({})
-",
-                v_refs
-            )
-            .as_str(),
-            config
+            let table_name = &table_inserts[0].table_name;
+            let parent_name = match contract.tables.get(table_name) {
+                Some(t) => t.parent.clone(),
+                None => PostgresqlGenerator::parent_name(table_name),
+            };
+            let rows: Vec<Vec<Column>> = table_inserts
                 .iter()
-                .map(|c| c.name.borrow_to_sql())
-                .collect::<Vec<&dyn ToSql>>(),
-        )?;
-        let mut res: Vec<ContractID> = vec![];
-        while let Some(row) = it.next()? {
-            res.push(ContractID {
-                address: row.get(0),
-                name: row.get(0),
+                .map(|insert| {
+                    insert.get_columns_with_parent(parent_name.as_deref())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            res.push(TableRows {
+                contract_schema: contract.cid.name.clone(),
+                table: table_name.clone(),
+                columns: rows[0]
+                    .iter()
+                    .map(|x| x.name.clone())
+                    .collect(),
+                rows,
             });
         }
-        Ok(res
-            .into_iter()
-            .filter(|dep| {
-                !config
-                    .iter()
-                    .any(|c| c.address == dep.address)
-            })
-            .collect())
+        Ok(res)
     }
 
-    pub(crate) fn get_dependent_levels(
-        &mut self,
-        config: &[ContractID],
-    ) -> Result<Vec<u32>> {
-        if config.is_empty() {
-            return Ok(vec![]);
-        }
-
-        let v_refs = (0..config.len())
-            .map(|i| format!("${}", (i + 1)))
-            .collect::<Vec<String>>()
-            .join(", ");
-
-        let mut conn = self.dbconn()?;
-
-        let mut it = conn.query_raw(
-            format!(
-                "
-SELECT DISTINCT
-    level
-FROM contract_deps
-WHERE dest_schema IN ({})
-  AND is_deep_copy
-",
-                v_refs
-            )
-            .as_str(),
-            config
-                .iter()
-                .map(|c| c.name.borrow_to_sql())
-                .collect::<Vec<&dyn ToSql>>(),
-        )?;
-        let mut res: Vec<i32> = vec![];
-        while let Some(row) = it.next()? {
-            res.push(row.get(0));
+    pub(crate) fn write_rows(
+        tx: &mut postgres::Transaction,
+        tables_rows: &[TableRows],
+    ) -> Result<()> {
+        for table_rows in tables_rows {
+            if table_rows.rows.len() >= Self::COPY_MIN_ROWS
+                && Self::copy_rows_for_table(tx, table_rows)?
+            {
+                continue;
+            }
+            for chunk in table_rows
+                .rows
+                .chunks(Self::INSERT_BATCH_SIZE)
+            {
+                Self::apply_inserts_for_table(tx, table_rows, chunk)?;
+            }
         }
-        Ok(res
-            .into_iter()
-            .map(|x| x as u32)
-            .collect())
+        Ok(())
     }
 
     pub(crate) fn apply_inserts_for_table(
         tx: &mut postgres::Transaction,
-        contract_id: &ContractID,
-        inserts: &[&Insert],
+        table_rows: &TableRows,
+        rows: &[Vec<Column>],
     ) -> Result<()> {
-        let meta = &inserts[0];
-
-        let columns = inserts[0].get_columns()?;
+        let columns = &table_rows.columns;
 
         let v_names: String = columns
             .iter()
-            .map(|x| PostgresqlGenerator::quote_id(&x.name))
+            .map(|x| PostgresqlGenerator::quote_id(x))
             .collect::<Vec<String>>()
             .join(", ");
 
-        let v_refs = (1..(columns.len() * inserts.len()) + 1)
+        let v_refs = (1..(columns.len() * rows.len()) + 1)
             .map(|i| format!("${}", i))
             .collect::<Vec<String>>()
             .chunks(columns.len())
@@ -845,133 +1056,121 @@ WHERE dest_schema IN ({})
             r#"
 INSERT INTO "{contract_schema}"."{table}" ( {v_names} )
 VALUES ( {v_refs} )"#,
-            contract_schema = contract_id.name,
-            table = meta.table_name,
+            contract_schema = table_rows.contract_schema,
+            table = table_rows.table,
             v_names = v_names,
             v_refs = v_refs,
         );
         let stmt = tx.prepare(qry.as_str())?;
 
-        let all_columns: Vec<Column> = inserts
-            .iter()
-            .map(|insert| insert.get_columns())
-            .collect::<Result<Vec<_>>>()?
-            .iter()
-            .flatten()
-            .cloned()
-            .collect();
-
         debug!(
             "qry: {}, values: {:#?}",
             qry,
-            all_columns
-                .iter()
-                .cloned()
-                .map(|x| x.value)
-                .collect::<Vec<Value>>()
+            rows.iter()
+                .flatten()
+                .map(|x| &x.value)
+                .collect::<Vec<&Value>>()
         );
 
-        let values: Vec<&dyn postgres::types::ToSql> = all_columns
+        let values: Vec<&dyn postgres::types::ToSql> = rows
             .iter()
+            .flatten()
             .map(|x| x.value.borrow_to_sql())
             .collect();
         tx.query_raw(&stmt, values)?;
         Ok(())
     }
 
-    pub(crate) fn delete_everything<F>(
-        &mut self,
-        node_cli: &NodeClient,
-        get_contract_rel: F,
-    ) -> Result<()>
-    where
-        F: Fn(&NodeClient, &ContractID) -> Result<relational::Contract>,
-    {
-        let mut conn = self.dbconn()?;
-
-        let main_schema = self.main_schema.clone();
-        let mut tx = conn.transaction()?;
+    /// Same as apply_inserts_for_table, with the rows bulk loaded (see
+    /// copy_rows)
+    fn copy_rows_for_table(
+        tx: &mut postgres::Transaction,
+        table_rows: &TableRows,
+    ) -> Result<bool> {
+        Self::copy_rows(
+            tx,
+            &format!(
+                r#""{}"."{}""#,
+                table_rows.contract_schema, table_rows.table
+            ),
+            &table_rows
+                .columns
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<&str>>(),
+            table_rows
+                .rows
+                .iter()
+                .map(|columns| {
+                    columns
+                        .iter()
+                        .map(|x| x.value.borrow_to_sql())
+                        .collect()
+                })
+                .collect(),
+        )
+    }
 
-        let contracts_table = tx.query_opt(
-            "
-SELECT
-    1
-FROM information_schema.tables
-WHERE table_schema = $1
-  AND table_name = 'contracts'
-",
-            &[&main_schema],
-        )?;
-        if contracts_table.is_some() {
-            for row in tx.query("SELECT name, address FROM contracts", &[])? {
-                let contract_id = ContractID {
-                    name: row.get(0),
-                    address: row.get(1),
-                };
-                let contract = get_contract_rel(node_cli, &contract_id)?;
-                Self::delete_contract_schema(&mut tx, &contract)?
+    /// Bulk loads rows into a table with a binary COPY, which is a lot
+    /// faster than INSERTs when there are many rows. Returns whether the
+    /// rows were loaded. If the COPY failed nothing is loaded, and the rows
+    /// are to be inserted with INSERTs instead.
+    fn copy_rows(
+        tx: &mut Transaction,
+        table: &str,
+        columns: &[&str],
+        rows: Vec<Vec<&dyn ToSql>>,
+    ) -> Result<bool> {
+        // In a savepoint, a failed COPY doesn't abort the whole transaction
+        let mut savepoint = tx.transaction()?;
+        match Self::copy_in(&mut savepoint, table, columns, rows) {
+            Ok(()) => {
+                savepoint.commit()?;
+                Ok(true)
+            }
+            Err(err) => {
+                warn!(
+                    "bulk load into {} failed, falling back to inserts: {:?}",
+                    table, err
+                );
+                savepoint.rollback()?;
+                Ok(false)
             }
         }
-        tx.simple_query(
-            "
-DROP FUNCTION IF EXISTS last_context_at(INT, INT, INT, INT, INT);
-DROP FUNCTION IF EXISTS last_context_at(INT, INT, INT, INT);
-DROP FUNCTION IF EXISTS last_context_at(INT, INT, INT);
-DROP FUNCTION IF EXISTS last_context_at(INT, INT);
-DROP FUNCTION IF EXISTS last_context_at(INT);
-DROP TABLE IF EXISTS bigmap_keys;
-DROP TABLE IF EXISTS contract_deps;
-DROP TABLE IF EXISTS bigmap_meta_actions;
-DROP VIEW  IF EXISTS txs_ordered;
-DROP TABLE IF EXISTS txs;
-DROP TABLE IF EXISTS tx_contexts;
-DROP TABLE IF EXISTS indexer_state;
-DROP TYPE  IF EXISTS indexer_mode;
-DROP TABLE IF EXISTS contract_levels;
-DROP TABLE IF EXISTS contracts;
-DROP TABLE IF EXISTS levels;
-",
-        )?;
-        tx.commit()?;
-        Ok(())
-    }
-
-    pub(crate) fn mark_missing_levels_empty(
-        &mut self,
-        contract_id: &ContractID,
-    ) -> Result<u64> {
-        let mut conn = self.dbconn()?;
-
-        Ok(conn.execute(
-            "
-INSERT INTO contract_levels(contract, level)
-SELECT $1, q.level
-FROM (
-    SELECT
-        g.level
-    FROM GENERATE_SERIES(
-        (SELECT MIN(level) FROM contract_levels WHERE contract = $1),
-        (SELECT MAX(level) FROM contract_levels WHERE contract = $1)
-    ) AS g(level)
-    LEFT JOIN contract_levels clvl
-      ON  clvl.contract = $1
-      AND clvl.level = g.level
-    WHERE clvl IS NULL
-) q
-",
-            &[&contract_id.name],
-        )?)
     }
 
-    pub(crate) fn get_head(&mut self) -> Result<Option<LevelMeta>> {
-        self.get_level_internal(None)
-    }
+    fn copy_in(
+        tx: &mut Transaction,
+        table: &str,
+        columns: &[&str],
+        rows: Vec<Vec<&dyn ToSql>>,
+    ) -> Result<()> {
+        let column_names = columns
+            .iter()
+            .map(|name| PostgresqlGenerator::quote_id(name))
+            .join(", ");
+        // The binary format is typed, the types are those of the table's
+        // columns
+        let types: Vec<Type> = tx
+            .prepare(&format!(
+                "SELECT {} FROM {} LIMIT 0",
+                column_names, table
+            ))?
+            .columns()
+            .iter()
+            .map(|col| col.type_().clone())
+            .collect();
 
-    pub(crate) fn get_level(
-        &mut self,
-        level: u32,
-    ) -> Result<Option<LevelMeta>> {
-        self.get_level_internal(Some(level as i32))
+        let writer = tx.copy_in(
+            format!("COPY {} ({}) FROM STDIN BINARY", table, column_names)
+                .as_str(),
+        )?;
+        let mut writer = BinaryCopyInWriter::new(writer, &types);
+        for row in rows {
+            writer.write_raw(row)?;
+        }
+        writer.finish()?;
+        Ok(())
     }
 
     fn get_level_internal(
@@ -1007,115 +1206,17 @@ WHERE ($1::INTEGER IS NULL AND level = (SELECT max(level) FROM levels)) OR level
         }))
     }
 
-    pub(crate) fn get_missing_levels(
-        &mut self,
-        contracts: &[ContractID],
-        end: u32,
-    ) -> Result<Vec<u32>> {
-        let mut conn = self.dbconn()?;
-
-        let mut rows: Vec<i32> = vec![];
-        for contract_id in contracts {
-            info!(
-                "querying db to check for any missing levels of {}..",
-                contract_id.name
-            );
-            let origination = self.get_origination(contract_id)?;
-            let start = origination.unwrap_or(1);
-            for row in conn.query(
-                format!(
-                    "
-SELECT
-    s.i
-FROM generate_series({},{}) s(i)
-LEFT JOIN contract_levels clvl
-  ON  clvl.contract = $1
-  AND clvl.level = s.i
-WHERE clvl IS NULL
-ORDER BY 1",
-                    start, end
-                )
-                .as_str(),
-                &[&contract_id.name],
-            )? {
-                rows.push(row.get(0));
-            }
-        }
-        rows.sort_unstable();
-        rows.dedup();
-        Ok(rows
-            .iter()
-            .map(|x| *x as u32)
-            .collect::<Vec<u32>>())
+    /// Gets the max id, locking it until the transaction ends. Used to hand
+    /// out ids to concurrent inserters without them overlapping.
+    pub(crate) fn lock_max_id(tx: &mut Transaction) -> Result<i64> {
+        let max_id: i64 = tx
+            .query_one("select max_id from indexer_state for update", &[])?
+            .get(0);
+        Ok(max_id)
     }
 
-    pub(crate) fn get_forked_levels(&mut self) -> Result<Vec<u32>> {
-        let mut conn = self.dbconn()?;
-
-        let mut rows: Vec<i32> = vec![];
-        for row in conn.query(
-            "
-SELECT DISTINCT
-  level
-FROM (
-  SELECT
-    level,
-    prev_hash AS chain_prev_hash,
-    LAG(level) OVER w as db_prev_level,
-    LAG(hash) OVER w AS db_prev_hash
-  FROM levels
-  WINDOW w AS (ORDER BY level)
-) q
-WHERE chain_prev_hash != db_prev_hash
-  AND db_prev_level = level - 1",
-            &[],
-        )? {
-            rows.push(row.get(0));
-        }
-        Ok(rows
-            .iter()
-            .map(|x| *x as u32)
-            .collect::<Vec<u32>>())
-    }
-
-    pub(crate) fn get_indexer_mode(&mut self) -> Result<IndexerMode> {
-        let mut conn = self.dbconn()?;
-
-        let mode: IndexerMode = conn
-            .query_one("select mode from indexer_state", &[])?
-            .get(0);
-        Ok(mode)
-    }
-
-    pub(crate) fn set_indexer_mode(&mut self, mode: IndexerMode) -> Result<()> {
-        let mut conn = self.dbconn()?;
-
-        let updated = conn.execute(
-            "
-update indexer_state
-set mode = $1",
-            &[&mode],
-        )?;
-        if updated == 1 {
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "wrong number of rows in indexer_state table. please fix manually. sorry"
-            ))
-        }
-    }
-
-    pub(crate) fn get_max_id(&mut self) -> Result<i64> {
-        let mut conn = self.dbconn()?;
-
-        let max_id: i64 = conn
-            .query_one("select max_id from indexer_state", &[])?
-            .get(0);
-        Ok(max_id)
-    }
-
-    pub(crate) fn set_max_id(tx: &mut Transaction, max_id: i64) -> Result<()> {
-        let updated = tx.execute(
+    pub(crate) fn set_max_id(tx: &mut Transaction, max_id: i64) -> Result<()> {
+        let updated = tx.execute(
             "
 update indexer_state
 set max_id = $1",
@@ -1130,88 +1231,55 @@ set max_id = $1",
         }
     }
 
-    pub(crate) fn get_fully_processed_levels(
-        &mut self,
-        contracts: &[ContractID],
-    ) -> Result<Vec<u32>> {
-        let mut conn = self.dbconn()?;
-
-        let fully_processed: Vec<u32> = conn
-            .query(
-                "
-SELECT
-    level
-FROM contract_levels
-WHERE contract = ANY($1)
-GROUP by 1
-HAVING COUNT(1) = array_length($1, 1)
-ORDER by 1",
-                &[&contracts
-                    .iter()
-                    .map(|c| &c.name)
-                    .collect::<Vec<&String>>()],
-            )?
-            .iter()
-            .map(|row| row.get(0))
-            .map(|lvl: i32| lvl as u32)
-            .collect();
-        Ok(fully_processed)
-    }
-
-    pub(crate) fn get_partial_processed_levels(
-        &mut self,
+    /// Deletes the data previously saved for the levels, so that they can
+    /// be saved again (see save_levels). With contracts_scoped set, only the
+    /// given contracts' data in the levels is deleted, that of all other
+    /// contracts is left as is.
+    /// Clears the levels before they are saved again, returns what was
+    /// actually cleared: the levels that were saved before, or with
+    /// contracts_scoped the contracts' levels that were
+    pub(crate) fn clear_levels(
+        tx: &mut Transaction,
+        contracts_scoped: bool,
         contracts: &[ContractID],
-    ) -> Result<Vec<u32>> {
-        let mut conn = self.dbconn()?;
-
-        let partial_processed: Vec<u32> = conn
-            .query(
-                "
-with all_levels as (
-    select distinct
-        level
-    from contract_levels
-    where contract = any($1)
-)
-select distinct
-    lvl.level
-from all_levels lvl, contracts c
-left join contract_levels orig
-  on  orig.contract = c.name
-  and orig.is_origination
-where lvl.level >= coalesce(orig.level, 0)
-  and c.name = any($1)
-  and not exists (
-    select 1
-    from contract_levels clvl
-    where clvl.level = lvl.level
-      and clvl.contract = c.name
-)
-order by 1",
-                &[&contracts
-                    .iter()
-                    .map(|c| &c.name)
-                    .collect::<Vec<&String>>()],
-            )?
+        levels: &[&LevelMeta],
+    ) -> Result<Vec<ClearedLevel>> {
+        let levels: Vec<i32> = levels
             .iter()
-            .map(|row| row.get(0))
-            .map(|lvl: i32| lvl as u32)
+            .map(|meta| meta.level as i32)
             .collect();
-        Ok(partial_processed)
+        if contracts_scoped {
+            Self::delete_contracts_levels(tx, contracts, &levels)
+        } else {
+            Ok(Self::delete_levels(tx, &levels)?
+                .into_iter()
+                .map(|level| ClearedLevel {
+                    level: level as u32,
+                    contract: None,
+                })
+                .collect())
+        }
     }
 
     pub(crate) fn save_levels(
         tx: &mut Transaction,
+        contracts_scoped: bool,
         levels: &[&LevelMeta],
     ) -> Result<()> {
-        Self::delete_levels(
-            tx,
-            &levels
-                .iter()
-                .map(|meta| meta.level as i32)
-                .collect::<Vec<i32>>(),
-        )?;
+        // (with contracts_scoped set, the levels may be kept for the data
+        // of other contracts)
+        let on_conflict = match contracts_scoped {
+            true => "ON CONFLICT DO NOTHING",
+            false => "",
+        };
+        Self::insert_levels(tx, levels, on_conflict)
+    }
 
+    fn insert_levels(
+        tx: &mut Transaction,
+        levels: &[&LevelMeta],
+        on_conflict: &str,
+    ) -> Result<()> {
         for lvls_chunk in levels.chunks(Self::INSERT_BATCH_SIZE) {
             let num_columns = 4;
             let v_refs = (1..(num_columns * lvls_chunk.len()) + 1)
@@ -1225,8 +1293,9 @@ order by 1",
 INSERT INTO levels(
     level, hash, prev_hash, baked_at
 )
-VALUES ( {} )",
-                v_refs
+VALUES ( {} )
+{}",
+                v_refs, on_conflict
             ))?;
 
             #[allow(clippy::type_complexity)]
@@ -1264,10 +1333,23 @@ VALUES ( {} )",
         Ok(())
     }
 
+    /// Returns the levels that were deleted (those of the levels that were
+    /// saved)
     pub(crate) fn delete_levels(
         tx: &mut Transaction,
         levels: &[i32],
-    ) -> Result<()> {
+    ) -> Result<Vec<i32>> {
+        let tx_context_ids: Vec<i64> = tx
+            .query(
+                "SELECT id FROM tx_contexts WHERE level = ANY($1)",
+                &[&levels],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        Self::detach_storage_snapshots(tx, &tx_context_ids)?;
+
+        let mut deleted: Vec<i32> = vec![];
         for lvls_chunk in levels.chunks(Self::INSERT_BATCH_SIZE) {
             let v_refs = (1..lvls_chunk.len() + 1)
                 .map(|i| format!("${}", i))
@@ -1308,12 +1390,73 @@ WHERE level IN ( {} )
                 "
 DELETE FROM levels
 WHERE level IN ( {} )
+RETURNING level
 ",
                 v_refs
             ))?;
-            tx.query_raw(&stmt, values)?;
+            deleted.extend(
+                tx.query_raw(&stmt, values)?
+                    .map(|row| row.try_get(0))
+                    .collect::<Vec<i32>>()?,
+            );
         }
-        Ok(())
+        Ok(deleted)
+    }
+
+    fn delete_contracts_levels(
+        tx: &mut Transaction,
+        contracts: &[ContractID],
+        levels: &[i32],
+    ) -> Result<Vec<ClearedLevel>> {
+        let names: Vec<&String> = contracts
+            .iter()
+            .map(|c| &c.name)
+            .collect();
+        let addresses: Vec<&String> = contracts
+            .iter()
+            .map(|c| &c.address)
+            .collect();
+        tx.execute(
+            "
+DELETE FROM contract_deps
+WHERE level = ANY($1)
+  AND dest_schema = ANY($2)",
+            &[&levels, &names],
+        )?;
+        let cleared: Vec<ClearedLevel> = tx
+            .query(
+                "
+DELETE FROM contract_levels
+WHERE level = ANY($1)
+  AND contract = ANY($2)
+RETURNING level, contract",
+                &[&levels, &names],
+            )?
+            .iter()
+            .map(|row| ClearedLevel {
+                level: row.get::<_, i32>(0) as u32,
+                contract: Some(row.get(1)),
+            })
+            .collect();
+        let tx_context_ids: Vec<i64> = tx
+            .query(
+                "
+SELECT id
+FROM tx_contexts
+WHERE level = ANY($1)
+  AND contract = ANY($2)",
+                &[&levels, &addresses],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        Self::detach_storage_snapshots(tx, &tx_context_ids)?;
+        // The contracts' tables are cleaned up by cascade
+        tx.execute(
+            "DELETE FROM tx_contexts WHERE id = ANY($1)",
+            &[&tx_context_ids],
+        )?;
+        Ok(cleared)
     }
 
     pub(crate) fn save_contract_levels(
@@ -1389,31 +1532,927 @@ ON CONFLICT DO NOTHING",
         }
         Ok(())
     }
+}
+
+impl Backend for DBClient {
+    fn clone_backend(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+
+    fn get_quepasa_version(&mut self) -> Result<String> {
+        let mut conn = self.dbconn()?;
+
+        let version: String = conn
+            .query_one(
+                "
+SELECT
+    quepasa_version
+FROM indexer_state
+            ",
+                &[],
+            )?
+            .get(0);
+        Ok(version)
+    }
+
+    fn common_tables_exist(&mut self) -> Result<bool> {
+        let mut conn = self.dbconn()?;
+
+        let res = conn.query_opt(
+            "
+SELECT 1
+FROM information_schema.tables
+WHERE table_schema = $1
+  AND table_name = 'levels'
+",
+            &[&self.main_schema],
+        )?;
+        Ok(res.is_some())
+    }
+
+    fn create_common_tables(&mut self) -> Result<()> {
+        let mut conn = self.dbconn()?;
+
+        conn.simple_query(
+            format!(r#"CREATE SCHEMA IF NOT EXISTS "{}""#, self.main_schema)
+                .as_str(),
+        )?;
+        conn.simple_query(
+            PostgresqlGenerator::create_common_tables(&self.main_schema)
+                .as_str(),
+        )?;
+        Ok(())
+    }
 
-    pub(crate) fn get_origination(
+    fn delete_everything(
         &mut self,
-        contract_id: &ContractID,
-    ) -> Result<Option<u32>> {
+        node_cli: &NodeClient,
+        get_contract_rel: ContractRelGetter,
+    ) -> Result<()> {
         let mut conn = self.dbconn()?;
 
-        let result = conn.query(
+        let main_schema = self.main_schema.clone();
+        let mut tx = conn.transaction()?;
+
+        let contracts_table = tx.query_opt(
             "
 SELECT
-    level
-FROM contract_levels
-WHERE contract = $1
-  AND is_origination = TRUE",
-            &[&contract_id.name],
+    1
+FROM information_schema.tables
+WHERE table_schema = $1
+  AND table_name = 'contracts'
+",
+            &[&main_schema],
         )?;
-        if result.is_empty() {
-            Ok(None)
-        } else if result.len() == 1 {
-            let level: i32 = result[0].get(0);
-            Ok(Some(level as u32))
-        } else {
-            Err(anyhow!("Too many results for get_origination"))
+        if contracts_table.is_some() {
+            for row in tx.query("SELECT name, address FROM contracts", &[])? {
+                let contract_id = ContractID {
+                    name: row.get(0),
+                    address: row.get(1),
+                };
+                let contract = get_contract_rel(node_cli, &contract_id)?;
+                Self::delete_contract_schema(&mut tx, &contract)?
+            }
         }
-    }
+        tx.simple_query(
+            "
+DROP FUNCTION IF EXISTS last_context_at(INT, INT, INT, INT, INT);
+DROP FUNCTION IF EXISTS last_context_at(INT, INT, INT, INT);
+DROP FUNCTION IF EXISTS last_context_at(INT, INT, INT);
+DROP FUNCTION IF EXISTS last_context_at(INT, INT);
+DROP FUNCTION IF EXISTS last_context_at(INT);
+DROP TABLE IF EXISTS bigmap_keys;
+DROP TABLE IF EXISTS contract_deps;
+DROP TABLE IF EXISTS contract_requests;
+DROP TABLE IF EXISTS catalog_tables;
+DROP TABLE IF EXISTS catalog_columns;
+DROP TABLE IF EXISTS storage_snapshots;
+DROP TABLE IF EXISTS bigmap_meta_actions;
+DROP VIEW  IF EXISTS txs_ordered;
+DROP TABLE IF EXISTS txs;
+DROP TABLE IF EXISTS tx_contexts;
+DROP TABLE IF EXISTS indexer_state;
+DROP TYPE  IF EXISTS indexer_mode;
+DROP TABLE IF EXISTS contract_levels;
+DROP TABLE IF EXISTS contracts;
+DROP TABLE IF EXISTS levels;
+",
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_contract(
+        &mut self,
+        node_cli: &NodeClient,
+        name: &str,
+        get_contract_rel: ContractRelGetter,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+
+        let contract_id = match tx.query_opt(
+            "SELECT name, address FROM contracts WHERE name = $1",
+            &[&name],
+        )? {
+            Some(row) => ContractID {
+                name: row.get(0),
+                address: row.get(1),
+            },
+            None => return Err(anyhow!("contract {} is not in the db", name)),
+        };
+
+        match get_contract_rel(node_cli, &contract_id) {
+            Ok(contract) => Self::delete_contract_schema(&mut tx, &contract)?,
+            Err(e) => warn!(
+                "failed to get the definition of contract {}, dropping its schema as a whole, err: {:?}",
+                contract_id.name, e
+            ),
+        }
+        // Also drops what is left in the schema (eg the function shortcuts)
+        tx.simple_query(
+            format!(r#"DROP SCHEMA IF EXISTS "{}" CASCADE"#, contract_id.name)
+                .as_str(),
+        )?;
+
+        // (snapshots only reference snapshots of the same contract)
+        tx.execute(
+            "
+DELETE FROM storage_snapshots
+WHERE tx_context_id IN (SELECT id FROM tx_contexts WHERE contract = $1)",
+            &[&contract_id.address],
+        )?;
+        // txs, bigmap_keys and bigmap_meta_actions are cleaned up by cascade
+        tx.execute(
+            "DELETE FROM tx_contexts WHERE contract = $1",
+            &[&contract_id.address],
+        )?;
+        tx.execute(
+            "DELETE FROM contract_deps WHERE dest_schema = $1",
+            &[&contract_id.name],
+        )?;
+        tx.execute(
+            "DELETE FROM contract_levels WHERE contract = $1",
+            &[&contract_id.name],
+        )?;
+        tx.execute(
+            "DELETE FROM contract_requests WHERE name = $1",
+            &[&contract_id.name],
+        )?;
+        tx.execute(
+            "DELETE FROM catalog_tables WHERE contract = $1",
+            &[&contract_id.name],
+        )?;
+        tx.execute(
+            "DELETE FROM catalog_columns WHERE contract = $1",
+            &[&contract_id.name],
+        )?;
+        tx.execute(
+            "DELETE FROM contracts WHERE name = $1",
+            &[&contract_id.name],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn create_contract_schemas(
+        &mut self,
+        contracts: &mut Vec<relational::Contract>,
+    ) -> Result<bool> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+
+        contracts.sort_by_key(|c| c.cid.name.clone());
+
+        let num_columns = 2;
+        let v_refs = (1..(num_columns * contracts.len()) + 1)
+            .map(|i| format!("${}", i))
+            .collect::<Vec<String>>()
+            .chunks(num_columns)
+            .map(|x| x.join(", "))
+            .join("), (");
+        let stmt = tx.prepare(&format!(
+            "
+INSERT INTO contracts (name, address)
+VALUES ({})
+ON CONFLICT DO NOTHING
+RETURNING name",
+            v_refs
+        ))?;
+
+        let values: Vec<&dyn postgres::types::ToSql> = contracts
+            .iter()
+            .flat_map(|c| {
+                [c.cid.name.borrow_to_sql(), c.cid.address.borrow_to_sql()]
+            })
+            .collect();
+
+        let new_contracts = tx
+            .query_raw(&stmt, values)?
+            .map(|x| x.try_get(0))
+            .collect::<Vec<String>>()?;
+        if new_contracts.is_empty() {
+            tx.rollback()?;
+            return Ok(false);
+        }
+        let mut stmnts: Vec<String> = vec![];
+        for name in &new_contracts {
+            let contract = contracts
+                .iter()
+                .find(|c| &c.cid.name == name)
+                .unwrap();
+
+            let (mut tables, noview_prefixes, nofunctions_prefixes): (
+                Vec<Table>,
+                Vec<String>,
+                Vec<String>,
+            ) = TableBuilder::tables_from_contract(contract);
+
+            tables.sort_by_key(|t| t.name.clone());
+
+            stmnts.push(format!(
+                r#"
+CREATE SCHEMA IF NOT EXISTS "{contract_schema}";
+"#,
+                contract_schema = contract.cid.name
+            ));
+
+            let generator = PostgresqlGenerator::new(
+                self.main_schema.clone(),
+                &contract.cid,
+            );
+
+            for table in &tables {
+                stmnts.extend(Self::create_table_stmnts(
+                    &generator,
+                    &contract.cid.name,
+                    table,
+                    &noview_prefixes,
+                    &nofunctions_prefixes,
+                )?);
+            }
+            Self::save_catalog(&mut tx, contract)?;
+        }
+        for stmnt in stmnts {
+            tx.simple_query(stmnt.as_str())?;
+        }
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    /// Evolves the schema of an already created contract, so that it can
+    /// hold the data of another version of the contract's storage type.
+    /// Evolution is additive only: missing tables (along with their derived
+    /// tables and functions) and missing columns are added, nothing is
+    /// dropped or altered. Functions of existing tables are kept as is, they
+    /// don't select columns that are added here.
+    fn add_contract_version_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+
+        let existing: Vec<String> = tx
+            .query(
+                "
+SELECT table_name
+FROM information_schema.tables
+WHERE table_schema = $1",
+                &[&contract.cid.name],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        // (the types as information_schema spells them, see
+        // Self::information_schema_type)
+        let existing_types: HashMap<(String, String), String> = tx
+            .query(
+                "
+SELECT
+    table_name,
+    column_name,
+    CASE
+        WHEN character_maximum_length IS NULL THEN data_type
+        ELSE data_type || '(' || character_maximum_length || ')'
+    END
+FROM information_schema.columns
+WHERE table_schema = $1",
+                &[&contract.cid.name],
+            )?
+            .iter()
+            .map(|row| ((row.get(0), row.get(1)), row.get(2)))
+            .collect();
+
+        let (mut tables, noview_prefixes, nofunctions_prefixes): (
+            Vec<Table>,
+            Vec<String>,
+            Vec<String>,
+        ) = TableBuilder::tables_from_contract(contract);
+        tables.sort_by_key(|t| t.name.clone());
+
+        let generator =
+            PostgresqlGenerator::new(self.main_schema.clone(), &contract.cid);
+
+        let mut stmnts: Vec<String> = vec![];
+        for table in &tables {
+            if !existing.contains(&table.name) {
+                info!(
+                    "contract {}: adding table {} for a new version of its storage type",
+                    contract.cid.name, table.name
+                );
+                stmnts.extend(Self::create_table_stmnts(
+                    &generator,
+                    &contract.cid.name,
+                    table,
+                    &noview_prefixes,
+                    &nofunctions_prefixes,
+                )?);
+                continue;
+            }
+
+            let keywords = table.keywords();
+            let columns: Vec<String> = table
+                .get_columns()
+                .into_iter()
+                .filter(|column| !keywords.contains(&column.name))
+                .filter_map(PostgresqlGenerator::create_sql)
+                .collect();
+            // Existing columns are never altered, a column whose type
+            // differs under this version of the storage type cannot hold
+            // its values
+            for column in table
+                .get_columns()
+                .into_iter()
+                .filter(|column| !keywords.contains(&column.name))
+            {
+                let (existing_type, column_sql) = match (
+                    existing_types
+                        .get(&(table.name.clone(), column.name.clone())),
+                    PostgresqlGenerator::create_sql(column),
+                ) {
+                    (Some(existing_type), Some(column_sql)) => {
+                        (existing_type, column_sql)
+                    }
+                    _ => continue,
+                };
+                let column_type =
+                    Self::information_schema_type(&column.name, &column_sql);
+                if *existing_type != column_type {
+                    return Err(anyhow!(
+                        "contract {}: column {} of table {} is of type {}, but a version of the contract's storage type needs it to be of type {} (existing columns are never altered)",
+                        contract.cid.name,
+                        column.name,
+                        table.name,
+                        existing_type,
+                        column_type,
+                    ));
+                }
+            }
+            for derived in [
+                table.name.clone(),
+                format!("{}_live", table.name),
+                format!("{}_ordered", table.name),
+            ] {
+                if !existing.contains(&derived) {
+                    continue;
+                }
+                for column in &columns {
+                    stmnts.push(format!(
+                        r#"ALTER TABLE "{}"."{}" ADD COLUMN IF NOT EXISTS {};"#,
+                        contract.cid.name, derived, column
+                    ));
+                }
+            }
+        }
+        for stmnt in stmnts {
+            tx.simple_query(stmnt.as_str())?;
+        }
+        Self::save_catalog(&mut tx, contract)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn rebuild_derived_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let (mut tables, noview_prefixes, _): (
+            Vec<Table>,
+            Vec<String>,
+            Vec<String>,
+        ) = TableBuilder::tables_from_contract(contract);
+        tables.retain(|table| {
+            !noview_prefixes
+                .iter()
+                .any(|prefix| table.path().starts_with(prefix))
+        });
+        tables.sort_by_key(|t| t.path().to_string());
+
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+        for (i, table) in tables.iter().enumerate() {
+            info!(
+                "rebuilding {table} _live and _ordered ({contract} table {table_i}/{table_total})",
+                contract = contract.cid.name,
+                table = table.name,
+                table_i = i + 1,
+                table_total = tables.len(),
+            );
+            self.repopulate_derived_table(&mut tx, &contract.cid, table)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_contracts(&mut self) -> Result<Vec<ContractID>> {
+        let mut conn = self.dbconn()?;
+        Ok(conn
+            .query("SELECT name, address FROM contracts ORDER BY name", &[])?
+            .iter()
+            .map(|row| ContractID {
+                name: row.get(0),
+                address: row.get(1),
+            })
+            .collect())
+    }
+
+    fn get_head(&mut self) -> Result<Option<LevelMeta>> {
+        self.get_level_internal(None)
+    }
+
+    fn get_level(&mut self, level: u32) -> Result<Option<LevelMeta>> {
+        self.get_level_internal(Some(level as i32))
+    }
+
+    fn get_missing_levels(
+        &mut self,
+        contracts: &[ContractID],
+        end: u32,
+    ) -> Result<Vec<u32>> {
+        let mut conn = self.dbconn()?;
+
+        let mut rows: Vec<i32> = vec![];
+        for contract_id in contracts {
+            info!(
+                "querying db to check for any missing levels of {}..",
+                contract_id.name
+            );
+            let origination = self.get_origination(contract_id)?;
+            let start = origination.unwrap_or(1);
+            for row in conn.query(
+                format!(
+                    "
+SELECT
+    s.i
+FROM generate_series({},{}) s(i)
+LEFT JOIN contract_levels clvl
+  ON  clvl.contract = $1
+  AND clvl.level = s.i
+WHERE clvl IS NULL
+ORDER BY 1",
+                    start, end
+                )
+                .as_str(),
+                &[&contract_id.name],
+            )? {
+                rows.push(row.get(0));
+            }
+        }
+        rows.sort_unstable();
+        rows.dedup();
+        Ok(rows
+            .iter()
+            .map(|x| *x as u32)
+            .collect::<Vec<u32>>())
+    }
+
+    fn get_forked_levels(&mut self) -> Result<Vec<u32>> {
+        let mut conn = self.dbconn()?;
+
+        let mut rows: Vec<i32> = vec![];
+        for row in conn.query(
+            "
+SELECT DISTINCT
+  level
+FROM (
+  SELECT
+    level,
+    prev_hash AS chain_prev_hash,
+    LAG(level) OVER w as db_prev_level,
+    LAG(hash) OVER w AS db_prev_hash
+  FROM levels
+  WINDOW w AS (ORDER BY level)
+) q
+WHERE chain_prev_hash != db_prev_hash
+  AND db_prev_level = level - 1",
+            &[],
+        )? {
+            rows.push(row.get(0));
+        }
+        Ok(rows
+            .iter()
+            .map(|x| *x as u32)
+            .collect::<Vec<u32>>())
+    }
+
+    fn get_fully_processed_levels(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<Vec<u32>> {
+        let mut conn = self.dbconn()?;
+
+        let fully_processed: Vec<u32> = conn
+            .query(
+                "
+SELECT
+    level
+FROM contract_levels
+WHERE contract = ANY($1)
+GROUP by 1
+HAVING COUNT(1) = array_length($1, 1)
+ORDER by 1",
+                &[&contracts
+                    .iter()
+                    .map(|c| &c.name)
+                    .collect::<Vec<&String>>()],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .map(|lvl: i32| lvl as u32)
+            .collect();
+        Ok(fully_processed)
+    }
+
+    fn get_partial_processed_levels(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<Vec<u32>> {
+        let mut conn = self.dbconn()?;
+
+        let partial_processed: Vec<u32> = conn
+            .query(
+                "
+with all_levels as (
+    select distinct
+        level
+    from contract_levels
+    where contract = any($1)
+)
+select distinct
+    lvl.level
+from all_levels lvl, contracts c
+left join contract_levels orig
+  on  orig.contract = c.name
+  and orig.is_origination
+where lvl.level >= coalesce(orig.level, 0)
+  and c.name = any($1)
+  and not exists (
+    select 1
+    from contract_levels clvl
+    where clvl.level = lvl.level
+      and clvl.contract = c.name
+)
+order by 1",
+                &[&contracts
+                    .iter()
+                    .map(|c| &c.name)
+                    .collect::<Vec<&String>>()],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .map(|lvl: i32| lvl as u32)
+            .collect();
+        Ok(partial_processed)
+    }
+
+    fn mark_missing_levels_empty(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<u64> {
+        let mut conn = self.dbconn()?;
+
+        Ok(conn.execute(
+            "
+INSERT INTO contract_levels(contract, level)
+SELECT $1, q.level
+FROM (
+    SELECT
+        g.level
+    FROM GENERATE_SERIES(
+        (SELECT MIN(level) FROM contract_levels WHERE contract = $1),
+        (SELECT MAX(level) FROM contract_levels WHERE contract = $1)
+    ) AS g(level)
+    LEFT JOIN contract_levels clvl
+      ON  clvl.contract = $1
+      AND clvl.level = g.level
+    WHERE clvl IS NULL
+) q
+",
+            &[&contract_id.name],
+        )?)
+    }
+
+    fn get_origination(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<Option<u32>> {
+        let mut conn = self.dbconn()?;
+
+        let result = conn.query(
+            "
+SELECT
+    level
+FROM contract_levels
+WHERE contract = $1
+  AND is_origination = TRUE",
+            &[&contract_id.name],
+        )?;
+        if result.is_empty() {
+            Ok(None)
+        } else if result.len() == 1 {
+            let level: i32 = result[0].get(0);
+            Ok(Some(level as u32))
+        } else {
+            Err(anyhow!("Too many results for get_origination"))
+        }
+    }
+
+    fn get_active_contract_levels(&mut self) -> Result<Vec<ContractLevel>> {
+        let mut conn = self.dbconn()?;
+
+        Ok(conn
+            .query(
+                "
+SELECT
+    c.address,
+    clvl.level
+FROM contract_levels clvl
+JOIN contracts c
+  ON c.name = clvl.contract
+WHERE clvl.is_origination
+   OR EXISTS (
+    SELECT 1
+    FROM tx_contexts ctx
+    WHERE ctx.level = clvl.level
+      AND ctx.contract = clvl.contract
+)
+   OR clvl.level = (
+    SELECT MAX(level)
+    FROM contract_levels
+    WHERE contract = clvl.contract
+)
+ORDER BY 1, 2",
+                &[],
+            )?
+            .iter()
+            .map(|row| ContractLevel {
+                contract: row.get(0),
+                level: row.get::<_, i32>(1) as u32,
+            })
+            .collect())
+    }
+
+    fn get_indexer_mode(&mut self) -> Result<IndexerMode> {
+        let mut conn = self.dbconn()?;
+
+        let mode: IndexerMode = conn
+            .query_one("select mode from indexer_state", &[])?
+            .get(0);
+        Ok(mode)
+    }
+
+    fn set_indexer_mode(&mut self, mode: IndexerMode) -> Result<()> {
+        let mut conn = self.dbconn()?;
+
+        let updated = conn.execute(
+            "
+update indexer_state
+set mode = $1",
+            &[&mode],
+        )?;
+        if updated == 1 {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "wrong number of rows in indexer_state table. please fix manually. sorry"
+            ))
+        }
+    }
+
+    fn get_derived_tables_stale(&mut self) -> Result<bool> {
+        let mut conn = self.dbconn()?;
+        Ok(conn
+            .query_one("SELECT derived_tables_stale FROM indexer_state", &[])?
+            .get(0))
+    }
+
+    fn set_derived_tables_stale(&mut self, stale: bool) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        conn.execute(
+            "UPDATE indexer_state SET derived_tables_stale = $1",
+            &[&stale],
+        )?;
+        Ok(())
+    }
+
+    fn get_config_deps(
+        &mut self,
+        config: &[ContractID],
+    ) -> Result<Vec<ContractID>> {
+        if config.is_empty() {
+            return Ok(vec![]);
+        }
+        let v_refs = (0..config.len())
+            .map(|i| format!("${}", (i + 1)))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let mut conn = self.dbconn()?;
+
+        let mut it = conn.query_raw(
+            format!(
+                "
+SELECT DISTINCT
+    src_contract
+FROM contract_deps
+WHERE dest_schema IN ({})
+",
+                v_refs
+            )
+            .as_str(),
+            config
+                .iter()
+                .map(|c| c.name.borrow_to_sql())
+                .collect::<Vec<&dyn ToSql>>(),
+        )?;
+        let mut res: Vec<ContractID> = vec![];
+        while let Some(row) = it.next()? {
+            res.push(ContractID {
+                address: row.get(0),
+                name: row.get(0),
+            });
+        }
+        Ok(res
+            .into_iter()
+            .filter(|dep| {
+                !config
+                    .iter()
+                    .any(|c| c.address == dep.address)
+            })
+            .collect())
+    }
+
+    fn get_dependent_levels(
+        &mut self,
+        config: &[ContractID],
+    ) -> Result<Vec<u32>> {
+        if config.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let v_refs = (0..config.len())
+            .map(|i| format!("${}", (i + 1)))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let mut conn = self.dbconn()?;
+
+        let mut it = conn.query_raw(
+            format!(
+                "
+SELECT DISTINCT
+    level
+FROM contract_deps
+WHERE dest_schema IN ({})
+  AND is_deep_copy
+",
+                v_refs
+            )
+            .as_str(),
+            config
+                .iter()
+                .map(|c| c.name.borrow_to_sql())
+                .collect::<Vec<&dyn ToSql>>(),
+        )?;
+        let mut res: Vec<i32> = vec![];
+        while let Some(row) = it.next()? {
+            res.push(row.get(0));
+        }
+        Ok(res
+            .into_iter()
+            .map(|x| x as u32)
+            .collect())
+    }
+
+    fn request_contract(&mut self, contract_id: &ContractID) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        conn.execute(
+            "
+INSERT INTO contract_requests (name, address)
+VALUES ($1, $2)
+ON CONFLICT (name) DO UPDATE
+SET address = EXCLUDED.address,
+    status = 'requested',
+    error = NULL,
+    requested_at = now(),
+    updated_at = now()",
+            &[&contract_id.name, &contract_id.address],
+        )?;
+        Ok(())
+    }
+
+    fn get_contract_requests(
+        &mut self,
+        statuses: &[ContractRequestStatus],
+    ) -> Result<Vec<ContractID>> {
+        let mut conn = self.dbconn()?;
+        let res = conn
+            .query(
+                "
+SELECT
+    name, address
+FROM contract_requests
+WHERE status = ANY($1)
+ORDER BY requested_at",
+                &[&statuses
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect::<Vec<&str>>()],
+            )?
+            .iter()
+            .map(|row| ContractID {
+                name: row.get(0),
+                address: row.get(1),
+            })
+            .collect();
+        Ok(res)
+    }
+
+    fn set_contract_request_status(
+        &mut self,
+        contract_id: &ContractID,
+        status: ContractRequestStatus,
+        error: Option<String>,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        conn.execute(
+            "
+UPDATE contract_requests
+SET status = $2,
+    error = $3,
+    updated_at = now()
+WHERE name = $1",
+            &[&contract_id.name, &status.as_str(), &error],
+        )?;
+        Ok(())
+    }
+
+    fn delete_levels(&mut self, levels: &[u32]) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let mut tx = conn.transaction()?;
+        DBClient::delete_levels(
+            &mut tx,
+            &levels
+                .iter()
+                .map(|lvl| *lvl as i32)
+                .collect::<Vec<i32>>(),
+        )?;
+        self.notify(&mut tx, &notify::forked_levels_payloads(levels)?)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn insert_batch(
+        &mut self,
+        stats: Option<&StatsLogger>,
+        contracts_scoped: bool,
+        processed_blocks: Vec<ProcessedBlock>,
+        notifications: &[String],
+    ) -> Result<Vec<ClearedLevel>> {
+        inserter::insert_batch(
+            self,
+            stats,
+            contracts_scoped,
+            processed_blocks,
+            notifications,
+        )
+    }
+
+    fn apply_migrations(&mut self, migrations: &[Migration]) -> Result<()> {
+        migrations::apply_migrations(self, migrations)
+    }
+}
+
+/// A table's rows of a contract's data in a batch (see DBClient::build_rows)
+pub(crate) struct TableRows {
+    contract_schema: String,
+    pub table: String,
+    columns: Vec<String>,
+    pub rows: Vec<Vec<Column>>,
 }
 
 pub(crate) type BigmapEntries = HashMap<
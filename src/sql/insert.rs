@@ -1,7 +1,9 @@
 use anyhow::{anyhow, Result};
+use bytes::BytesMut;
 use chrono::{DateTime, Utc};
 use pg_bigdecimal::PgNumeric;
-use postgres::types::BorrowToSql;
+use postgres::types::{accepts, to_sql_checked};
+use postgres::types::{BorrowToSql, IsNull, ToSql, Type};
 use std::collections::HashMap;
 
 use crate::sql::postgresql_generator::PostgresqlGenerator;
@@ -16,9 +18,36 @@ pub enum Value {
     Int(i32),
     BigInt(i64),
     Timestamp(Option<DateTime<Utc>>),
+    Micheline(RawJson),
     Null,
 }
 
+/// Already serialized json, that is passed on as is to json(b) columns.
+#[derive(
+    Ord, PartialOrd, Clone, Debug, Eq, PartialEq, Serialize, Deserialize,
+)]
+pub struct RawJson(pub String);
+
+impl ToSql for RawJson {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> std::result::Result<IsNull, Box<dyn std::error::Error + Sync + Send>>
+    {
+        if *ty == Type::JSONB {
+            // jsonb's binary format is the textual json, prefixed with a
+            // format version byte
+            out.extend_from_slice(&[1]);
+        }
+        out.extend_from_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    accepts!(JSON, JSONB);
+    to_sql_checked!();
+}
+
 impl Value {
     pub(crate) fn borrow_to_sql(&self) -> &dyn postgres::types::ToSql {
         match self {
@@ -32,6 +61,7 @@ impl Value {
                     .borrow_to_sql()
             }
             Value::Numeric(n) => n.borrow_to_sql(),
+            Value::Micheline(j) => j.borrow_to_sql(),
             Value::Null => "NULL".borrow_to_sql(),
         }
     }
@@ -73,6 +103,17 @@ pub struct Insert {
 
 impl Insert {
     pub fn get_columns(&self) -> Result<Vec<Column>> {
+        self.get_columns_with_parent(
+            PostgresqlGenerator::parent_name(&self.table_name).as_deref(),
+        )
+    }
+
+    /// Same as get_columns, with the table referenced by fk_id given
+    /// instead of derived from the table's name (see TablePath)
+    pub fn get_columns_with_parent(
+        &self,
+        parent_name: Option<&str>,
+    ) -> Result<Vec<Column>> {
         let mut res = self.columns.clone();
 
         res.push(Column {
@@ -80,10 +121,7 @@ impl Insert {
             value: Value::BigInt(self.id),
         });
         if let Some(fk_id) = self.fk_id {
-            let parent_name = PostgresqlGenerator::parent_name(
-                &self.table_name,
-            )
-            .ok_or_else(|| {
+            let parent_name = parent_name.ok_or_else(|| {
                 anyhow!(
                     "
                 failed to get parent name from table={}",
@@ -91,7 +129,7 @@ impl Insert {
                 )
             })?;
             res.push(Column {
-                name: PostgresqlGenerator::parent_ref(&parent_name),
+                name: PostgresqlGenerator::parent_ref(parent_name),
                 value: Value::BigInt(fk_id),
             });
         }
@@ -121,8 +159,14 @@ impl Insert {
     }
 
     pub fn get_column(&self, name: &str) -> Result<Option<Column>> {
-        let col = self.get_columns()?;
-        Ok(col
+        if name == "id" {
+            return Ok(Some(Column {
+                name: "id".to_string(),
+                value: Value::BigInt(self.id),
+            }));
+        }
+        Ok(self
+            .columns
             .iter()
             .find(|column| column.name == name)
             .cloned())
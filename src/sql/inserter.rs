@@ -1,70 +1,118 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use std::collections::hash_map::Entry::Vacant;
 use std::collections::HashMap;
 use std::thread;
 use std::time::Instant;
 
 use crate::config::ContractID;
+use crate::events::{Event, EventSink};
+use crate::metrics;
 use crate::octez::block::{LevelMeta, Tx, TxContext};
+use crate::sql::backend::Backend;
 use crate::sql::db;
 use crate::sql::db::DBClient;
 use crate::sql::insert;
 use crate::sql::insert::Insert;
+use crate::sql::snapshots;
 use crate::sql::types::BigmapMetaAction;
 use crate::stats::StatsLogger;
 use crate::storage_structure::relational;
 
 pub(crate) struct DBInserter {
-    dbcli: DBClient,
+    dbcli: Box<dyn Backend>,
 
     // the number of processed blocks to collect before inserting into the db
     batch_size: usize,
+
+    // only replace the processed contracts' data in the levels, instead of
+    // replacing the levels entirely
+    contracts_scoped: bool,
+
+    // emits the inserted changes once they are committed
+    event_sink: Option<EventSink>,
 }
 
 pub(crate) type ProcessedBlock = Vec<ProcessedContractBlock>;
 
+/// A level whose previously saved data was cleared by a batch: all of it,
+/// or only the contract's (with contracts_scoped)
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ClearedLevel {
+    pub level: u32,
+    pub contract: Option<String>,
+}
+
 impl DBInserter {
-    pub(crate) fn new(dbcli: DBClient, batch_size: usize) -> Self {
-        Self { dbcli, batch_size }
+    pub(crate) fn new(dbcli: Box<dyn Backend>, batch_size: usize) -> Self {
+        Self {
+            dbcli,
+            batch_size,
+            contracts_scoped: false,
+            event_sink: None,
+        }
+    }
+
+    pub(crate) fn contracts_scoped(&mut self) {
+        self.contracts_scoped = true
+    }
+
+    pub(crate) fn set_event_sink(&mut self, event_sink: Option<EventSink>) {
+        self.event_sink = event_sink
     }
 
     pub(crate) fn run(
         &self,
         stats: &StatsLogger,
         recv_ch: flume::Receiver<Box<ProcessedBlock>>,
-    ) -> Result<thread::JoinHandle<()>> {
+    ) -> Result<thread::JoinHandle<Result<()>>> {
         let batch_size = self.batch_size;
+        let contracts_scoped = self.contracts_scoped;
         let dbcli = self.dbcli.clone();
+        let event_sink = self.event_sink.clone();
         let stats_cl = stats.clone();
 
         let thread_handle = thread::spawn(move || {
-            Self::exec(dbcli, batch_size, &stats_cl, recv_ch).unwrap();
+            Self::exec(
+                dbcli,
+                batch_size,
+                contracts_scoped,
+                event_sink.as_ref(),
+                &stats_cl,
+                recv_ch,
+            )
         });
         Ok(thread_handle)
     }
 
     fn exec(
-        mut dbcli: DBClient,
+        mut dbcli: Box<dyn Backend>,
         batch_size: usize,
+        contracts_scoped: bool,
+        event_sink: Option<&EventSink>,
         stats: &StatsLogger,
         recv_ch: flume::Receiver<Box<ProcessedBlock>>,
     ) -> Result<()> {
-        let update_derived = false;
-        #[cfg(feature = "regression_force_update_derived")]
-        let update_derived = true | update_derived;
-
-        let mut batch = ProcessedBatch::new(dbcli.get_max_id()?);
+        let mut batch: Vec<ProcessedBlock> = vec![];
 
         let mut accum_begin = Instant::now();
         for processed_block in recv_ch {
-            batch.add(*processed_block);
+            batch.push(*processed_block);
 
             if batch.len() >= batch_size {
                 let accum_elapsed = accum_begin.elapsed();
 
                 let insert_begin = Instant::now();
-                insert_batch(&mut dbcli, Some(stats), update_derived, &batch)?;
+                insert_and_emit(
+                    dbcli.as_mut(),
+                    Some(stats),
+                    contracts_scoped,
+                    event_sink,
+                    std::mem::take(&mut batch),
+                    &[],
+                )?;
                 let insert_elapsed = insert_begin.elapsed();
+                metrics::INSERTER_BATCH_DURATION
+                    .observe(insert_elapsed.as_secs_f64());
 
                 stats.set(
                     "inserter",
@@ -76,80 +124,257 @@ impl DBInserter {
                     "prev batch's insert time",
                     format!("{:?}", insert_elapsed),
                 )?;
-                batch.clear();
                 accum_begin = Instant::now();
             }
         }
-        insert_batch(&mut dbcli, Some(stats), update_derived, &batch)?;
+        insert_and_emit(
+            dbcli.as_mut(),
+            Some(stats),
+            contracts_scoped,
+            event_sink,
+            batch,
+            &[],
+        )?;
 
         Ok(())
     }
 }
 
+/// Inserts the processed block, notifying the notification along with it
+/// (see notify::level_payload)
 pub(crate) fn insert_processed(
-    dbcli: &mut DBClient,
-    update_derived_tables: bool,
+    dbcli: &mut dyn Backend,
+    contracts_scoped: bool,
+    event_sink: Option<&EventSink>,
     processed: ProcessedBlock,
+    notification: String,
 ) -> Result<()> {
-    let mut batch = ProcessedBatch::new(dbcli.get_max_id()?);
-    batch.add(processed);
+    insert_and_emit(
+        dbcli,
+        None,
+        contracts_scoped,
+        event_sink,
+        vec![processed],
+        &[notification],
+    )
+}
 
-    insert_batch(dbcli, None, update_derived_tables, &batch)
+/// Inserts the batch, and emits its changes to the event sink once the
+/// batch is committed
+fn insert_and_emit(
+    dbcli: &mut dyn Backend,
+    stats: Option<&StatsLogger>,
+    contracts_scoped: bool,
+    event_sink: Option<&EventSink>,
+    processed_blocks: Vec<ProcessedBlock>,
+    notifications: &[String],
+) -> Result<()> {
+    // (the events are derived beforehand, inserting consumes the batch)
+    let events = match event_sink {
+        Some(_) => Event::from_processed_blocks(&processed_blocks)?,
+        None => vec![],
+    };
+    let cleared = dbcli.insert_batch(
+        stats,
+        contracts_scoped,
+        processed_blocks,
+        notifications,
+    )?;
+    if let Some(event_sink) = event_sink {
+        // What was emitted before for the levels the batch replaced is
+        // retracted first
+        let mut retractions = Event::retractions(&cleared);
+        retractions.extend(events);
+        event_sink.emit(&retractions)?;
+    }
+    Ok(())
 }
 
-fn insert_batch(
+/// Inserts the batch into a PostgreSQL database (see Backend::insert_batch)
+pub(crate) fn insert_batch(
     dbcli: &mut DBClient,
     stats: Option<&StatsLogger>,
-    update_derived_tables: bool,
-    batch: &ProcessedBatch,
-) -> Result<()> {
+    contracts_scoped: bool,
+    processed_blocks: Vec<ProcessedBlock>,
+    notifications: &[String],
+) -> Result<Vec<ClearedLevel>> {
     let mut conn = dbcli.dbconn()?;
 
     let mut db_tx = conn.transaction()?;
 
+    // The ids are assigned while holding the lock on max_id, so that
+    // concurrent inserters (eg the head loop and a contract being
+    // bootstrapped next to it) never hand out the same ids
+    let mut batch = ProcessedBatch::new(DBClient::lock_max_id(&mut db_tx)?);
+    for processed_block in processed_blocks {
+        batch.add(processed_block);
+    }
+
     DBClient::set_max_id(&mut db_tx, batch.get_max_id())?;
-    DBClient::save_levels(
+    let levels = batch
+        .levels
+        .values()
+        .collect::<Vec<&LevelMeta>>();
+    let cleared = DBClient::clear_levels(
         &mut db_tx,
+        contracts_scoped,
         &batch
-            .levels
-            .values()
-            .collect::<Vec<&LevelMeta>>(),
+            .contract_tx_contexts
+            .keys()
+            .cloned()
+            .collect::<Vec<ContractID>>(),
+        &levels,
     )?;
+    DBClient::save_levels(&mut db_tx, contracts_scoped, &levels)?;
     DBClient::save_contract_deps(&mut db_tx, &batch.contract_deps)?;
-    DBClient::save_contract_levels(&mut db_tx, &batch.contract_levels)?;
 
     DBClient::save_tx_contexts(&mut db_tx, &batch.tx_contexts)?;
     DBClient::save_txs(&mut db_tx, &batch.txs)?;
-
-    for (contract_id, inserts) in &batch.contract_inserts {
-        let num_rows = inserts.len();
-        if let Some(stats) = stats {
-            stats.add("inserter", "contract data rows", num_rows)?;
-        }
-        DBClient::apply_inserts(&mut db_tx, contract_id, inserts)?;
-    }
     DBClient::save_bigmap_keyhashes(
         &mut db_tx,
         batch.bigmap_keyhashes.clone(),
     )?;
     DBClient::save_bigmap_meta_actions(&mut db_tx, &batch.bigmap_meta_actions)?;
 
-    if update_derived_tables {
-        for (contract_id, (contract, ctxs)) in &batch.contract_tx_contexts {
-            dbcli.update_derived_tables(
-                &mut db_tx,
-                contract,
-                ctxs,
-            ).with_context(|| {
-                format!(
-                    "insert failed (levels={:?}, contract={}): could not update derived tables",
-                    batch.levels.keys(), contract_id.name,
-                )})?;
+    let mut batch_levels: Vec<i32> = batch.levels.keys().cloned().collect();
+    batch_levels.sort_unstable();
+    let mut contracts_data: Vec<ContractData> = vec![];
+    for (contract_id, inserts) in batch.contract_inserts.drain() {
+        let (contract, ctxs) = batch
+            .contract_tx_contexts
+            .remove(&contract_id)
+            .unwrap();
+        let skip_unchanged_snapshots =
+            dbcli.skips_unchanged_snapshots(&contract.cid.name);
+        let prev_snapshot = match skip_unchanged_snapshots {
+            true => snapshots::get_prev_snapshot(&mut db_tx, &ctxs)?,
+            false => None,
+        };
+        contracts_data.push(ContractData {
+            contract,
+            skip_unchanged_snapshots,
+            tx_contexts: ctxs,
+            inserts,
+            prev_snapshot,
+            snapshots: vec![],
+            rows: vec![],
+        });
+    }
+
+    // Only building the contracts' rows is done concurrently, all of the
+    // batch is written in this one transaction: a level and all of its data
+    // is either saved entirely or not at all
+    let num_threads =
+        std::cmp::min(dbcli.get_inserter_cap(), contracts_data.len());
+    if num_threads > 1 {
+        let (work_send, work_recv) = flume::unbounded::<&mut ContractData>();
+        for contract_data in contracts_data.iter_mut() {
+            work_send
+                .send(contract_data)
+                .map_err(|_| anyhow!("inserter work channel closed"))?;
+        }
+        drop(work_send);
+        thread::scope(|scope| -> Result<()> {
+            let threads: Vec<thread::ScopedJoinHandle<Result<()>>> = (0
+                ..num_threads)
+                .map(|_| {
+                    let work_recv = work_recv.clone();
+                    scope.spawn(move || {
+                        for contract_data in work_recv {
+                            build_contract_rows(stats, contract_data)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().map_err(|e| {
+                    anyhow!("inserter thread failed with err: {:?}", e)
+                })??;
+            }
+            Ok(())
+        })?;
+    } else {
+        for contract_data in contracts_data.iter_mut() {
+            build_contract_rows(stats, contract_data)?;
         }
     }
+    for contract_data in &contracts_data {
+        DBClient::save_storage_snapshots(&mut db_tx, &contract_data.snapshots)?;
+        DBClient::write_rows(&mut db_tx, &contract_data.rows)?;
+    }
+
+    // The derived tables are updated with every batch, whatever the order
+    // in which levels are processed. This is done one contract after the
+    // other, in the batch's transaction.
+    for contract_data in &contracts_data {
+        let contract = &contract_data.contract;
+        dbcli.update_derived_tables(
+            &mut db_tx,
+            contract,
+            &contract_data.tx_contexts,
+        ).with_context(|| {
+            format!(
+                "insert failed (levels={:?}, contract={}): could not update derived tables",
+                batch_levels, contract.cid.name,
+            )})?;
+    }
+    DBClient::save_contract_levels(&mut db_tx, &batch.contract_levels)?;
+    dbcli.notify(&mut db_tx, notifications)?;
 
     db_tx.commit()?;
 
+    for contract_data in &contracts_data {
+        for table_rows in &contract_data.rows {
+            metrics::rows_inserted(
+                &contract_data.contract.cid.name,
+                &table_rows.table,
+                table_rows.rows.len(),
+            );
+        }
+    }
+
+    Ok(cleared)
+}
+
+/// A contract's part of a batch
+struct ContractData {
+    contract: relational::Contract,
+    tx_contexts: Vec<TxContext>,
+    inserts: Vec<Insert>,
+
+    // whether to skip the contract's unchanged snapshots, and its last
+    // snapshot before the batch when so (see
+    // snapshots::skip_unchanged_snapshots)
+    skip_unchanged_snapshots: bool,
+    prev_snapshot: Option<(String, i64)>,
+
+    // the storage_snapshots rows and the table rows to write, once built
+    // (see build_contract_rows)
+    snapshots: Vec<snapshots::StorageSnapshot>,
+    rows: Vec<db::TableRows>,
+}
+
+fn build_contract_rows(
+    stats: Option<&StatsLogger>,
+    contract_data: &mut ContractData,
+) -> Result<()> {
+    let contract = &contract_data.contract;
+    if contract_data.skip_unchanged_snapshots {
+        contract_data.snapshots = snapshots::skip_unchanged_snapshots(
+            contract,
+            &contract_data.tx_contexts,
+            &mut contract_data.inserts,
+            contract_data.prev_snapshot.take(),
+        )?;
+    }
+
+    let num_rows = contract_data.inserts.len();
+    if let Some(stats) = stats {
+        stats.add("inserter", "contract data rows", num_rows)?;
+    }
+    contract_data.rows =
+        DBClient::build_rows(contract, &contract_data.inserts)?;
     Ok(())
 }
 
@@ -231,9 +456,7 @@ impl ProcessedContractBlock {
     }
 }
 
-struct ProcessedBatch {
-    size: usize,
-
+pub(crate) struct ProcessedBatch {
     pub levels: HashMap<i32, LevelMeta>,
     pub tx_contexts: Vec<TxContext>,
     pub txs: Vec<Tx>,
@@ -252,8 +475,6 @@ struct ProcessedBatch {
 impl ProcessedBatch {
     pub fn new(max_id: i64) -> Self {
         Self {
-            size: 0,
-
             levels: HashMap::new(),
             tx_contexts: vec![],
             txs: vec![],
@@ -269,33 +490,15 @@ impl ProcessedBatch {
         }
     }
 
-    pub fn len(&self) -> usize {
-        self.size
-    }
-
     pub fn get_max_id(&self) -> i64 {
         self.max_id
     }
 
-    pub fn clear(&mut self) {
-        self.levels.clear();
-        self.tx_contexts.clear();
-        self.txs.clear();
-        self.bigmap_keyhashes.clear();
-        self.bigmap_meta_actions.clear();
-        self.contract_levels.clear();
-        self.contract_inserts.clear();
-        self.contract_deps.clear();
-
-        self.size = 0;
-    }
-
     pub fn add(&mut self, processed_block: ProcessedBlock) {
         for mut cres in processed_block.into_iter() {
             self.max_id = cres.offset_ids(self.max_id);
             self.add_cres(cres);
         }
-        self.size += 1;
     }
 
     fn add_cres(&mut self, cres: ProcessedContractBlock) {
@@ -316,12 +519,20 @@ impl ProcessedBatch {
                 (cres.contract.clone(), vec![]),
             );
         }
-        let contract_ctxs: &mut Vec<TxContext> = &mut self
+        let (contract, contract_ctxs) = self
             .contract_tx_contexts
             .get_mut(&cres.contract.cid)
-            .unwrap()
-            .1;
+            .unwrap();
         contract_ctxs.extend(cres.tx_contexts.clone());
+        // The batch may span multiple versions of the contract's type (see
+        // Executor::get_contract_version), know about all of their tables
+        for (name, path) in &cres.contract.tables {
+            if !contract.tables.contains_key(name) {
+                contract
+                    .tables
+                    .insert(name.clone(), path.clone());
+            }
+        }
 
         self.contract_levels.push((
             cres.contract.cid.clone(),
@@ -0,0 +1,300 @@
+use crate::sql::backend::Backend;
+use crate::sql::db::DBClient;
+use anyhow::{anyhow, Context, Result};
+use postgres::Transaction;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+pub(crate) enum MigrationStep {
+    Sql(&'static str),
+    /// A step that cannot be expressed in plain SQL, with a description of
+    /// what it does (for the dry-run)
+    Rust(&'static str, fn(&mut Transaction) -> Result<()>),
+}
+
+/// Migrates the database from one schema version to the next (see
+/// schema_version).
+pub(crate) struct Migration {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub description: &'static str,
+    pub steps: Vec<MigrationStep>,
+}
+
+/// All migrations, in order. Add a migration here for every release that
+/// changes the schema of the common tables or of the contract schemas.
+fn migrations() -> Vec<Migration> {
+    vec![Migration {
+        from: "1.2",
+        to: "1.3",
+        description: "add the tables and columns introduced in Que Pasa 1.3",
+        steps: vec![
+            MigrationStep::Sql(
+                "
+CREATE TABLE IF NOT EXISTS contract_requests (
+    name TEXT PRIMARY KEY,
+    address VARCHAR(100) NOT NULL,
+    status TEXT NOT NULL DEFAULT 'requested',
+    error TEXT,
+    requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+CREATE TABLE IF NOT EXISTS catalog_tables (
+    contract TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    parent_table TEXT,
+    parent_fk_column TEXT,
+    PRIMARY KEY (contract, table_name)
+);
+CREATE TABLE IF NOT EXISTS catalog_columns (
+    contract TEXT NOT NULL,
+    table_name TEXT NOT NULL,
+    column_name TEXT NOT NULL,
+    original_name TEXT NOT NULL,
+    michelson_type TEXT,
+    annotation TEXT,
+    is_index BOOLEAN NOT NULL,
+    PRIMARY KEY (contract, table_name, column_name)
+);
+CREATE TABLE IF NOT EXISTS storage_snapshots (
+    tx_context_id BIGINT PRIMARY KEY REFERENCES tx_contexts(id) ON DELETE CASCADE,
+    hash TEXT NOT NULL,
+    source_tx_context_id BIGINT REFERENCES tx_contexts(id) ON DELETE RESTRICT
+);
+CREATE INDEX IF NOT EXISTS storage_snapshots_source
+    ON storage_snapshots(source_tx_context_id);",
+            ),
+            MigrationStep::Sql(
+                "
+ALTER TABLE indexer_state
+    ADD COLUMN IF NOT EXISTS derived_tables_stale BOOLEAN NOT NULL DEFAULT false;",
+            ),
+            MigrationStep::Rust(
+                "if the database is mid-bootstrap, mark its derived tables to be rebuilt on the next run",
+                mark_derived_tables_stale,
+            ),
+        ],
+    }]
+}
+
+/// Older versions only derived the _live and _ordered tables once the
+/// bootstrap was done, those of a database left mid-bootstrap are stale.
+fn mark_derived_tables_stale(tx: &mut Transaction) -> Result<()> {
+    let updated = tx.execute(
+        "
+UPDATE indexer_state
+SET derived_tables_stale = true
+WHERE mode = 'Bootstrap'",
+        &[],
+    )?;
+    if updated > 0 {
+        info!("the database is mid-bootstrap, its derived tables are rebuilt on the next run");
+    }
+    Ok(())
+}
+
+pub(crate) fn schema_version(v: &str) -> String {
+    match v {
+        // The first versions of Que Pasa didn't follow the semantics of using
+        // minor versioning for non-db schema related changes only
+        "1.0.0" | "1.0.1" | "1.0.2" | "1.0.3" | "1.0.4" | "1.0.5" => {
+            v.to_string()
+        }
+        _ => {
+            // Minor version bumps (_._.x) have same db schemas
+            v.to_string()
+                .rsplit_once('.')
+                .map(|(db_ver, _)| db_ver.to_string())
+                .unwrap_or_default()
+        }
+    }
+}
+
+/// Returns the migrations that bring a database initialized with Que Pasa
+/// db_version up to date with Que Pasa version target_version, in the order
+/// in which they are to be applied.
+fn plan<'a>(
+    migrations: &'a [Migration],
+    db_version: &str,
+    target_version: &str,
+) -> Result<Vec<&'a Migration>> {
+    let target = schema_version(target_version);
+    let mut at = schema_version(db_version);
+
+    let mut res: Vec<&Migration> = vec![];
+    while at != target {
+        let next = migrations
+            .iter()
+            .find(|m| m.from == at)
+            .ok_or_else(|| {
+                anyhow!(
+                    "no migration path from Que Pasa {} to {} (stuck at schema version {})",
+                    db_version,
+                    target_version,
+                    at
+                )
+            })?;
+        if res.iter().any(|m| m.from == next.to) {
+            return Err(anyhow!(
+                "migrations form a cycle at schema version {}",
+                next.to
+            ));
+        }
+        res.push(next);
+        at = next.to.to_string();
+    }
+    Ok(res)
+}
+
+/// Returns the migrations needed to bring the database up to date with this
+/// version of Que Pasa.
+pub(crate) fn plan_migrations(
+    dbcli: &mut dyn Backend,
+) -> Result<Vec<Migration>> {
+    let db_version = dbcli.get_quepasa_version()?;
+    let all = migrations();
+    let planned: Vec<(&str, &str)> =
+        plan(&all, &db_version, crate::config::QUEPASA_VERSION)?
+            .iter()
+            .map(|m| (m.from, m.to))
+            .collect();
+    Ok(all
+        .into_iter()
+        .filter(|m| planned.contains(&(m.from, m.to)))
+        .collect())
+}
+
+pub(crate) fn print_plan(migrations: &[Migration]) {
+    if migrations.is_empty() {
+        info!("no migrations to apply, the database is up to date");
+        return;
+    }
+    for (i, m) in migrations.iter().enumerate() {
+        info!(
+            "migration {}/{}: {} -> {}: {}",
+            i + 1,
+            migrations.len(),
+            m.from,
+            m.to,
+            m.description
+        );
+        for step in &m.steps {
+            match step {
+                MigrationStep::Sql(sql) => info!("  sql: {}", sql.trim()),
+                MigrationStep::Rust(description, _) => {
+                    info!("  step: {}", description)
+                }
+            }
+        }
+    }
+}
+
+/// Applies the migrations in a single transaction, and marks the database as
+/// being of this version of Que Pasa. Either all migrations are applied, or
+/// none are.
+pub(crate) fn apply_migrations(
+    dbcli: &mut DBClient,
+    migrations: &[Migration],
+) -> Result<()> {
+    let mut conn = dbcli.dbconn()?;
+    let mut tx = conn.transaction()?;
+    for m in migrations {
+        info!(
+            "applying migration {} -> {}: {}",
+            m.from, m.to, m.description
+        );
+        for step in &m.steps {
+            match step {
+                MigrationStep::Sql(sql) => tx.batch_execute(sql)?,
+                MigrationStep::Rust(_, f) => f(&mut tx)?,
+            }
+        }
+    }
+    tx.execute(
+        "UPDATE indexer_state SET quepasa_version = $1",
+        &[&crate::config::QUEPASA_VERSION],
+    )
+    .with_context(|| "failed to update the database's version")?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_plan() {
+    let migrations = vec![
+        Migration {
+            from: "1.2",
+            to: "1.3",
+            description: "a",
+            steps: vec![],
+        },
+        Migration {
+            from: "1.3",
+            to: "1.4",
+            description: "b",
+            steps: vec![
+                MigrationStep::Sql("ALTER TABLE levels ADD COLUMN x INT"),
+                MigrationStep::Rust("nothing", |_| Ok(())),
+            ],
+        },
+        Migration {
+            from: "1.4",
+            to: "2.0",
+            description: "c",
+            steps: vec![],
+        },
+    ];
+
+    struct TestCase {
+        db_version: &'static str,
+        target_version: &'static str,
+        exp: Option<Vec<&'static str>>,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            db_version: "1.2.3",
+            target_version: "1.2.7",
+            exp: Some(vec![]),
+        },
+        TestCase {
+            db_version: "1.2.3",
+            target_version: "1.3.0",
+            exp: Some(vec!["a"]),
+        },
+        TestCase {
+            db_version: "1.2.7",
+            target_version: "2.0.1",
+            exp: Some(vec!["a", "b", "c"]),
+        },
+        TestCase {
+            db_version: "1.3.0",
+            target_version: "1.4.0",
+            exp: Some(vec!["b"]),
+        },
+        TestCase {
+            db_version: "1.0.5",
+            target_version: "1.2.7",
+            exp: None,
+        },
+        TestCase {
+            db_version: "1.4.0",
+            target_version: "1.3.0",
+            exp: None,
+        },
+    ];
+
+    for tc in tests {
+        println!("test case: {} -> {}", tc.db_version, tc.target_version);
+        let got = plan(&migrations, tc.db_version, tc.target_version)
+            .ok()
+            .map(|res| {
+                res.iter()
+                    .map(|m| m.description)
+                    .collect::<Vec<&str>>()
+            });
+        assert_eq!(tc.exp, got);
+    }
+}
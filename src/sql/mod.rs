@@ -1,7 +1,14 @@
+pub mod backend;
+pub mod catalog;
 pub mod db;
 pub mod insert;
 pub mod inserter;
+pub mod migrations;
+pub mod notify;
 pub mod postgresql_generator;
+pub mod snapshots;
+pub mod sqlite;
+pub mod sqlite_generator;
 pub mod table;
 pub mod table_builder;
 pub mod types;
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+use crate::octez::block::LevelMeta;
+use crate::sql::inserter::ProcessedBlock;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+// PostgreSQL's limit on the size of a notification's payload (in bytes,
+// exclusive)
+const MAX_PAYLOAD_SIZE: usize = 8000;
+
+// max number of levels per fork notification, so that its payload stays
+// below the limit
+const MAX_FORKED_LEVELS: usize = 500;
+
+#[derive(Serialize, Debug)]
+struct TouchedContract {
+    contract: String,
+    // the tables the level inserted rows into (their _live and _ordered
+    // tables are updated accordingly)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tables: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Notification {
+    Level {
+        level: u32,
+        hash: Option<String>,
+        contracts: Vec<TouchedContract>,
+        // set if the payload would exceed the size limit with all of the
+        // touched contracts and tables, the tables (or the contracts
+        // entirely) are left out then
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        truncated: bool,
+    },
+    Fork {
+        levels: Vec<u32>,
+    },
+}
+
+/// The payload of the notification that the level was committed: its level,
+/// hash and the contracts it touched (those it has txs or inserted rows for)
+/// along with their tables
+pub(crate) fn level_payload(
+    meta: &LevelMeta,
+    processed_block: &ProcessedBlock,
+) -> Result<String> {
+    let mut touched: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for cres in processed_block {
+        if cres.inserts.is_empty() && cres.tx_contexts.is_empty() {
+            continue;
+        }
+        let tables = touched
+            .entry(cres.contract.cid.name.clone())
+            .or_default();
+        tables.extend(
+            cres.inserts
+                .iter()
+                .map(|insert| insert.table_name.clone()),
+        );
+        tables.sort();
+        tables.dedup();
+    }
+
+    let notification =
+        |with_contracts: bool, with_tables: bool| Notification::Level {
+            level: meta.level,
+            hash: meta.hash.clone(),
+            contracts: match with_contracts {
+                true => touched
+                    .iter()
+                    .map(|(contract, tables)| TouchedContract {
+                        contract: contract.clone(),
+                        tables: match with_tables {
+                            true => Some(tables.clone()),
+                            false => None,
+                        },
+                    })
+                    .collect(),
+                false => vec![],
+            },
+            truncated: !with_tables,
+        };
+    let mut payload = serde_json::to_string(&notification(true, true))?;
+    if payload.len() >= MAX_PAYLOAD_SIZE {
+        payload = serde_json::to_string(&notification(true, false))?;
+    }
+    if payload.len() >= MAX_PAYLOAD_SIZE {
+        payload = serde_json::to_string(&notification(false, false))?;
+    }
+    Ok(payload)
+}
+
+/// The payloads of the notifications that the levels were deleted because
+/// of a fork (they are processed again right after)
+pub(crate) fn forked_levels_payloads(levels: &[u32]) -> Result<Vec<String>> {
+    let mut levels = levels.to_vec();
+    levels.sort_unstable();
+    levels
+        .chunks(MAX_FORKED_LEVELS)
+        .map(|chunk| {
+            Ok(serde_json::to_string(&Notification::Fork {
+                levels: chunk.to_vec(),
+            })?)
+        })
+        .collect()
+}
+
+#[test]
+fn test_level_payload() {
+    use crate::config::ContractID;
+    use crate::octez::block::TxContext;
+    use crate::sql::insert::Insert;
+    use crate::sql::inserter::ProcessedContractBlock;
+    use crate::storage_structure::relational::{self, ASTBuilder};
+    use crate::storage_structure::typing::type_ast_from_json;
+    use std::collections::HashMap;
+
+    let meta = LevelMeta {
+        level: 10,
+        hash: Some("BLx".to_string()),
+        prev_hash: Some("BLw".to_string()),
+        baked_at: None,
+    };
+    let storage_ast = ASTBuilder::new("storage")
+        .build_relational_ast(
+            &type_ast_from_json(&serde_json::json!({"prim": "unit"})).unwrap(),
+        )
+        .unwrap();
+    let contract_block =
+        |name: &str, tables: &[&str], txs: usize| ProcessedContractBlock {
+            level: meta.clone(),
+            contract: relational::Contract {
+                cid: ContractID {
+                    name: name.to_string(),
+                    address: "KT1".to_string(),
+                },
+                level_floor: None,
+                storage_ast: storage_ast.clone(),
+                entrypoint_asts: HashMap::new(),
+                tables: HashMap::new(),
+                selection: Default::default(),
+            },
+            is_origination: false,
+            inserts: tables
+                .iter()
+                .map(|table| Insert {
+                    table_name: table.to_string(),
+                    id: 1,
+                    fk_id: None,
+                    columns: vec![],
+                })
+                .collect(),
+            tx_contexts: (0..txs)
+                .map(|i| TxContext {
+                    id: Some(i as i64),
+                    contract: "KT1".to_string(),
+                    level: 10,
+                    operation_group_number: i,
+                    operation_number: 0,
+                    content_number: 0,
+                    internal_number: None,
+                })
+                .collect(),
+            txs: vec![],
+            bigmap_contract_deps: vec![],
+            bigmap_keyhashes: HashMap::new(),
+            bigmap_meta_actions: vec![],
+        };
+
+    struct TestCase {
+        name: String,
+        processed_block: ProcessedBlock,
+        exp: String,
+    }
+    let many_tables: Vec<String> = (0..1000)
+        .map(|i| format!("storage.table_{}", i))
+        .collect();
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "untouched contracts are left out".to_string(),
+            processed_block: vec![
+                contract_block("b", &["storage", "storage.ledger", "storage"], 1),
+                contract_block("a", &[], 1),
+                contract_block("c", &[], 0),
+            ],
+            exp: r#"{"kind":"level","level":10,"hash":"BLx","contracts":[{"contract":"a","tables":[]},{"contract":"b","tables":["storage","storage.ledger"]}]}"#.to_string(),
+        },
+        TestCase {
+            name: "tables left out when too long".to_string(),
+            processed_block: vec![contract_block(
+                "a",
+                &many_tables.iter().map(|t| t.as_str()).collect::<Vec<&str>>(),
+                1,
+            )],
+            exp: r#"{"kind":"level","level":10,"hash":"BLx","contracts":[{"contract":"a"}],"truncated":true}"#.to_string(),
+        },
+    ];
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        assert_eq!(tc.exp, level_payload(&meta, &tc.processed_block).unwrap());
+    }
+
+    assert_eq!(
+        vec![r#"{"kind":"fork","levels":[9,10]}"#.to_string()],
+        forked_levels_payloads(&[10, 9]).unwrap()
+    );
+}
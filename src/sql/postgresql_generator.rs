@@ -81,13 +81,15 @@ impl PostgresqlGenerator {
             ExprTy::Address => Some(Self::address(&name)),
             ExprTy::Bool => Some(Self::bool(&name)),
             ExprTy::Bytes => Some(Self::bytes(&name)),
-            ExprTy::Int | ExprTy::Nat | ExprTy::Mutez => {
-                Some(Self::numeric(&name))
-            }
+            ExprTy::Int
+            | ExprTy::Nat
+            | ExprTy::Mutez
+            | ExprTy::SaplingState => Some(Self::numeric(&name)),
             ExprTy::KeyHash | ExprTy::Signature | ExprTy::Contract => {
                 Some(Self::string(&name))
             }
             ExprTy::Stop => None,
+            ExprTy::Micheline => Some(Self::jsonb(&name)),
             ExprTy::String => Some(Self::string(&name)),
             ExprTy::Timestamp => Some(Self::timestamp(&name)),
             ExprTy::Unit => Some(Self::unit(&name)),
@@ -114,6 +116,10 @@ impl PostgresqlGenerator {
         format!("{} TEXT", name)
     }
 
+    pub(crate) fn jsonb(name: &str) -> String {
+        format!("{} JSONB", name)
+    }
+
     pub(crate) fn numeric(name: &str) -> String {
         format!("{} NUMERIC", name)
     }
@@ -358,7 +364,10 @@ impl PostgresqlGenerator {
             // changes at later levels
             return None;
         }
-        Self::parent_name(&table.name)
+        match table.parent() {
+            Some(parent) => parent,
+            None => Self::parent_name(&table.name),
+        }
     }
 
     pub(crate) fn parent_name(name: &str) -> Option<String> {
@@ -428,6 +437,15 @@ impl PostgresqlGenerator {
         &self,
         table: &Table,
     ) -> Result<Vec<String>> {
+        let (live, ordered) = Self::derived_tables(table);
+        Ok(vec![
+            self.create_table_definition(&live)?,
+            self.create_table_definition(&ordered)?,
+        ])
+    }
+
+    /// The _live and _ordered tables derived from the table
+    pub(crate) fn derived_tables(table: &Table) -> (Table, Table) {
         let mut live = table.clone();
         live.name = format!("{}_live", live.name);
         live.add_column("level", &ExprTy::Int);
@@ -452,10 +470,7 @@ impl PostgresqlGenerator {
         ordered.add_fk("id".to_string(), table.name.clone(), "id".to_string());
         ordered.id_unique = false;
 
-        Ok(vec![
-            self.create_table_definition(&live)?,
-            self.create_table_definition(&ordered)?,
-        ])
+        (live, ordered)
     }
 
     /*
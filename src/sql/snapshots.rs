@@ -0,0 +1,261 @@
+use crate::octez::block::TxContext;
+use crate::sql::db::DBClient;
+use crate::sql::insert::{Insert, Value};
+use crate::sql::table_builder::TableBuilder;
+use crate::storage_structure::relational;
+use anyhow::Result;
+use postgres::Transaction;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// A storage snapshot's tx context id and hash, and for a skipped snapshot
+/// the tx context id of the snapshot it is the same as
+pub(crate) type StorageSnapshot = (i64, String, Option<i64>);
+
+/// Returns the hash of the contract's last snapshot before the given tx
+/// contexts, along with the tx context its rows are stored under.
+pub(crate) fn get_prev_snapshot(
+    tx: &mut Transaction,
+    tx_contexts: &[TxContext],
+) -> Result<Option<(String, i64)>> {
+    match tx_contexts.iter().min() {
+        Some(first) => DBClient::get_last_storage_snapshot(tx, first),
+        None => Ok(None),
+    }
+}
+
+/// Leaves out the storage snapshots that are the same as the contract's
+/// previous snapshot (prev, see get_prev_snapshot), they are stored as a
+/// reference to that snapshot instead (in the storage_snapshots table,
+/// which the derived tables and the table functions resolve). Returns the
+/// storage_snapshots rows to save. Doesn't touch the database, so that the
+/// snapshots of different contracts can be compared concurrently.
+pub(crate) fn skip_unchanged_snapshots(
+    contract: &relational::Contract,
+    tx_contexts: &[TxContext],
+    inserts: &mut Vec<Insert>,
+    mut prev: Option<(String, i64)>,
+) -> Result<Vec<StorageSnapshot>> {
+    let mut tx_contexts: Vec<&TxContext> = tx_contexts.iter().collect();
+    tx_contexts.sort();
+
+    let (tables, noview_prefixes, _) =
+        TableBuilder::tables_from_contract(contract);
+    let known_tables: HashSet<&str> = tables
+        .iter()
+        .map(|t| t.name.as_str())
+        .collect();
+    // The tables _live and _ordered tables are derived for, ie the storage
+    // tables outside of big maps
+    let snapshot_tables: HashSet<&str> = tables
+        .iter()
+        .filter(|t| {
+            t.contains_snapshots()
+                && !noview_prefixes
+                    .iter()
+                    .any(|prefix| t.path().starts_with(prefix))
+        })
+        .map(|t| t.name.as_str())
+        .collect();
+
+    let mut rows: HashMap<i64, Vec<&Insert>> = HashMap::new();
+    for insert in inserts.iter() {
+        rows.entry(insert.get_tx_context_id()?)
+            .or_default()
+            .push(insert);
+    }
+
+    let mut snapshots: Vec<StorageSnapshot> = vec![];
+    for ctx in tx_contexts {
+        let id = ctx.id.unwrap();
+        let ctx_rows = match rows.get(&id) {
+            Some(ctx_rows) => ctx_rows,
+            None => continue,
+        };
+        let snapshot_rows: Vec<&Insert> = ctx_rows
+            .iter()
+            .filter(|row| snapshot_tables.contains(row.table_name.as_str()))
+            .cloned()
+            .collect();
+        if snapshot_rows.is_empty() {
+            continue;
+        }
+        // Rows of tables of another version of the contract's type may
+        // reference the snapshot's rows, then the snapshot is kept as is
+        let skippable = ctx_rows
+            .iter()
+            .all(|row| known_tables.contains(row.table_name.as_str()));
+
+        let hash = snapshot_hash(&snapshot_rows)?;
+        match &prev {
+            Some((prev_hash, source)) if skippable && *prev_hash == hash => {
+                snapshots.push((id, hash, Some(*source)));
+            }
+            _ => {
+                snapshots.push((id, hash.clone(), None));
+                prev = Some((hash, id));
+            }
+        }
+    }
+
+    let skipped: HashSet<i64> = snapshots
+        .iter()
+        .filter(|(_, _, source)| source.is_some())
+        .map(|(id, _, _)| *id)
+        .collect();
+    let owned_snapshot_tables: HashSet<String> = snapshot_tables
+        .iter()
+        .map(|t| t.to_string())
+        .collect();
+    inserts.retain(|insert| {
+        !owned_snapshot_tables.contains(&insert.table_name)
+            || !insert
+                .get_tx_context_id()
+                .is_ok_and(|id| skipped.contains(&id))
+    });
+
+    Ok(snapshots)
+}
+
+/// Hashes a snapshot's rows, leaving out their ids (and tx_context_id), so
+/// that snapshots of the same storage hash the same whatever ids they were
+/// given.
+fn snapshot_hash(rows: &[&Insert]) -> Result<String> {
+    let ids: HashSet<i64> = rows.iter().map(|row| row.id).collect();
+    let mut children: HashMap<i64, Vec<&Insert>> = HashMap::new();
+    let mut roots: Vec<&Insert> = vec![];
+    for row in rows {
+        match row
+            .fk_id
+            .filter(|fk_id| ids.contains(fk_id))
+        {
+            Some(fk_id) => children
+                .entry(fk_id)
+                .or_default()
+                .push(row),
+            None => roots.push(row),
+        }
+    }
+
+    fn canonical(
+        row: &Insert,
+        children: &HashMap<i64, Vec<&Insert>>,
+    ) -> Result<String> {
+        let mut columns: Vec<(&str, &Value)> = row
+            .columns
+            .iter()
+            .filter(|column| column.name != "tx_context_id")
+            .map(|column| (column.name.as_str(), &column.value))
+            .collect();
+        columns.sort();
+        let mut nested: Vec<String> = children
+            .get(&row.id)
+            .into_iter()
+            .flatten()
+            .map(|child| canonical(child, children))
+            .collect::<Result<Vec<String>>>()?;
+        nested.sort();
+        Ok(serde_json::to_string(&(&row.table_name, columns, nested))?)
+    }
+
+    let mut canonical_roots: Vec<String> = roots
+        .iter()
+        .map(|row| canonical(row, &children))
+        .collect::<Result<Vec<String>>>()?;
+    canonical_roots.sort();
+    Ok(hex::encode(Sha256::digest(
+        serde_json::to_string(&canonical_roots)?.as_bytes(),
+    )))
+}
+
+#[test]
+fn test_snapshot_hash() {
+    use crate::sql::insert::Column;
+
+    fn row(
+        table_name: &str,
+        id: i64,
+        fk_id: Option<i64>,
+        tx_context_id: i64,
+        columns: Vec<(&str, Value)>,
+    ) -> Insert {
+        let mut res = Insert {
+            table_name: table_name.to_string(),
+            id,
+            fk_id,
+            columns: vec![Column {
+                name: "tx_context_id".to_string(),
+                value: Value::BigInt(tx_context_id),
+            }],
+        };
+        for (name, value) in columns {
+            res.columns.push(Column {
+                name: name.to_string(),
+                value,
+            });
+        }
+        res
+    }
+    fn snapshot(offset: i64, admin: &str, tokens: &[i32]) -> Vec<Insert> {
+        let mut res = vec![row(
+            "storage",
+            offset,
+            None,
+            offset + 100,
+            vec![("admin", Value::String(admin.to_string()))],
+        )];
+        for (i, token) in tokens.iter().enumerate() {
+            res.push(row(
+                "storage.tokens",
+                offset + 1 + i as i64,
+                Some(offset),
+                offset + 100,
+                vec![("idx_int", Value::Int(*token))],
+            ));
+        }
+        res
+    }
+    fn hash(rows: &[Insert]) -> String {
+        snapshot_hash(&rows.iter().collect::<Vec<&Insert>>()).unwrap()
+    }
+
+    struct TestCase {
+        name: String,
+        left: Vec<Insert>,
+        right: Vec<Insert>,
+        exp_equal: bool,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "same storage, other ids".to_string(),
+            left: snapshot(10, "tz1", &[1, 2]),
+            right: snapshot(20, "tz1", &[1, 2]),
+            exp_equal: true,
+        },
+        TestCase {
+            name: "same storage, rows in another order".to_string(),
+            left: snapshot(10, "tz1", &[1, 2]),
+            right: snapshot(20, "tz1", &[2, 1]),
+            exp_equal: true,
+        },
+        TestCase {
+            name: "changed value".to_string(),
+            left: snapshot(10, "tz1", &[1, 2]),
+            right: snapshot(20, "tz2", &[1, 2]),
+            exp_equal: false,
+        },
+        TestCase {
+            name: "removed child row".to_string(),
+            left: snapshot(10, "tz1", &[1, 2]),
+            right: snapshot(20, "tz1", &[1]),
+            exp_equal: false,
+        },
+    ];
+    for tc in tests {
+        println!("test case: {}", tc.name);
+        assert_eq!(tc.exp_equal, hash(&tc.left) == hash(&tc.right));
+    }
+}
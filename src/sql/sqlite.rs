@@ -0,0 +1,1732 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use pg_bigdecimal::PgNumeric;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rusqlite::types::{Null, ToSqlOutput};
+use rusqlite::{
+    params, params_from_iter, Connection, OptionalExtension, ToSql, Transaction,
+};
+
+use crate::config::ContractID;
+use crate::metrics;
+use crate::octez::block::{LevelMeta, Tx, TxContext};
+use crate::octez::levels_file::ContractLevel;
+use crate::octez::node::NodeClient;
+use crate::sql::backend::{Backend, ContractRelGetter};
+use crate::sql::catalog;
+use crate::sql::db::{
+    BigmapEntries, BigmapEntry, BigmapKeysGetter, ContractRequestStatus,
+    IndexerMode,
+};
+use crate::sql::insert::{Insert, Value};
+use crate::sql::inserter::{ClearedLevel, ProcessedBatch, ProcessedBlock};
+use crate::sql::migrations::Migration;
+use crate::sql::postgresql_generator::PostgresqlGenerator;
+use crate::sql::sqlite_generator::SqliteGenerator;
+use crate::sql::table::Table;
+use crate::sql::table_builder::TableBuilder;
+use crate::sql::types::BigmapMetaAction;
+use crate::stats::StatsLogger;
+use crate::storage_structure::relational;
+
+/// A SQLite database (see Backend). All of its tables are in one namespace,
+/// the common tables are named as in PostgreSQL's main schema and the
+/// contracts' tables are prefixed with the contract's name (see
+/// SqliteGenerator). SQLite has a single writer, so the data of a batch is
+/// inserted in one transaction, and the derived tables of its contracts are
+/// derived again from scratch.
+#[derive(Clone)]
+pub struct SqliteClient {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteClient {
+    // max number of values in an IN (..) list, SQLite limits the number of
+    // parameters of a statement
+    const IN_LIST_SIZE: usize = 100;
+
+    /// Opens the database at the path, sqlite::memory: opens an in-memory
+    /// database
+    pub(crate) fn connect(path: &str) -> Result<Self> {
+        let conn = match path {
+            ":memory:" => Connection::open_in_memory()?,
+            _ => Connection::open(path).with_context(|| {
+                format!("failed to open sqlite database {}", path)
+            })?,
+        };
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn dbconn(&self) -> Result<MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|err| anyhow!("err: {}", err))
+    }
+
+    fn in_list(from: usize, n: usize) -> String {
+        (from..from + n)
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn create_table_stmnts(
+        generator: &SqliteGenerator,
+        table: &Table,
+        noview_prefixes: &[String],
+    ) -> Vec<String> {
+        let mut stmnts = vec![generator.create_table_definition(table)];
+        if !noview_prefixes
+            .iter()
+            .any(|prefix| table.path().starts_with(prefix))
+        {
+            stmnts.extend(generator.create_derived_table_definitions(table));
+        }
+        stmnts
+    }
+
+    fn contract_table_names(
+        tx: &Transaction,
+        contract_id: &ContractID,
+    ) -> Result<Vec<String>> {
+        let prefix = format!("{}.", contract_id.name);
+        let mut stmt = tx.prepare(
+            "
+SELECT name
+FROM sqlite_master
+WHERE type = 'table'
+  AND substr(name, 1, length(?1)) = ?1
+ORDER BY name DESC",
+        )?;
+        let names = stmt
+            .query_map([&prefix], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(names
+            .into_iter()
+            .map(|name| name[prefix.len()..].to_string())
+            .collect())
+    }
+
+    fn save_catalog(
+        tx: &Transaction,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let (tables, columns) = catalog::contract_catalog(contract);
+
+        let mut stmt = tx.prepare(
+            "
+INSERT INTO catalog_tables (contract, table_name, path, kind, parent_table, parent_fk_column)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+ON CONFLICT (contract, table_name) DO UPDATE
+SET path = excluded.path,
+    kind = excluded.kind,
+    parent_table = excluded.parent_table,
+    parent_fk_column = excluded.parent_fk_column",
+        )?;
+        for table in &tables {
+            stmt.execute(params![
+                contract.cid.name,
+                table.table_name,
+                table.path,
+                table.kind,
+                table.parent_table,
+                table.parent_fk_column,
+            ])?;
+        }
+
+        let mut stmt = tx.prepare(
+            "
+INSERT INTO catalog_columns (contract, table_name, column_name, original_name, michelson_type, annotation, is_index)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+ON CONFLICT (contract, table_name, column_name) DO UPDATE
+SET original_name = excluded.original_name,
+    michelson_type = excluded.michelson_type,
+    annotation = excluded.annotation,
+    is_index = excluded.is_index",
+        )?;
+        for column in &columns {
+            stmt.execute(params![
+                contract.cid.name,
+                column.table_name,
+                column.column_name,
+                column.original_name,
+                column.michelson_type,
+                column.annotation,
+                column.is_index,
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn get_level_internal(
+        &mut self,
+        level: Option<u32>,
+    ) -> Result<Option<LevelMeta>> {
+        let conn = self.dbconn()?;
+
+        let res = conn
+            .query_row(
+                "
+SELECT
+    level, hash, prev_hash, baked_at
+FROM levels
+WHERE (?1 IS NULL AND level = (
+    SELECT lvl.level
+    FROM levels lvl
+    WHERE EXISTS (
+        SELECT 1 FROM contract_levels clvl WHERE clvl.level = lvl.level
+    )
+    ORDER BY lvl.level DESC
+    LIMIT 1
+)) OR level = ?1",
+                [level],
+                |row| {
+                    Ok(LevelMeta {
+                        level: row.get(0)?,
+                        hash: row.get(1)?,
+                        prev_hash: row.get(2)?,
+                        baked_at: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(res)
+    }
+
+    fn query_levels(
+        &self,
+        qry: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<u32>> {
+        let conn = self.dbconn()?;
+        let mut stmt = conn.prepare(qry)?;
+        let levels = stmt
+            .query_map(params, |row| row.get::<_, u32>(0))?
+            .collect::<rusqlite::Result<Vec<u32>>>()?;
+        Ok(levels)
+    }
+
+    fn update_derived_tables(
+        tx: &Transaction,
+        contract: &relational::Contract,
+        tx_contexts: &[TxContext],
+    ) -> Result<()> {
+        if tx_contexts.is_empty() {
+            return Ok(());
+        }
+        let tx_context_ids: Vec<i64> = tx_contexts
+            .iter()
+            .map(|ctx| ctx.id.unwrap())
+            .collect();
+        let (mut tables, noview_prefixes, _) =
+            TableBuilder::tables_from_contract(contract);
+        // A snapshot table's _live table is derived from its parent table's
+        // _ordered table, so parent tables go first
+        tables.sort_by_key(|t| t.path().to_string());
+
+        let generator = SqliteGenerator::new(&contract.cid);
+        for table in &tables {
+            if noview_prefixes
+                .iter()
+                .any(|prefix| table.path().starts_with(prefix))
+            {
+                continue;
+            }
+            tx.execute_batch(
+                &generator.update_derived_tables(table, &tx_context_ids)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn repopulate_derived_tables(
+        tx: &Transaction,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let (mut tables, noview_prefixes, _) =
+            TableBuilder::tables_from_contract(contract);
+        // A snapshot table's _live table is derived from its parent table,
+        // so parent tables go first
+        tables.sort_by_key(|t| t.path().to_string());
+
+        let generator = SqliteGenerator::new(&contract.cid);
+        for table in &tables {
+            if noview_prefixes
+                .iter()
+                .any(|prefix| table.path().starts_with(prefix))
+            {
+                continue;
+            }
+            tx.execute_batch(&generator.repopulate_derived_tables(table)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for SqliteClient {
+    fn clone_backend(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+
+    fn get_quepasa_version(&mut self) -> Result<String> {
+        let conn = self.dbconn()?;
+        Ok(conn.query_row(
+            "SELECT quepasa_version FROM indexer_state",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn common_tables_exist(&mut self) -> Result<bool> {
+        let conn = self.dbconn()?;
+        let res = conn
+            .query_row(
+                "
+SELECT 1
+FROM sqlite_master
+WHERE type = 'table'
+  AND name = 'levels'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(res.is_some())
+    }
+
+    fn create_common_tables(&mut self) -> Result<()> {
+        let conn = self.dbconn()?;
+        conn.execute_batch(&SqliteGenerator::create_common_tables())?;
+        Ok(())
+    }
+
+    fn delete_everything(
+        &mut self,
+        _node_cli: &NodeClient,
+        _get_contract_rel: ContractRelGetter,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let tx = conn.transaction()?;
+        // Tables reference each other, the references are only checked once
+        // all are dropped
+        tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+        let objects: Vec<(String, String)> = tx
+            .prepare(
+                "
+SELECT type, name
+FROM sqlite_master
+WHERE type IN ('table', 'view')
+  AND name NOT LIKE 'sqlite_%'
+ORDER BY type DESC, name DESC",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        for (kind, name) in objects {
+            tx.execute_batch(&format!(
+                "DROP {} IF EXISTS {}",
+                kind.to_uppercase(),
+                PostgresqlGenerator::quote_id(&name)
+            ))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_contract(
+        &mut self,
+        _node_cli: &NodeClient,
+        name: &str,
+        _get_contract_rel: ContractRelGetter,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let tx = conn.transaction()?;
+
+        let contract_id = tx
+            .query_row(
+                "SELECT name, address FROM contracts WHERE name = ?1",
+                [name],
+                |row| {
+                    Ok(ContractID {
+                        name: row.get(0)?,
+                        address: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("contract {} is not in the db", name))?;
+
+        info!("deleting tables of contract {}", contract_id.name);
+        tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+        for table in Self::contract_table_names(&tx, &contract_id)? {
+            tx.execute_batch(&format!(
+                "DROP TABLE {}",
+                SqliteGenerator::table_name(&contract_id, &table)
+            ))?;
+        }
+
+        // txs, bigmap_keys and bigmap_meta_actions are cleaned up by cascade
+        tx.execute(
+            "DELETE FROM tx_contexts WHERE contract = ?1",
+            [&contract_id.address],
+        )?;
+        for qry in [
+            "DELETE FROM contract_deps WHERE dest_schema = ?1",
+            "DELETE FROM contract_levels WHERE contract = ?1",
+            "DELETE FROM contract_requests WHERE name = ?1",
+            "DELETE FROM catalog_tables WHERE contract = ?1",
+            "DELETE FROM catalog_columns WHERE contract = ?1",
+            "DELETE FROM contracts WHERE name = ?1",
+        ] {
+            tx.execute(qry, [&contract_id.name])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn apply_migrations(&mut self, _migrations: &[Migration]) -> Result<()> {
+        Err(anyhow!("migrating SQLite databases is not supported"))
+    }
+
+    fn create_contract_schemas(
+        &mut self,
+        contracts: &mut Vec<relational::Contract>,
+    ) -> Result<bool> {
+        let mut conn = self.dbconn()?;
+        let tx = conn.transaction()?;
+
+        contracts.sort_by_key(|c| c.cid.name.clone());
+
+        let mut any_new = false;
+        for contract in contracts.iter() {
+            let inserted = tx.execute(
+                "
+INSERT INTO contracts (name, address)
+VALUES (?1, ?2)
+ON CONFLICT DO NOTHING",
+                params![contract.cid.name, contract.cid.address],
+            )?;
+            if inserted == 0 {
+                continue;
+            }
+            any_new = true;
+
+            let (mut tables, noview_prefixes, _) =
+                TableBuilder::tables_from_contract(contract);
+            tables.sort_by_key(|t| t.name.clone());
+
+            let generator = SqliteGenerator::new(&contract.cid);
+            for table in &tables {
+                for stmnt in Self::create_table_stmnts(
+                    &generator,
+                    table,
+                    &noview_prefixes,
+                ) {
+                    tx.execute_batch(&stmnt)?;
+                }
+            }
+            Self::save_catalog(&tx, contract)?;
+        }
+        tx.commit()?;
+        Ok(any_new)
+    }
+
+    /// See DBClient::add_contract_version_tables
+    fn add_contract_version_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let tx = conn.transaction()?;
+
+        let existing = Self::contract_table_names(&tx, &contract.cid)?;
+        let (mut tables, noview_prefixes, _) =
+            TableBuilder::tables_from_contract(contract);
+        tables.sort_by_key(|t| t.name.clone());
+
+        let generator = SqliteGenerator::new(&contract.cid);
+        let mut stmnts: Vec<String> = vec![];
+        for table in &tables {
+            if !existing.contains(&table.name) {
+                info!(
+                    "contract {}: adding table {} for a new version of its storage type",
+                    contract.cid.name, table.name
+                );
+                stmnts.extend(Self::create_table_stmnts(
+                    &generator,
+                    table,
+                    &noview_prefixes,
+                ));
+                continue;
+            }
+
+            let keywords = table.keywords();
+            let columns: Vec<(&String, String)> = table
+                .get_columns()
+                .into_iter()
+                .filter(|column| !keywords.contains(&column.name))
+                .filter_map(|column| {
+                    SqliteGenerator::create_sql(column)
+                        .map(|sql| (&column.name, sql))
+                })
+                .collect();
+            // Existing columns are never altered, a column whose type
+            // differs under this version of the storage type cannot hold
+            // its values
+            let existing_types: HashMap<String, String> = tx
+                .prepare("SELECT name, type FROM pragma_table_info(?1)")?
+                .query_map(
+                    [format!("{}.{}", contract.cid.name, table.name)],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?
+                .collect::<rusqlite::Result<HashMap<String, String>>>()?;
+            for (name, column) in &columns {
+                let column_type = column
+                    .trim_start_matches(&PostgresqlGenerator::quote_id(name))
+                    .trim();
+                match existing_types.get(*name) {
+                    Some(existing_type) if existing_type != column_type => {
+                        return Err(anyhow!(
+                            "contract {}: column {} of table {} is of type {}, but a version of the contract's storage type needs it to be of type {} (existing columns are never altered)",
+                            contract.cid.name,
+                            name,
+                            table.name,
+                            existing_type,
+                            column_type,
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            for derived in [
+                table.name.clone(),
+                format!("{}_live", table.name),
+                format!("{}_ordered", table.name),
+            ] {
+                if !existing.contains(&derived) {
+                    continue;
+                }
+                let table_name =
+                    SqliteGenerator::table_name(&contract.cid, &derived);
+                let present: Vec<String> = tx
+                    .prepare(&format!("SELECT * FROM {} LIMIT 0", table_name))?
+                    .column_names()
+                    .into_iter()
+                    .map(|name| name.to_string())
+                    .collect();
+                for (name, column) in &columns {
+                    if !present.contains(name) {
+                        stmnts.push(format!(
+                            "ALTER TABLE {} ADD COLUMN {};",
+                            table_name, column
+                        ));
+                    }
+                }
+            }
+        }
+        for stmnt in stmnts {
+            tx.execute_batch(&stmnt)?;
+        }
+        Self::save_catalog(&tx, contract)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn rebuild_derived_tables(
+        &mut self,
+        contract: &relational::Contract,
+    ) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let tx = conn.transaction()?;
+        Self::repopulate_derived_tables(&tx, contract)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_contracts(&mut self) -> Result<Vec<ContractID>> {
+        let conn = self.dbconn()?;
+        let mut stmt =
+            conn.prepare("SELECT name, address FROM contracts ORDER BY name")?;
+        let contracts = stmt
+            .query_map([], |row| {
+                Ok(ContractID {
+                    name: row.get(0)?,
+                    address: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ContractID>>>()?;
+        Ok(contracts)
+    }
+
+    fn insert_batch(
+        &mut self,
+        stats: Option<&StatsLogger>,
+        contracts_scoped: bool,
+        processed_blocks: Vec<ProcessedBlock>,
+        // SQLite has no notifications (see backend::connect)
+        _notifications: &[String],
+    ) -> Result<Vec<ClearedLevel>> {
+        let mut conn = self.dbconn()?;
+        let tx = conn.transaction()?;
+
+        let max_id: i64 =
+            tx.query_row("SELECT max_id FROM indexer_state", [], |row| {
+                row.get(0)
+            })?;
+        let mut batch = ProcessedBatch::new(max_id);
+        for processed_block in processed_blocks {
+            batch.add(processed_block);
+        }
+        tx.execute(
+            "UPDATE indexer_state SET max_id = ?1",
+            [batch.get_max_id()],
+        )?;
+
+        let mut batch_levels: Vec<i32> = batch.levels.keys().cloned().collect();
+        batch_levels.sort_unstable();
+        let cleared = if contracts_scoped {
+            delete_contracts_levels(
+                &tx,
+                &batch
+                    .contract_tx_contexts
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<ContractID>>(),
+                &batch_levels,
+            )?
+        } else {
+            delete_levels(&tx, &batch_levels)?
+                .into_iter()
+                .map(|level| ClearedLevel {
+                    level: level as u32,
+                    contract: None,
+                })
+                .collect()
+        };
+        save_levels(
+            &tx,
+            &batch
+                .levels
+                .values()
+                .collect::<Vec<&LevelMeta>>(),
+        )?;
+        save_contract_deps(&tx, &batch.contract_deps)?;
+        save_tx_contexts(&tx, &batch.tx_contexts)?;
+        save_txs(&tx, &batch.txs)?;
+        save_bigmap_keyhashes(&tx, &batch.bigmap_keyhashes)?;
+        save_bigmap_meta_actions(&tx, &batch.bigmap_meta_actions)?;
+
+        let mut contract_ids: Vec<ContractID> = batch
+            .contract_inserts
+            .keys()
+            .cloned()
+            .collect();
+        contract_ids.sort_by_key(|c| c.name.clone());
+        let mut inserted: HashMap<(String, String), usize> = HashMap::new();
+        for contract_id in contract_ids {
+            let inserts = batch
+                .contract_inserts
+                .remove(&contract_id)
+                .unwrap();
+            let (contract, tx_contexts) = batch
+                .contract_tx_contexts
+                .remove(&contract_id)
+                .unwrap();
+
+            if let Some(stats) = stats {
+                stats.add("inserter", "contract data rows", inserts.len())?;
+            }
+            for insert in &inserts {
+                *inserted
+                    .entry((
+                        contract.cid.name.clone(),
+                        insert.table_name.clone(),
+                    ))
+                    .or_default() += 1;
+            }
+            apply_inserts(&tx, &contract, &inserts)?;
+            Self::update_derived_tables(&tx, &contract, &tx_contexts)
+                .with_context(|| {
+                    format!(
+                        "insert failed (levels={:?}, contract={}): could not update derived tables",
+                        batch_levels, contract.cid.name,
+                    )
+                })?;
+        }
+        save_contract_levels(&tx, &batch.contract_levels)?;
+
+        tx.commit()?;
+
+        for ((contract, table), num_rows) in &inserted {
+            metrics::rows_inserted(contract, table, *num_rows);
+        }
+        Ok(cleared)
+    }
+
+    fn delete_levels(&mut self, levels: &[u32]) -> Result<()> {
+        let mut conn = self.dbconn()?;
+        let tx = conn.transaction()?;
+        delete_levels(
+            &tx,
+            &levels
+                .iter()
+                .map(|lvl| *lvl as i32)
+                .collect::<Vec<i32>>(),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_head(&mut self) -> Result<Option<LevelMeta>> {
+        self.get_level_internal(None)
+    }
+
+    fn get_level(&mut self, level: u32) -> Result<Option<LevelMeta>> {
+        self.get_level_internal(Some(level))
+    }
+
+    fn get_missing_levels(
+        &mut self,
+        contracts: &[ContractID],
+        end: u32,
+    ) -> Result<Vec<u32>> {
+        let mut res: Vec<u32> = vec![];
+        for contract_id in contracts {
+            info!(
+                "querying db to check for any missing levels of {}..",
+                contract_id.name
+            );
+            let start = self
+                .get_origination(contract_id)?
+                .unwrap_or(1);
+            res.extend(self.query_levels(
+                "
+WITH RECURSIVE s(i) AS (
+    SELECT ?2 WHERE ?2 <= ?3
+    UNION ALL
+    SELECT i + 1 FROM s WHERE i < ?3
+)
+SELECT
+    s.i
+FROM s
+LEFT JOIN contract_levels clvl
+  ON  clvl.contract = ?1
+  AND clvl.level = s.i
+WHERE clvl.level IS NULL
+ORDER BY 1",
+                params![contract_id.name, start, end],
+            )?);
+        }
+        res.sort_unstable();
+        res.dedup();
+        Ok(res)
+    }
+
+    fn get_forked_levels(&mut self) -> Result<Vec<u32>> {
+        self.query_levels(
+            "
+SELECT DISTINCT
+  level
+FROM (
+  SELECT
+    level,
+    prev_hash AS chain_prev_hash,
+    LAG(level) OVER w as db_prev_level,
+    LAG(hash) OVER w AS db_prev_hash
+  FROM levels
+  WINDOW w AS (ORDER BY level)
+) q
+WHERE chain_prev_hash != db_prev_hash
+  AND db_prev_level = level - 1",
+            &[],
+        )
+    }
+
+    fn get_fully_processed_levels(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<Vec<u32>> {
+        if contracts.is_empty() {
+            return Ok(vec![]);
+        }
+        let names: Vec<&String> = contracts
+            .iter()
+            .map(|c| &c.name)
+            .collect();
+        let n_contracts = contracts.len();
+        let mut params: Vec<&dyn ToSql> = vec![&n_contracts];
+        params.extend(
+            names
+                .iter()
+                .map(|name| name as &dyn ToSql),
+        );
+        self.query_levels(
+            &format!(
+                "
+SELECT
+    level
+FROM contract_levels
+WHERE contract IN ({})
+GROUP by 1
+HAVING COUNT(1) = ?1
+ORDER by 1",
+                Self::in_list(2, names.len())
+            ),
+            &params,
+        )
+    }
+
+    fn get_partial_processed_levels(
+        &mut self,
+        contracts: &[ContractID],
+    ) -> Result<Vec<u32>> {
+        if contracts.is_empty() {
+            return Ok(vec![]);
+        }
+        let names: Vec<&dyn ToSql> = contracts
+            .iter()
+            .map(|c| &c.name as &dyn ToSql)
+            .collect();
+        let in_list = Self::in_list(1, names.len());
+        self.query_levels(
+            &format!(
+                "
+with all_levels as (
+    select distinct
+        level
+    from contract_levels
+    where contract in ({in_list})
+)
+select distinct
+    lvl.level
+from all_levels lvl, contracts c
+left join contract_levels orig
+  on  orig.contract = c.name
+  and orig.is_origination
+where lvl.level >= coalesce(orig.level, 0)
+  and c.name in ({in_list})
+  and not exists (
+    select 1
+    from contract_levels clvl
+    where clvl.level = lvl.level
+      and clvl.contract = c.name
+)
+order by 1",
+                in_list = in_list
+            ),
+            &names,
+        )
+    }
+
+    fn mark_missing_levels_empty(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<u64> {
+        let conn = self.dbconn()?;
+        Ok(conn.execute(
+            "
+INSERT INTO contract_levels(contract, level)
+WITH RECURSIVE g(level) AS (
+    SELECT MIN(level) FROM contract_levels WHERE contract = ?1
+    UNION ALL
+    SELECT level + 1 FROM g WHERE level < (
+        SELECT MAX(level) FROM contract_levels WHERE contract = ?1
+    )
+)
+SELECT ?1, g.level
+FROM g
+LEFT JOIN contract_levels clvl
+  ON  clvl.contract = ?1
+  AND clvl.level = g.level
+WHERE g.level IS NOT NULL
+  AND clvl.level IS NULL",
+            [&contract_id.name],
+        )? as u64)
+    }
+
+    fn get_origination(
+        &mut self,
+        contract_id: &ContractID,
+    ) -> Result<Option<u32>> {
+        let res = self.query_levels(
+            "
+SELECT
+    level
+FROM contract_levels
+WHERE contract = ?1
+  AND is_origination = TRUE",
+            &[&contract_id.name],
+        )?;
+        match res.len() {
+            0 => Ok(None),
+            1 => Ok(Some(res[0])),
+            _ => Err(anyhow!("Too many results for get_origination")),
+        }
+    }
+
+    fn get_active_contract_levels(&mut self) -> Result<Vec<ContractLevel>> {
+        let conn = self.dbconn()?;
+        let mut stmt = conn.prepare(
+            "
+SELECT
+    c.address,
+    clvl.level
+FROM contract_levels clvl
+JOIN contracts c
+  ON c.name = clvl.contract
+WHERE clvl.is_origination
+   OR EXISTS (
+    SELECT 1
+    FROM tx_contexts ctx
+    WHERE ctx.level = clvl.level
+      AND ctx.contract = clvl.contract
+)
+   OR clvl.level = (
+    SELECT MAX(level)
+    FROM contract_levels
+    WHERE contract = clvl.contract
+)
+ORDER BY 1, 2",
+        )?;
+        let res = stmt
+            .query_map([], |row| {
+                Ok(ContractLevel {
+                    contract: row.get(0)?,
+                    level: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<ContractLevel>>>()?;
+        Ok(res)
+    }
+
+    fn get_indexer_mode(&mut self) -> Result<IndexerMode> {
+        let conn = self.dbconn()?;
+        let mode: String =
+            conn.query_row("SELECT mode FROM indexer_state", [], |row| {
+                row.get(0)
+            })?;
+        match mode.as_str() {
+            "Bootstrap" => Ok(IndexerMode::Bootstrap),
+            "Head" => Ok(IndexerMode::Head),
+            _ => Err(anyhow!("unknown indexer mode {}", mode)),
+        }
+    }
+
+    fn set_indexer_mode(&mut self, mode: IndexerMode) -> Result<()> {
+        let conn = self.dbconn()?;
+        let mode = match mode {
+            IndexerMode::Bootstrap => "Bootstrap",
+            IndexerMode::Head => "Head",
+        };
+        let updated =
+            conn.execute("UPDATE indexer_state SET mode = ?1", [mode])?;
+        if updated == 1 {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "wrong number of rows in indexer_state table. please fix manually. sorry"
+            ))
+        }
+    }
+
+    fn get_derived_tables_stale(&mut self) -> Result<bool> {
+        let conn = self.dbconn()?;
+        Ok(conn.query_row(
+            "SELECT derived_tables_stale FROM indexer_state",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn set_derived_tables_stale(&mut self, stale: bool) -> Result<()> {
+        let conn = self.dbconn()?;
+        conn.execute(
+            "UPDATE indexer_state SET derived_tables_stale = ?1",
+            [stale],
+        )?;
+        Ok(())
+    }
+
+    fn get_config_deps(
+        &mut self,
+        config: &[ContractID],
+    ) -> Result<Vec<ContractID>> {
+        if config.is_empty() {
+            return Ok(vec![]);
+        }
+        let conn = self.dbconn()?;
+        let mut stmt = conn.prepare(&format!(
+            "
+SELECT DISTINCT
+    src_contract
+FROM contract_deps
+WHERE dest_schema IN ({})",
+            Self::in_list(1, config.len())
+        ))?;
+        let deps = stmt
+            .query_map(
+                params_from_iter(config.iter().map(|c| &c.name)),
+                |row| row.get::<_, String>(0),
+            )?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(deps
+            .into_iter()
+            .filter(|dep| !config.iter().any(|c| &c.address == dep))
+            .map(|dep| ContractID {
+                address: dep.clone(),
+                name: dep,
+            })
+            .collect())
+    }
+
+    fn get_dependent_levels(
+        &mut self,
+        config: &[ContractID],
+    ) -> Result<Vec<u32>> {
+        if config.is_empty() {
+            return Ok(vec![]);
+        }
+        let names: Vec<&dyn ToSql> = config
+            .iter()
+            .map(|c| &c.name as &dyn ToSql)
+            .collect();
+        self.query_levels(
+            &format!(
+                "
+SELECT DISTINCT
+    level
+FROM contract_deps
+WHERE dest_schema IN ({})
+  AND is_deep_copy",
+                Self::in_list(1, config.len())
+            ),
+            &names,
+        )
+    }
+
+    fn request_contract(&mut self, contract_id: &ContractID) -> Result<()> {
+        let conn = self.dbconn()?;
+        conn.execute(
+            "
+INSERT INTO contract_requests (name, address)
+VALUES (?1, ?2)
+ON CONFLICT (name) DO UPDATE
+SET address = excluded.address,
+    status = 'requested',
+    error = NULL,
+    requested_at = CURRENT_TIMESTAMP,
+    updated_at = CURRENT_TIMESTAMP",
+            [&contract_id.name, &contract_id.address],
+        )?;
+        Ok(())
+    }
+
+    fn get_contract_requests(
+        &mut self,
+        statuses: &[ContractRequestStatus],
+    ) -> Result<Vec<ContractID>> {
+        if statuses.is_empty() {
+            return Ok(vec![]);
+        }
+        let conn = self.dbconn()?;
+        let mut stmt = conn.prepare(&format!(
+            "
+SELECT
+    name, address
+FROM contract_requests
+WHERE status IN ({})
+ORDER BY requested_at",
+            Self::in_list(1, statuses.len())
+        ))?;
+        let res = stmt
+            .query_map(
+                params_from_iter(statuses.iter().map(|s| s.as_str())),
+                |row| {
+                    Ok(ContractID {
+                        name: row.get(0)?,
+                        address: row.get(1)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<ContractID>>>()?;
+        Ok(res)
+    }
+
+    fn set_contract_request_status(
+        &mut self,
+        contract_id: &ContractID,
+        status: ContractRequestStatus,
+        error: Option<String>,
+    ) -> Result<()> {
+        let conn = self.dbconn()?;
+        conn.execute(
+            "
+UPDATE contract_requests
+SET status = ?2,
+    error = ?3,
+    updated_at = CURRENT_TIMESTAMP
+WHERE name = ?1",
+            params![contract_id.name, status.as_str(), error],
+        )?;
+        Ok(())
+    }
+}
+
+impl BigmapKeysGetter for SqliteClient {
+    fn get(&mut self, level: u32, bigmap_id: i32) -> Result<Vec<BigmapEntry>> {
+        let conn = self.dbconn()?;
+        let mut stmt = conn.prepare_cached(
+            "
+SELECT
+    keyhash,
+    key,
+    value
+FROM bigmap_keys bigmap
+JOIN tx_contexts ctx
+  ON ctx.id = bigmap.tx_context_id
+WHERE bigmap_id = ?1
+  AND ctx.level <= ?2",
+        )?;
+        let res = stmt
+            .query_map(params![bigmap_id, level], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<BigmapEntry>>>()?;
+        Ok(res)
+    }
+}
+
+/// Binds an insert's value as it is stored in SQLite (see
+/// SqliteGenerator::create_sql)
+struct SqliteValue<'a>(&'a Value);
+
+impl ToSql for SqliteValue<'_> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self.0 {
+            Value::String(s) => ToSqlOutput::from(s.as_str()),
+            Value::Bool(b) => ToSqlOutput::from(*b),
+            Value::Numeric(n) => ToSqlOutput::from(numeric_text(n)),
+            Value::Int(i) => ToSqlOutput::from(*i),
+            Value::BigInt(i) => ToSqlOutput::from(*i),
+            Value::Timestamp(Some(t)) => t.to_sql()?,
+            Value::Timestamp(None) => ToSqlOutput::from("infinity"),
+            Value::Micheline(j) => ToSqlOutput::from(j.0.as_str()),
+            Value::Null => ToSqlOutput::from(Null),
+        })
+    }
+}
+
+fn numeric_text(n: &PgNumeric) -> String {
+    match &n.n {
+        Some(n) => n.to_string(),
+        None => "NaN".to_string(),
+    }
+}
+
+fn apply_inserts(
+    tx: &Transaction,
+    contract: &relational::Contract,
+    inserts: &[Insert],
+) -> Result<()> {
+    // Parent rows go before their children, the children reference them
+    let mut table_grouped: HashMap<&str, Vec<&Insert>> = HashMap::new();
+    for insert in inserts {
+        table_grouped
+            .entry(insert.table_name.as_str())
+            .or_default()
+            .push(insert);
+    }
+    let mut tables: Vec<&str> = table_grouped.keys().cloned().collect();
+    tables.sort_unstable();
+
+    for table in tables {
+        let parent_name = match contract.tables.get(table) {
+            Some(t) => t.parent.clone(),
+            None => PostgresqlGenerator::parent_name(table),
+        };
+        for insert in &table_grouped[table] {
+            let columns =
+                insert.get_columns_with_parent(parent_name.as_deref())?;
+            let mut stmt = tx.prepare_cached(&format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                SqliteGenerator::table_name(&contract.cid, table),
+                columns
+                    .iter()
+                    .map(|x| PostgresqlGenerator::quote_id(&x.name))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                SqliteClient::in_list(1, columns.len()),
+            ))?;
+            stmt.execute(params_from_iter(
+                columns
+                    .iter()
+                    .map(|x| SqliteValue(&x.value)),
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn save_levels(tx: &Transaction, levels: &[&LevelMeta]) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "
+INSERT INTO levels(
+    level, hash, prev_hash, baked_at
+)
+VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT DO NOTHING",
+    )?;
+    for meta in levels {
+        let baked_at: Option<DateTime<Utc>> = meta.baked_at;
+        stmt.execute(params![meta.level, meta.hash, meta.prev_hash, baked_at])?;
+    }
+    Ok(())
+}
+
+/// Returns the levels that were deleted (those of the levels that were
+/// saved)
+fn delete_levels(tx: &Transaction, levels: &[i32]) -> Result<Vec<i32>> {
+    let mut deleted: Vec<i32> = vec![];
+    for lvls_chunk in levels.chunks(SqliteClient::IN_LIST_SIZE) {
+        let in_list = SqliteClient::in_list(1, lvls_chunk.len());
+        // The contracts' tables are cleaned up by cascade
+        for table in ["contract_deps", "contract_levels"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE level IN ({})", table, in_list),
+                params_from_iter(lvls_chunk),
+            )?;
+        }
+        let mut stmt = tx.prepare(&format!(
+            "DELETE FROM levels WHERE level IN ({}) RETURNING level",
+            in_list
+        ))?;
+        deleted.extend(
+            stmt.query_map(params_from_iter(lvls_chunk), |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<i32>>>()?,
+        );
+    }
+    Ok(deleted)
+}
+
+/// Like delete_levels, but only deletes the given contracts' data in the
+/// levels. Returns the contracts' levels that were deleted.
+fn delete_contracts_levels(
+    tx: &Transaction,
+    contracts: &[ContractID],
+    levels: &[i32],
+) -> Result<Vec<ClearedLevel>> {
+    fn params<'a>(
+        contract: &'a dyn ToSql,
+        levels: &'a [i32],
+    ) -> Vec<&'a dyn ToSql> {
+        let mut params: Vec<&dyn ToSql> = vec![contract];
+        params.extend(levels.iter().map(|l| l as &dyn ToSql));
+        params
+    }
+
+    let mut cleared: Vec<ClearedLevel> = vec![];
+    for contract_id in contracts {
+        for lvls_chunk in levels.chunks(SqliteClient::IN_LIST_SIZE) {
+            let in_list = SqliteClient::in_list(2, lvls_chunk.len());
+            tx.execute(
+                &format!(
+                    "DELETE FROM contract_deps WHERE dest_schema = ?1 AND level IN ({})",
+                    in_list
+                ),
+                params(&contract_id.name, lvls_chunk).as_slice(),
+            )?;
+            let mut stmt = tx.prepare(&format!(
+                "DELETE FROM contract_levels WHERE contract = ?1 AND level IN ({}) RETURNING level",
+                in_list
+            ))?;
+            cleared.extend(
+                stmt.query_map(
+                    params(&contract_id.name, lvls_chunk).as_slice(),
+                    |row| {
+                        Ok(ClearedLevel {
+                            level: row.get::<_, i32>(0)? as u32,
+                            contract: Some(contract_id.name.clone()),
+                        })
+                    },
+                )?
+                .collect::<rusqlite::Result<Vec<ClearedLevel>>>()?,
+            );
+            tx.execute(
+                &format!(
+                    "DELETE FROM tx_contexts WHERE contract = ?1 AND level IN ({})",
+                    in_list
+                ),
+                params(&contract_id.address, lvls_chunk).as_slice(),
+            )?;
+        }
+    }
+    Ok(cleared)
+}
+
+fn save_contract_levels(
+    tx: &Transaction,
+    clvls: &[(ContractID, i32, bool)],
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "
+INSERT INTO contract_levels(
+    contract, level, is_origination
+)
+VALUES (?1, ?2, ?3)",
+    )?;
+    for (contract, level, is_origination) in clvls {
+        stmt.execute(params![contract.name, level, is_origination])?;
+    }
+    Ok(())
+}
+
+fn save_contract_deps(
+    tx: &Transaction,
+    deps: &[(i32, String, ContractID, bool)],
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "
+INSERT INTO contract_deps (level, src_contract, dest_schema, is_deep_copy)
+VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT DO NOTHING",
+    )?;
+    for (level, src_addr, dest, is_deep_copy) in deps {
+        stmt.execute(params![level, src_addr, dest.name, is_deep_copy])?;
+    }
+    Ok(())
+}
+
+fn save_tx_contexts(tx: &Transaction, tx_contexts: &[TxContext]) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "
+INSERT INTO tx_contexts(
+    id,
+    level,
+    contract,
+    operation_group_number,
+    operation_number,
+    content_number,
+    internal_number
+)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for tx_context in tx_contexts {
+        stmt.execute(params![
+            tx_context
+                .id
+                .ok_or_else(|| anyhow!("Missing ID on TxContext"))?,
+            tx_context.level,
+            tx_context.contract,
+            tx_context.operation_group_number,
+            tx_context.operation_number,
+            tx_context.content_number,
+            tx_context.internal_number,
+        ])?;
+    }
+    Ok(())
+}
+
+fn save_txs(tx: &Transaction, txs: &[Tx]) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "
+INSERT INTO txs(
+    tx_context_id,
+
+    operation_hash,
+    source,
+    destination,
+    entrypoint,
+
+    amount,
+    fee,
+    gas_limit,
+    storage_limit,
+
+    consumed_milligas,
+    storage_size,
+    paid_storage_size_diff
+)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+    )?;
+    for t in txs {
+        stmt.execute(params![
+            t.tx_context_id,
+            t.operation_hash,
+            t.source,
+            t.destination,
+            t.entrypoint,
+            t.amount.as_ref().map(numeric_text),
+            t.fee,
+            t.gas_limit,
+            t.storage_limit,
+            t.consumed_milligas,
+            t.storage_size,
+            t.paid_storage_size_diff,
+        ])?;
+    }
+    Ok(())
+}
+
+fn save_bigmap_keyhashes(
+    tx: &Transaction,
+    bigmap_keyhashes: &BigmapEntries,
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "
+INSERT INTO bigmap_keys (
+    tx_context_id, bigmap_id, keyhash, key, value
+)
+VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for ((bigmap_id, tx_context, keyhash), (key, value)) in bigmap_keyhashes {
+        stmt.execute(params![tx_context.id, bigmap_id, keyhash, key, value])?;
+    }
+    Ok(())
+}
+
+fn save_bigmap_meta_actions(
+    tx: &Transaction,
+    actions: &[BigmapMetaAction],
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "
+INSERT INTO bigmap_meta_actions (
+    tx_context_id, bigmap_id, action, value
+)
+VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for action in actions {
+        stmt.execute(params![
+            action.tx_context_id,
+            action.bigmap_id,
+            action.action,
+            action.value,
+        ])?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_replay() {
+    use crate::executor::Executor;
+    use crate::octez::replay::ReplayDir;
+
+    let mut node_cli = NodeClient::new(
+        vec!["http://localhost:0".to_string()],
+        "main".to_string(),
+        0,
+    );
+    node_cli.use_replay_dir(ReplayDir::new("test/").unwrap());
+    let levels = ReplayDir::new("test/")
+        .unwrap()
+        .levels();
+
+    let mut dbcli = SqliteClient::connect(":memory:").unwrap();
+    dbcli.create_common_tables().unwrap();
+
+    let mut executor = Executor::new(node_cli, Box::new(dbcli.clone()), 3600);
+    let contract_id = ContractID {
+        name: "c11".to_string(),
+        address: "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+    };
+    executor
+        .add_contract(&contract_id)
+        .unwrap();
+    executor
+        .create_contract_schemas()
+        .unwrap();
+    executor
+        .exec_levels(1, 1, levels)
+        .unwrap();
+
+    // The number of rows matches what is indexed in PostgreSQL
+    let conn = dbcli.dbconn().unwrap();
+    for (table, exp) in [
+        ("storage", 22),
+        ("storage_live", 1),
+        ("storage_ordered", 22),
+        ("storage.ledger", 31),
+        ("storage.ledger_live", 23),
+        ("storage.ledger_ordered", 31),
+        ("storage.questions", 14),
+        ("storage.questions_live", 7),
+        ("storage.questions_ordered", 14),
+    ] {
+        println!("test case: {}", table);
+        let got: usize = conn
+            .query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM {}",
+                    SqliteGenerator::table_name(&contract_id, table)
+                ),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(exp, got);
+    }
+}
+
+#[test]
+fn test_add_contract_version_tables() {
+    use crate::storage_structure::relational::ASTBuilder;
+    use crate::storage_structure::typing::type_ast_from_json;
+
+    let contract = |storage_def: serde_json::Value| {
+        let mut builder = ASTBuilder::new("storage");
+        let storage_ast = builder
+            .build_relational_ast(&type_ast_from_json(&storage_def).unwrap())
+            .unwrap();
+        relational::Contract {
+            cid: ContractID {
+                name: "test".to_string(),
+                address: "KT1".to_string(),
+            },
+            level_floor: None,
+            storage_ast,
+            entrypoint_asts: HashMap::new(),
+            tables: builder.tables().clone(),
+            selection: Default::default(),
+        }
+    };
+    let field = |prim: &str, annot: &str| serde_json::json!({"prim": prim, "annots": [annot]});
+
+    struct TestCase {
+        name: String,
+        storage_def: serde_json::Value,
+        exp_err: Option<String>,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "same storage type".to_string(),
+            storage_def: serde_json::json!({
+                "prim": "pair",
+                "args": [field("nat", "%a"), field("string", "%b")],
+            }),
+            exp_err: None,
+        },
+        TestCase {
+            name: "added field".to_string(),
+            storage_def: serde_json::json!({
+                "prim": "pair",
+                "args": [field("nat", "%a"), field("string", "%b"), field("bool", "%c")],
+            }),
+            exp_err: None,
+        },
+        TestCase {
+            name: "changed field type".to_string(),
+            storage_def: serde_json::json!({
+                "prim": "pair",
+                "args": [field("bool", "%a"), field("string", "%b")],
+            }),
+            exp_err: Some("contract test: column a of table storage is of type TEXT, but a version of the contract's storage type needs it to be of type BOOLEAN (existing columns are never altered)".to_string()),
+        },
+    ];
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let mut dbcli = SqliteClient::connect(":memory:").unwrap();
+        dbcli.create_common_tables().unwrap();
+        dbcli
+            .create_contract_schemas(&mut vec![contract(serde_json::json!({
+                "prim": "pair",
+                "args": [field("nat", "%a"), field("string", "%b")],
+            }))])
+            .unwrap();
+
+        let got = dbcli
+            .add_contract_version_tables(&contract(tc.storage_def))
+            .map_err(|err| err.to_string())
+            .err();
+        assert_eq!(tc.exp_err, got);
+    }
+}
+
+#[test]
+fn test_update_derived_tables() {
+    use crate::executor::Executor;
+    use crate::octez::replay::ReplayDir;
+
+    let contract_id = ContractID {
+        name: "c11".to_string(),
+        address: "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+    };
+    let derived_tables = [
+        ("storage_live", "id"),
+        ("storage_ordered", "ordering, id"),
+        ("storage.ledger_live", "id"),
+        ("storage.ledger_ordered", "ordering, id"),
+        ("storage.questions_live", "id"),
+        ("storage.questions_ordered", "ordering, id"),
+    ];
+    let dump = |dbcli: &SqliteClient| -> Vec<Vec<String>> {
+        let conn = dbcli.dbconn().unwrap();
+        derived_tables
+            .iter()
+            .flat_map(|(table, order_by)| {
+                let table = SqliteGenerator::table_name(&contract_id, table);
+                if table.ends_with("_ordered\"") {
+                    // (the ordering doesn't necessarily start at 1)
+                    conn.execute(
+                        &format!(
+                            "UPDATE {table} SET ordering = ordering + 1 - (SELECT min(ordering) FROM {table})",
+                            table = table,
+                        ),
+                        [],
+                    )
+                    .unwrap();
+                }
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT * FROM {} ORDER BY {}",
+                        table, order_by
+                    ))
+                    .unwrap();
+                let num_columns = stmt.column_count();
+                stmt.query_map([], |row| {
+                    (0..num_columns)
+                        .map(|i| {
+                            row.get::<_, rusqlite::types::Value>(i)
+                                .map(|v| format!("{}: {:?}", table, v))
+                        })
+                        .collect::<rusqlite::Result<Vec<String>>>()
+                })
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<Vec<String>>>>()
+                .unwrap()
+            })
+            .collect()
+    };
+
+    struct TestCase {
+        name: String,
+        reverse_levels: bool,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "levels processed from old to new".to_string(),
+            reverse_levels: false,
+        },
+        TestCase {
+            name: "levels processed from new to old".to_string(),
+            reverse_levels: true,
+        },
+    ];
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let mut node_cli = NodeClient::new(
+            vec!["http://localhost:0".to_string()],
+            "main".to_string(),
+            0,
+        );
+        node_cli.use_replay_dir(ReplayDir::new("test/").unwrap());
+        let mut levels = ReplayDir::new("test/")
+            .unwrap()
+            .levels();
+        levels.sort_unstable();
+        if tc.reverse_levels {
+            levels.reverse();
+        }
+
+        let mut dbcli = SqliteClient::connect(":memory:").unwrap();
+        dbcli.create_common_tables().unwrap();
+
+        let mut executor =
+            Executor::new(node_cli, Box::new(dbcli.clone()), 3600);
+        executor
+            .add_contract(&contract_id)
+            .unwrap();
+        executor
+            .create_contract_schemas()
+            .unwrap();
+        executor
+            .exec_levels(1, 1, levels)
+            .unwrap();
+
+        // The derived tables, updated with every batch, are the same as
+        // when derived from scratch
+        let got = dump(&dbcli);
+        executor
+            .rebuild_derived_tables()
+            .unwrap();
+        let exp = dump(&dbcli);
+        assert!(!exp.is_empty());
+        assert_eq!(exp, got);
+    }
+}
+
+#[test]
+fn test_reprocessed_levels_retracted() {
+    use crate::events::EventSink;
+    use crate::executor::Executor;
+    use crate::octez::replay::ReplayDir;
+
+    let path = std::env::temp_dir().join("quepasa-test-retracted-events");
+    let _ = std::fs::remove_file(&path);
+
+    let mut node_cli = NodeClient::new(
+        vec!["http://localhost:0".to_string()],
+        "main".to_string(),
+        0,
+    );
+    node_cli.use_replay_dir(ReplayDir::new("test/").unwrap());
+    let mut levels = ReplayDir::new("test/")
+        .unwrap()
+        .levels();
+    levels.sort_unstable();
+
+    let mut dbcli = SqliteClient::connect(":memory:").unwrap();
+    dbcli.create_common_tables().unwrap();
+
+    let mut executor = Executor::new(node_cli, Box::new(dbcli.clone()), 3600);
+    executor.set_event_sink(
+        EventSink::connect(&format!("file:{}", path.to_str().unwrap()))
+            .unwrap(),
+    );
+    let contract_id = ContractID {
+        name: "c11".to_string(),
+        address: "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+    };
+    executor
+        .add_contract(&contract_id)
+        .unwrap();
+    executor
+        .create_contract_schemas()
+        .unwrap();
+
+    let retractions = || -> Vec<serde_json::Value> {
+        std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|event: &serde_json::Value| event["kind"] == "retract")
+            .collect()
+    };
+
+    // Levels processed for the first time have nothing to retract, those
+    // processed again have what was emitted for them retracted
+    executor
+        .exec_levels(1, 1, levels.clone())
+        .unwrap();
+    assert_eq!(Vec::<serde_json::Value>::new(), retractions());
+
+    executor
+        .exec_levels(1, 1, levels[..2].to_vec())
+        .unwrap();
+    assert_eq!(
+        levels[..2]
+            .iter()
+            .map(|level| serde_json::json!({
+                "kind": "retract",
+                "level": level,
+            }))
+            .collect::<Vec<serde_json::Value>>(),
+        retractions()
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,330 @@
+use anyhow::Result;
+use askama::Template;
+
+use crate::config::{ContractID, QUEPASA_VERSION};
+use crate::sql::postgresql_generator::PostgresqlGenerator;
+use crate::sql::table::{Column, Table};
+use crate::storage_structure::typing::ExprTy;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+#[derive(Template)]
+#[template(path = "sqlite/repopulate-snapshot-derived.sql", escape = "none")]
+struct RepopulateSnapshotDerivedTmpl<'a> {
+    contract_schema: &'a str,
+    table: &'a str,
+    parent_table: &'a str,
+    columns: &'a [String],
+}
+
+#[derive(Template)]
+#[template(path = "sqlite/repopulate-changes-derived.sql", escape = "none")]
+struct RepopulateChangesDerivedTmpl<'a> {
+    contract_schema: &'a str,
+    table: &'a str,
+    columns: &'a [String],
+    indices: &'a [String],
+}
+
+#[derive(Template)]
+#[template(path = "sqlite/update-snapshot-derived.sql", escape = "none")]
+struct UpdateSnapshotDerivedTmpl<'a> {
+    contract_schema: &'a str,
+    table: &'a str,
+    parent_table: &'a str,
+    columns: &'a [String],
+    tx_context_ids: &'a [i64],
+}
+
+#[derive(Template)]
+#[template(path = "sqlite/update-changes-derived.sql", escape = "none")]
+struct UpdateChangesDerivedTmpl<'a> {
+    contract_schema: &'a str,
+    table: &'a str,
+    columns: &'a [String],
+    indices: &'a [String],
+    tx_context_ids: &'a [i64],
+}
+
+/// Generates the SQLite counterpart of what PostgresqlGenerator generates.
+/// SQLite has no schemas, a contract's tables are named
+/// "<contract>.<table>" instead (eg "nft.storage.ledger"). Table functions
+/// are not generated.
+#[derive(Clone, Debug)]
+pub struct SqliteGenerator {
+    contract_id: ContractID,
+}
+
+impl SqliteGenerator {
+    pub(crate) fn new(contract_id: &ContractID) -> Self {
+        Self {
+            contract_id: contract_id.clone(),
+        }
+    }
+
+    pub(crate) fn create_common_tables() -> String {
+        format!(
+            include_str!("../../sql/sqlite/common-tables.sql"),
+            quepasa_version = QUEPASA_VERSION,
+        )
+    }
+
+    pub(crate) fn table_name(contract_id: &ContractID, table: &str) -> String {
+        PostgresqlGenerator::quote_id(&format!(
+            "{}.{}",
+            contract_id.name, table
+        ))
+    }
+
+    pub(crate) fn create_sql(column: &Column) -> Option<String> {
+        match column.name.as_str() {
+            "id" => return Some("id INTEGER PRIMARY KEY".to_string()),
+            "tx_context_id" => {
+                return Some("tx_context_id BIGINT NOT NULL".to_string())
+            }
+            "deleted" => {
+                return Some(
+                    "deleted BOOLEAN NOT NULL DEFAULT false".to_string(),
+                )
+            }
+            "bigmap_id" => return Some("bigmap_id INTEGER".to_string()),
+            // (the derived tables' level and ordering are compared and
+            // sorted on, as numbers)
+            "level" => return Some("level INTEGER".to_string()),
+            "ordering" => return Some("ordering INTEGER".to_string()),
+            _ => {}
+        }
+
+        let name = PostgresqlGenerator::quote_id(&column.name);
+        match column.column_type {
+            ExprTy::Bool => Some(format!("{} BOOLEAN", name)),
+            // SQLite's numbers are at most 64 bits, numeric values are
+            // stored as text so that none of their digits get lost
+            ExprTy::Int
+            | ExprTy::Nat
+            | ExprTy::Mutez
+            | ExprTy::SaplingState => Some(format!("{} TEXT", name)),
+            ExprTy::Address
+            | ExprTy::Bytes
+            | ExprTy::KeyHash
+            | ExprTy::Signature
+            | ExprTy::Contract
+            | ExprTy::Micheline
+            | ExprTy::String
+            | ExprTy::Timestamp
+            | ExprTy::Unit => Some(format!("{} TEXT", name)),
+            ExprTy::Stop => None,
+            _ => panic!(
+                "unrecoverable err, cannot make sql column for type {:#?}",
+                column.column_type
+            ),
+        }
+    }
+
+    pub(crate) fn create_columns(&self, table: &Table) -> Vec<String> {
+        let mut cols: Vec<String> =
+            match PostgresqlGenerator::table_parent_name(table) {
+                Some(t) => vec![format!(
+                    r#""{parent_ref}" BIGINT"#,
+                    parent_ref = PostgresqlGenerator::parent_ref(&t)
+                )],
+                None => vec![],
+            };
+        for column in table.get_columns() {
+            if !table.id_unique && column.name == *"id" {
+                cols.push("id BIGINT NOT NULL".to_string());
+                continue;
+            }
+            if let Some(val) = Self::create_sql(column) {
+                cols.push(val);
+            }
+        }
+        cols
+    }
+
+    fn create_foreign_key_constraints(&self, table: &Table) -> Vec<String> {
+        let mut fks: Vec<(String, String, String)> =
+            table.fk.keys().cloned().collect();
+        fks.sort();
+
+        if let Some(parent) = PostgresqlGenerator::table_parent_name(table) {
+            fks.push((
+                PostgresqlGenerator::parent_ref(&parent),
+                parent,
+                "id".to_string(),
+            ));
+        };
+
+        let mut res = vec![
+            "FOREIGN KEY (tx_context_id) REFERENCES tx_contexts(id) ON DELETE CASCADE".to_string(),
+        ];
+        res.extend(fks.into_iter().map(|(col, ref_table, ref_col)| {
+            format!(
+                r#"FOREIGN KEY ("{col}") REFERENCES {ref_table}({ref_col})"#,
+                col = col,
+                ref_table = Self::table_name(&self.contract_id, &ref_table),
+                ref_col = ref_col,
+            )
+        }));
+        res
+    }
+
+    // SQLite's index names are unique across the database, they are
+    // prefixed with the table's name
+    fn create_index(&self, table: &Table) -> Vec<String> {
+        if table.indices.is_empty() {
+            return vec![];
+        }
+        let table_name = Self::table_name(&self.contract_id, &table.name);
+        let index_name = |postfix: &str| {
+            Self::table_name(
+                &self.contract_id,
+                &format!("{}_{}", table.name, postfix),
+            )
+        };
+        let uniqueness_constraint = match table.has_uniqueness() {
+            true => "UNIQUE",
+            false => "",
+        };
+        let mut res: Vec<String> = vec![format!(
+            "CREATE {unique} INDEX {index} ON {table}({columns});",
+            unique = uniqueness_constraint,
+            index = index_name("keys"),
+            table = table_name,
+            columns =
+                PostgresqlGenerator::table_sql_indices(table, true).join(", ")
+        )];
+        if let Some(parent) = PostgresqlGenerator::table_parent_name(table) {
+            res.push(format!(
+                r#"CREATE INDEX {index} ON {table}("{parent_ref}");"#,
+                index = index_name("parent"),
+                table = table_name,
+                parent_ref = PostgresqlGenerator::parent_ref(&parent),
+            ));
+        };
+        if !table.id_unique {
+            res.push(format!(
+                "CREATE INDEX {index} ON {table}(id);",
+                index = index_name("id"),
+                table = table_name,
+            ));
+        }
+        res
+    }
+
+    pub(crate) fn create_table_definition(&self, table: &Table) -> String {
+        let mut columns: Vec<String> = self.create_columns(table);
+        columns.extend(self.create_foreign_key_constraints(table));
+        let mut v: Vec<String> = vec![format!(
+            "CREATE TABLE {} (\n\t{});",
+            Self::table_name(&self.contract_id, &table.name),
+            columns.join(",\n\t")
+        )];
+        v.extend(self.create_index(table));
+        v.join("\n")
+    }
+
+    pub(crate) fn create_derived_table_definitions(
+        &self,
+        table: &Table,
+    ) -> Vec<String> {
+        let (live, ordered) = PostgresqlGenerator::derived_tables(table);
+        vec![
+            self.create_table_definition(&live),
+            self.create_table_definition(&ordered),
+        ]
+    }
+
+    /// Updates the table's _live and _ordered tables with the rows of the
+    /// given (newly inserted) tx contexts
+    pub(crate) fn update_derived_tables(
+        &self,
+        table: &Table,
+        tx_context_ids: &[i64],
+    ) -> Result<String> {
+        let columns: Vec<String> =
+            PostgresqlGenerator::table_sql_columns(table, false);
+
+        if table.contains_snapshots() {
+            let parent_table: String =
+                PostgresqlGenerator::table_parent_name(table)
+                    .unwrap_or_else(|| table.name.clone());
+            let tmpl = UpdateSnapshotDerivedTmpl {
+                contract_schema: &self.contract_id.name,
+                table: &table.name,
+                parent_table: &parent_table,
+                columns: &columns,
+                tx_context_ids,
+            };
+            return Ok(tmpl.render()?);
+        }
+        let tmpl = UpdateChangesDerivedTmpl {
+            contract_schema: &self.contract_id.name,
+            table: &table.name,
+            columns: &columns,
+            indices: &PostgresqlGenerator::table_sql_indices(table, false),
+            tx_context_ids,
+        };
+        Ok(tmpl.render()?)
+    }
+
+    /// Derives the table's _live and _ordered tables again from scratch
+    pub(crate) fn repopulate_derived_tables(
+        &self,
+        table: &Table,
+    ) -> Result<String> {
+        let columns: Vec<String> =
+            PostgresqlGenerator::table_sql_columns(table, false);
+
+        if table.contains_snapshots() {
+            let parent_table: String =
+                PostgresqlGenerator::table_parent_name(table)
+                    .unwrap_or_else(|| table.name.clone());
+            let tmpl = RepopulateSnapshotDerivedTmpl {
+                contract_schema: &self.contract_id.name,
+                table: &table.name,
+                parent_table: &parent_table,
+                columns: &columns,
+            };
+            return Ok(tmpl.render()?);
+        }
+        let tmpl = RepopulateChangesDerivedTmpl {
+            contract_schema: &self.contract_id.name,
+            table: &table.name,
+            columns: &columns,
+            indices: &PostgresqlGenerator::table_sql_indices(table, false),
+        };
+        Ok(tmpl.render()?)
+    }
+}
+
+#[test]
+fn test_create_table_definition() {
+    use crate::sql::table::Table;
+
+    let generator = SqliteGenerator::new(&ContractID {
+        name: "nft".to_string(),
+        address: "KT1".to_string(),
+    });
+
+    let mut table = Table::new("storage.ledger".to_string());
+    table.add_index("tx_context_id", &ExprTy::Int);
+    table.add_column("id", &ExprTy::Int);
+    table.add_index("idx_address", &ExprTy::Address);
+    table.add_column("nat", &ExprTy::Nat);
+
+    assert_eq!(
+        r#"CREATE TABLE "nft.storage.ledger" (
+	"storage_id" BIGINT,
+	tx_context_id BIGINT NOT NULL,
+	id INTEGER PRIMARY KEY,
+	"idx_address" TEXT,
+	"nat" TEXT,
+	FOREIGN KEY (tx_context_id) REFERENCES tx_contexts(id) ON DELETE CASCADE,
+	FOREIGN KEY ("storage_id") REFERENCES "nft.storage"(id));
+CREATE UNIQUE INDEX "nft.storage.ledger_keys" ON "nft.storage.ledger"("tx_context_id", "idx_address", "storage_id");
+CREATE INDEX "nft.storage.ledger_parent" ON "nft.storage.ledger"("storage_id");"#,
+        generator.create_table_definition(&table)
+    );
+}
@@ -1,3 +1,4 @@
+use crate::storage_structure::naming::TablePath;
 use crate::storage_structure::typing::ExprTy;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -19,6 +20,13 @@ pub struct Table {
     unique: bool,
     snapshots: bool,
     pointers: bool,
+
+    // where the table comes from in the contract's type, if it was named by
+    // a naming policy (see TablePath)
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    parent: Option<String>,
 }
 
 impl Table {
@@ -33,9 +41,32 @@ impl Table {
             fk: HashMap::new(),
             id_unique: true,
             pointers: false,
+            path: None,
+            parent: None,
         }
     }
 
+    pub(crate) fn set_path(&mut self, path: &TablePath) {
+        self.path = Some(path.path.clone());
+        self.parent = path.parent.clone();
+    }
+
+    /// The table's path in the contract's type, this is its name unless it
+    /// was renamed or shortened
+    pub(crate) fn path(&self) -> &str {
+        self.path
+            .as_deref()
+            .unwrap_or(&self.name)
+    }
+
+    /// The table referenced by the table's <parent>_id column. None if the
+    /// table has no known path, then the parent is derived from its name.
+    pub(crate) fn parent(&self) -> Option<Option<String>> {
+        self.path
+            .as_ref()
+            .map(|_| self.parent.clone())
+    }
+
     pub(crate) fn has_uniqueness(&self) -> bool {
         self.unique
     }
@@ -97,7 +128,8 @@ impl Table {
             | ExprTy::BigMap(..)
             | ExprTy::List(..)
             | ExprTy::Option(..)
-            | ExprTy::Pair(..) => {
+            | ExprTy::Pair(..)
+            | ExprTy::Ticket(..) => {
                 panic!(
                     "unrecoverable err, add_column called with ExprTy {:?}",
                     column_type
@@ -134,7 +166,8 @@ impl Table {
             | ExprTy::BigMap(..)
             | ExprTy::List(..)
             | ExprTy::Option(..)
-            | ExprTy::Pair(..) => {
+            | ExprTy::Pair(..)
+            | ExprTy::Ticket(..) => {
                 panic!(
                     "unrecoverable err, add_index called with ExprTy {:?}",
                     column_type
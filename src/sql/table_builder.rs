@@ -3,6 +3,7 @@ use crate::storage_structure::relational::{
     Contract, RelationalAST, RelationalEntry,
 };
 use crate::storage_structure::typing::ExprTy;
+use crate::storage_update::sapling;
 use std::collections::HashMap;
 
 pub type TableMap = HashMap<String, Table>;
@@ -18,8 +19,10 @@ impl TableBuilder {
         // Generate the SQL schema for this contract
         let mut builder = TableBuilder::new("storage");
         builder.populate(&contract.storage_ast);
+        builder.set_paths(contract);
 
-        let nofunctions_tables = builder.get_functionless_table_prefixes();
+        let mut nofunctions_tables = builder.get_functionless_table_prefixes();
+        nofunctions_tables.push(sapling::UPDATES_TABLE.to_string());
         let mut noview_tables = nofunctions_tables.clone();
         noview_tables.push("entry.".to_string());
         let mut tables: Vec<Table> = builder.tables.into_values().collect();
@@ -28,6 +31,7 @@ impl TableBuilder {
             let mut entrypoint_table_builder =
                 TableBuilder::new(format!("entry.{}", entrypoint).as_str());
             entrypoint_table_builder.populate(entrypoint_ast);
+            entrypoint_table_builder.set_paths(contract);
 
             tables.append(
                 &mut entrypoint_table_builder
@@ -37,6 +41,10 @@ impl TableBuilder {
             );
         }
 
+        if !contract.selection.storage.snapshot {
+            tables.retain(|t| t.name != "storage");
+        }
+
         (tables, noview_tables, nofunctions_tables)
     }
 
@@ -48,6 +56,14 @@ impl TableBuilder {
         res
     }
 
+    fn set_paths(&mut self, contract: &Contract) {
+        for (name, table) in self.tables.iter_mut() {
+            if let Some(path) = contract.tables.get(name) {
+                table.set_path(path);
+            }
+        }
+    }
+
     fn get_functionless_table_prefixes(&self) -> Vec<String> {
         let mut res: Vec<String> = vec![];
 
@@ -61,7 +77,7 @@ impl TableBuilder {
                     if t.contains_snapshots() {
                         None
                     } else {
-                        Some(format!("{}.", t.name))
+                        Some(format!("{}.", t.path()))
                     }
                 })
                 .collect::<Vec<String>>(),
@@ -95,6 +111,18 @@ impl TableBuilder {
         }
     }
 
+    fn touch_sapling_updates_table(&mut self) {
+        let mut t = self.get_table(sapling::UPDATES_TABLE);
+        t.add_index("sapling_state_id", &ExprTy::Int);
+        t.add_column("action", &ExprTy::String);
+        t.add_column("source", &ExprTy::Int);
+        t.add_column("memo_size", &ExprTy::Int);
+        t.add_column("commitments", &ExprTy::Int);
+        t.add_column("nullifiers", &ExprTy::Int);
+        t.add_column("root", &ExprTy::String);
+        self.store_table(t);
+    }
+
     fn store_table(&mut self, table: Table) {
         self.tables
             .insert(table.name.clone(), table);
@@ -173,7 +201,12 @@ impl TableBuilder {
                     self.populate(right_ast);
                 }
             }
-            RelationalAST::Leaf { rel_entry } => self.add_column(rel_entry),
+            RelationalAST::Leaf { rel_entry } => {
+                if rel_entry.column_type == ExprTy::SaplingState {
+                    self.touch_sapling_updates_table();
+                }
+                self.add_column(rel_entry)
+            }
         }
     }
 }
@@ -1,3 +1,4 @@
+use crate::metrics;
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -25,6 +26,10 @@ impl StatsLogger {
         }
     }
 
+    pub(crate) fn interval(&self) -> Duration {
+        self.interval
+    }
+
     pub(crate) fn add(
         &self,
         report: &str,
@@ -41,6 +46,9 @@ impl StatsLogger {
             .get_mut(report)
             .unwrap()
             .add(field, n);
+        metrics::STATS
+            .with_label_values(&[report, field])
+            .inc_by(n as u64);
 
         Ok(())
     }
@@ -1,2 +1,4 @@
+pub mod naming;
 pub mod relational;
+pub mod selection;
 pub mod typing;
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// PostgreSQL's identifier length limit (NAMEDATALEN - 1)
+pub(crate) const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Longest suffix appended to table names for derived tables and functions
+/// (eg "_at_deref"), table names are kept short enough to allow for it
+const MAX_TABLE_SUFFIX_LEN: usize = 9;
+
+const HASH_LEN: usize = 8;
+
+/// Where a table of a contract's schema comes from
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct TablePath {
+    /// The table's path in the contract's storage or parameter type (ie the
+    /// name it would have had without the naming policy)
+    pub path: String,
+    /// The name of the table it references with its <parent>_id column
+    pub parent: Option<String>,
+    /// Per column name, where the column comes from
+    pub columns: HashMap<String, ColumnPath>,
+}
+
+/// Where a column of a contract's schema comes from
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ColumnPath {
+    /// The column's name before it was shortened
+    pub name: String,
+    /// The annotation of the value in the contract's type, if any
+    pub annotation: Option<String>,
+    /// The Michelson type of the value (see Ele::prim)
+    pub michelson_type: Option<String>,
+}
+
+/// Decides on the names of a contract's tables and columns. Table names are
+/// derived from their path in the contract's type (eg storage.ledger.value),
+/// unless renamed in the contract's settings. Names that are too long for
+/// PostgreSQL are shortened deterministically: they're cut off and suffixed
+/// with a hash of the full name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct NamingPolicy {
+    renames: HashMap<String, String>,
+}
+
+impl NamingPolicy {
+    pub(crate) fn new(renames: HashMap<String, String>) -> Self {
+        Self { renames }
+    }
+
+    pub(crate) fn table_name(&self, path: &str) -> String {
+        match self.renames.get(path) {
+            Some(name) => {
+                shorten(name, MAX_IDENTIFIER_LEN - MAX_TABLE_SUFFIX_LEN)
+            }
+            None => shorten(path, MAX_IDENTIFIER_LEN - MAX_TABLE_SUFFIX_LEN),
+        }
+    }
+
+    pub(crate) fn column_name(&self, name: &str) -> String {
+        shorten(name, MAX_IDENTIFIER_LEN)
+    }
+}
+
+fn shorten(name: &str, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name.to_string();
+    }
+    let mut keep = max_len - HASH_LEN - 1;
+    while !name.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    format!("{}_{:08x}", &name[..keep], fnv1a(name))
+}
+
+// FNV-1a (32 bit), a hash that is stable across platforms and releases, so
+// that shortened names never change for the same input
+fn fnv1a(s: &str) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for b in s.bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+#[test]
+fn test_naming_policy() {
+    let mut renames: HashMap<String, String> = HashMap::new();
+    renames.insert("storage.ledger.value".to_string(), "ledger".to_string());
+    let policy = NamingPolicy::new(renames);
+
+    let long_path = format!("storage.{}", "nested_record_field.".repeat(4));
+    let long_column = "a_very_long_annotation_".repeat(3);
+
+    struct TestCase {
+        name: String,
+        got: String,
+        exp: String,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "short table name".to_string(),
+            got: policy.table_name("storage.ledger"),
+            exp: "storage.ledger".to_string(),
+        },
+        TestCase {
+            name: "renamed table".to_string(),
+            got: policy.table_name("storage.ledger.value"),
+            exp: "ledger".to_string(),
+        },
+        TestCase {
+            name: "long table name".to_string(),
+            got: policy.table_name(&long_path),
+            exp: format!("{}_{:08x}", &long_path[..45], fnv1a(&long_path)),
+        },
+        TestCase {
+            name: "short column name".to_string(),
+            got: policy.column_name("owner"),
+            exp: "owner".to_string(),
+        },
+        TestCase {
+            name: "long column name".to_string(),
+            got: policy.column_name(&long_column),
+            exp: format!("{}_{:08x}", &long_column[..54], fnv1a(&long_column)),
+        },
+    ];
+
+    for tc in tests {
+        println!("test case: {}", tc.name);
+        assert_eq!(tc.exp, tc.got);
+        assert!(tc.got.len() <= MAX_IDENTIFIER_LEN);
+    }
+
+    // Shortening is deterministic, and distinguishes names with the same
+    // prefix
+    assert_eq!(policy.table_name(&long_path), policy.table_name(&long_path));
+    assert!(
+        policy.table_name(&format!("{}a", long_path))
+            != policy.table_name(&format!("{}b", long_path))
+    );
+}
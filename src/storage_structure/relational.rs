@@ -1,4 +1,6 @@
 use crate::sql::postgresql_generator::PostgresqlGenerator;
+use crate::storage_structure::naming::{ColumnPath, NamingPolicy, TablePath};
+use crate::storage_structure::selection::{PathSelection, Selection};
 use crate::storage_structure::typing::{Ele, ExprTy};
 
 use crate::config::ContractID;
@@ -15,6 +17,11 @@ pub(crate) struct Contract {
 
     pub storage_ast: RelationalAST,
     pub entrypoint_asts: HashMap<String, RelationalAST>,
+
+    /// Per table name, where the table comes from (see NamingPolicy)
+    pub tables: HashMap<String, TablePath>,
+    /// Which parts of the contract are indexed
+    pub selection: Selection,
 }
 
 pub type Indexes = HashMap<String, u32>;
@@ -34,26 +41,38 @@ fn get_column_name(expr: &ExprTy) -> &str {
         ExprTy::Timestamp => "timestamp",
         ExprTy::Unit => "unit",
         ExprTy::Stop => "stop",
+        ExprTy::Micheline => "micheline",
+        ExprTy::SaplingState => "sapling_state",
         ExprTy::Pair(..)
         | ExprTy::Map(..)
         | ExprTy::BigMap(..)
         | ExprTy::List(..)
         | ExprTy::OrEnumeration(..)
-        | ExprTy::Option(..) => "",
+        | ExprTy::Option(..)
+        | ExprTy::Ticket(..) => "",
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct Context {
     pub table_name: String,
+    table_path: String,
     prefix: String,
+
+    // whether the value is below an included path (see Selection), and
+    // whether it is part of a big map's contents
+    selected: bool,
+    in_bigmap: bool,
 }
 
 impl Context {
     pub(crate) fn init(root_table_name: &str) -> Self {
         Context {
             table_name: root_table_name.to_string(),
+            table_path: root_table_name.to_string(),
             prefix: "".to_string(),
+            selected: true,
+            in_bigmap: false,
         }
     }
 
@@ -78,17 +97,18 @@ impl Context {
 
     pub(crate) fn start_table(&self, name: &str) -> Self {
         let mut c = self.next();
-        c.table_name = format!("{}.{}", self.table_name, name);
+        c.table_path = format!("{}.{}", self.table_path, name);
+        c.table_name = c.table_path.clone();
         c
     }
 
     pub(crate) fn table_leaf_name(&self) -> String {
-        self.table_name
+        self.table_path
             .rfind('.')
             .map(|pos| {
-                self.table_name[pos + 1..self.table_name.len()].to_string()
+                self.table_path[pos + 1..self.table_path.len()].to_string()
             })
-            .unwrap_or_else(|| self.table_name.clone())
+            .unwrap_or_else(|| self.table_path.clone())
     }
 }
 
@@ -155,7 +175,12 @@ pub struct ASTBuilder {
     table_names: HashMap<String, u32>,
     column_names: HashMap<(String, String), u32>,
 
+    naming: NamingPolicy,
+    tables: HashMap<String, TablePath>,
+    selection: Selection,
+
     bigmaps_retain: bool,
+    lambdas_retain: bool,
 }
 
 lazy_static! {
@@ -177,7 +202,12 @@ impl ASTBuilder {
             table_names: HashMap::new(),
             column_names: HashMap::new(),
 
+            naming: NamingPolicy::default(),
+            tables: HashMap::new(),
+            selection: Selection::default(),
+
             bigmaps_retain: true,
+            lambdas_retain: false,
         };
         for column_name in RESERVED.iter() {
             res.column_names
@@ -191,13 +221,87 @@ impl ASTBuilder {
         self
     }
 
+    /// Keep lambda (and never/constant) values as raw Micheline, instead of
+    /// dropping them.
+    pub(crate) fn micheline_lambdas(&mut self) -> &mut Self {
+        self.lambdas_retain = true;
+        self
+    }
+
+    pub(crate) fn naming(&mut self, naming: NamingPolicy) -> &mut Self {
+        self.naming = naming;
+        self
+    }
+
+    /// Leave out the values that are not selected, they are built as if
+    /// they were lambdas (see drop_lambda)
+    pub(crate) fn selection(&mut self, selection: Selection) -> &mut Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Where the tables of the built ASTs come from, per table name
+    pub(crate) fn tables(&self) -> &HashMap<String, TablePath> {
+        &self.tables
+    }
+
+    fn drop_lambda(&self, ele: &Ele) -> Option<Ele> {
+        match ele.expr_type {
+            ExprTy::Micheline if !self.lambdas_retain => {
+                Some(ele_set_type(ele, ExprTy::Stop))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the context to build the value in, or None if the value is
+    /// left out by the selection
+    fn select(&self, ctx: &Context, ele: &Ele) -> Option<Context> {
+        let is_table = matches!(
+            ele.expr_type,
+            ExprTy::Map(..) | ExprTy::BigMap(..) | ExprTy::List(..)
+        );
+        if ele.expr_type == ExprTy::Stop {
+            return Some(ctx.clone());
+        }
+
+        let mut ctx = ctx.clone();
+        let path = match &ele.name {
+            Some(name) => Some(format!("{}.{}", ctx.table_path, name)),
+            None if is_table => Some(format!("{}.noname", ctx.table_path)),
+            None => None,
+        };
+        match path.map(|path| self.selection.storage_path(&path)) {
+            Some(PathSelection::Excluded) => return None,
+            Some(PathSelection::Included) => ctx.selected = true,
+            _ => {}
+        }
+
+        let traversed = match ele.expr_type {
+            ExprTy::Pair(..) | ExprTy::Option(..) => true,
+            _ => is_table,
+        };
+        if !ctx.selected && !traversed {
+            return None;
+        }
+
+        if !self.selection.storage.snapshot && !ctx.in_bigmap {
+            match ele.expr_type {
+                ExprTy::BigMap(..) => ctx.in_bigmap = true,
+                ExprTy::Pair(..) | ExprTy::Option(..) => {}
+                _ => return None,
+            }
+        }
+        Some(ctx)
+    }
+
     fn start_table(&mut self, ctx: &Context, ele: &Ele) -> Context {
         let name = match &ele.name {
             Some(s) => s.clone(),
             None => "noname".to_string(),
         };
 
-        let full_name = ctx.start_table(&name).table_name;
+        let full_name = ctx.start_table(&name).table_path;
         let mut c = 0;
         if self
             .table_names
@@ -223,7 +327,28 @@ impl ASTBuilder {
         };
 
         let parent_table = &ctx.table_name;
-        let ctx = ctx.start_table(&name);
+        let mut ctx = ctx.start_table(&name);
+        ctx.table_name = self.naming.table_name(&ctx.table_path);
+        let mut c = 0;
+        while self
+            .tables
+            .get(&ctx.table_name)
+            .is_some_and(|t| t.path != ctx.table_path)
+        {
+            // A rename clashes with the name of another table
+            c += 1;
+            ctx.table_name = self
+                .naming
+                .table_name(&format!("{}_{}", ctx.table_path, c));
+        }
+        self.tables.insert(
+            ctx.table_name.clone(),
+            TablePath {
+                path: ctx.table_path.clone(),
+                parent: Some(parent_table.clone()),
+                columns: HashMap::new(),
+            },
+        );
 
         self.column_names.insert(
             (
@@ -270,24 +395,54 @@ impl ASTBuilder {
         }
         self.column_names
             .insert((table.clone(), name.clone()), c);
-        if c == 0 {
+        let name = if c == 0 {
             name
         } else {
             let postfixed = format!("{}_{}", name, c);
             self.column_names
-                .insert((table, postfixed.clone()), 0);
+                .insert((table.clone(), postfixed.clone()), 0);
             postfixed
+        };
+
+        let shortened = self.naming.column_name(&name);
+        if let Some(t) = self.tables.get_mut(&table) {
+            t.columns.insert(
+                shortened.clone(),
+                ColumnPath {
+                    name,
+                    annotation: ele.name.clone(),
+                    michelson_type: ele.prim.clone(),
+                },
+            );
         }
+        shortened
     }
 
     pub(crate) fn build_relational_ast(
         &mut self,
         ele: &Ele,
     ) -> Result<RelationalAST> {
-        self.build_relational_ast_internal(
-            &Context::init(&self.root_table),
-            ele,
-        )
+        // The root table (storage, or entry.<entrypoint>) keeps its name
+        if !self
+            .tables
+            .contains_key(&self.root_table)
+        {
+            self.tables.insert(
+                self.root_table.clone(),
+                TablePath {
+                    path: self.root_table.clone(),
+                    parent: None,
+                    columns: HashMap::new(),
+                },
+            );
+        }
+        let mut ctx = Context::init(&self.root_table);
+        ctx.selected = self
+            .selection
+            .storage
+            .include
+            .is_empty();
+        self.build_relational_ast_internal(&ctx, ele)
     }
 
     fn build_relational_ast_internal(
@@ -295,6 +450,18 @@ impl ASTBuilder {
         ctx: &Context,
         ele: &Ele,
     ) -> Result<RelationalAST> {
+        if let Some(stop_ele) = self.drop_lambda(ele) {
+            return self.build_relational_ast_internal(ctx, &stop_ele);
+        }
+        let ctx = &match self.select(ctx, ele) {
+            Some(ctx) => ctx,
+            None => {
+                return self.build_relational_ast_internal(
+                    ctx,
+                    &ele_set_type(ele, ExprTy::Stop),
+                )
+            }
+        };
         match &ele.expr_type {
             ExprTy::Pair(left_type, right_type) => {
                 let ctx = &ele
@@ -373,6 +540,11 @@ impl ASTBuilder {
                     .build_enumeration_or(ctx, ele, &name, false)?
                     .0)
             }
+            ExprTy::Ticket(contents_type) => self
+                .build_relational_ast_internal(
+                    ctx,
+                    &ticket_as_pair(ele, contents_type),
+                ),
             _ => Ok(RelationalAST::Leaf {
                 rel_entry: RelationalEntry {
                     table_name: ctx.table_name.clone(),
@@ -508,6 +680,9 @@ impl ASTBuilder {
         ctx: &Context,
         ele: &Ele,
     ) -> Result<RelationalAST> {
+        if let Some(stop_ele) = self.drop_lambda(ele) {
+            return self.build_index(ctx, &stop_ele);
+        }
         match &ele.expr_type {
             ExprTy::Option(elem_type) => {
                 let ctx = &ele
@@ -540,12 +715,15 @@ impl ASTBuilder {
                     .build_enumeration_or(ctx, ele, &name, true)?
                     .0)
             }
+            ExprTy::Ticket(contents_type) => {
+                self.build_index(ctx, &ticket_as_pair(ele, contents_type))
+            }
             ExprTy::BigMap { .. }
             | ExprTy::Map { .. }
             | ExprTy::List { .. } => {
                 Err(anyhow!("unexpected input type to index: ele={:#?}", ele))
             }
-            ExprTy::Stop => Ok(RelationalAST::Leaf {
+            ExprTy::Stop | ExprTy::Micheline => Ok(RelationalAST::Leaf {
                 rel_entry: RelationalEntry {
                     table_name: ctx.table_name.clone(),
                     column_name: self.column_name(ctx, ele, true),
@@ -584,54 +762,104 @@ fn ele_set_annot(ele: &Ele, annot: Option<String>) -> Ele {
     e
 }
 
+fn ele_set_type(ele: &Ele, expr_type: ExprTy) -> Ele {
+    let mut e = ele.clone();
+    e.expr_type = expr_type;
+    e
+}
+
+/// A ticket value is always of form `Pair ticketer (Pair contents amount)`,
+/// so we index it as if it were that pair, which gives it a ticketer, an
+/// amount and the contents' regular columns.
+fn ticket_as_pair(ele: &Ele, contents_type: &Ele) -> Ele {
+    Ele {
+        name: ele.name.clone(),
+        expr_type: ExprTy::Pair(
+            Box::new(Ele {
+                name: Some("ticketer".to_string()),
+                expr_type: ExprTy::Address,
+                prim: Some("address".to_string()),
+            }),
+            Box::new(Ele {
+                name: None,
+                expr_type: ExprTy::Pair(
+                    Box::new(contents_type.clone()),
+                    Box::new(Ele {
+                        name: Some("amount".to_string()),
+                        expr_type: ExprTy::Nat,
+                        prim: Some("nat".to_string()),
+                    }),
+                ),
+                prim: Some("pair".to_string()),
+            }),
+        ),
+        prim: Some("pair".to_string()),
+    }
+}
+
 #[test]
 fn test_relational_ast_builder() {
     fn simple(n: Option<String>, t: ExprTy) -> Ele {
         Ele {
             expr_type: t,
             name: n,
+            prim: None,
         }
     }
     fn or(n: Option<String>, l: Ele, r: Ele) -> Ele {
         Ele {
             expr_type: ExprTy::OrEnumeration(Box::new(l), Box::new(r)),
             name: n,
+            prim: None,
         }
     }
     fn pair(n: Option<String>, l: Ele, r: Ele) -> Ele {
         Ele {
             expr_type: ExprTy::Pair(Box::new(l), Box::new(r)),
             name: n,
+            prim: None,
         }
     }
     fn set(n: Option<String>, elems: Ele) -> Ele {
         Ele {
             expr_type: ExprTy::List(true, Box::new(elems)),
             name: n,
+            prim: None,
         }
     }
     fn list(n: Option<String>, elems: Ele) -> Ele {
         Ele {
             expr_type: ExprTy::List(false, Box::new(elems)),
             name: n,
+            prim: None,
         }
     }
     fn map(n: Option<String>, key: Ele, value: Ele) -> Ele {
         Ele {
             expr_type: ExprTy::Map(Box::new(key), Box::new(value)),
             name: n,
+            prim: None,
         }
     }
     fn bigmap(n: Option<String>, key: Ele, value: Ele) -> Ele {
         Ele {
             expr_type: ExprTy::BigMap(Box::new(key), Box::new(value)),
             name: n,
+            prim: None,
         }
     }
     fn option(n: Option<String>, elem: Ele) -> Ele {
         Ele {
             expr_type: ExprTy::Option(Box::new(elem)),
             name: n,
+            prim: None,
+        }
+    }
+    fn ticket(n: Option<String>, contents: Ele) -> Ele {
+        Ele {
+            expr_type: ExprTy::Ticket(Box::new(contents)),
+            name: n,
+            prim: None,
         }
     }
 
@@ -803,6 +1031,56 @@ fn test_relational_ast_builder() {
                 }}),
             }),
         },
+        TestCase {
+            name: "variant record nested in an or gets a child table per non-unit variant, unit variants stay in the parent table".to_string(),
+            ele: or(Some("action".to_string()), pair(Some("transfer".to_string()), simple(Some("to".to_string()), ExprTy::Address), simple(Some("amount".to_string()), ExprTy::Nat)), or(None, simple(Some("pause".to_string()), ExprTy::Unit), simple(Some("mint".to_string()), ExprTy::Nat))),
+            exp: Some(RelationalAST::OrEnumeration {
+                or_unfold: Some(RelationalEntry {
+                    table_name: "storage".to_string(),
+                    column_name: "action".to_string(),
+                    column_type: ExprTy::String,
+                    value: None,
+                    is_index: false,
+                }),
+                left_table: Some("storage.transfer".to_string()),
+                left_ast: Box::new(RelationalAST::Pair {
+                    left_ast: Box::new(RelationalAST::Leaf {rel_entry: RelationalEntry {
+                        table_name: "storage.transfer".to_string(),
+                        column_name: "transfer_to".to_string(),
+                        column_type: ExprTy::Address,
+                        value: None,
+                        is_index: false,
+                    }}),
+                    right_ast: Box::new(RelationalAST::Leaf {rel_entry: RelationalEntry {
+                        table_name: "storage.transfer".to_string(),
+                        column_name: "transfer_amount".to_string(),
+                        column_type: ExprTy::Nat,
+                        value: None,
+                        is_index: false,
+                    }}),
+                }),
+                right_table: None,
+                right_ast: Box::new(RelationalAST::OrEnumeration {
+                    or_unfold: None,
+                    left_table: None,
+                    left_ast: Box::new(RelationalAST::Leaf {rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "action_1".to_string(),
+                        column_type: ExprTy::Unit,
+                        value: Some("pause".to_string()),
+                        is_index: false,
+                    }}),
+                    right_table: Some("storage.mint".to_string()),
+                    right_ast: Box::new(RelationalAST::Leaf {rel_entry: RelationalEntry {
+                        table_name: "storage.mint".to_string(),
+                        column_name: "mint".to_string(),
+                        column_type: ExprTy::Nat,
+                        value: None,
+                        is_index: false,
+                    }}),
+                }),
+            }),
+        },
         TestCase {
             name: "set (no annot)".to_string(),
             ele: set(None, pair(Some("left_side".to_string()), simple(Some("var_a".to_string()), ExprTy::String), simple(Some("var_b".to_string()), ExprTy::Nat))),
@@ -1119,6 +1397,48 @@ fn test_relational_ast_builder() {
                 }}),
             }),
         },
+        TestCase {
+            name: "ticket (with annot) unfolds into ticketer, contents and amount columns".to_string(),
+            ele: ticket(Some("reward".to_string()), simple(None, ExprTy::String)),
+            exp: Some(RelationalAST::Pair {
+                left_ast: Box::new(RelationalAST::Leaf {rel_entry: RelationalEntry {
+                    table_name: "storage".to_string(),
+                    column_name: "reward_ticketer".to_string(),
+                    column_type: ExprTy::Address,
+                    value: None,
+                    is_index: false,
+                }}),
+                right_ast: Box::new(RelationalAST::Pair {
+                    left_ast: Box::new(RelationalAST::Leaf {rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "reward_string".to_string(),
+                        column_type: ExprTy::String,
+                        value: None,
+                        is_index: false,
+                    }}),
+                    right_ast: Box::new(RelationalAST::Leaf {rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "reward_amount".to_string(),
+                        column_type: ExprTy::Nat,
+                        value: None,
+                        is_index: false,
+                    }}),
+                }),
+            }),
+        },
+        TestCase {
+            name: "lambda is dropped by default (not kept as micheline)".to_string(),
+            ele: simple(Some("proposal".to_string()), ExprTy::Micheline),
+            exp: Some(RelationalAST::Leaf {
+                rel_entry: RelationalEntry {
+                    table_name: "storage".to_string(),
+                    column_name: "proposal".to_string(),
+                    column_type: ExprTy::Stop,
+                    value: None,
+                    is_index: false,
+                },
+            }),
+        },
         // More complex test cases involving multiple complex types, to test
         // how they interact with each other through global state such as
         // deduplication of table names and column names
@@ -1420,3 +1740,247 @@ fn test_relational_ast_builder() {
         assert_eq!(tc.exp.unwrap(), got.unwrap());
     }
 }
+
+#[test]
+fn test_relational_ast_builder_naming() {
+    use crate::sql::table_builder::TableBuilder;
+    use crate::storage_structure::typing::type_ast_from_json;
+
+    let long_annot = "a_very_long_annotation_".repeat(3);
+    let storage_def: serde_json::Value = serde_json::from_str(&format!(
+        r#"{{
+  "prim": "pair",
+  "args": [
+    {{
+      "prim": "map",
+      "annots": ["%ledger"],
+      "args": [
+        {{"prim": "address"}},
+        {{
+          "prim": "map",
+          "annots": ["%balances"],
+          "args": [{{"prim": "nat"}}, {{"prim": "nat"}}]
+        }}
+      ]
+    }},
+    {{"prim": "nat", "annots": ["%{}"]}}
+  ]
+}}"#,
+        long_annot
+    ))
+    .unwrap();
+
+    let mut renames: HashMap<String, String> = HashMap::new();
+    renames.insert(
+        "storage.ledger.balances".to_string(),
+        "balances".to_string(),
+    );
+
+    let mut builder = ASTBuilder::new("storage");
+    builder.naming(NamingPolicy::new(renames));
+    let storage_ast = builder
+        .build_relational_ast(&type_ast_from_json(&storage_def).unwrap())
+        .unwrap();
+
+    let got = builder.tables();
+    let balances = got.get("balances").unwrap();
+    assert_eq!("storage.ledger.balances", balances.path);
+    assert_eq!(Some("storage.ledger".to_string()), balances.parent);
+    assert_eq!(
+        Some("nat"),
+        balances
+            .columns
+            .get("idx_nat")
+            .and_then(|column| column.michelson_type.as_deref())
+    );
+    let storage = got.get("storage").unwrap();
+    assert_eq!(None, storage.parent);
+    let (shortened, column) = storage
+        .columns
+        .iter()
+        .find(|(_, column)| column.name == long_annot)
+        .unwrap();
+    assert!(shortened.len() <= 63);
+    assert_eq!(Some(long_annot.clone()), column.annotation);
+    assert_eq!(Some("nat".to_string()), column.michelson_type);
+
+    // The tables are named accordingly, and keep referencing their parent
+    let contract = Contract {
+        cid: ContractID {
+            name: "test".to_string(),
+            address: "KT1".to_string(),
+        },
+        level_floor: None,
+        storage_ast,
+        entrypoint_asts: HashMap::new(),
+        tables: got.clone(),
+        selection: Selection::default(),
+    };
+    let (tables, _, _) = TableBuilder::tables_from_contract(&contract);
+    let balances = tables
+        .iter()
+        .find(|t| t.name == "balances")
+        .unwrap();
+    assert_eq!("storage.ledger.balances", balances.path());
+    assert_eq!(
+        Some("storage.ledger".to_string()),
+        PostgresqlGenerator::table_parent_name(balances)
+    );
+}
+
+#[test]
+fn test_relational_ast_builder_selection() {
+    use crate::sql::table_builder::TableBuilder;
+    use crate::storage_structure::selection::StorageSelection;
+    use crate::storage_structure::typing::type_ast_from_json;
+
+    let storage_def: serde_json::Value = serde_json::from_str(
+        r#"{
+  "prim": "pair",
+  "args": [
+    {
+      "prim": "big_map",
+      "annots": ["%ledger"],
+      "args": [
+        {"prim": "address"},
+        {
+          "prim": "pair",
+          "args": [
+            {"prim": "nat", "annots": ["%balance"]},
+            {
+              "prim": "map",
+              "annots": ["%allowances"],
+              "args": [{"prim": "address"}, {"prim": "nat"}]
+            }
+          ]
+        }
+      ]
+    },
+    {
+      "prim": "pair",
+      "args": [
+        {"prim": "address", "annots": ["%admin"]},
+        {
+          "prim": "map",
+          "annots": ["%metadata"],
+          "args": [{"prim": "string"}, {"prim": "bytes"}]
+        }
+      ]
+    }
+  ]
+}"#,
+    )
+    .unwrap();
+    let type_ast = type_ast_from_json(&storage_def).unwrap();
+
+    struct TestCase {
+        name: String,
+        selection: StorageSelection,
+        exp_tables: Vec<&'static str>,
+        exp_storage_columns: Vec<&'static str>,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "everything".to_string(),
+            selection: StorageSelection::default(),
+            exp_tables: vec![
+                "storage",
+                "storage.ledger",
+                "storage.ledger.allowances",
+                "storage.metadata",
+            ],
+            exp_storage_columns: vec!["admin"],
+        },
+        TestCase {
+            name: "excluded map".to_string(),
+            selection: StorageSelection {
+                exclude: vec!["storage.metadata".to_string()],
+                ..StorageSelection::default()
+            },
+            exp_tables: vec![
+                "storage",
+                "storage.ledger",
+                "storage.ledger.allowances",
+            ],
+            exp_storage_columns: vec!["admin"],
+        },
+        TestCase {
+            name: "excluded map in a big map".to_string(),
+            selection: StorageSelection {
+                exclude: vec!["storage.ledger.allowances".to_string()],
+                ..StorageSelection::default()
+            },
+            exp_tables: vec!["storage", "storage.ledger", "storage.metadata"],
+            exp_storage_columns: vec!["admin"],
+        },
+        TestCase {
+            name: "included big map".to_string(),
+            selection: StorageSelection {
+                include: vec!["storage.ledger".to_string()],
+                ..StorageSelection::default()
+            },
+            exp_tables: vec![
+                "storage",
+                "storage.ledger",
+                "storage.ledger.allowances",
+            ],
+            exp_storage_columns: vec![],
+        },
+        TestCase {
+            name: "no storage snapshot".to_string(),
+            selection: StorageSelection {
+                snapshot: false,
+                ..StorageSelection::default()
+            },
+            exp_tables: vec!["storage.ledger", "storage.ledger.allowances"],
+            exp_storage_columns: vec![],
+        },
+    ];
+
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let selection = Selection {
+            storage: tc.selection,
+            ..Selection::default()
+        };
+        let mut builder = ASTBuilder::new("storage");
+        builder.selection(selection.clone());
+        let storage_ast = builder
+            .build_relational_ast(&type_ast)
+            .unwrap();
+        let contract = Contract {
+            cid: ContractID {
+                name: "test".to_string(),
+                address: "KT1".to_string(),
+            },
+            level_floor: None,
+            storage_ast,
+            entrypoint_asts: HashMap::new(),
+            tables: builder.tables().clone(),
+            selection,
+        };
+
+        let (tables, _, _) = TableBuilder::tables_from_contract(&contract);
+        let mut got_tables: Vec<&str> = tables
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect();
+        got_tables.sort_unstable();
+        assert_eq!(tc.exp_tables, got_tables);
+
+        let got_storage_columns: Vec<String> = tables
+            .iter()
+            .filter(|t| t.name == "storage")
+            .flat_map(|t| {
+                let keywords = t.keywords();
+                t.get_columns()
+                    .into_iter()
+                    .filter(move |c| !keywords.contains(&c.name))
+                    .filter(|c| PostgresqlGenerator::create_sql(c).is_some())
+                    .map(|c| c.name.clone())
+            })
+            .collect();
+        assert_eq!(tc.exp_storage_columns, got_storage_columns);
+    }
+}
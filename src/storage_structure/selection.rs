@@ -0,0 +1,174 @@
+#[cfg(test)]
+use pretty_assertions::assert_eq;
+
+/// Which parts of a contract are indexed, as set per contract in the
+/// contract settings. By default everything is.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+pub(crate) struct Selection {
+    #[serde(default)]
+    pub storage: StorageSelection,
+    #[serde(default)]
+    pub entrypoints: Filter,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+pub(crate) struct StorageSelection {
+    /// Paths of storage values, as they appear in table names (eg
+    /// storage.ledger for the value annotated %ledger in the storage root).
+    /// Selecting a path selects everything below it.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Whether to keep snapshots of the storage. If not, only the contents
+    /// of big maps are indexed.
+    #[serde(default = "default_true")]
+    pub snapshot: bool,
+}
+
+impl Default for StorageSelection {
+    fn default() -> Self {
+        Self {
+            include: vec![],
+            exclude: vec![],
+            snapshot: true,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+pub(crate) struct Filter {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl Filter {
+    pub(crate) fn selects(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|i| i == name))
+            && !self.exclude.iter().any(|e| e == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum PathSelection {
+    /// The value and everything below it is indexed (unless excluded
+    /// further down)
+    Included,
+    /// The value is only indexed for the sake of values below it that are
+    /// included
+    Ancestor,
+    Excluded,
+}
+
+impl Selection {
+    pub(crate) fn selects_entrypoint(&self, entrypoint: &str) -> bool {
+        self.entrypoints.selects(entrypoint)
+    }
+
+    pub(crate) fn storage_path(&self, path: &str) -> PathSelection {
+        let below = |p: &str, of: &str| {
+            p == of || (p.starts_with(of) && p[of.len()..].starts_with('.'))
+        };
+        if self
+            .storage
+            .exclude
+            .iter()
+            .any(|e| below(path, e))
+        {
+            return PathSelection::Excluded;
+        }
+        if self.storage.include.is_empty()
+            || self
+                .storage
+                .include
+                .iter()
+                .any(|i| below(path, i))
+        {
+            return PathSelection::Included;
+        }
+        if self
+            .storage
+            .include
+            .iter()
+            .any(|i| below(i, path))
+        {
+            return PathSelection::Ancestor;
+        }
+        PathSelection::Excluded
+    }
+}
+
+#[test]
+fn test_storage_path() {
+    let selection = Selection {
+        storage: StorageSelection {
+            include: vec![
+                "storage.assets.ledger".to_string(),
+                "storage.admin".to_string(),
+            ],
+            exclude: vec!["storage.assets.ledger.metadata".to_string()],
+            snapshot: true,
+        },
+        entrypoints: Filter::default(),
+    };
+
+    struct TestCase {
+        path: &'static str,
+        exp: PathSelection,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            path: "storage.admin",
+            exp: PathSelection::Included,
+        },
+        TestCase {
+            path: "storage.assets.ledger.value",
+            exp: PathSelection::Included,
+        },
+        TestCase {
+            path: "storage.assets",
+            exp: PathSelection::Ancestor,
+        },
+        TestCase {
+            path: "storage.assets.ledger.metadata",
+            exp: PathSelection::Excluded,
+        },
+        TestCase {
+            path: "storage.assets.ledger_2",
+            exp: PathSelection::Excluded,
+        },
+        TestCase {
+            path: "storage.administrator",
+            exp: PathSelection::Excluded,
+        },
+    ];
+    for tc in tests {
+        println!("test case: {}", tc.path);
+        assert_eq!(tc.exp, selection.storage_path(tc.path));
+    }
+
+    assert_eq!(
+        PathSelection::Included,
+        Selection::default().storage_path("storage.anything")
+    );
+}
+
+#[test]
+fn test_selects_entrypoint() {
+    let mut selection = Selection::default();
+    assert!(selection.selects_entrypoint("transfer"));
+
+    selection.entrypoints.exclude = vec!["update_operators".to_string()];
+    assert!(selection.selects_entrypoint("transfer"));
+    assert!(!selection.selects_entrypoint("update_operators"));
+
+    selection.entrypoints.include = vec!["mint".to_string()];
+    assert!(!selection.selects_entrypoint("transfer"));
+    assert!(selection.selects_entrypoint("mint"));
+}
@@ -22,12 +22,18 @@ pub enum ExprTy {
     Pair(Box<Ele>, Box<Ele>),
     OrEnumeration(Box<Ele>, Box<Ele>),
     Option(Box<Ele>),
+    Ticket(Box<Ele>),
+    Micheline,
+    SaplingState,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Ele {
     pub expr_type: ExprTy,
     pub name: Option<String>,
+    /// The Michelson type the element was derived from (eg key for a
+    /// KeyHash, lambda for a Micheline), if it was derived from one
+    pub prim: Option<String>,
 }
 
 fn annotation(json: &serde_json::Value) -> Option<String> {
@@ -50,37 +56,56 @@ macro_rules! simple_expr {
         Ele {
             name: $name,
             expr_type: $typ,
+            prim: None,
         }
     };
 }
 
 macro_rules! complex_expr {
     ($typ:expr, $name:expr, $args:expr) => {{
-        let args = $args.unwrap();
+        let args = $args.ok_or_else(|| anyhow!("Args was none!"))?;
+        if args.len() != 2 {
+            return Err(anyhow!(
+                "expected 2 args, got {}: {:?}",
+                args.len(),
+                args
+            ));
+        }
         Ele {
             name: $name,
             expr_type: $typ(
                 Box::new(type_ast_from_json(&args[0].clone())?),
                 Box::new(type_ast_from_json(&args[1].clone())?),
             ),
+            prim: None,
         }
     }};
 }
 
-/// An or can be a variant record, or a simple enumeration.
-pub(crate) fn is_enumeration_or(json: &serde_json::Value) -> bool {
-    let prim = match &json["prim"] {
-        serde_json::Value::String(s) => s.as_str(),
-        _ => return false,
-    };
-    match prim {
-        "or" => true,
-        "unit" => false,
-        _ => true,
-    }
+// the type of a type's single arg (eg an option's)
+macro_rules! single_arg_expr {
+    ($args:expr) => {{
+        let args = $args.ok_or_else(|| anyhow!("Args was none!"))?;
+        if args.len() != 1 {
+            return Err(anyhow!(
+                "expected 1 arg, got {}: {:?}",
+                args.len(),
+                args
+            ));
+        }
+        Box::new(type_ast_from_json(&args[0])?)
+    }};
 }
 
 pub(crate) fn type_ast_from_json(json: &serde_json::Value) -> Result<Ele> {
+    let mut ele = ele_from_json(json)?;
+    if let serde_json::Value::String(prim) = &json["prim"] {
+        ele.prim = Some(prim.to_ascii_lowercase());
+    }
+    Ok(ele)
+}
+
+fn ele_from_json(json: &serde_json::Value) -> Result<Ele> {
     let annot = annotation(json);
     let args = args(json);
     if let serde_json::Value::String(prim) = &json["prim"] {
@@ -88,7 +113,11 @@ pub(crate) fn type_ast_from_json(json: &serde_json::Value) -> Result<Ele> {
             "address" => Ok(simple_expr!(ExprTy::Address, annot)),
             "big_map" => Ok(complex_expr!(ExprTy::BigMap, annot, args)),
             "bool" => Ok(simple_expr!(ExprTy::Bool, annot)),
-            "bytes" | "chest" | "chest_key" => Ok(simple_expr!(
+            "bytes"
+            | "chest"
+            | "chest_key"
+            | "sapling_transaction"
+            | "sapling_transaction_deprecated" => Ok(simple_expr!(
                 ExprTy::Bytes,
                 annot.or_else(|| Some(
                     prim.to_ascii_lowercase()
@@ -102,60 +131,43 @@ pub(crate) fn type_ast_from_json(json: &serde_json::Value) -> Result<Ele> {
             "map" => Ok(complex_expr!(ExprTy::Map, annot, args)),
             "mutez" => Ok(simple_expr!(ExprTy::Mutez, annot)),
             "nat" => Ok(simple_expr!(ExprTy::Nat, annot)),
-            "option" => {
-                let args = args.ok_or_else(|| anyhow!("Args was none!"))?;
-                Ok(Ele {
-                    name: annot,
-                    expr_type: ExprTy::Option(Box::new(type_ast_from_json(
-                        &args[0].clone(),
-                    )?)),
-                })
-            }
-            "or" => {
-                if is_enumeration_or(json) {
-                    Ok(complex_expr!(ExprTy::OrEnumeration, annot, args))
-                } else {
-                    unimplemented!(
-                        "Or used as variant record found, don't know how to deal with it {}",
-                        json.to_string()
-                    );
-                }
-            }
+            "option" => Ok(Ele {
+                name: annot,
+                expr_type: ExprTy::Option(single_arg_expr!(args)),
+                prim: None,
+            }),
+            // An or is either a simple enumeration (all its branches are
+            // units) or a variant record. Both are represented by the same
+            // type, the relational layer decides which branches end up in
+            // their own table.
+            "or" => Ok(complex_expr!(ExprTy::OrEnumeration, annot, args)),
             "pair" => {
-                let args_count = args
-                    .clone()
-                    .ok_or_else(|| anyhow!("NoneError"))?
-                    .len();
-                match args_count {
-                    0 | 1 => {
-                        return Err(anyhow!("Pair with {} args", args_count))
-                    }
-                    2 => Ok(complex_expr!(ExprTy::Pair, annot, args)),
+                let mut args = args.ok_or_else(|| anyhow!("Args was none!"))?;
+                match args.len() {
+                    0 | 1 => Err(anyhow!(
+                        "expected at least 2 args, got {}: {:?}",
+                        args.len(),
+                        args
+                    )),
+                    2 => Ok(complex_expr!(ExprTy::Pair, annot, Some(args))),
                     _ => {
-                        let mut args_cloned = args
-                            .ok_or_else(|| anyhow!("Args was none!"))
-                            .unwrap();
-                        args_cloned.reverse();
+                        args.reverse();
                         let unfolded =
-                            parser::lexer_unfold_many_pair(&mut args_cloned);
+                            parser::lexer_unfold_many_pair(&mut args);
                         type_ast_from_json(&unfolded)
                     }
                 }
             }
-            "set" => {
-                let inner_ast = type_ast_from_json(&args.unwrap()[0])?;
-                Ok(Ele {
-                    name: annot,
-                    expr_type: ExprTy::List(true, Box::new(inner_ast)),
-                })
-            }
-            "list" => {
-                let inner_ast = type_ast_from_json(&args.unwrap()[0])?;
-                Ok(Ele {
-                    name: annot,
-                    expr_type: ExprTy::List(false, Box::new(inner_ast)),
-                })
-            }
+            "set" => Ok(Ele {
+                name: annot,
+                expr_type: ExprTy::List(true, single_arg_expr!(args)),
+                prim: None,
+            }),
+            "list" => Ok(Ele {
+                name: annot,
+                expr_type: ExprTy::List(false, single_arg_expr!(args)),
+                prim: None,
+            }),
             "string" => Ok(simple_expr!(ExprTy::String, annot)),
             "chain_id" | "bls12_381_g1" | "bls12_381_g2" | "bls12_381_fr" => {
                 Ok(simple_expr!(
@@ -167,16 +179,21 @@ pub(crate) fn type_ast_from_json(json: &serde_json::Value) -> Result<Ele> {
                     ))
                 ))
             }
+            "ticket" => Ok(Ele {
+                name: annot,
+                expr_type: ExprTy::Ticket(single_arg_expr!(args)),
+                prim: None,
+            }),
             "timestamp" => Ok(simple_expr!(ExprTy::Timestamp, annot)),
             "unit" => Ok(simple_expr!(ExprTy::Unit, annot)),
-            // - ignoring constants, as far as we can see now there's no reason
-            // to index these
-            // - ignoring tickets and sapling_state because it's not clear to
-            // us right now how this info would be used exactly
-            // - ignoring lambdas because they're a pandoras box. probably are
-            // impossible to index in a meaningful way
-            "constant" | "never" | "ticket" | "sapling_state" | "lambda" => {
-                Ok(simple_expr!(ExprTy::Stop, annot))
+            // sapling states are only indexed by their id, a summary of their
+            // updates is taken from the lazy storage diffs
+            "sapling_state" => Ok(simple_expr!(ExprTy::SaplingState, annot)),
+            // lambdas are a pandoras box, they're impossible to index in a
+            // meaningful way. at most we can keep their raw micheline (only
+            // if asked for, otherwise the relational layer drops them)
+            "constant" | "never" | "lambda" => {
+                Ok(simple_expr!(ExprTy::Micheline, annot))
             }
             "contract" | "signature" => {
                 Ok(simple_expr!(ExprTy::KeyHash, annot))
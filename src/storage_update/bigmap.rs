@@ -7,6 +7,7 @@ use pretty_assertions::assert_eq;
 use crate::octez::block::{
     BigMapDiff, Block, LazyStorageDiff, TxContext, Update, Updates::*,
 };
+use crate::storage_update::sapling::SaplingStateUpdate;
 
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) enum Op {
@@ -26,6 +27,9 @@ pub(crate) enum Op {
     Clear {
         bigmap: i32,
     },
+    // Not a big map op, sapling states are lazy storage too. These are
+    // processed separately from the big map ops (see from_block)
+    Sapling(SaplingStateUpdate),
 }
 
 impl Op {
@@ -35,6 +39,7 @@ impl Op {
             Op::Clear { bigmap, .. } => *bigmap,
             Op::Copy { bigmap, .. } => *bigmap,
             Op::Alloc { bigmap } => *bigmap,
+            Op::Sapling(update) => update.sapling_state,
         }
     }
 
@@ -44,13 +49,16 @@ impl Op {
             Op::Clear { bigmap } => *bigmap = id,
             Op::Copy { bigmap, .. } => *bigmap = id,
             Op::Alloc { bigmap } => *bigmap = id,
+            Op::Sapling(update) => update.sapling_state = id,
         }
     }
 
     pub fn from_raw_lazy(raw: &LazyStorageDiff) -> Result<Vec<Self>> {
         // The structure that replaced the deprecated "big_map_diff"
-        if raw.kind != "big_map" {
-            return Ok(vec![]);
+        match raw.kind.as_str() {
+            "big_map" => {}
+            "sapling_state" => return Ok(vec![Self::from_raw_sapling(raw)?]),
+            _ => return Ok(vec![]),
         }
         let bigmap = raw.id.parse::<i32>()?;
         let mut ops = match raw.diff.action.as_str() {
@@ -119,6 +127,48 @@ impl Op {
         Ok(ops)
     }
 
+    fn from_raw_sapling(raw: &LazyStorageDiff) -> Result<Self> {
+        let (commitments, nullifiers) = match &raw.diff.updates {
+            Some(SaplingUpdates(u)) => {
+                (u.commitments_and_ciphertexts.len(), u.nullifiers.len())
+            }
+            None => (0, 0),
+            _ => {
+                return Err(anyhow!(
+                    "unknown sapling updates shape: {:#?}",
+                    raw.diff.updates
+                ))
+            }
+        };
+        let source = match raw.diff.action.as_str() {
+            "alloc" | "update" | "remove" => None,
+            "copy" => Some(
+                raw.diff
+                    .source
+                    .clone()
+                    .ok_or_else(|| {
+                        anyhow!("'source' missing in sapling copy {:?}", raw)
+                    })?
+                    .parse()?,
+            ),
+            _ => {
+                return Err(anyhow!(
+                    "unknown sapling_state action: {}",
+                    raw.diff.action
+                ))
+            }
+        };
+        Ok(Op::Sapling(SaplingStateUpdate {
+            sapling_state: raw.id.parse()?,
+            action: raw.diff.action.clone(),
+            source,
+            memo_size: raw.diff.memo_size,
+            commitments,
+            nullifiers,
+            root: None,
+        }))
+    }
+
     pub fn from_raw(raw: &BigMapDiff) -> Result<Option<Self>> {
         // From the depricated "big_map_diff" entries
         match raw.action.as_str() {
@@ -194,7 +244,11 @@ impl IntraBlockBigmapDiffsProcessor {
                         .as_ref()
                         .unwrap()
                     {
-                        ops.extend(Op::from_raw_lazy(lazy_diff)?);
+                        ops.extend(
+                            Op::from_raw_lazy(lazy_diff)?
+                                .into_iter()
+                                .filter(|op| !matches!(op, Op::Sapling(_))),
+                        );
                     }
                 } else {
                     if op_res.big_map_diff.is_none() {
@@ -334,6 +388,8 @@ impl IntraBlockBigmapDiffsProcessor {
                                 res.push(op.clone());
                             }
                         }
+                        // never part of tx_bigmap_ops
+                        Op::Sapling(_) => {}
                     };
                 }
                 targets = targets
@@ -657,3 +713,94 @@ fn test_normalizer() {
         assert_eq!(tc.exp_ops, got_ops);
     }
 }
+
+#[test]
+fn test_from_raw_lazy_sapling() {
+    use serde_json::json;
+
+    let tests: Vec<(serde_json::Value, Vec<Op>)> = vec![
+        (
+            json!({
+                "kind": "big_map",
+                "id": "5",
+                "diff": {"action": "remove"},
+            }),
+            vec![Op::Clear { bigmap: 5 }],
+        ),
+        (
+            json!({
+                "kind": "sapling_state",
+                "id": "14",
+                "diff": {
+                    "action": "alloc",
+                    "updates": {
+                        "commitments_and_ciphertexts": [],
+                        "nullifiers": [],
+                    },
+                    "memo_size": 8,
+                },
+            }),
+            vec![Op::Sapling(SaplingStateUpdate {
+                sapling_state: 14,
+                action: "alloc".to_string(),
+                source: None,
+                memo_size: Some(8),
+                commitments: 0,
+                nullifiers: 0,
+                root: None,
+            })],
+        ),
+        (
+            json!({
+                "kind": "sapling_state",
+                "id": "14",
+                "diff": {
+                    "action": "update",
+                    "updates": {
+                        "commitments_and_ciphertexts": [
+                            ["a1", {"epk": "e1"}],
+                            ["a2", {"epk": "e2"}],
+                        ],
+                        "nullifiers": ["n1"],
+                    },
+                },
+            }),
+            vec![Op::Sapling(SaplingStateUpdate {
+                sapling_state: 14,
+                action: "update".to_string(),
+                source: None,
+                memo_size: None,
+                commitments: 2,
+                nullifiers: 1,
+                root: None,
+            })],
+        ),
+        (
+            json!({
+                "kind": "sapling_state",
+                "id": "-3",
+                "diff": {
+                    "action": "copy",
+                    "source": "14",
+                    "updates": {
+                        "commitments_and_ciphertexts": [],
+                        "nullifiers": [],
+                    },
+                },
+            }),
+            vec![Op::Sapling(SaplingStateUpdate {
+                sapling_state: -3,
+                action: "copy".to_string(),
+                source: Some(14),
+                memo_size: None,
+                commitments: 0,
+                nullifiers: 0,
+                root: None,
+            })],
+        ),
+    ];
+    for (raw, exp) in tests {
+        let raw: LazyStorageDiff = serde_json::from_value(raw).unwrap();
+        assert_eq!(exp, Op::from_raw_lazy(&raw).unwrap());
+    }
+}
@@ -1,2 +1,3 @@
 pub mod bigmap;
 pub mod processor;
+pub mod sapling;
@@ -13,11 +13,14 @@ use crate::storage_structure::relational::{
 use crate::storage_structure::typing::ExprTy;
 use crate::storage_update::bigmap;
 use crate::storage_update::bigmap::IntraBlockBigmapDiffsProcessor;
+use crate::storage_update::sapling;
+use crate::storage_update::sapling::SaplingStateUpdate;
 use crate::storage_value::parser;
 use anyhow::{anyhow, Context, Result};
 use num::ToPrimitive;
 use pg_bigdecimal::{BigDecimal, PgNumeric};
 use serde_json::json;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
 #[cfg(test)]
@@ -198,6 +201,14 @@ where
             })?;
 
         for (tx_context, param_parsed, parsed_storage) in &storages {
+            let param_parsed =
+                param_parsed
+                    .as_ref()
+                    .filter(|(entrypoint, _)| {
+                        contract
+                            .selection
+                            .selects_entrypoint(entrypoint)
+                    });
             if let Some((entrypoint, param_v)) = param_parsed {
                 #[cfg(not(test))]
                 let allow_missing_entrpoint_asts: bool = false;
@@ -279,9 +290,102 @@ where
             }
         }
 
+        if !contract.selection.storage.snapshot {
+            // The storage's root rows are still touched on the way to the
+            // contents of its big maps
+            self.inserts
+                .retain(|k, _| k.table_name != "storage");
+        }
+
+        let sapling_updates: Vec<(TxContext, Vec<SaplingStateUpdate>)> = block
+            .map_tx_contexts(|tx_context, tx, _is_origination, op_res| {
+                if tx_context.contract != contract.cid.address {
+                    return Ok(None);
+                }
+                let mut updates: Vec<SaplingStateUpdate> = vec![];
+                for lazy_diff in op_res
+                    .lazy_storage_diff
+                    .iter()
+                    .flatten()
+                {
+                    for op in bigmap::Op::from_raw_lazy(lazy_diff)? {
+                        if let bigmap::Op::Sapling(update) = op {
+                            updates.push(update);
+                        }
+                    }
+                }
+                if updates.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some((self.tx_context(tx_context, tx), updates)))
+            })?;
+        let with_root = sapling::states_with_root(
+            sapling_updates
+                .iter()
+                .flat_map(|(_, updates)| updates),
+        );
+        let mut roots: HashMap<i32, String> = HashMap::new();
+        for (tx_context, updates) in &sapling_updates {
+            for update in updates {
+                let mut update = update.clone();
+                if with_root.contains(&update.sapling_state) {
+                    let root = match roots.entry(update.sapling_state) {
+                        Entry::Occupied(e) => e.get().clone(),
+                        Entry::Vacant(e) => e
+                            .insert(self.node_cli.get_sapling_root(
+                                tx_context.level,
+                                update.sapling_state,
+                            )?)
+                            .clone(),
+                    };
+                    update.root = Some(root);
+                }
+                self.process_sapling_update(&update, tx_context);
+            }
+        }
+
         Ok(())
     }
 
+    fn process_sapling_update(
+        &mut self,
+        update: &SaplingStateUpdate,
+        tx_context: &TxContext,
+    ) {
+        fn numeric(i: i64) -> insert::Value {
+            insert::Value::Numeric(PgNumeric::new(Some(BigDecimal::from(i))))
+        }
+
+        let ctx = &ProcessStorageContext::new(
+            self.id_generator.get_id(),
+            sapling::UPDATES_TABLE.to_string(),
+        );
+        let mut cells = vec![
+            ("sapling_state_id", numeric(update.sapling_state as i64)),
+            ("action", insert::Value::String(update.action.clone())),
+            ("commitments", numeric(update.commitments as i64)),
+            ("nullifiers", numeric(update.nullifiers as i64)),
+        ];
+        if let Some(source) = update.source {
+            cells.push(("source", numeric(source as i64)));
+        }
+        if let Some(memo_size) = update.memo_size {
+            cells.push(("memo_size", numeric(memo_size as i64)));
+        }
+        if let Some(root) = &update.root {
+            cells.push(("root", insert::Value::String(root.clone())));
+        }
+        for (column, value) in cells {
+            self.sql_add_cell(
+                ctx,
+                sapling::UPDATES_TABLE,
+                column,
+                value,
+                tx_context,
+            );
+        }
+    }
+
     pub(crate) fn drain_bigmap_contract_dependencies(
         &mut self,
     ) -> Vec<(String, i32, bool)> {
@@ -628,6 +732,10 @@ where
                     });
                 Ok(())
             }
+            bigmap::Op::Sapling(_) => Err(anyhow!(
+                "unexpected sapling state update among the big map ops: {:?}",
+                op
+            )),
         }
     }
 
@@ -682,12 +790,27 @@ where
             debug::pp_depth(4, rel_ast)
         );
         match rel_ast {
-            RelationalAST::Leaf { rel_entry } => {
-                if let ExprTy::Stop = rel_entry.column_type {
+            RelationalAST::Leaf { rel_entry } => match rel_entry.column_type {
+                ExprTy::Stop => {
                     // we don't even try to store lambdas.
                     return Ok(());
                 }
-            }
+                ExprTy::Micheline => {
+                    // the value is code, its sequences must not be unfolded
+                    // as if they were data
+                    let v =
+                        Self::storage2sql_value(&rel_entry.column_type, value)?;
+                    self.sql_add_cell(
+                        ctx,
+                        &rel_entry.table_name,
+                        &rel_entry.column_name,
+                        v,
+                        tx_context,
+                    );
+                    return Ok(());
+                }
+                _ => {}
+            },
             RelationalAST::OrEnumeration {
                 or_unfold: Some(or_unfold),
                 ..
@@ -981,7 +1104,10 @@ where
                     "storage2sql_value: failed to match type with value"
                 )),
             },
-            ExprTy::Int | ExprTy::Nat | ExprTy::Mutez => match v {
+            ExprTy::Int
+            | ExprTy::Nat
+            | ExprTy::Mutez
+            | ExprTy::SaplingState => match v {
                 parser::Value::Int(i)
                 | parser::Value::Mutez(i)
                 | parser::Value::Nat(i) => Ok(insert::Value::Numeric(
@@ -991,6 +1117,9 @@ where
                     "storage2sql_value: failed to match type with value"
                 )),
             },
+            ExprTy::Micheline => Ok(insert::Value::Micheline(
+                insert::RawJson(v.to_micheline()?),
+            )),
             _ => Err(anyhow!(
                 "storage2sql_value: failed to match type with value (type={:?}), value={:?})", t, v
             )),
@@ -1645,6 +1774,313 @@ fn test_process_michelson_value() {
                 },
             ],
         },
+        TestCase {
+            name: "ticket (Pair ticketer (Pair contents amount))".to_string(),
+            rel_ast: RelationalAST::Pair {
+                left_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "reward_ticketer".to_string(),
+                        column_type: ExprTy::Address,
+                        value: None,
+                        is_index: false,
+                    },
+                }),
+                right_ast: Box::new(RelationalAST::Pair {
+                    left_ast: Box::new(RelationalAST::Leaf {
+                        rel_entry: RelationalEntry {
+                            table_name: "storage".to_string(),
+                            column_name: "reward_string".to_string(),
+                            column_type: ExprTy::String,
+                            value: None,
+                            is_index: false,
+                        },
+                    }),
+                    right_ast: Box::new(RelationalAST::Leaf {
+                        rel_entry: RelationalEntry {
+                            table_name: "storage".to_string(),
+                            column_name: "reward_amount".to_string(),
+                            column_type: ExprTy::Nat,
+                            value: None,
+                            is_index: false,
+                        },
+                    }),
+                }),
+            },
+            value: parser::Value::Pair(
+                Box::new(parser::Value::Address(
+                    "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+                )),
+                Box::new(parser::Value::Pair(
+                    Box::new(parser::Value::String("gold".to_string())),
+                    Box::new(parser::Value::Int(BigInt::from(5))),
+                )),
+            ),
+            tx_context: TxContext {
+                id: Some(32),
+                level: 10,
+                contract: "test".to_string(),
+                operation_group_number: 1,
+                operation_number: 2,
+                content_number: 3,
+                internal_number: None,
+            },
+            exp_inserts: vec![Insert {
+                table_name: "storage".to_string(),
+                id: 1,
+                fk_id: None,
+                columns: vec![
+                    Column {
+                        name: "tx_context_id".to_string(),
+                        value: insert::Value::BigInt(32),
+                    },
+                    Column {
+                        name: "reward_amount".to_string(),
+                        value: numeric(5),
+                    },
+                    Column {
+                        name: "reward_string".to_string(),
+                        value: insert::Value::String("gold".to_string()),
+                    },
+                    Column {
+                        name: "reward_ticketer".to_string(),
+                        value: insert::Value::String(
+                            "KT1U7Adyu5A7JWvEVSKjJEkG2He2SU1nATfq".to_string(),
+                        ),
+                    },
+                ],
+            }],
+        },
+        TestCase {
+            name: "lambda (kept as raw micheline)".to_string(),
+            rel_ast: RelationalAST::Leaf {
+                rel_entry: RelationalEntry {
+                    table_name: "storage".to_string(),
+                    column_name: "proposal".to_string(),
+                    column_type: ExprTy::Micheline,
+                    value: None,
+                    is_index: false,
+                },
+            },
+            value: parser::parse_json(&json!([
+                {"prim": "DROP"},
+                {"prim": "NIL", "args": [{"prim": "operation"}]},
+            ]))
+            .unwrap(),
+            tx_context: TxContext {
+                id: Some(32),
+                level: 10,
+                contract: "test".to_string(),
+                operation_group_number: 1,
+                operation_number: 2,
+                content_number: 3,
+                internal_number: None,
+            },
+            exp_inserts: vec![Insert {
+                table_name: "storage".to_string(),
+                id: 1,
+                fk_id: None,
+                columns: vec![
+                    Column {
+                        name: "tx_context_id".to_string(),
+                        value: insert::Value::BigInt(32),
+                    },
+                    Column {
+                        name: "proposal".to_string(),
+                        value: insert::Value::Micheline(insert::RawJson(
+                            r#"[{"prim":"DROP"},{"args":[{"prim":"operation"}],"prim":"NIL"}]"#.to_string(),
+                        )),
+                    },
+                ],
+            }],
+        },
+        TestCase {
+            name: "comb pair with a lambda (kept as raw micheline)".to_string(),
+            rel_ast: RelationalAST::Pair {
+                left_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "counter".to_string(),
+                        column_type: ExprTy::Nat,
+                        value: None,
+                        is_index: false,
+                    },
+                }),
+                right_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "proposal".to_string(),
+                        column_type: ExprTy::Micheline,
+                        value: None,
+                        is_index: false,
+                    },
+                }),
+            },
+            value: parser::parse_json(&json!([
+                {"int": "1"},
+                [{"prim": "DROP"}],
+            ]))
+            .unwrap(),
+            tx_context: TxContext {
+                id: Some(32),
+                level: 10,
+                contract: "test".to_string(),
+                operation_group_number: 1,
+                operation_number: 2,
+                content_number: 3,
+                internal_number: None,
+            },
+            exp_inserts: vec![Insert {
+                table_name: "storage".to_string(),
+                id: 1,
+                fk_id: None,
+                columns: vec![
+                    Column {
+                        name: "tx_context_id".to_string(),
+                        value: insert::Value::BigInt(32),
+                    },
+                    Column {
+                        name: "proposal".to_string(),
+                        value: insert::Value::Micheline(insert::RawJson(
+                            r#"[{"prim":"DROP"}]"#.to_string(),
+                        )),
+                    },
+                    Column {
+                        name: "counter".to_string(),
+                        value: numeric(1),
+                    },
+                ],
+            }],
+        },
+        TestCase {
+            name: "comb pair with a lambda (dropped)".to_string(),
+            rel_ast: RelationalAST::Pair {
+                left_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "counter".to_string(),
+                        column_type: ExprTy::Nat,
+                        value: None,
+                        is_index: false,
+                    },
+                }),
+                right_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage".to_string(),
+                        column_name: "proposal".to_string(),
+                        column_type: ExprTy::Stop,
+                        value: None,
+                        is_index: false,
+                    },
+                }),
+            },
+            value: parser::parse_json(&json!([
+                {"int": "1"},
+                [{"prim": "DROP"}],
+            ]))
+            .unwrap(),
+            tx_context: TxContext {
+                id: Some(32),
+                level: 10,
+                contract: "test".to_string(),
+                operation_group_number: 1,
+                operation_number: 2,
+                content_number: 3,
+                internal_number: None,
+            },
+            exp_inserts: vec![Insert {
+                table_name: "storage".to_string(),
+                id: 1,
+                fk_id: None,
+                columns: vec![
+                    Column {
+                        name: "tx_context_id".to_string(),
+                        value: insert::Value::BigInt(32),
+                    },
+                    Column {
+                        name: "counter".to_string(),
+                        value: numeric(1),
+                    },
+                ],
+            }],
+        },
+        TestCase {
+            name: "list of lambdas".to_string(),
+            rel_ast: RelationalAST::List {
+                table: "storage.proposals".to_string(),
+                elems_unique: false,
+                elems_ast: Box::new(RelationalAST::Leaf {
+                    rel_entry: RelationalEntry {
+                        table_name: "storage.proposals".to_string(),
+                        column_name: "proposals".to_string(),
+                        column_type: ExprTy::Micheline,
+                        value: None,
+                        is_index: false,
+                    },
+                }),
+            },
+            value: parser::parse_json(&json!([
+                [{"prim": "DROP"}, {"prim": "UNIT"}],
+                [{"prim": "Lambda_rec", "args": [[{"prim": "DROP"}]]}],
+            ]))
+            .unwrap(),
+            tx_context: TxContext {
+                id: Some(32),
+                level: 10,
+                contract: "test".to_string(),
+                operation_group_number: 1,
+                operation_number: 2,
+                content_number: 3,
+                internal_number: None,
+            },
+            exp_inserts: vec![
+                Insert {
+                    table_name: "storage".to_string(),
+                    id: 1,
+                    fk_id: None,
+                    columns: vec![Column {
+                        name: "tx_context_id".to_string(),
+                        value: insert::Value::BigInt(32),
+                    }],
+                },
+                Insert {
+                    table_name: "storage.proposals".to_string(),
+                    id: 2,
+                    fk_id: Some(1),
+                    columns: vec![
+                        Column {
+                            name: "tx_context_id".to_string(),
+                            value: insert::Value::BigInt(32),
+                        },
+                        Column {
+                            name: "proposals".to_string(),
+                            value: insert::Value::Micheline(insert::RawJson(
+                                r#"[{"prim":"DROP"},{"prim":"UNIT"}]"#
+                                    .to_string(),
+                            )),
+                        },
+                    ],
+                },
+                Insert {
+                    table_name: "storage.proposals".to_string(),
+                    id: 3,
+                    fk_id: Some(1),
+                    columns: vec![
+                        Column {
+                            name: "tx_context_id".to_string(),
+                            value: insert::Value::BigInt(32),
+                        },
+                        Column {
+                            name: "proposals".to_string(),
+                            value: insert::Value::Micheline(insert::RawJson(
+                                r#"[{"args":[[{"prim":"DROP"}]],"prim":"Lambda_rec"}]"#
+                                    .to_string(),
+                            )),
+                        },
+                    ],
+                },
+            ],
+        },
     ];
 
     for tc in tests {
@@ -1855,6 +2291,8 @@ fn test_process_block() {
                         storage_ast: rel_ast.clone(),
                         level_floor: None,
                         entrypoint_asts: HashMap::new(),
+                        tables: HashMap::new(),
+                        selection: Default::default(),
                     },
                 )
                 .unwrap();
@@ -1916,6 +2354,14 @@ impl crate::octez::node::StorageGetter for DummyStorageGetter {
     ) -> Result<Option<serde_json::Value>> {
         Err(anyhow!("dummy storage getter was not expected to be called in test_block tests"))
     }
+
+    fn get_sapling_root(
+        &self,
+        _level: u32,
+        _sapling_state: i32,
+    ) -> Result<String> {
+        Err(anyhow!("dummy storage getter was not expected to be called in test_block tests"))
+    }
 }
 
 #[cfg(test)]
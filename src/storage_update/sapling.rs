@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+/// All sapling state updates of a contract end up in this table, in the
+/// contract's schema.
+pub(crate) const UPDATES_TABLE: &str = "sapling_state_updates";
+
+/// Summary of what happened to a sapling state in one operation, taken from
+/// the lazy_storage_diff (see bigmap::Op::from_raw_lazy). The diff does not
+/// contain the root of the resulting state, it's requested from the node's
+/// context instead. The context only has the state as of the end of the
+/// level, so that is the root of all updates to a state within a level.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SaplingStateUpdate {
+    pub sapling_state: i32,
+    pub action: String,
+    pub source: Option<i32>,
+    pub memo_size: Option<u32>,
+    pub commitments: usize,
+    pub nullifiers: usize,
+    /// The root of the state as of the end of the level (not necessarily
+    /// that right after this update)
+    pub root: Option<String>,
+}
+
+/// The states of a level's updates that still exist at the end of the
+/// level, ie that have a root that can be requested. Temporary states (with
+/// a negative id) do not outlive their operation, and a removed state is
+/// gone for good (ids are not reused), whichever update of the level
+/// removed it.
+pub(crate) fn states_with_root<'a>(
+    updates: impl Iterator<Item = &'a SaplingStateUpdate>,
+) -> HashSet<i32> {
+    let mut res: HashSet<i32> = HashSet::new();
+    let mut removed: HashSet<i32> = HashSet::new();
+    for update in updates {
+        if update.action == "remove" {
+            removed.insert(update.sapling_state);
+        } else if update.sapling_state >= 0 {
+            res.insert(update.sapling_state);
+        }
+    }
+    res.retain(|state| !removed.contains(state));
+    res
+}
+
+#[test]
+fn test_states_with_root() {
+    use pretty_assertions::assert_eq;
+
+    fn update(sapling_state: i32, action: &str) -> SaplingStateUpdate {
+        SaplingStateUpdate {
+            sapling_state,
+            action: action.to_string(),
+            source: None,
+            memo_size: None,
+            commitments: 0,
+            nullifiers: 0,
+            root: None,
+        }
+    }
+
+    struct TestCase {
+        name: &'static str,
+        updates: Vec<SaplingStateUpdate>,
+        exp: Vec<i32>,
+    }
+    let tests: Vec<TestCase> = vec![
+        TestCase {
+            name: "updated states",
+            updates: vec![update(14, "update"), update(15, "alloc")],
+            exp: vec![14, 15],
+        },
+        TestCase {
+            name: "temporary state copied into a new state",
+            updates: vec![update(-1, "alloc"), update(16, "copy")],
+            exp: vec![16],
+        },
+        TestCase {
+            name: "state removed by a later update of the level",
+            updates: vec![
+                update(14, "update"),
+                update(15, "update"),
+                update(14, "remove"),
+            ],
+            exp: vec![15],
+        },
+    ];
+    for tc in tests {
+        println!("test case: {}", tc.name);
+
+        let mut got: Vec<i32> = states_with_root(tc.updates.iter())
+            .into_iter()
+            .collect();
+        got.sort_unstable();
+        assert_eq!(tc.exp, got);
+    }
+}
@@ -27,6 +27,7 @@ pub enum Value {
     Pair(Box<Value>, Box<Value>),
     Left(Box<Value>),
     Right(Box<Value>),
+    Micheline(String),
 }
 
 impl Value {
@@ -68,6 +69,34 @@ impl Value {
         }
     }
 
+    /// The raw Micheline of a lambda value. Its instructions are parsed as
+    /// is, but its sequences are parsed as lists (or as nested pairs if it's
+    /// the top level value), these are folded back into sequences here.
+    pub fn to_micheline(&self) -> Result<String> {
+        fn code(v: &Value) -> Result<serde_json::Value> {
+            match v {
+                Value::Micheline(json) => Ok(serde_json::from_str(json)?),
+                Value::List(xs) => Ok(serde_json::Value::Array(
+                    xs.iter()
+                        .map(code)
+                        .collect::<Result<_>>()?,
+                )),
+                Value::Pair(..) => code(&v.unpair_list()?),
+                _ => Err(anyhow!("not a lambda value: {:?}", v)),
+            }
+        }
+        let json = match code(self)? {
+            // a sequence of a single instruction, lexed into the instruction
+            instr @ serde_json::Value::Object(_)
+                if instr["prim"] != "Lambda_rec" =>
+            {
+                json!([instr])
+            }
+            json => json,
+        };
+        Ok(json.to_string())
+    }
+
     pub fn unpair_elts(&self) -> Result<Value> {
         match self {
             Value::Pair(l, rest) => {
@@ -170,6 +199,11 @@ pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
         _ => vec![],
     };
     if let Some(s) = &json["prim"].as_str() {
+        if is_instruction(s) {
+            // part of a lambda, whether it is code or data is decided by the
+            // type when the value is processed (see Value::to_micheline)
+            return Ok(Value::Micheline(json.to_string()));
+        }
         let mut prim = s.to_string();
         prim.make_ascii_uppercase();
         match prim.as_str() {
@@ -205,7 +239,6 @@ pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
                     }
                 }
             }
-            "PUSH" => return Ok(Value::None),
             "SOME" => {
                 if !args.is_empty() {
                     return parse_lexed(&args[0]);
@@ -264,6 +297,16 @@ pub(crate) fn parse_lexed(json: &serde_json::Value) -> Result<Value> {
     Ok(Value::None)
 }
 
+/// Instructions are distinguishable from data constructors because their
+/// prims are fully uppercased (eg PAIR vs Pair). Lambda_rec wraps the code of
+/// a recursive lambda, so it's kept as is as well.
+fn is_instruction(prim: &str) -> bool {
+    prim == "Lambda_rec"
+        || prim
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
 pub(crate) fn lexer_unfold_many_pair(
     v: &mut Vec<serde_json::Value>,
 ) -> serde_json::Value {